@@ -5,6 +5,7 @@ use test::Bencher;
 use std::io::BufReader;
 
 use xml_air::parser::{XmlReader};
+use xml_air::scan::find_special;
 
 
 static LINE: &'static str = "test \n reader\r\n";
@@ -48,6 +49,35 @@ fn xml_reader_throughput_all(b: &mut test::Bencher) {
 }
 
 
+/// A single long text node, without new lines
+pub fn make_long_text() -> String {
+    let mut result: String = String::new();
+    for _ in range(0u, 1000) { result.push_str("some plain text "); }
+    result.push('<');
+    result
+}
+
+#[bench]
+fn xml_reader_text_throughput(b: &mut test::Bencher) {
+    let file = make_long_text();
+    b.bytes = file.len() as u64;
+    b.iter(|| -> () {
+        let mut input = BufReader::new(file.as_bytes());
+        let mut reader = XmlReader::from_reader(&mut input);
+
+        reader.read_until('<', true);
+    });
+}
+
+#[bench]
+fn scan_throughput(b: &mut test::Bencher) {
+    let file = make_long_text();
+    b.bytes = file.len() as u64;
+    b.iter(|| -> () {
+        find_special(file.as_bytes());
+    });
+}
+
 #[bench]
 fn reader_throughput(b: &mut test::Bencher) {
     let file = make_pretend_file();
@@ -0,0 +1,181 @@
+//! Parsing of input from readers that don't block, e.g. sockets with
+//! a zero read timeout, as part of an event loop.
+//!
+//! `AsyncParser` polls its reader for input and feeds it to a
+//! `PushParser`, so the same XML ER state machine is used as by `Parser`.
+//! Polling never waits for input: if the reader has none, no event is
+//! ready, and the caller polls again once the reader is readable.
+//! Only built with `async` feature.
+
+use std::collections::{RingBuf, Deque};
+use std::io::{Reader, IoResult, IoError, EndOfFile, ResourceUnavailable, TimedOut};
+
+use parser::XmlEvent;
+use push::PushParser;
+
+/// Outcome of polling for a value, which may not be available yet
+#[deriving(PartialEq, Eq, Clone, Show)]
+pub enum Async<T> {
+    Ready(T),
+    NotReady
+}
+
+/// Result of polling, failing with errors of the reader
+pub type Poll<T> = IoResult<Async<T>>;
+
+/// Sequence of values that are polled for, without blocking
+pub trait Stream<T> {
+    /// Returns `Ready(Some(value))` with next value, `Ready(None)` once
+    /// there are no more, or `NotReady` if next one isn't available yet.
+    fn poll(&mut self) -> Poll<Option<T>>;
+}
+
+/// Size of chunks read from the reader
+static CHUNK_LEN: uint = 4096;
+
+/// Parser over a reader that fails with `ResourceUnavailable` or
+/// `TimedOut` when it has no input yet, instead of blocking.
+///
+/// E.g.
+///
+///     let mut socket = try!(TcpStream::connect("example.com", 80));
+///     socket.set_read_timeout(Some(0));
+///     let mut parser = AsyncParser::new(socket);
+///     loop {
+///         match try!(parser.poll()) {
+///             Ready(Some(event))  => ...,
+///             Ready(None)         => break,
+///             // Other work is done until socket is readable
+///             NotReady            => ...
+///         }
+///     }
+pub struct AsyncParser<R> {
+    reader: R,
+    parser: PushParser,
+    buf: Vec<u8>,
+    /// Events of received input, not polled yet
+    events: RingBuf<XmlEvent<'static>>,
+    /// Whether all input was parsed
+    done: bool
+}
+
+impl<R: Reader> AsyncParser<R> {
+    pub fn new(reader: R) -> AsyncParser<R> {
+        AsyncParser::with_parser(reader, PushParser::new())
+    }
+
+    /// Constructs AsyncParser using `parser`, e.g. one with
+    /// forced encoding or error behavior.
+    pub fn with_parser(reader: R, parser: PushParser) -> AsyncParser<R> {
+        AsyncParser {
+            reader: reader,
+            parser: parser,
+            buf: Vec::from_elem(CHUNK_LEN, 0u8),
+            events: RingBuf::new(),
+            done: false
+        }
+    }
+
+    /// Returns the reader, e.g. to register it with an event loop
+    pub fn get_ref<'a>(&'a self) -> &'a R {
+        &self.reader
+    }
+}
+
+impl<R: Reader> Stream<XmlEvent<'static>> for AsyncParser<R> {
+    /// Returns next event if it can be parsed from input the reader
+    /// has so far. Reads as much input as it needs, and no more.
+    fn poll(&mut self) -> Poll<Option<XmlEvent<'static>>> {
+        while self.events.is_empty() && !self.done {
+            let events = match self.reader.read(self.buf.as_mut_slice()) {
+                Ok(0)   => return Ok(NotReady),
+                Ok(len) => self.parser.feed(self.buf.slice_to(len)),
+                Err(IoError{kind: EndOfFile, ..}) => {
+                    self.done = true;
+                    self.parser.finish()
+                },
+                Err(IoError{kind: ResourceUnavailable, ..})
+                | Err(IoError{kind: TimedOut, ..}) => return Ok(NotReady),
+                Err(err) => return Err(err)
+            };
+            self.events.extend(events.into_iter());
+        }
+        Ok(Ready(self.events.pop_front()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AsyncParser, Stream, Ready, NotReady};
+    use parser::{XmlEvent, ElemStart, ElemEnd, TextEvent};
+    use name::Name;
+    use std::io::{Reader, IoResult, EndOfFile, ResourceUnavailable, BrokenPipe};
+    use std::io::standard_error;
+    use std::slice::bytes::copy_memory;
+    use std::str::Owned;
+
+    /// Reader that returns one chunk at a time, and has no input
+    /// before each of them, like a socket input arrives at in packets
+    struct Packets {
+        chunks: Vec<&'static [u8]>,
+        ready: bool
+    }
+
+    impl Reader for Packets {
+        fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+            if self.chunks.is_empty() {
+                return Err(standard_error(EndOfFile))
+            }
+            if !self.ready {
+                self.ready = true;
+                return Err(standard_error(ResourceUnavailable))
+            }
+            self.ready = false;
+            let chunk = self.chunks.remove(0).unwrap();
+            copy_memory(buf, chunk);
+            Ok(chunk.len())
+        }
+    }
+
+    fn packets(chunks: &[&'static [u8]]) -> Packets {
+        Packets {
+            chunks: chunks.to_vec(),
+            ready: false
+        }
+    }
+
+    fn text(text: &str) -> XmlEvent<'static> {
+        TextEvent(Owned(text.to_string()))
+    }
+
+    #[test]
+    fn test_poll_packets() {
+        let mut parser = AsyncParser::new(packets(&[b"<a>te", b"xt</", b"a>"]));
+        assert_eq!(Ok(NotReady), parser.poll());
+        match parser.poll() {
+            Ok(Ready(Some(ElemStart(elem)))) => assert_eq!("a", elem.name.as_slice()),
+            ev => assert!(false, "expected start tag, got {}", ev)
+        }
+        assert_eq!(Ok(Ready(Some(text("te")))), parser.poll());
+        assert_eq!(Ok(NotReady), parser.poll());
+        assert_eq!(Ok(Ready(Some(text("xt")))), parser.poll());
+        assert_eq!(Ok(NotReady), parser.poll());
+        assert_eq!(Ok(Ready(Some(ElemEnd(Name::new("a"))))), parser.poll());
+        assert_eq!(Ok(Ready(None)), parser.poll());
+        assert_eq!(Ok(Ready(None)), parser.poll());
+    }
+
+    #[test]
+    fn test_poll_error() {
+        struct Broken;
+        impl Reader for Broken {
+            fn read(&mut self, _: &mut [u8]) -> IoResult<uint> {
+                Err(standard_error(BrokenPipe))
+            }
+        }
+        match AsyncParser::new(Broken).poll() {
+            Err(err) => assert_eq!(BrokenPipe, err.kind),
+            ev => assert!(false, "expected error, got {}", ev)
+        }
+    }
+}
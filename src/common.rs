@@ -1,6 +1,10 @@
 use std::vec::Vec;
 use std::fmt;
 use std::string::String;
+use std::mem;
+use std::slice;
+use name::Name;
+use util::split_name;
 
 /// A struct representing an XML root document
 pub struct XDoc {
@@ -16,10 +20,21 @@ pub struct XDoc {
 #[deriving(Clone, PartialEq, Eq, Show)]
 pub struct XPi {
     /// The processing instruction's target
-    target: String,
+    pub target: String,
     /// The processing instruction's value
     /// Must not contain ?>
-    value: String
+    pub value: String
+}
+
+/// A struct representing an XML declaration
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct XDecl {
+    /// Declared XML version
+    pub version: String,
+    /// Declared encoding of the document
+    pub encoding: Option<String>,
+    /// Whether document was declared standalone
+    pub standalone: Option<bool>
 }
 
 #[deriving(Clone, PartialEq, Eq, Show)]
@@ -33,7 +48,7 @@ pub struct XDoctype {
 #[deriving(Clone, PartialEq, Eq, Show)]
 pub struct XElem {
     /// The element's name
-    pub name: String,
+    pub name: Name,
     /// The element's namespace
     pub namespace: XmlNS,
     /// The element's `Attribute`s
@@ -48,20 +63,25 @@ pub struct XElem {
 #[deriving(Clone, PartialEq, Eq, Show)]
 pub struct XmlAttr {
     /// The attribute's name
-    pub name: String,
+    pub name: Name,
     /// The attribute's value
     pub value: String,
     /// The attribute's namespace
     pub namespace: XmlNS
 }
 
-#[deriving(Clone, PartialEq, Eq, Show)]
+/// Namespace bound to `xml` prefix
+pub static XML_NS_URI: &'static str = "http://www.w3.org/XML/1998/namespace";
+/// Namespace of `xmlns` attributes, which declare namespaces
+pub static XMLNS_NS_URI: &'static str = "http://www.w3.org/2000/xmlns/";
+
+#[deriving(Clone, PartialEq, Eq, Show, Encodable, Decodable)]
 /// A struct that models an XML namespace
 pub struct XmlNS {
     /// The namespace's shorthand name
-    pub name: String,
+    pub name: Name,
     /// The namespace's uri value
-    pub uri: String
+    pub uri: Name
 }
 
 
@@ -69,26 +89,577 @@ pub struct XmlNS {
 /// An Enum describing a XML Node
 #[deriving(Clone, PartialEq, Eq, Show)]
 pub enum XNode {
+    ElemNode(XElem),
+    TextNode(String),
+    CDataNode(String),
+    CommentNode(String),
+    PINode(XPi)
+}
+
+impl XElem {
+    /// Checks if element has qualified name `name`, as written in
+    /// the document, e.g. `p:rec`
+    pub fn is_named(&self, name: &str) -> bool {
+        let (prefix, local) = split_name(name);
+        self.name.as_slice() == local && self.namespace.name.as_slice() == prefix
+    }
+
+    /// Checks if element has local name `local` in namespace `uri`,
+    /// whatever prefix it was written with
+    pub fn is_named_ns(&self, uri: &str, local: &str) -> bool {
+        self.name.as_slice() == local && self.namespace.uri.as_slice() == uri
+    }
+
+    /// Returns value of attribute with qualified name `name`
+    pub fn attr<'a>(&'a self, name: &str) -> Option<&'a str> {
+        let (prefix, local) = split_name(name);
+        self.attributes.iter()
+            .find(|attr| attr.name.as_slice() == local
+                         && attr.namespace.name.as_slice() == prefix)
+            .map(|attr| attr.value.as_slice())
+    }
+
+    /// Returns value of attribute with local name `local` in
+    /// namespace `uri`. Unprefixed attributes are in no namespace,
+    /// which `uri` of `""` stands for.
+    pub fn attr_ns<'a>(&'a self, uri: &str, local: &str) -> Option<&'a str> {
+        self.attributes.iter()
+            .find(|attr| attr.name.as_slice() == local
+                         && attr.namespace.uri.as_slice() == uri)
+            .map(|attr| attr.value.as_slice())
+    }
+
+    /// Iterates over child elements
+    pub fn child_elems<'a>(&'a self) -> ChildElems<'a> {
+        ChildElems {
+            iter: self.children.iter()
+        }
+    }
+
+    /// Returns first child element
+    pub fn first_child<'a>(&'a self) -> Option<&'a XElem> {
+        self.child_elems().next()
+    }
+
+    /// Iterates over child elements with qualified name `name`
+    pub fn children_named<'a, 'b>(&'a self, name: &'b str) -> ChildrenNamed<'a, 'b> {
+        ChildrenNamed {
+            elems: self.child_elems(),
+            uri: None,
+            name: name
+        }
+    }
+
+    /// Iterates over child elements with local name `local` in
+    /// namespace `uri`
+    pub fn children_named_ns<'a, 'b>(&'a self, uri: &'b str, local: &'b str)
+                                 -> ChildrenNamed<'a, 'b> {
+        ChildrenNamed {
+            elems: self.child_elems(),
+            uri: Some(uri),
+            name: local
+        }
+    }
+
+    /// Iterates over nodes below element in document order
+    pub fn descendants<'a>(&'a self) -> Descendants<'a> {
+        Descendants {
+            stack: vec![self.children.iter()]
+        }
+    }
+
+    /// Concatenates text and CDATA below element
+    pub fn text(&self) -> String {
+        let mut text = String::new();
+        for node in self.descendants() {
+            match *node {
+                TextNode(ref s) | CDataNode(ref s) => text.push_str(s.as_slice()),
+                _ => {}
+            }
+        }
+        text
+    }
+}
+
+/// Editing of elements. Names of elements and attributes are bound by
+/// their `XmlNS`, and edits add the namespace declarations those need,
+/// so an edited element is written with the same names.
+impl XElem {
+    /// Constructs element with local name `local` in `namespace`.
+    /// Namespace is declared once element is added to a document or
+    /// another element, or by `declare_namespaces`.
+    pub fn new(namespace: XmlNS, local: &str) -> XElem {
+        XElem {
+            name: Name::new(local),
+            namespace: namespace,
+            attributes: Vec::new(),
+            children: Vec::new()
+        }
+    }
+
+    /// Sets value of unprefixed attribute `name`, adding it if missing
+    pub fn set_attr(&mut self, name: &str, value: &str) {
+        let no_ns = XmlNS {
+            name: Name::new(""),
+            uri: Name::new("")
+        };
+        self.put_attr(no_ns, name, value);
+    }
+
+    /// Sets value of attribute with local name `local` in `namespace`,
+    /// adding it if missing. Namespaced attributes need a prefix, so
+    /// without one a prefix bound to the namespace in element is used,
+    /// or a new one is made up.
+    pub fn set_attr_ns(&mut self, namespace: XmlNS, local: &str, value: &str) {
+        if namespace.uri.as_slice().is_empty() {
+            return self.set_attr(local, value)
+        }
+        let namespace = if namespace.name.as_slice().is_empty() {
+            self.attr_prefix(namespace.uri.as_slice())
+        } else {
+            namespace
+        };
+        let is_bound = is_bound(self.in_scope().as_slice(), &namespace);
+        self.put_attr(namespace.clone(), local, value);
+        if !is_bound {
+            self.declare(&namespace);
+            self.redeclare_children();
+        }
+    }
+
+    /// Returns namespace `uri` with a prefix bound to it in element,
+    /// or with an unused prefix `nsN` if there is none
+    fn attr_prefix(&self, uri: &str) -> XmlNS {
+        let scope = self.in_scope();
+        let bound = scope.iter().rev().find(|ns| {
+            !ns.name.as_slice().is_empty() && ns.uri.as_slice() == uri
+                && is_bound(scope.as_slice(), *ns)
+        });
+        match bound {
+            Some(ns)    => return ns.clone(),
+            None        => {}
+        }
+        let mut i = 0u;
+        loop {
+            let prefix = format!("ns{}", i);
+            if !scope.iter().any(|ns| ns.name.as_slice() == prefix.as_slice()) {
+                return XmlNS {
+                    name: Name::new(prefix.as_slice()),
+                    uri: Name::new(uri)
+                }
+            }
+            i += 1;
+        }
+    }
+
+    /// Sets value of attribute, replacing one with same namespace and
+    /// local name
+    fn put_attr(&mut self, namespace: XmlNS, local: &str, value: &str) {
+        for attr in self.attributes.iter_mut() {
+            if attr.name.as_slice() == local && attr.namespace.uri == namespace.uri {
+                attr.namespace = namespace;
+                attr.value = value.to_string();
+                return
+            }
+        }
+        self.attributes.push(XmlAttr {
+            name: Name::new(local),
+            value: value.to_string(),
+            namespace: namespace
+        });
+    }
+
+    /// Removes attribute with qualified name `name` and returns its
+    /// value. Namespace declarations are kept, since names rely on them.
+    pub fn remove_attr(&mut self, name: &str) -> Option<String> {
+        let (prefix, local) = split_name(name);
+        let pos = self.attributes.iter().position(|attr| {
+            !attr.is_declaration() && attr.name.as_slice() == local
+                && attr.namespace.name.as_slice() == prefix
+        });
+        pos.and_then(|pos| self.attributes.remove(pos)).map(|attr| attr.value)
+    }
+
+    /// Removes attribute with local name `local` in namespace `uri`
+    /// and returns its value. Namespace declarations are kept.
+    pub fn remove_attr_ns(&mut self, uri: &str, local: &str) -> Option<String> {
+        let pos = self.attributes.iter().position(|attr| {
+            !attr.is_declaration() && attr.name.as_slice() == local
+                && attr.namespace.uri.as_slice() == uri
+        });
+        pos.and_then(|pos| self.attributes.remove(pos)).map(|attr| attr.value)
+    }
+
+    /// Adds `node` after the last child
+    pub fn append_child(&mut self, node: XNode) {
+        let len = self.children.len();
+        self.insert_child(len, node);
+    }
+
+    /// Inserts `node` as child at `index`
+    ///
+    /// Fails if `index` is greater than number of children
+    pub fn insert_child(&mut self, index: uint, node: XNode) {
+        let mut node = node;
+        self.declare_in_child(&mut node);
+        self.children.insert(index, node);
+    }
+
+    /// Removes and returns child at `index`, if there is one.
+    /// Removed element keeps names it had, see `detach_child`.
+    pub fn remove_child(&mut self, index: uint) -> Option<XNode> {
+        self.children.remove(index).map(|node| match node {
+            ElemNode(mut elem)  => {
+                elem.declare_namespaces();
+                ElemNode(elem)
+            },
+            node                => node
+        })
+    }
+
+    /// Replaces child at `index` with `node` and returns the old child
+    ///
+    /// Fails if there is no child at `index`
+    pub fn replace_child(&mut self, index: uint, node: XNode) -> XNode {
+        let old = self.remove_child(index).expect("no child to replace");
+        self.insert_child(index, node);
+        old
+    }
+
+    /// Removes element child at `index` and returns it, along with
+    /// declarations of namespaces it used from its ancestors. Returns
+    /// `None` if child isn't an element.
+    pub fn detach_child(&mut self, index: uint) -> Option<XElem> {
+        match self.children.as_slice().get(index) {
+            Some(&ElemNode(_))  => {},
+            _                   => return None
+        }
+        match self.remove_child(index) {
+            Some(ElemNode(elem))    => Some(elem),
+            _                       => None
+        }
+    }
+
+    /// Changes local name of element, keeping its namespace
+    pub fn rename(&mut self, local: &str) {
+        self.name = Name::new(local);
+    }
+
+    /// Changes local name and namespace of element
+    pub fn rename_ns(&mut self, namespace: XmlNS, local: &str) {
+        self.name = Name::new(local);
+        if namespace == self.namespace {
+            return
+        }
+        let is_bound = is_bound(self.bindings().as_slice(), &namespace);
+        self.namespace = namespace.clone();
+        if !is_bound {
+            self.declare(&namespace);
+        }
+        self.redeclare_children();
+    }
+
+    /// Makes element the only child of `wrapper`, and returns wrapper
+    pub fn wrap(self, wrapper: XElem) -> XElem {
+        let mut wrapper = wrapper;
+        wrapper.append_child(ElemNode(self));
+        wrapper
+    }
+
+    /// Replaces element child at `index` with its own children.
+    /// Returns whether child was an element.
+    pub fn unwrap_child(&mut self, index: uint) -> bool {
+        let elem = match self.detach_child(index) {
+            Some(elem)  => elem,
+            None        => return false
+        };
+        for (i, child) in elem.children.into_iter().enumerate() {
+            self.insert_child(index + i, child);
+        }
+        true
+    }
+
+    /// Adds declarations for namespaces of names in element and its
+    /// descendants, that aren't declared in element or between element
+    /// and the name.
+    pub fn declare_namespaces(&mut self) {
+        declare_in_scope(self, &mut Vec::new());
+    }
+
+    /// Namespaces known to be bound in element, through its name,
+    /// attributes and declarations. Later ones take precedence.
+    fn in_scope(&self) -> Vec<XmlNS> {
+        let mut scope = vec![self.namespace.clone()];
+        scope.extend(self.bindings().into_iter());
+        scope
+    }
+
+    /// Namespaces bound in element through its attributes and
+    /// declarations
+    fn bindings(&self) -> Vec<XmlNS> {
+        let mut scope = Vec::new();
+        for attr in self.attributes.iter() {
+            if !attr.is_declaration() && !attr.namespace.name.as_slice().is_empty() {
+                scope.push(attr.namespace.clone());
+            }
+        }
+        scope.extend(self.attributes.iter().filter_map(|attr| attr.declared_ns()));
+        scope
+    }
+
+    /// Declares `namespace` in element, replacing declaration
+    /// of the same prefix
+    fn declare(&mut self, namespace: &XmlNS) {
+        for attr in self.attributes.iter_mut() {
+            match attr.declared_ns() {
+                Some(ref ns) if ns.name == namespace.name => {
+                    attr.value = namespace.uri.as_slice().to_string();
+                    return
+                },
+                _ => {}
+            }
+        }
+        self.attributes.push(XmlAttr::declaration(namespace));
+    }
+
+    /// Adds declarations child `node` needs, given namespaces bound
+    /// in element
+    fn declare_in_child(&self, node: &mut XNode) {
+        match *node {
+            ElemNode(ref mut elem)  => declare_in_scope(elem, &mut self.in_scope()),
+            _                       => {}
+        }
+    }
+
+    /// Adds declarations children need after bindings in element changed
+    fn redeclare_children(&mut self) {
+        let mut scope = self.in_scope();
+        for child in self.children.iter_mut() {
+            match *child {
+                ElemNode(ref mut elem)  => declare_in_scope(elem, &mut scope),
+                _                       => {}
+            }
+        }
+    }
+}
+
+/// Adds declarations `elem` and its descendants need, given namespaces
+/// bound in `scope`
+fn declare_in_scope(elem: &mut XElem, scope: &mut Vec<XmlNS>) {
+    let outer = scope.len();
+    scope.extend(elem.attributes.iter().filter_map(|attr| attr.declared_ns()));
+
+    let mut used = vec![elem.namespace.clone()];
+    for attr in elem.attributes.iter() {
+        if !attr.is_declaration() && !attr.namespace.name.as_slice().is_empty() {
+            used.push(attr.namespace.clone());
+        }
+    }
+    for ns in used.into_iter() {
+        // Prefix that wasn't bound when parsed can't be declared
+        let is_unbound = ns.uri.as_slice().is_empty() && !ns.name.as_slice().is_empty();
+        if !is_unbound && !is_bound(scope.as_slice(), &ns) {
+            elem.declare(&ns);
+            scope.push(ns);
+        }
+    }
+
+    for child in elem.children.iter_mut() {
+        match *child {
+            ElemNode(ref mut child) => declare_in_scope(child, scope),
+            _                       => {}
+        }
+    }
+    scope.truncate(outer);
+}
+
+/// Checks if prefix of `ns` is bound to its uri in `scope`
+fn is_bound(scope: &[XmlNS], ns: &XmlNS) -> bool {
+    match ns.name.as_slice() {
+        "xml" | "xmlns" => return true,
+        _               => {}
+    }
+    match scope.iter().rev().find(|bound| bound.name == ns.name) {
+        Some(bound) => bound.uri == ns.uri,
+        // Unprefixed names are in no namespace by default
+        None        => ns.name.as_slice().is_empty() && ns.uri.as_slice().is_empty()
+    }
+}
+
+impl XmlAttr {
+    /// Constructs attribute that declares `namespace`
+    pub fn declaration(namespace: &XmlNS) -> XmlAttr {
+        let (name, prefix) = if namespace.name.as_slice().is_empty() {
+            (Name::new("xmlns"), Name::new(""))
+        } else {
+            (namespace.name.clone(), Name::new("xmlns"))
+        };
+        XmlAttr {
+            name: name,
+            value: namespace.uri.as_slice().to_string(),
+            namespace: XmlNS {
+                name: prefix,
+                uri: Name::new(XMLNS_NS_URI)
+            }
+        }
+    }
+
+    /// Checks if attribute declares a namespace
+    pub fn is_declaration(&self) -> bool {
+        self.namespace.uri.as_slice() == XMLNS_NS_URI
+    }
+
+    /// Returns namespace declared by attribute, if it's a declaration
+    pub fn declared_ns(&self) -> Option<XmlNS> {
+        if !self.is_declaration() {
+            return None
+        }
+        let prefix = if self.namespace.name.as_slice().is_empty() {
+            Name::new("")
+        } else {
+            self.name.clone()
+        };
+        Some(XmlNS {
+            name: prefix,
+            uri: Name::new(self.value.as_slice())
+        })
+    }
+}
+
+impl XDoc {
+    /// Constructs document with `root` element
+    pub fn new(root: XElem) -> XDoc {
+        let mut root = root;
+        root.declare_namespaces();
+        XDoc {
+            root: root,
+            pi: Vec::new()
+        }
+    }
+
+    pub fn root<'a>(&'a self) -> &'a XElem {
+        &self.root
+    }
+
+    pub fn root_mut<'a>(&'a mut self) -> &'a mut XElem {
+        &mut self.root
+    }
+
+    /// Replaces root element with `root`, and returns the old one
+    pub fn set_root(&mut self, root: XElem) -> XElem {
+        let mut root = root;
+        root.declare_namespaces();
+        mem::replace(&mut self.root, root)
+    }
+
+    /// Makes root element the only child of `wrapper`
+    pub fn wrap_root(&mut self, wrapper: XElem) {
+        let mut wrapper = wrapper;
+        wrapper.declare_namespaces();
+        let root = mem::replace(&mut self.root, wrapper);
+        self.root.append_child(ElemNode(root));
+    }
+
+    /// Returns processing instructions before root element
+    pub fn pis<'a>(&'a self) -> &'a [XPi] {
+        self.pi.as_slice()
+    }
+
+    /// Adds processing instruction before root element
+    pub fn push_pi(&mut self, pi: XPi) {
+        self.pi.push(pi);
+    }
+}
+
+/// Iterator over child elements, see `XElem::child_elems`
+pub struct ChildElems<'a> {
+    iter: slice::Items<'a, XNode>
+}
+
+impl<'a> Iterator<&'a XElem> for ChildElems<'a> {
+    fn next(&mut self) -> Option<&'a XElem> {
+        for node in self.iter.by_ref() {
+            match *node {
+                ElemNode(ref elem)  => return Some(elem),
+                _                   => {}
+            }
+        }
+        None
+    }
+}
+
+/// Iterator over child elements with given name,
+/// see `XElem::children_named`
+pub struct ChildrenNamed<'a, 'b> {
+    elems: ChildElems<'a>,
+    /// Namespace to match local name in, qualified name is matched
+    /// if it's `None`
+    uri: Option<&'b str>,
+    name: &'b str
+}
+
+impl<'a, 'b> Iterator<&'a XElem> for ChildrenNamed<'a, 'b> {
+    fn next(&mut self) -> Option<&'a XElem> {
+        for elem in self.elems.by_ref() {
+            let is_match = match self.uri {
+                Some(uri)   => elem.is_named_ns(uri, self.name),
+                None        => elem.is_named(self.name)
+            };
+            if is_match {
+                return Some(elem)
+            }
+        }
+        None
+    }
+}
+
+/// Iterator over nodes below an element, see `XElem::descendants`
+pub struct Descendants<'a> {
+    /// Children left to visit, for each element on the way down
+    stack: Vec<slice::Items<'a, XNode>>
+}
+
+impl<'a> Iterator<&'a XNode> for Descendants<'a> {
+    fn next(&mut self) -> Option<&'a XNode> {
+        loop {
+            let node = match self.stack.last_mut() {
+                Some(iter)  => iter.next(),
+                None        => return None
+            };
+            match node {
+                Some(node)  => {
+                    match *node {
+                        ElemNode(ref elem)  => self.stack.push(elem.children.iter()),
+                        _                   => {}
+                    }
+                    return Some(node)
+                },
+                None        => { self.stack.pop(); }
+            }
+        }
+    }
 }
 
 
 /// If an error occurs while parsing some XML, this is the structure which is
 /// returned
-#[deriving(PartialEq, Eq, Clone, Show)]
+#[deriving(PartialEq, Eq, Clone, Show, Encodable, Decodable)]
 pub struct XmlError {
     /// The line number at which the error occurred
     pub line: uint,
     /// The column number at which the error occurred
     pub col: uint,
+    /// Byte offset in input at which the error occurred
+    pub offset: u64,
     /// A message describing the type of the error
     pub msg: String,
     /// Type of error
-    //kind: ErrKind,
+    pub kind: ErrKind,
     /// Position and context of error in Context
     pub mark: Option<Mark>
 }
 
-#[deriving(PartialEq, Eq, Clone, Show)]
+#[deriving(PartialEq, Eq, Clone, Show, Encodable, Decodable)]
 pub enum ErrKind {
     NonDigitError,
     UnreadableChar,
@@ -98,17 +669,56 @@ pub enum ErrKind {
     NumParsingError,
     RestrictedCharError,
     MinMinInComment,
-    PrematureEOF
+    PrematureEOF,
+    UnknownEntity,
+    UnboundPrefix,
+    MissingAttrValue,
+    UnquotedAttrValue,
+    DuplicateAttr,
+    EmptyEndTag,
+    MismatchedEndTag,
+    UnsupportedDoctype,
+    /// Encoding given in XML declaration can't be decoded, so input
+    /// is read in the encoding detected without it
+    UnsupportedEncoding,
+    /// Attribute, text or child element a mapped field is read from
+    /// is missing, see `mapping` module
+    MissingField,
+    /// Value of a mapped field can't be parsed
+    InvalidValue
 }
 
+/// Describes what parser does upon encountering a recoverable error
+#[deriving(PartialEq, Eq, Clone, Show, Encodable, Decodable)]
 pub enum ErrBehavior {
+    /// Error isn't reported
     Ignore,
+    /// Error is reported and parsing continues
     Warn,
+    /// Error is reported and parsing stops
     Fail
 }
 
+/// Version of XML specification a document follows
+#[deriving(PartialEq, Eq, Clone, Show, Encodable, Decodable)]
+pub enum XmlVersion {
+    Version10,
+    Version11
+}
 
-#[deriving(PartialEq, Eq, Clone)]
+impl XmlVersion {
+    /// Finds version by its value in XML declaration
+    pub fn from_str(version: &str) -> Option<XmlVersion> {
+        match version {
+            "1.0"   => Some(Version10),
+            "1.1"   => Some(Version11),
+            _       => None
+        }
+    }
+}
+
+
+#[deriving(PartialEq, Eq, Clone, Encodable, Decodable)]
 /// This struct models the pretty error output
 pub struct Mark {
     /// Message displayed in first in marked message
@@ -154,3 +764,178 @@ fn main() {
 
 }
 
+
+#[cfg(test)]
+mod test {
+    use super::{XDoc, XElem, XmlAttr, XmlNS, XNode};
+    use super::{ElemNode, TextNode, CDataNode, CommentNode};
+    use name::Name;
+    use writer::{elem_to_string, doc_to_string};
+
+    fn ns(prefix: &str, uri: &str) -> XmlNS {
+        XmlNS {
+            name: Name::new(prefix),
+            uri: Name::new(uri)
+        }
+    }
+
+    fn elem(prefix: &str, uri: &str, name: &str, children: Vec<XNode>) -> XElem {
+        XElem {
+            name: Name::new(name),
+            namespace: ns(prefix, uri),
+            attributes: Vec::new(),
+            children: children
+        }
+    }
+
+    fn text(text: &str) -> XNode {
+        TextNode(text.to_string())
+    }
+
+    /// Tree of `<r xmlns='urn:r' xmlns:p='urn:p' id='1' p:id='2'>`
+    /// with children `a`, `p:a`, comment, `b` and text
+    fn make_tree() -> XElem {
+        let mut root = elem("", "urn:r", "r", vec![
+            ElemNode(elem("", "urn:r", "a", vec![text("x"), CDataNode("y".to_string())])),
+            ElemNode(elem("p", "urn:p", "a", vec![
+                ElemNode(elem("", "urn:r", "c", vec![text("z")]))
+            ])),
+            CommentNode("c".to_string()),
+            ElemNode(elem("", "urn:r", "b", Vec::new())),
+            text("t")
+        ]);
+        root.attributes = vec![
+            XmlAttr { name: Name::new("id"), value: "1".to_string(), namespace: ns("", "") },
+            XmlAttr { name: Name::new("id"), value: "2".to_string(), namespace: ns("p", "urn:p") }
+        ];
+        root
+    }
+
+    #[test]
+    fn test_names() {
+        let root = make_tree();
+        assert!(root.is_named("r"));
+        assert!(!root.is_named("p:r"));
+        assert!(root.is_named_ns("urn:r", "r"));
+        assert!(!root.is_named_ns("", "r"));
+
+        assert_eq!(Some("1"),   root.attr("id"));
+        assert_eq!(Some("2"),   root.attr("p:id"));
+        assert_eq!(None,        root.attr("q:id"));
+        assert_eq!(Some("1"),   root.attr_ns("", "id"));
+        assert_eq!(Some("2"),   root.attr_ns("urn:p", "id"));
+        assert_eq!(None,        root.attr_ns("urn:r", "id"));
+    }
+
+    #[test]
+    fn test_children() {
+        let root = make_tree();
+        assert_eq!(3, root.child_elems().count());
+        assert!(root.first_child().unwrap().is_named("a"));
+        assert_eq!(1, root.children_named("a").count());
+        assert_eq!(1, root.children_named("p:a").count());
+        let named: Vec<&XElem> = root.children_named_ns("urn:p", "a").collect();
+        assert_eq!(1, named.len());
+        assert!(named[0].is_named("p:a"));
+        assert_eq!(0, root.children_named("c").count());
+    }
+
+    #[test]
+    fn test_descendants() {
+        let root = make_tree();
+        // a, x, y, p:a, c, z, comment, b, t
+        assert_eq!(9, root.descendants().count());
+        let elems: Vec<&str> = root.descendants().filter_map(|node| match *node {
+            ElemNode(ref elem)  => Some(elem.name.as_slice()),
+            _                   => None
+        }).collect();
+        assert_eq!(vec!["a", "a", "c", "b"], elems);
+        assert_eq!("xyzt".to_string(), root.text());
+        assert_eq!("z".to_string(), root.children_named("p:a").next().unwrap().text());
+    }
+    #[test]
+    fn test_edit_attrs() {
+        let mut root = XElem::new(ns("", "urn:r"), "r");
+        root.declare_namespaces();
+        root.set_attr("id", "1");
+        root.set_attr_ns(ns("p", "urn:p"), "id", "2");
+        assert_eq!("<r xmlns=\"urn:r\" id=\"1\" p:id=\"2\" xmlns:p=\"urn:p\"/>".to_string(),
+                   elem_to_string(&root));
+
+        root.set_attr("id", "3");
+        assert_eq!(Some("3"),               root.attr("id"));
+        assert_eq!(Some("2".to_string()),   root.remove_attr("p:id"));
+        assert_eq!(None,                    root.remove_attr("xmlns:p"));
+        assert_eq!(Some("3".to_string()),   root.remove_attr_ns("", "id"));
+        assert_eq!("<r xmlns=\"urn:r\" xmlns:p=\"urn:p\"/>".to_string(),
+                   elem_to_string(&root));
+
+        // Attributes need a prefix, even in the default namespace
+        root.set_attr_ns(ns("", "urn:p"), "a", "4");
+        root.set_attr_ns(ns("", "urn:r"), "b", "5");
+        assert_eq!(Some("4"),               root.attr("p:a"));
+        assert_eq!(Some("5"),               root.attr("ns0:b"));
+    }
+
+    #[test]
+    fn test_edit_children() {
+        let mut root = XElem::new(ns("", "urn:r"), "r");
+        root.set_attr_ns(ns("p", "urn:p"), "a", "1");
+        root.declare_namespaces();
+        root.append_child(ElemNode(XElem::new(ns("p", "urn:p"), "c")));
+        root.insert_child(0, text("t"));
+        root.append_child(ElemNode(XElem::new(ns("", "urn:r"), "d")));
+        assert_eq!("<r p:a=\"1\" xmlns:p=\"urn:p\" xmlns=\"urn:r\">t<p:c/><d/></r>".to_string(),
+                   elem_to_string(&root));
+
+        let detached = root.detach_child(1).unwrap();
+        assert_eq!("<p:c xmlns:p=\"urn:p\"/>".to_string(), elem_to_string(&detached));
+        assert_eq!(None, root.detach_child(0));
+
+        match root.replace_child(1, ElemNode(XElem::new(ns("", ""), "e"))) {
+            ElemNode(old)   => {
+                assert_eq!("<d xmlns=\"urn:r\"/>".to_string(), elem_to_string(&old))
+            },
+            node            => assert!(false, "expected element, got {}", node)
+        }
+        assert_eq!("<r p:a=\"1\" xmlns:p=\"urn:p\" xmlns=\"urn:r\">t<e xmlns=\"\"/></r>".to_string(),
+                   elem_to_string(&root));
+        assert_eq!(Some(text("t")), root.remove_child(0));
+        assert_eq!(None,            root.remove_child(5));
+    }
+
+    #[test]
+    fn test_rename_wrap() {
+        let mut elem = XElem::new(ns("", "urn:r"), "a");
+        elem.append_child(ElemNode(XElem::new(ns("", "urn:r"), "b")));
+        elem.declare_namespaces();
+        elem.rename("x");
+        assert_eq!("<x xmlns=\"urn:r\"><b/></x>".to_string(), elem_to_string(&elem));
+        // Child keeps its namespace, once it's no longer the default
+        elem.rename_ns(ns("", "urn:x"), "x");
+        assert_eq!("<x xmlns=\"urn:x\"><b xmlns=\"urn:r\"/></x>".to_string(),
+                   elem_to_string(&elem));
+
+        let mut wrapper = elem.wrap(XElem::new(ns("q", "urn:q"), "w"));
+        wrapper.declare_namespaces();
+        assert_eq!("<q:w xmlns:q=\"urn:q\"><x xmlns=\"urn:x\"><b xmlns=\"urn:r\"/></x></q:w>"
+                   .to_string(), elem_to_string(&wrapper));
+        assert!(wrapper.unwrap_child(0));
+        assert_eq!("<q:w xmlns:q=\"urn:q\"><b xmlns=\"urn:r\"/></q:w>".to_string(),
+                   elem_to_string(&wrapper));
+        assert!(wrapper.unwrap_child(0));
+        assert!(!wrapper.unwrap_child(0));
+        assert_eq!("<q:w xmlns:q=\"urn:q\"/>".to_string(), elem_to_string(&wrapper));
+    }
+
+    #[test]
+    fn test_edit_doc() {
+        let mut doc = XDoc::new(XElem::new(ns("p", "urn:p"), "r"));
+        assert_eq!("<p:r xmlns:p=\"urn:p\"/>".to_string(), doc_to_string(&doc));
+        doc.wrap_root(XElem::new(ns("", ""), "w"));
+        assert_eq!("<w><p:r xmlns:p=\"urn:p\"/></w>".to_string(), doc_to_string(&doc));
+        let old = doc.set_root(XElem::new(ns("", "urn:n"), "n"));
+        assert!(old.is_named("w"));
+        assert_eq!("<n xmlns=\"urn:n\"/>".to_string(), doc_to_string(&doc));
+    }
+}
@@ -0,0 +1,588 @@
+//! Decoding of XML into types implementing `Decodable`.
+//!
+//! Values are decoded from an element tree, so fields can come in any
+//! order. Records of a large document can be decoded one at a time,
+//! building each with `Parser::read_subtree`:
+//!
+//!     match parser.pull() {
+//!         Some(ElemStart(elem)) if elem.is_named("entry") => {
+//!             let elem = parser.read_subtree(elem);
+//!             let entry: Entry = try!(Decodable::decode(&mut XmlDecoder::new(elem)));
+//!             ...
+//!         },
+//!         ...
+//!     }
+//!
+//! Structs are mapped to elements as follows:
+//!
+//! * Field `name` is read from attribute `name`, or else from child
+//!   elements named `name`, in any namespace. Trailing `_` is dropped
+//!   from field names, so `type_` is read from `type`, and names with
+//!   `-` are matched by fields with `_`.
+//! * Field `p__name` is read from attribute or element `name` in
+//!   namespace bound to `p` by `XmlDecoder::bind_namespace`, or with
+//!   prefix `p` as written if `p` isn't bound.
+//! * Field `_text` is read from text children of the element.
+//! * `Option` fields are `None` when there is no such attribute or
+//!   child. `Vec` fields get all children of that name, or words of an
+//!   attribute value, and are empty when there is neither.
+//! * Enums are read from an element whose first child element is named
+//!   as the variant, ignoring ASCII case, with variant fields read from
+//!   that child. Unit variants can also be written as text.
+//! * Maps are read from attributes and child elements of an element,
+//!   keyed by local name.
+//!
+//! This is a libserialize `Decoder`, not a serde `Deserializer`, as
+//! serde isn't a dependency of this crate. Decoding needs the whole
+//! element of a value, so it reads a tree rather than pulling events.
+//!
+//! Only built with `serde` feature.
+
+use std::ascii::StrAsciiExt;
+use std::collections::HashMap;
+use std::mem;
+use std::from_str::FromStr;
+use serialize;
+use serialize::Decodable;
+
+use common::{XElem, XmlNS, XmlError, ElemNode, TextNode, CDataNode, XMLNS_NS_URI};
+use common::UnsupportedDoctype;
+use parser::{Parser, ElemStart, EmptyElem, ErrEvent};
+use source::SliceSource;
+
+/// Error in decoding a value
+#[deriving(Clone, PartialEq, Show)]
+pub enum DecoderError {
+    /// Error found in parsing document
+    ParseError(XmlError),
+    /// Expected kind of value, and text found instead
+    ExpectedError(String, String),
+    /// Field has no attribute or child elements to be read from
+    MissingFieldError(String),
+    UnknownVariantError(String),
+    ApplicationError(String)
+}
+
+pub type DecodeResult<T> = Result<T, DecoderError>;
+
+/// Part of document a value is decoded from
+enum Item {
+    ElemItem(XElem),
+    /// Child elements read by a field
+    ElemsItem(Vec<XElem>),
+    /// Attribute value or text
+    TextItem(String),
+    /// Field with nothing to be read from
+    MissingItem(String)
+}
+
+/// Decoder of values from an element
+pub struct XmlDecoder {
+    stack: Vec<Item>,
+    namespaces: HashMap<String, String>
+}
+
+impl XmlDecoder {
+    /// Constructs decoder of a value from `elem`
+    pub fn new(elem: XElem) -> XmlDecoder {
+        XmlDecoder {
+            stack: vec![ElemItem(elem)],
+            namespaces: HashMap::new()
+        }
+    }
+
+    /// Binds `prefix` of field names to namespace `uri`
+    pub fn bind_namespace(&mut self, prefix: &str, uri: &str) {
+        self.namespaces.insert(prefix.to_string(), uri.to_string());
+    }
+
+    fn pop(&mut self) -> Item {
+        self.stack.pop().unwrap()
+    }
+
+    fn pop_elem(&mut self) -> DecodeResult<XElem> {
+        match self.pop() {
+            ElemItem(elem)      => Ok(elem),
+            ElemsItem(elems)    => Ok(elems.into_iter().next().unwrap()),
+            TextItem(text)      => Err(ExpectedError("element".to_string(), text)),
+            MissingItem(name)   => Err(MissingFieldError(name))
+        }
+    }
+
+    fn pop_text(&mut self) -> DecodeResult<String> {
+        match self.pop() {
+            TextItem(text)      => Ok(text),
+            MissingItem(name)   => Err(MissingFieldError(name)),
+            item                => {
+                self.stack.push(item);
+                Ok(try!(self.pop_elem()).text())
+            }
+        }
+    }
+
+    fn read_number<T: FromStr>(&mut self, kind: &str) -> DecodeResult<T> {
+        let text = try!(self.pop_text());
+        match from_str(text.as_slice().trim()) {
+            Some(n) => Ok(n),
+            None    => Err(ExpectedError(kind.to_string(), text))
+        }
+    }
+
+    /// Returns what field `name` of element on top of stack is read
+    /// from, taking its child elements out of the element
+    fn field(&mut self, name: &str) -> DecodeResult<Item> {
+        let (prefix, local) = match name.find_str("__") {
+            Some(i) => (Some(name.slice_to(i)), name.slice_from(i + 2)),
+            None    => (None, name)
+        };
+        let uri = prefix.and_then(|prefix| self.namespaces.find_equiv(&prefix))
+                        .map(|uri| uri.clone());
+        let elem = match self.stack.last_mut() {
+            Some(&ElemItem(ref mut elem))   => elem,
+            _ => return Err(ApplicationError(format!("field {} outside of struct", name)))
+        };
+        if local == "_text" {
+            let mut text = String::new();
+            for node in elem.children.iter() {
+                match *node {
+                    TextNode(ref s) | CDataNode(ref s)  => text.push_str(s.as_slice()),
+                    _                                   => {}
+                }
+            }
+            return Ok(TextItem(text))
+        }
+
+        let local = local.trim_right_chars('_');
+        let hyphenated = local.replace("_", "-");
+        let is_named = |ns: &XmlNS, elem_local: &str, is_attr: bool| {
+            (elem_local == local || elem_local == hyphenated.as_slice()) && match prefix {
+                Some(prefix)    => match uri {
+                    Some(ref uri)   => ns.uri.as_slice() == uri.as_slice(),
+                    None            => ns.name.as_slice() == prefix
+                },
+                None            => !is_attr || ns.uri.as_slice().is_empty()
+            }
+        };
+
+        for attr in elem.attributes.iter() {
+            if attr.namespace.uri.as_slice() != XMLNS_NS_URI
+               && is_named(&attr.namespace, attr.name.as_slice(), true) {
+                return Ok(TextItem(attr.value.clone()))
+            }
+        }
+        let children = mem::replace(&mut elem.children, Vec::new());
+        let (matched, rest) = children.partition(|node| match *node {
+            ElemNode(ref child) => is_named(&child.namespace, child.name.as_slice(), false),
+            _                   => false
+        });
+        elem.children = rest;
+        if matched.is_empty() {
+            return Ok(MissingItem(name.to_string()))
+        }
+        Ok(ElemsItem(matched.into_iter().filter_map(|node| match node {
+            ElemNode(child) => Some(child),
+            _               => None
+        }).collect()))
+    }
+}
+
+/// Returns index of variant `name` in `names`
+fn variant_index(names: &[&str], name: &str) -> DecodeResult<uint> {
+    match names.iter().position(|&variant| variant == name) {
+        Some(i) => Ok(i),
+        None    => match names.iter().position(|&variant| variant.eq_ignore_ascii_case(name)) {
+            Some(i) => Ok(i),
+            None    => Err(UnknownVariantError(name.to_string()))
+        }
+    }
+}
+
+/// Decodes `T` from root element of `input`. Errors found in parsing
+/// the document fail decoding, other than its DOCTYPE being ignored.
+pub fn decode<T: Decodable<XmlDecoder, DecoderError>>(input: &str) -> DecodeResult<T> {
+    let mut source = SliceSource::from_str(input);
+    let mut parser = Parser::from_reader(&mut source);
+    let mut root = None;
+    loop {
+        match parser.pull() {
+            Some(ElemStart(elem)) if root.is_none() => root = Some(parser.read_subtree(elem)),
+            Some(EmptyElem(elem)) if root.is_none() => root = Some(elem),
+            // DTD isn't needed, entities it declares fail where used
+            Some(ErrEvent(ref err)) if err.kind == UnsupportedDoctype => {},
+            Some(ErrEvent(err)) => return Err(ParseError(err)),
+            Some(_)             => {},
+            None                => break
+        }
+    }
+    match root {
+        Some(root)  => Decodable::decode(&mut XmlDecoder::new(root)),
+        None        => Err(ExpectedError("root element".to_string(), String::new()))
+    }
+}
+
+impl serialize::Decoder<DecoderError> for XmlDecoder {
+    fn read_nil(&mut self) -> DecodeResult<()> {
+        self.pop();
+        Ok(())
+    }
+
+    fn read_uint(&mut self) -> DecodeResult<uint> { self.read_number("uint") }
+    fn read_u64(&mut self) -> DecodeResult<u64> { self.read_number("u64") }
+    fn read_u32(&mut self) -> DecodeResult<u32> { self.read_number("u32") }
+    fn read_u16(&mut self) -> DecodeResult<u16> { self.read_number("u16") }
+    fn read_u8(&mut self) -> DecodeResult<u8> { self.read_number("u8") }
+    fn read_int(&mut self) -> DecodeResult<int> { self.read_number("int") }
+    fn read_i64(&mut self) -> DecodeResult<i64> { self.read_number("i64") }
+    fn read_i32(&mut self) -> DecodeResult<i32> { self.read_number("i32") }
+    fn read_i16(&mut self) -> DecodeResult<i16> { self.read_number("i16") }
+    fn read_i8(&mut self) -> DecodeResult<i8> { self.read_number("i8") }
+    fn read_f64(&mut self) -> DecodeResult<f64> { self.read_number("f64") }
+    fn read_f32(&mut self) -> DecodeResult<f32> { self.read_number("f32") }
+
+    fn read_bool(&mut self) -> DecodeResult<bool> {
+        let text = try!(self.pop_text());
+        match text.as_slice().trim() {
+            "true" | "1"    => Ok(true),
+            "false" | "0"   => Ok(false),
+            _               => Err(ExpectedError("bool".to_string(), text))
+        }
+    }
+
+    fn read_char(&mut self) -> DecodeResult<char> {
+        let text = try!(self.pop_text());
+        if text.as_slice().char_len() == 1 {
+            Ok(text.as_slice().char_at(0))
+        } else {
+            Err(ExpectedError("char".to_string(), text))
+        }
+    }
+
+    fn read_str(&mut self) -> DecodeResult<String> {
+        self.pop_text()
+    }
+
+    fn read_enum<T>(&mut self, _name: &str, f: |&mut XmlDecoder| -> DecodeResult<T>)
+                   -> DecodeResult<T> {
+        f(self)
+    }
+
+    fn read_enum_variant<T>(&mut self, names: &[&str],
+                            f: |&mut XmlDecoder, uint| -> DecodeResult<T>)
+                           -> DecodeResult<T> {
+        let (index, item) = match self.pop() {
+            TextItem(text)      => (try!(variant_index(names, text.as_slice().trim())),
+                                    TextItem(text)),
+            MissingItem(name)   => return Err(MissingFieldError(name)),
+            item                => {
+                self.stack.push(item);
+                let elem = try!(self.pop_elem());
+                if elem.child_elems().next().is_none() {
+                    let text = elem.text();
+                    (try!(variant_index(names, text.as_slice().trim())), TextItem(text))
+                } else {
+                    let child = elem.children.into_iter().filter_map(|node| match node {
+                        ElemNode(child) => Some(child),
+                        _               => None
+                    }).next().unwrap();
+                    (try!(variant_index(names, child.name.as_slice())), ElemItem(child))
+                }
+            }
+        };
+        // Variant fields are read from the item, unit variants leave it
+        self.stack.push(item);
+        let len = self.stack.len();
+        let value = try!(f(self, index));
+        if self.stack.len() == len {
+            self.pop();
+        }
+        Ok(value)
+    }
+
+    fn read_enum_variant_arg<T>(&mut self, index: uint, f: |&mut XmlDecoder| -> DecodeResult<T>)
+                               -> DecodeResult<T> {
+        if index > 0 {
+            return Err(ApplicationError("variants with several fields are not supported"
+                                        .to_string()))
+        }
+        f(self)
+    }
+
+    fn read_enum_struct_variant<T>(&mut self, names: &[&str],
+                                   f: |&mut XmlDecoder, uint| -> DecodeResult<T>)
+                                  -> DecodeResult<T> {
+        self.read_enum_variant(names, f)
+    }
+
+    fn read_enum_struct_variant_field<T>(&mut self, name: &str, index: uint,
+                                         f: |&mut XmlDecoder| -> DecodeResult<T>)
+                                        -> DecodeResult<T> {
+        self.read_struct_field(name, index, f)
+    }
+
+    fn read_struct<T>(&mut self, _name: &str, _len: uint,
+                      f: |&mut XmlDecoder| -> DecodeResult<T>) -> DecodeResult<T> {
+        let elem = try!(self.pop_elem());
+        self.stack.push(ElemItem(elem));
+        let value = try!(f(self));
+        self.pop();
+        Ok(value)
+    }
+
+    fn read_struct_field<T>(&mut self, name: &str, _index: uint,
+                            f: |&mut XmlDecoder| -> DecodeResult<T>) -> DecodeResult<T> {
+        let item = try!(self.field(name));
+        self.stack.push(item);
+        f(self)
+    }
+
+    fn read_tuple<T>(&mut self, f: |&mut XmlDecoder, uint| -> DecodeResult<T>)
+                    -> DecodeResult<T> {
+        self.read_seq(f)
+    }
+
+    fn read_tuple_arg<T>(&mut self, _index: uint, f: |&mut XmlDecoder| -> DecodeResult<T>)
+                        -> DecodeResult<T> {
+        f(self)
+    }
+
+    fn read_tuple_struct<T>(&mut self, _name: &str, f: |&mut XmlDecoder, uint| -> DecodeResult<T>)
+                           -> DecodeResult<T> {
+        self.read_seq(f)
+    }
+
+    fn read_tuple_struct_arg<T>(&mut self, _index: uint, f: |&mut XmlDecoder| -> DecodeResult<T>)
+                               -> DecodeResult<T> {
+        f(self)
+    }
+
+    fn read_option<T>(&mut self, f: |&mut XmlDecoder, bool| -> DecodeResult<T>)
+                     -> DecodeResult<T> {
+        match self.stack.last() {
+            Some(&MissingItem(_))   => {
+                self.pop();
+                f(self, false)
+            },
+            _                       => f(self, true)
+        }
+    }
+
+    fn read_seq<T>(&mut self, f: |&mut XmlDecoder, uint| -> DecodeResult<T>)
+                  -> DecodeResult<T> {
+        let items: Vec<Item> = match self.pop() {
+            ElemItem(elem)      => elem.children.into_iter().filter_map(|node| match node {
+                ElemNode(child) => Some(ElemItem(child)),
+                _               => None
+            }).collect(),
+            ElemsItem(elems)    => elems.into_iter().map(|elem| ElemItem(elem)).collect(),
+            TextItem(text)      => text.as_slice().words()
+                                       .map(|word| TextItem(word.to_string())).collect(),
+            MissingItem(_)      => Vec::new()
+        };
+        let len = items.len();
+        let base = self.stack.len();
+        for item in items.into_iter().rev() {
+            self.stack.push(item);
+        }
+        let value = try!(f(self, len));
+        // Items that weren't read, e.g. by a tuple, are dropped
+        self.stack.truncate(base);
+        Ok(value)
+    }
+
+    fn read_seq_elt<T>(&mut self, _index: uint, f: |&mut XmlDecoder| -> DecodeResult<T>)
+                      -> DecodeResult<T> {
+        f(self)
+    }
+
+    fn read_map<T>(&mut self, f: |&mut XmlDecoder, uint| -> DecodeResult<T>)
+                  -> DecodeResult<T> {
+        let elem = try!(self.pop_elem());
+        let mut entries = Vec::new();
+        for attr in elem.attributes.into_iter() {
+            if attr.namespace.uri.as_slice() != XMLNS_NS_URI {
+                entries.push((attr.name.as_slice().to_string(), TextItem(attr.value)));
+            }
+        }
+        for node in elem.children.into_iter() {
+            match node {
+                ElemNode(child) => entries.push((child.name.as_slice().to_string(),
+                                                 ElemItem(child))),
+                _               => {}
+            }
+        }
+        let len = entries.len();
+        for (key, value) in entries.into_iter().rev() {
+            self.stack.push(value);
+            self.stack.push(TextItem(key));
+        }
+        f(self, len)
+    }
+
+    fn read_map_elt_key<T>(&mut self, _index: uint, f: |&mut XmlDecoder| -> DecodeResult<T>)
+                          -> DecodeResult<T> {
+        f(self)
+    }
+
+    fn read_map_elt_val<T>(&mut self, _index: uint, f: |&mut XmlDecoder| -> DecodeResult<T>)
+                          -> DecodeResult<T> {
+        f(self)
+    }
+
+    fn error(&mut self, err: &str) -> DecoderError {
+        ApplicationError(err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{XmlDecoder, decode, MissingFieldError, ExpectedError, UnknownVariantError};
+    use super::ParseError;
+    use std::collections::HashMap;
+    use serialize::Decodable;
+    use common::UnknownEntity;
+    use parser::{Parser, ElemStart};
+    use source::SliceSource;
+
+    #[deriving(Decodable, PartialEq, Show)]
+    enum Kind {
+        Book,
+        Article
+    }
+
+    #[deriving(Decodable, PartialEq, Show)]
+    struct Entry {
+        id: uint,
+        type_: Kind,
+        tags: Vec<String>,
+        link: Link,
+        _text: String,
+        xlink__href: Option<String>
+    }
+
+    #[deriving(Decodable, PartialEq, Show)]
+    struct Link {
+        rel: String
+    }
+
+    #[deriving(Decodable, PartialEq, Show)]
+    struct Feed {
+        title: String,
+        entry: Vec<Entry>,
+        updated: Option<String>,
+        is_public: bool
+    }
+
+    #[test]
+    fn test_decode_struct() {
+        let input = "<feed xmlns:xlink='http://www.w3.org/1999/xlink' is-public='1'>\
+                     <title>News</title>\
+                     <entry id=' 1' type='Book' tags='a b' xlink:href='#1'>one\
+                     <link rel='self'/></entry>\
+                     <entry id='2'>two<link rel='alt'/><tags>c</tags><type>article</type></entry>\
+                     </feed>";
+        let feed: Feed = decode(input).unwrap();
+        assert_eq!(Feed {
+            title: "News".to_string(),
+            entry: vec![
+                Entry {
+                    id: 1,
+                    type_: Book,
+                    tags: vec!["a".to_string(), "b".to_string()],
+                    link: Link { rel: "self".to_string() },
+                    _text: "one".to_string(),
+                    xlink__href: Some("#1".to_string())
+                },
+                Entry {
+                    id: 2,
+                    type_: Article,
+                    tags: vec!["c".to_string()],
+                    link: Link { rel: "alt".to_string() },
+                    _text: "two".to_string(),
+                    xlink__href: None
+                }
+            ],
+            updated: None,
+            is_public: true
+        }, feed);
+    }
+
+    #[deriving(Decodable, PartialEq, Show)]
+    struct Circle {
+        r: f64
+    }
+
+    #[deriving(Decodable, PartialEq, Show)]
+    enum Shape {
+        CircleShape(Circle),
+        Square { side: f64 },
+        Dot
+    }
+
+    #[deriving(Decodable, PartialEq, Show)]
+    struct Drawing {
+        shape: Vec<Shape>,
+        attrs: HashMap<String, String>
+    }
+
+    #[test]
+    fn test_decode_enums() {
+        let input = "<drawing><shape><circleshape r='1.5'/></shape><shape><square side='2'/>\
+                     </shape><shape>Dot</shape><attrs a='x'><b>y</b></attrs></drawing>";
+        let drawing: Drawing = decode(input).unwrap();
+        assert_eq!(vec![CircleShape(Circle { r: 1.5 }), Square { side: 2.0 }, Dot],
+                   drawing.shape);
+        assert_eq!(2, drawing.attrs.len());
+        assert_eq!(Some(&"y".to_string()), drawing.attrs.find_equiv(&"b"));
+
+        let err = decode::<Drawing>("<drawing><shape><line/></shape></drawing>").unwrap_err();
+        assert_eq!(UnknownVariantError("line".to_string()), err);
+    }
+
+    #[test]
+    fn test_decode_records() {
+        let input = "<log xmlns:l='urn:l'><l:e l:x='1'/><skip/>\
+                     <l:e l:x='2'><l:x>3</l:x></l:e></log>";
+        let mut source = SliceSource::from_str(input);
+        let mut parser = Parser::from_reader(&mut source);
+
+        #[deriving(Decodable)]
+        struct Record {
+            log__x: Vec<uint>
+        }
+        let mut values = Vec::new();
+        loop {
+            match parser.pull() {
+                Some(ElemStart(elem)) if elem.name.as_slice() == "e" => {
+                    let elem = parser.read_subtree(elem);
+                    let mut decoder = XmlDecoder::new(elem);
+                    decoder.bind_namespace("log", "urn:l");
+                    let record: Record = Decodable::decode(&mut decoder).unwrap();
+                    values.push(record.log__x);
+                },
+                Some(_) => {},
+                None    => break
+            }
+        }
+        // Attribute is read rather than children of same name
+        assert_eq!(vec![vec![1], vec![2]], values);
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert_eq!(MissingFieldError("id".to_string()), decode::<Entry>("<entry/>").unwrap_err());
+        assert_eq!(ExpectedError("uint".to_string(), "x".to_string()),
+                   decode::<Entry>("<entry id='x'/>").unwrap_err());
+        match decode::<Link>("<link rel='&x;'/>") {
+            Err(ParseError(err))    => assert_eq!(UnknownEntity, err.kind),
+            res                     => assert!(false, "expected parse error, got {}", res)
+        }
+        assert_eq!(ExpectedError("root element".to_string(), String::new()),
+                   decode::<Link>("<!-- -->").unwrap_err());
+    }
+
+    #[test]
+    fn test_decode_doctype() {
+        let input = "<!DOCTYPE link [<!ATTLIST link rel CDATA 'up'>]><link rel='next'/>";
+        assert_eq!(Link { rel: "next".to_string() }, decode::<Link>(input).unwrap());
+    }
+}
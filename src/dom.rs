@@ -0,0 +1,462 @@
+//! Arena allocated document tree.
+//!
+//! All nodes of a `Document` are kept in a single vector and refer to
+//! each other by `NodeId`, so building a tree takes few allocations and
+//! every node knows its parent and siblings. Nodes built by the parser
+//! get ids in document order.
+
+use common::{XmlNS, XmlAttr, XPi, XDecl, XmlError, XDoc, XElem};
+use common::{ElemNode, TextNode, CDataNode, CommentNode, PINode};
+use name::Name;
+use parser::{Parser, DeclEvent, ElemStart, ElemEnd, EmptyElem, PIEvent};
+use parser::{TextEvent, CDataEvent, CommentEvent, ErrEvent};
+use source::XmlSource;
+
+/// Index of a node in its `Document`
+#[deriving(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Show)]
+pub struct NodeId(uint);
+
+impl NodeId {
+    pub fn index(&self) -> uint {
+        let NodeId(index) = *self;
+        index
+    }
+}
+
+/// Element without its children, which are separate nodes
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct Element {
+    pub name: Name,
+    pub namespace: XmlNS,
+    pub attributes: Vec<XmlAttr>
+}
+
+/// What a node holds
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum NodeKind {
+    /// Root of document, parent of top level nodes
+    RootKind,
+    ElementKind(Element),
+    TextKind(String),
+    CDataKind(String),
+    CommentKind(String),
+    PIKind(XPi)
+}
+
+/// Position of a node in the `XDoc` a document was built from
+/// by `Document::from_xdoc`
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum XDocPos {
+    /// Processing instruction at index in `XDoc::pis`
+    PIPos(uint),
+    /// Root element, or node below it found by taking the child at each
+    /// index in turn, starting from the root element
+    TreePos(Vec<uint>)
+}
+
+/// A node along with links to its neighbours
+#[deriving(Clone, Show)]
+pub struct Node {
+    pub kind: NodeKind,
+    parent: Option<NodeId>,
+    first_child: Option<NodeId>,
+    last_child: Option<NodeId>,
+    prev_sibling: Option<NodeId>,
+    next_sibling: Option<NodeId>
+}
+
+/// Document tree in an arena
+pub struct Document {
+    nodes: Vec<Node>,
+    /// Declaration of the document, if it has one
+    pub decl: Option<XDecl>,
+    /// Errors found while parsing the document
+    pub errors: Vec<XmlError>
+}
+
+impl Document {
+    /// Constructs document with just the root node
+    pub fn new() -> Document {
+        Document {
+            nodes: vec![Node {
+                kind: RootKind,
+                parent: None,
+                first_child: None,
+                last_child: None,
+                prev_sibling: None,
+                next_sibling: None
+            }],
+            decl: None,
+            errors: Vec::new()
+        }
+    }
+
+    /// Builds document out of events pulled from `parser`
+    pub fn from_parser<'r, R: XmlSource<'r>>(parser: &mut Parser<'r, R>) -> Document {
+        let mut doc = Document::new();
+        // Open elements, synchronized with `parser.depth`
+        let mut open = vec![doc.root()];
+
+        loop {
+            let event = match parser.pull() {
+                Some(event) => event,
+                None        => break
+            };
+            let parent = *open.last().unwrap();
+            match event {
+                DeclEvent(decl)     => doc.decl = Some(decl),
+                ElemStart(elem)     => {
+                    let id = doc.append(parent, ElementKind(Element {
+                        name: elem.name,
+                        namespace: elem.namespace,
+                        attributes: elem.attributes
+                    }));
+                    open.push(id);
+                },
+                EmptyElem(elem)     => {
+                    doc.append(parent, ElementKind(Element {
+                        name: elem.name,
+                        namespace: elem.namespace,
+                        attributes: elem.attributes
+                    }));
+                },
+                ElemEnd(_)          => {},
+                PIEvent(pi)         => { doc.append(parent, PIKind(pi)); },
+                TextEvent(text)     => { doc.append(parent, TextKind(text.into_string())); },
+                CDataEvent(text)    => { doc.append(parent, CDataKind(text.into_string())); },
+                CommentEvent(text)  => { doc.append(parent, CommentKind(text.into_string())); },
+                ErrEvent(err)       => doc.errors.push(err)
+            }
+            // Parser decides which elements end tags close
+            while open.len() > parser.depth + 1 {
+                open.pop();
+            }
+        }
+        doc
+    }
+
+    /// Builds document out of tree of `xdoc`. Nodes are copied, see
+    /// `xdoc_pos` for finding them in `xdoc`.
+    pub fn from_xdoc(xdoc: &XDoc) -> Document {
+        let mut doc = Document::new();
+        let root = doc.root();
+        for pi in xdoc.pis().iter() {
+            doc.append(root, PIKind(pi.clone()));
+        }
+        doc.append_tree(root, xdoc.root());
+        doc
+    }
+
+    /// Adds `elem` and its descendants below `parent`
+    fn append_tree(&mut self, parent: NodeId, elem: &XElem) {
+        let id = self.append(parent, ElementKind(Element {
+            name: elem.name.clone(),
+            namespace: elem.namespace.clone(),
+            attributes: elem.attributes.clone()
+        }));
+        for child in elem.children.iter() {
+            let kind = match *child {
+                ElemNode(ref elem)      => {
+                    self.append_tree(id, elem);
+                    continue
+                },
+                TextNode(ref text)      => TextKind(text.clone()),
+                CDataNode(ref text)     => CDataKind(text.clone()),
+                CommentNode(ref text)   => CommentKind(text.clone()),
+                PINode(ref pi)          => PIKind(pi.clone())
+            };
+            self.append(id, kind);
+        }
+    }
+
+    /// Returns position of node `id` in the `XDoc` document was built
+    /// from by `from_xdoc`, or `None` for the root node. Children are
+    /// indexed as by `XElem::remove_child` and other editing methods,
+    /// and attributes are copied in order, so nodes found in document,
+    /// e.g. by XPath, can be changed in the `XDoc`.
+    pub fn xdoc_pos(&self, id: NodeId) -> Option<XDocPos> {
+        let mut path = Vec::new();
+        let mut id = id;
+        loop {
+            let parent = match self.parent(id) {
+                Some(parent)    => parent,
+                None            => return None
+            };
+            if parent == self.root() {
+                return Some(match self.element(id) {
+                    Some(_) => {
+                        path.reverse();
+                        TreePos(path)
+                    },
+                    None    => PIPos(self.child_index(id))
+                })
+            }
+            path.push(self.child_index(id));
+            id = parent;
+        }
+    }
+
+    /// Returns index of `id` among children of its parent
+    fn child_index(&self, id: NodeId) -> uint {
+        let mut index = 0;
+        let mut prev = self.prev_sibling(id);
+        loop {
+            match prev {
+                Some(sibling)   => {
+                    index += 1;
+                    prev = self.prev_sibling(sibling);
+                },
+                None            => return index
+            }
+        }
+    }
+
+    /// Returns id of the root node
+    pub fn root(&self) -> NodeId {
+        NodeId(0)
+    }
+
+    /// Returns the top level element
+    pub fn root_element(&self) -> Option<NodeId> {
+        let root = self.root();
+        self.children(root).find(|&id| self.element(id).is_some())
+    }
+
+    /// Number of nodes, including the root
+    pub fn len(&self) -> uint {
+        self.nodes.len()
+    }
+
+    pub fn node<'a>(&'a self, id: NodeId) -> &'a Node {
+        &self.nodes[id.index()]
+    }
+
+    pub fn node_mut<'a>(&'a mut self, id: NodeId) -> &'a mut Node {
+        &mut self.nodes[id.index()]
+    }
+
+    pub fn kind<'a>(&'a self, id: NodeId) -> &'a NodeKind {
+        &self.node(id).kind
+    }
+
+    /// Returns element at `id`, if node is one
+    pub fn element<'a>(&'a self, id: NodeId) -> Option<&'a Element> {
+        match self.node(id).kind {
+            ElementKind(ref elem)   => Some(elem),
+            _                       => None
+        }
+    }
+
+    pub fn parent(&self, id: NodeId) -> Option<NodeId> {
+        self.node(id).parent
+    }
+
+    pub fn first_child(&self, id: NodeId) -> Option<NodeId> {
+        self.node(id).first_child
+    }
+
+    pub fn last_child(&self, id: NodeId) -> Option<NodeId> {
+        self.node(id).last_child
+    }
+
+    pub fn next_sibling(&self, id: NodeId) -> Option<NodeId> {
+        self.node(id).next_sibling
+    }
+
+    pub fn prev_sibling(&self, id: NodeId) -> Option<NodeId> {
+        self.node(id).prev_sibling
+    }
+
+    /// Adds a node holding `kind` as the last child of `parent`
+    pub fn append(&mut self, parent: NodeId, kind: NodeKind) -> NodeId {
+        let id = NodeId(self.nodes.len());
+        let prev = self.last_child(parent);
+        self.nodes.push(Node {
+            kind: kind,
+            parent: Some(parent),
+            first_child: None,
+            last_child: None,
+            prev_sibling: prev,
+            next_sibling: None
+        });
+        match prev {
+            Some(prev)  => self.node_mut(prev).next_sibling = Some(id),
+            None        => self.node_mut(parent).first_child = Some(id)
+        }
+        self.node_mut(parent).last_child = Some(id);
+        id
+    }
+
+    /// Iterates over children of `id`
+    pub fn children<'a>(&'a self, id: NodeId) -> Children<'a> {
+        Children {
+            doc: self,
+            next: self.first_child(id)
+        }
+    }
+
+    /// Iterates over parent of `id`, its parent and so on up to the root
+    pub fn ancestors<'a>(&'a self, id: NodeId) -> Ancestors<'a> {
+        Ancestors {
+            doc: self,
+            next: self.parent(id)
+        }
+    }
+
+    /// Iterates over nodes below `id` in document order
+    pub fn descendants<'a>(&'a self, id: NodeId) -> Descendants<'a> {
+        Descendants {
+            doc: self,
+            start: id,
+            next: self.first_child(id)
+        }
+    }
+
+    /// Concatenates text and CDATA below `id`
+    pub fn text(&self, id: NodeId) -> String {
+        let mut text = String::new();
+        for node in self.descendants(id) {
+            match *self.kind(node) {
+                TextKind(ref s) | CDataKind(ref s) => text.push_str(s.as_slice()),
+                _ => {}
+            }
+        }
+        text
+    }
+}
+
+pub struct Children<'a> {
+    doc: &'a Document,
+    next: Option<NodeId>
+}
+
+impl<'a> Iterator<NodeId> for Children<'a> {
+    fn next(&mut self) -> Option<NodeId> {
+        let current = self.next;
+        self.next = current.and_then(|id| self.doc.next_sibling(id));
+        current
+    }
+}
+
+pub struct Ancestors<'a> {
+    doc: &'a Document,
+    next: Option<NodeId>
+}
+
+impl<'a> Iterator<NodeId> for Ancestors<'a> {
+    fn next(&mut self) -> Option<NodeId> {
+        let current = self.next;
+        self.next = current.and_then(|id| self.doc.parent(id));
+        current
+    }
+}
+
+pub struct Descendants<'a> {
+    doc: &'a Document,
+    start: NodeId,
+    next: Option<NodeId>
+}
+
+impl<'a> Iterator<NodeId> for Descendants<'a> {
+    fn next(&mut self) -> Option<NodeId> {
+        let current = match self.next {
+            Some(id)    => id,
+            None        => return None
+        };
+        self.next = match self.doc.first_child(current) {
+            Some(child) => Some(child),
+            None        => self.following(current)
+        };
+        Some(current)
+    }
+}
+
+impl<'a> Descendants<'a> {
+    /// Finds node after subtree of `node`, climbing up until a node has
+    /// a following sibling, but not past the start
+    fn following(&self, node: NodeId) -> Option<NodeId> {
+        let mut node = node;
+        while node != self.start {
+            match self.doc.next_sibling(node) {
+                Some(next)  => return Some(next),
+                None        => node = self.doc.parent(node).unwrap()
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Document, NodeId, TextKind, CommentKind, PIKind, PIPos, TreePos};
+    use common::{XDoc, XElem, XmlNS, XPi, TextNode, ElemNode};
+    use name::Name;
+    use parser::Parser;
+    use source::SliceSource;
+
+    fn parse(input: &str) -> Document {
+        let mut source = SliceSource::from_str(input);
+        let mut parser = Parser::from_reader(&mut source);
+        Document::from_parser(&mut parser)
+    }
+
+    #[test]
+    fn test_from_parser() {
+        let doc = parse("<?xml version='1.0'?><!--c--><a x='1'><b>t</b><c/>u</a>");
+        assert_eq!("1.0".to_string(), doc.decl.unwrap().version);
+        assert_eq!(7, doc.len());
+
+        let a = doc.root_element().unwrap();
+        assert_eq!("a", doc.element(a).unwrap().name.as_slice());
+        assert_eq!(Some(doc.root()), doc.parent(a));
+        assert_eq!(&CommentKind("c".to_string()), doc.kind(doc.prev_sibling(a).unwrap()));
+
+        let children: Vec<NodeId> = doc.children(a).collect();
+        assert_eq!(3, children.len());
+        assert_eq!("c", doc.element(children[1]).unwrap().name.as_slice());
+        assert_eq!(&TextKind("u".to_string()), doc.kind(children[2]));
+        assert_eq!(Some(children[2]), doc.last_child(a));
+        assert_eq!(Some(children[1]), doc.prev_sibling(children[2]));
+    }
+
+    #[test]
+    fn test_navigation() {
+        let doc = parse("<a><b><c>x</c></b><d>y</d></a>");
+        let a = doc.root_element().unwrap();
+        let ids: Vec<uint> = doc.descendants(a).map(|id| id.index()).collect();
+        // Ids follow document order
+        assert_eq!(vec![2, 3, 4, 5, 6], ids);
+        let x = NodeId(4);
+        let up: Vec<NodeId> = doc.ancestors(x).collect();
+        assert_eq!(vec![NodeId(3), NodeId(2), a, doc.root()], up);
+        assert_eq!("xy".to_string(), doc.text(a));
+        assert_eq!("x".to_string(), doc.text(NodeId(2)));
+        assert_eq!(0, doc.descendants(x).count());
+    }
+    #[test]
+    fn test_from_xdoc() {
+        let ns = XmlNS { name: Name::new(""), uri: Name::new("") };
+        let mut root = XElem::new(ns.clone(), "a");
+        let mut child = XElem::new(ns, "b");
+        child.append_child(TextNode("x".to_string()));
+        root.append_child(ElemNode(child));
+        root.append_child(TextNode("y".to_string()));
+        let mut xdoc = XDoc::new(root);
+        xdoc.push_pi(XPi { target: "p".to_string(), value: String::new() });
+
+        let doc = Document::from_xdoc(&xdoc);
+        assert_eq!(6, doc.len());
+        assert_eq!(&PIKind(XPi { target: "p".to_string(), value: String::new() }),
+                   doc.kind(doc.first_child(doc.root()).unwrap()));
+        let a = doc.root_element().unwrap();
+        assert_eq!("xy".to_string(), doc.text(a));
+        let ids: Vec<uint> = doc.descendants(a).map(|id| id.index()).collect();
+        assert_eq!(vec![3, 4, 5], ids);
+
+        assert_eq!(None,                        doc.xdoc_pos(doc.root()));
+        assert_eq!(Some(PIPos(0)),              doc.xdoc_pos(NodeId(1)));
+        assert_eq!(Some(TreePos(vec![])),       doc.xdoc_pos(a));
+        assert_eq!(Some(TreePos(vec![0, 0])),   doc.xdoc_pos(NodeId(4)));
+        assert_eq!(Some(TreePos(vec![1])),      doc.xdoc_pos(NodeId(5)));
+    }
+}
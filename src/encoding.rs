@@ -0,0 +1,346 @@
+use std::io::{Buffer, IoError, IoResult, EndOfFile, InvalidInput};
+use std::io::standard_error;
+use std::char::from_u32;
+use std::str::from_utf8;
+use std::ascii::StrAsciiExt;
+
+/// Character encodings `XmlReader` is able to decode
+#[deriving(PartialEq, Eq, Clone, Show, Encodable, Decodable)]
+pub enum Encoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    /// ISO-8859-1, also used for US-ASCII documents
+    Latin1
+}
+
+impl Encoding {
+    /// Finds encoding by the name used in `encoding` pseudo-attribute
+    /// of XML declaration. Names are case insensitive.
+    pub fn from_label(label: &str) -> Option<Encoding> {
+        match label.to_ascii_lower().as_slice() {
+            "utf-8" | "utf8"                => Some(Utf8),
+            // Endianness of plain UTF-16 is given by its BOM
+            "utf-16" | "utf-16be"           => Some(Utf16Be),
+            "utf-16le"                      => Some(Utf16Le),
+            "iso-8859-1" | "iso_8859-1"
+            | "latin1" | "l1"
+            | "us-ascii" | "ascii"          => Some(Latin1),
+            _                               => None
+        }
+    }
+
+    fn is_utf16(&self) -> bool {
+        *self == Utf16Le || *self == Utf16Be
+    }
+}
+
+/// Detects encoding of a document from its first bytes, following
+/// appendix F of XML specification. Byte order mark takes precedence
+/// over XML declaration.
+///
+/// Returns encoding and the length of byte order mark that should
+/// be skipped.
+pub fn sniff(buf: &[u8]) -> (Encoding, uint) {
+    match buf {
+        [0xEF, 0xBB, 0xBF, ..]      => (Utf8, 3),
+        [0xFE, 0xFF, ..]            => (Utf16Be, 2),
+        [0xFF, 0xFE, ..]            => (Utf16Le, 2),
+        // UTF-16 without BOM, declaration can't tell us more
+        [0x3C, 0x00, 0x3F, 0x00, ..] => (Utf16Le, 0),
+        [0x00, 0x3C, 0x00, 0x3F, ..] => (Utf16Be, 0),
+        [0x3C, 0x3F, 0x78, 0x6D, ..] => {
+            let declared = declaration(buf)
+                .and_then(|decl| decl_pseudo_attr(decl, "encoding"))
+                .and_then(|label| Encoding::from_label(label.as_slice()));
+            match declared {
+                // An ASCII compatible document can't be UTF-16
+                Some(enc) if !enc.is_utf16() => (enc, 0),
+                _                            => (Utf8, 0)
+            }
+        },
+        _                           => (Utf8, 0)
+    }
+}
+
+/// Returns text of XML declaration `<?xml ... ?>` at the start of
+/// ASCII compatible `buf`, if it's all there.
+pub fn declaration<'a>(buf: &'a [u8]) -> Option<&'a str> {
+    if !buf.starts_with(b"<?xml") {
+        return None
+    }
+    let end = buf.windows(2).position(|w| w == b"?>");
+    end.and_then(|end| from_utf8(buf.slice_to(end + 2)))
+}
+
+/// Returns ASCII text at the start of `buf`, which is encoded in
+/// `encoding`. Declaration is ASCII, so UTF-16 can be narrowed down
+/// bytewise.
+fn ascii_start(buf: &[u8], encoding: &Encoding) -> Vec<u8> {
+    match *encoding {
+        Utf16Le => buf.chunks(2).take_while(|u| u.len() == 2 && u[1] == 0)
+                                .map(|u| u[0]).collect(),
+        Utf16Be => buf.chunks(2).take_while(|u| u.len() == 2 && u[0] == 0)
+                                .map(|u| u[1]).collect(),
+        _       => buf.to_vec()
+    }
+}
+
+/// Finds `version` given in XML declaration at the start of `buf`,
+/// which is encoded in `encoding` and has no byte order mark.
+pub fn declared_version(buf: &[u8], encoding: &Encoding) -> Option<String> {
+    let ascii = ascii_start(buf, encoding);
+    declaration(ascii.as_slice())
+        .and_then(|decl| decl_pseudo_attr(decl, "version"))
+}
+
+/// Checks if `buf`, the start of a document, may be followed by more
+/// of its XML declaration, so more input is needed to detect encoding.
+pub fn decl_incomplete(buf: &[u8]) -> bool {
+    // Shortest start that tells UTF-16 apart
+    if buf.len() < 4 {
+        return true
+    }
+    let (encoding, bom_len) = sniff(buf);
+    let ascii = ascii_start(buf.slice_from(bom_len), &encoding);
+    let ascii = ascii.as_slice();
+    if ascii.len() < 5 {
+        b"<?xml".starts_with(ascii)
+    } else {
+        ascii.starts_with(b"<?xml") && !ascii.windows(2).any(|w| w == b"?>")
+    }
+}
+
+/// Finds value of pseudo-attribute `name` in text of XML declaration.
+///
+/// E.g. for `<?xml version="1.0" encoding='UTF-8'?>` and `encoding`
+/// returns `UTF-8`.
+pub fn decl_pseudo_attr(decl: &str, name: &str) -> Option<String> {
+    let start = match decl.find_str(name) {
+        Some(start) => start + name.len(),
+        None        => return None
+    };
+    let rest = decl.slice_from(start).trim_left();
+    if !rest.starts_with("=") {
+        return None
+    }
+    let rest = rest.slice_from(1).trim_left();
+    let quote = match rest.chars().next() {
+        Some(q) if q == '"' || q == '\'' => q,
+        _                                => return None
+    };
+    let rest = rest.slice_from(1);
+    rest.find(quote).map(|end| rest.slice_to(end).to_string())
+}
+
+/// Result of decoding a single char
+#[deriving(PartialEq, Eq, Show)]
+pub enum Decoded {
+    /// Decoded char and the number of bytes it took
+    DecodedChar(char, uint),
+    /// Malformed byte sequence of given length
+    Malformed(uint),
+    /// Error reported by the underlying reader, including `EndOfFile`
+    DecodeErr(IoError)
+}
+
+/// Decodes chars in given `Encoding` out of a `Buffer`
+#[deriving(Clone, Encodable, Decodable)]
+pub struct Decoder {
+    pub encoding: Encoding,
+    /// UTF-16 code unit read while looking for a low surrogate
+    pending_unit: Option<u16>
+}
+
+fn peek_byte<B: Buffer>(src: &mut B) -> IoResult<u8> {
+    let buf = try!(src.fill_buf());
+    if buf.is_empty() {
+        Err(standard_error(EndOfFile))
+    } else {
+        Ok(buf[0])
+    }
+}
+
+impl Decoder {
+    pub fn new(encoding: Encoding) -> Decoder {
+        Decoder {
+            encoding: encoding,
+            pending_unit: None
+        }
+    }
+
+    /// Reads a single char from `src`. Bytes that aren't part of a
+    /// malformed sequence are never consumed, so decoding can resume
+    /// right after it.
+    pub fn decode<B: Buffer>(&mut self, src: &mut B) -> Decoded {
+        match self.encoding {
+            Utf8    => decode_utf8(src),
+            Latin1  => match src.read_byte() {
+                Ok(b)   => DecodedChar(b as char, 1),
+                Err(e)  => DecodeErr(e)
+            },
+            Utf16Le | Utf16Be => self.decode_utf16(src)
+        }
+    }
+
+    fn read_unit<B: Buffer>(&self, src: &mut B) -> IoResult<u16> {
+        let first = try!(src.read_byte()) as u16;
+        let second = match src.read_byte() {
+            Ok(b)   => b as u16,
+            // Odd number of bytes in input
+            Err(IoError{kind: EndOfFile, ..}) => {
+                return Err(IoError {
+                    kind: InvalidInput,
+                    desc: "truncated UTF-16 code unit",
+                    detail: None
+                })
+            },
+            Err(e)  => return Err(e)
+        };
+        match self.encoding {
+            Utf16Le => Ok(second << 8 | first),
+            _       => Ok(first << 8 | second)
+        }
+    }
+
+    fn decode_utf16<B: Buffer>(&mut self, src: &mut B) -> Decoded {
+        let unit = match self.pending_unit.take() {
+            Some(unit)  => unit,
+            None        => match self.read_unit(src) {
+                Ok(unit)    => unit,
+                Err(IoError{kind: InvalidInput, ..}) => return Malformed(1),
+                Err(e)      => return DecodeErr(e)
+            }
+        };
+
+        match unit {
+            0xD800...0xDBFF => {
+                let low = match self.read_unit(src) {
+                    Ok(low)     => low,
+                    Err(IoError{kind: EndOfFile, ..})
+                    | Err(IoError{kind: InvalidInput, ..}) => return Malformed(2),
+                    Err(e)      => return DecodeErr(e)
+                };
+                if low < 0xDC00 || low > 0xDFFF {
+                    // Not a pair, `low` starts the next char
+                    self.pending_unit = Some(low);
+                    return Malformed(2)
+                }
+                let code = 0x10000 + ((unit as u32 - 0xD800) << 10)
+                                   + (low as u32 - 0xDC00);
+                DecodedChar(from_u32(code).unwrap(), 4)
+            },
+            0xDC00...0xDFFF => Malformed(2),
+            _               => DecodedChar(from_u32(unit as u32).unwrap(), 2)
+        }
+    }
+}
+
+fn decode_utf8<B: Buffer>(src: &mut B) -> Decoded {
+    let first = match src.read_byte() {
+        Ok(b)   => b,
+        Err(e)  => return DecodeErr(e)
+    };
+    let width = match first {
+        0x00...0x7F => return DecodedChar(first as char, 1),
+        0xC2...0xDF => 2u,
+        0xE0...0xEF => 3u,
+        0xF0...0xF4 => 4u,
+        _           => return Malformed(1)
+    };
+
+    let mut code = (first & (0x7F >> width)) as u32;
+    for i in range(1, width) {
+        // Second byte has tighter bounds, to rule out overlong forms,
+        // surrogates and values past U+10FFFF
+        let (low, high) = match (i, first) {
+            (1, 0xE0) => (0xA0, 0xBF),
+            (1, 0xED) => (0x80, 0x9F),
+            (1, 0xF0) => (0x90, 0xBF),
+            (1, 0xF4) => (0x80, 0x8F),
+            _         => (0x80, 0xBF)
+        };
+        match peek_byte(src) {
+            Ok(b) if low <= b && b <= high => {
+                src.consume(1);
+                code = code << 6 | (b & 0x3F) as u32;
+            },
+            Ok(_)
+            | Err(IoError{kind: EndOfFile, ..}) => return Malformed(i),
+            Err(e) => return DecodeErr(e)
+        }
+    }
+    DecodedChar(from_u32(code).unwrap(), width)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{sniff, decl_pseudo_attr, declared_version, decl_incomplete};
+    use super::{Decoder, DecodedChar, Malformed};
+    use super::{Utf8, Utf16Le, Utf16Be, Latin1};
+    use std::io::BufReader;
+
+    #[test]
+    fn test_sniff_bom() {
+        assert_eq!((Utf8, 3),       sniff(b"\xEF\xBB\xBF<a/>"));
+        assert_eq!((Utf16Be, 2),    sniff(b"\xFE\xFF\x00<"));
+        assert_eq!((Utf16Le, 2),    sniff(b"\xFF\xFE<\x00"));
+        assert_eq!((Utf16Le, 0),    sniff(b"<\x00?\x00x\x00"));
+        assert_eq!((Utf8, 0),       sniff(b"<a/>"));
+    }
+
+    #[test]
+    fn test_sniff_decl() {
+        assert_eq!((Latin1, 0), sniff(b"<?xml version='1.0' encoding='ISO-8859-1'?>"));
+        assert_eq!((Utf8, 0),   sniff(b"<?xml version=\"1.0\" encoding = \"utf-8\"?>"));
+        // Can't be, since we read it as ASCII
+        assert_eq!((Utf8, 0),   sniff(b"<?xml version='1.0' encoding='UTF-16'?>"));
+        assert_eq!((Utf8, 0),   sniff(b"<?xml version='1.0' encoding='EBCDIC'?>"));
+    }
+
+    #[test]
+    fn test_decl_incomplete() {
+        assert!(decl_incomplete(b"\xEF\xBB\xBF<?"));
+        assert!(decl_incomplete(b"<?xml version='1.0'"));
+        assert!(decl_incomplete(b"<\x00?\x00x\x00m\x00l\x00 \x00"));
+        assert!(!decl_incomplete(b"<?xml version='1.0'?>"));
+        assert!(!decl_incomplete(b"<?xm-stylesheet"));
+        assert!(!decl_incomplete(b"<a/>"));
+    }
+
+    #[test]
+    fn test_pseudo_attr() {
+        let decl = "<?xml version=\"1.1\" encoding='latin1'?>";
+        assert_eq!(Some("1.1".to_string()),     decl_pseudo_attr(decl, "version"));
+        assert_eq!(Some("latin1".to_string()),  decl_pseudo_attr(decl, "encoding"));
+        assert_eq!(None,                        decl_pseudo_attr(decl, "standalone"));
+    }
+
+    #[test]
+    fn test_declared_version() {
+        assert_eq!(Some("1.1".to_string()),
+                   declared_version(b"<?xml version='1.1'?>", &Utf8));
+        assert_eq!(Some("1.1".to_string()),
+                   declared_version(b"\x00<\x00?\x00x\x00m\x00l\x00 \x00v\x00e\x00r\x00s\x00i\x00o\x00n\x00=\x00'\x001\x00.\x001\x00'\x00?\x00>", &Utf16Be));
+        assert_eq!(None, declared_version(b"<a/>", &Utf8));
+    }
+
+    #[test]
+    fn test_decode_utf16() {
+        let mut read = BufReader::new(b"a\x00\x3D\xD8\x00\xDE\x00\xDC");
+        let mut dec = Decoder::new(Utf16Le);
+        assert_eq!(DecodedChar('a', 2),         dec.decode(&mut read));
+        assert_eq!(DecodedChar('\U0001F600', 4), dec.decode(&mut read));
+        assert_eq!(Malformed(2),                dec.decode(&mut read));
+    }
+
+    #[test]
+    fn test_decode_utf8() {
+        let mut read = BufReader::new(b"\xC3\xA9\xE2\x82x\xFF");
+        let mut dec = Decoder::new(Utf8);
+        assert_eq!(DecodedChar('é', 2), dec.decode(&mut read));
+        assert_eq!(Malformed(2),        dec.decode(&mut read));
+        assert_eq!(DecodedChar('x', 1), dec.decode(&mut read));
+        assert_eq!(Malformed(1),        dec.decode(&mut read));
+    }
+}
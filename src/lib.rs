@@ -22,12 +22,36 @@
 // Ignore dead code
 #![allow(dead_code)]
 
+extern crate serialize;
+extern crate libc;
+
 pub use util::{is_hex_digit, is_digit};
+pub use util::{is_xml10_char, is_xml11_char, is_restricted_char};
 
 // Import mods
 pub mod parser;
 pub mod common;
 pub mod util;
+pub mod encoding;
+pub mod source;
+pub mod scan;
+pub mod name;
+pub mod push;
+pub mod parallel;
+pub mod dom;
+pub mod writer;
+pub mod xpath;
+pub mod select;
+pub mod stream;
+pub mod mapping;
+#[cfg(unix)]
+pub mod mmap;
+#[cfg(feature = "async")]
+pub mod async;
+#[cfg(feature = "serde")]
+pub mod de;
+#[cfg(feature = "serde")]
+pub mod ser;
 
 
 
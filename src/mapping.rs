@@ -0,0 +1,509 @@
+//! Typed mapping of structs to elements, through `FromXml` and `ToXml`.
+//!
+//! The traits are usually derived with the `xml_air_macros` compiler
+//! plugin, which reads `#[xml(...)]` attributes of fields:
+//!
+//!     #![feature(phase)]
+//!     #[phase(plugin)]
+//!     extern crate xml_air_macros;
+//!     extern crate xml_air;
+//!
+//!     #[derive(FromXml, ToXml)]
+//!     struct Entry {
+//!         #[xml(attr)]
+//!         id: uint,
+//!         #[xml(attr = "href", ns = "http://www.w3.org/1999/xlink")]
+//!         href: Option<String>,
+//!         #[xml(child = "title")]
+//!         title: String,
+//!         #[xml(child = "link", ns = "http://www.w3.org/2005/Atom")]
+//!         links: Vec<Link>,
+//!         #[xml(text)]
+//!         summary: String
+//!     }
+//!
+//!     let entry: Entry = try!(mapping::from_str(input));
+//!     let xml = mapping::to_string(&entry, "entry");
+//!
+//! Fields are mapped as follows:
+//!
+//! * `#[xml(attr)]` reads field from attribute named as the field, and
+//!   `#[xml(attr = "name")]` from attribute `name`. Values are parsed
+//!   with `FromStr` and written with `Show`.
+//! * `#[xml(text)]` reads field from text of the element.
+//! * `#[xml(child = "name")]` reads field from child element `name`,
+//!   with `FromXml`. Fields without `#[xml(...)]` are read from child
+//!   element named as the field. Text, numbers and `bool` are read from
+//!   text of the child.
+//! * `#[xml(ns = "uri")]` puts the attribute or child element in
+//!   namespace `uri`. Names without it are in no namespace, whatever
+//!   default namespace the document declares.
+//! * `Option` fields are `None` when there is no such attribute, text or
+//!   child. `Vec` fields get all children of that name.
+//!
+//! Errors for missing or malformed fields are `XmlError`s of kind
+//! `MissingField` or `InvalidValue`, positioned at the start tag of the
+//! element lacking the field, or holding the malformed value.
+
+use std::fmt::Show;
+use std::from_str::FromStr;
+
+use common::{XElem, XmlNS, XmlError, ErrKind, MissingField, InvalidValue};
+use common::{ElemNode, TextNode, CDataNode, CommentNode, PINode};
+use common::UnsupportedDoctype;
+use parser::{Parser, ElemStart, ElemEnd, EmptyElem, TextEvent, CDataEvent, CommentEvent};
+use parser::{PIEvent, DeclEvent, ErrEvent};
+use source::{XmlSource, SliceSource};
+use name::Name;
+use writer::elem_to_string;
+
+/// Position of an element's start tag, and of its child elements
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct TagPos {
+    /// Line of the `<`
+    pub line: uint,
+    /// Column of the `<`
+    pub col: uint,
+    /// Byte offset of the `<` in input
+    pub offset: u64,
+    /// Positions of child elements, in order
+    pub children: Vec<TagPos>
+}
+
+impl TagPos {
+    /// Constructs position of a tag without children, out of line,
+    /// column and offset as returned by `Parser::tag_position`
+    pub fn new(position: (uint, uint, u64)) -> TagPos {
+        let (line, col, offset) = position;
+        TagPos {
+            line: line,
+            col: col,
+            offset: offset,
+            children: Vec::new()
+        }
+    }
+
+    /// Position of an element that wasn't read from a document,
+    /// e.g. one built in code. Errors for it are at line 0.
+    pub fn unknown() -> TagPos {
+        TagPos::new((0, 0, 0))
+    }
+
+    /// Returns position of `index`th child element, or of this
+    /// element if that one isn't known
+    pub fn child<'a>(&'a self, index: uint) -> &'a TagPos {
+        match self.children.get(index) {
+            Some(pos)   => pos,
+            None        => self
+        }
+    }
+
+    /// Returns error of `kind` at the tag
+    pub fn error(&self, kind: ErrKind, msg: String) -> XmlError {
+        XmlError {
+            line: self.line,
+            col: self.col,
+            offset: self.offset,
+            msg: msg,
+            kind: kind,
+            mark: None
+        }
+    }
+}
+
+/// Types read from an element
+pub trait FromXml {
+    /// Reads value from `elem`, whose tags are at `pos`
+    fn from_xml(elem: &XElem, pos: &TagPos) -> Result<Self, XmlError>;
+}
+
+/// Types written as an element
+pub trait ToXml {
+    /// Returns value as element with local name `local` in namespace
+    /// `uri`, or in no namespace if it's empty
+    fn to_xml(&self, uri: &str, local: &str) -> XElem;
+}
+
+macro_rules! text_value(
+    ($($t:ty),*) => ($(
+        impl FromXml for $t {
+            fn from_xml(elem: &XElem, pos: &TagPos) -> Result<$t, XmlError> {
+                parse_value(elem.text().as_slice(), pos, "element", elem.name.as_slice())
+            }
+        }
+
+        impl ToXml for $t {
+            fn to_xml(&self, uri: &str, local: &str) -> XElem {
+                let mut elem = new_elem(uri, local);
+                write_text(&mut elem, self);
+                elem
+            }
+        }
+    )*)
+)
+
+text_value!(String, bool, char, int, i8, i16, i32, i64, uint, u8, u16, u32, u64, f32, f64)
+
+/// Reads `T` from root element of `input`. Fails with first error
+/// found in document, other than its DOCTYPE being ignored.
+pub fn from_str<T: FromXml>(input: &str) -> Result<T, XmlError> {
+    let mut source = SliceSource::from_str(input);
+    let mut parser = Parser::from_reader(&mut source);
+    let mut root = None;
+    loop {
+        match parser.pull() {
+            Some(ElemStart(elem)) if root.is_none() => {
+                root = Some(try!(read_elem(&mut parser, elem)))
+            },
+            Some(EmptyElem(elem)) if root.is_none() => {
+                root = Some((elem, TagPos::new(parser.tag_position())))
+            },
+            // DTD isn't needed, entities it declares fail where used
+            Some(ErrEvent(ref err)) if err.kind == UnsupportedDoctype => {},
+            Some(ErrEvent(err)) => return Err(err),
+            Some(_)             => {},
+            None                => break
+        }
+    }
+    match root {
+        Some((elem, pos))   => FromXml::from_xml(&elem, &pos),
+        None                => {
+            Err(parser.position_error(MissingField, "document has no root element".to_string()))
+        }
+    }
+}
+
+/// Returns `value` written as root element `root`
+pub fn to_string<T: ToXml>(value: &T, root: &str) -> String {
+    let mut elem = value.to_xml("", root);
+    elem.declare_namespaces();
+    elem_to_string(&elem)
+}
+
+/// Reads rest of element whose `ElemStart` was just pulled, like
+/// `Parser::read_subtree`, along with positions of its tags.
+/// Fails with first error found in element.
+pub fn read_elem<'r, R: XmlSource<'r>>(parser: &mut Parser<'r, R>, elem: XElem)
+                                     -> Result<(XElem, TagPos), XmlError> {
+    // Element and its open descendants
+    let mut open = vec![elem];
+    let mut positions = vec![TagPos::new(parser.tag_position())];
+    loop {
+        let node = match parser.pull() {
+            Some(ElemStart(elem))   => {
+                open.push(elem);
+                positions.push(TagPos::new(parser.tag_position()));
+                continue
+            },
+            Some(EmptyElem(elem))   => {
+                positions.last_mut().unwrap().children.push(TagPos::new(parser.tag_position()));
+                ElemNode(elem)
+            },
+            Some(ElemEnd(_))        => {
+                let elem = open.pop().unwrap();
+                let pos = positions.pop().unwrap();
+                if open.is_empty() {
+                    return Ok((elem, pos))
+                }
+                positions.last_mut().unwrap().children.push(pos);
+                ElemNode(elem)
+            },
+            Some(TextEvent(text))   => TextNode(text.into_string()),
+            Some(CDataEvent(text))  => CDataNode(text.into_string()),
+            Some(CommentEvent(text)) => CommentNode(text.into_string()),
+            Some(PIEvent(pi))       => PINode(pi),
+            Some(DeclEvent(_))      => continue,
+            Some(ErrEvent(err))     => return Err(err),
+            // Parser reports unclosed elements before input ends
+            None                    => {
+                return Err(parser.position_error(MissingField, "element is not closed".to_string()))
+            }
+        };
+        open.last_mut().unwrap().children.push(node);
+    }
+}
+
+/// Returns `local` qualified by namespace `uri` for error messages
+fn qualified(uri: &str, local: &str) -> String {
+    if uri.is_empty() {
+        local.to_string()
+    } else {
+        format!("{{{}}}{}", uri, local)
+    }
+}
+
+fn parse_value<T: FromStr>(value: &str, pos: &TagPos, what: &str, name: &str)
+                          -> Result<T, XmlError> {
+    match FromStr::from_str(value) {
+        Some(value) => Ok(value),
+        None        => {
+            let msg = format!("invalid value '{}' of {} '{}'", value, what, name);
+            Err(pos.error(InvalidValue, msg))
+        }
+    }
+}
+
+/// Returns element with local name `local` in namespace `uri`
+pub fn new_elem(uri: &str, local: &str) -> XElem {
+    XElem::new(XmlNS { name: Name::new(""), uri: Name::new(uri) }, local)
+}
+
+/// Reads value of attribute `local` in namespace `uri` of `elem`
+pub fn read_attr<T: FromStr>(elem: &XElem, pos: &TagPos, uri: &str, local: &str)
+                            -> Result<T, XmlError> {
+    match try!(read_attr_opt(elem, pos, uri, local)) {
+        Some(value) => Ok(value),
+        None        => {
+            let msg = format!("missing attribute '{}'", qualified(uri, local));
+            Err(pos.error(MissingField, msg))
+        }
+    }
+}
+
+/// Reads value of attribute `local` in namespace `uri` of `elem`,
+/// if it has one
+pub fn read_attr_opt<T: FromStr>(elem: &XElem, pos: &TagPos, uri: &str, local: &str)
+                                -> Result<Option<T>, XmlError> {
+    match elem.attr_ns(uri, local) {
+        Some(value) => {
+            parse_value(value, pos, "attribute", qualified(uri, local).as_slice()).map(Some)
+        },
+        None        => Ok(None)
+    }
+}
+
+/// Reads text of `elem`
+pub fn read_text<T: FromStr>(elem: &XElem, pos: &TagPos) -> Result<T, XmlError> {
+    parse_value(elem.text().as_slice(), pos, "text of element", elem.name.as_slice())
+}
+
+/// Reads text of `elem`, if it isn't empty
+pub fn read_text_opt<T: FromStr>(elem: &XElem, pos: &TagPos) -> Result<Option<T>, XmlError> {
+    let text = elem.text();
+    if text.is_empty() {
+        return Ok(None)
+    }
+    parse_value(text.as_slice(), pos, "text of element", elem.name.as_slice()).map(Some)
+}
+
+/// Reads first child element of `elem` with local name `local`
+/// in namespace `uri`
+pub fn read_child<T: FromXml>(elem: &XElem, pos: &TagPos, uri: &str, local: &str)
+                             -> Result<T, XmlError> {
+    match try!(read_child_opt(elem, pos, uri, local)) {
+        Some(value) => Ok(value),
+        None        => {
+            let msg = format!("missing child element '{}'", qualified(uri, local));
+            Err(pos.error(MissingField, msg))
+        }
+    }
+}
+
+/// Reads first child element of `elem` with local name `local`
+/// in namespace `uri`, if it has one
+pub fn read_child_opt<T: FromXml>(elem: &XElem, pos: &TagPos, uri: &str, local: &str)
+                                 -> Result<Option<T>, XmlError> {
+    for (i, child) in elem.child_elems().enumerate() {
+        if child.is_named_ns(uri, local) {
+            return FromXml::from_xml(child, pos.child(i)).map(Some)
+        }
+    }
+    Ok(None)
+}
+
+/// Reads all child elements of `elem` with local name `local`
+/// in namespace `uri`
+pub fn read_children<T: FromXml>(elem: &XElem, pos: &TagPos, uri: &str, local: &str)
+                                -> Result<Vec<T>, XmlError> {
+    let mut values = Vec::new();
+    for (i, child) in elem.child_elems().enumerate() {
+        if child.is_named_ns(uri, local) {
+            values.push(try!(FromXml::from_xml(child, pos.child(i))));
+        }
+    }
+    Ok(values)
+}
+
+/// Sets attribute `local` in namespace `uri` of `elem` to `value`
+pub fn write_attr<T: Show>(elem: &mut XElem, uri: &str, local: &str, value: &T) {
+    let namespace = XmlNS { name: Name::new(""), uri: Name::new(uri) };
+    elem.set_attr_ns(namespace, local, value.to_string().as_slice());
+}
+
+/// Sets attribute `local` in namespace `uri` of `elem` to `value`,
+/// if there is one
+pub fn write_attr_opt<T: Show>(elem: &mut XElem, uri: &str, local: &str, value: &Option<T>) {
+    match *value {
+        Some(ref value) => write_attr(elem, uri, local, value),
+        None            => {}
+    }
+}
+
+/// Adds `value` as text of `elem`
+pub fn write_text<T: Show>(elem: &mut XElem, value: &T) {
+    let text = value.to_string();
+    if !text.is_empty() {
+        elem.append_child(TextNode(text));
+    }
+}
+
+/// Adds `value` as text of `elem`, if there is one
+pub fn write_text_opt<T: Show>(elem: &mut XElem, value: &Option<T>) {
+    match *value {
+        Some(ref value) => write_text(elem, value),
+        None            => {}
+    }
+}
+
+/// Adds `value` as child element of `elem`, with local name `local`
+/// in namespace `uri`
+pub fn write_child<T: ToXml>(elem: &mut XElem, uri: &str, local: &str, value: &T) {
+    elem.append_child(ElemNode(value.to_xml(uri, local)));
+}
+
+/// Adds `value` as child element of `elem`, if there is one
+pub fn write_child_opt<T: ToXml>(elem: &mut XElem, uri: &str, local: &str, value: &Option<T>) {
+    match *value {
+        Some(ref value) => write_child(elem, uri, local, value),
+        None            => {}
+    }
+}
+
+/// Adds each of `values` as child element of `elem`
+pub fn write_children<T: ToXml>(elem: &mut XElem, uri: &str, local: &str, values: &Vec<T>) {
+    for value in values.iter() {
+        write_child(elem, uri, local, value);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FromXml, ToXml, TagPos, from_str, to_string, new_elem};
+    use super::{read_attr, read_attr_opt, read_text, read_child, read_children};
+    use super::{write_attr, write_attr_opt, write_text, write_child, write_children};
+    use common::{XElem, XmlError, MissingField, InvalidValue, UnknownEntity};
+
+    static ATOM: &'static str = "http://www.w3.org/2005/Atom";
+
+    /// Impls are written as `#[derive(FromXml, ToXml)]` writes them
+    #[deriving(PartialEq, Show)]
+    struct Entry {
+        id: uint,
+        lang: Option<String>,
+        title: String,
+        links: Vec<Link>
+    }
+
+    #[deriving(PartialEq, Show)]
+    struct Link {
+        href: String,
+        text: String
+    }
+
+    impl FromXml for Entry {
+        fn from_xml(elem: &XElem, pos: &TagPos) -> Result<Entry, XmlError> {
+            Ok(Entry {
+                id: try!(read_attr(elem, pos, "", "id")),
+                lang: try!(read_attr_opt(elem, pos, "", "lang")),
+                title: try!(read_child(elem, pos, "", "title")),
+                links: try!(read_children(elem, pos, ATOM, "link"))
+            })
+        }
+    }
+
+    impl ToXml for Entry {
+        fn to_xml(&self, uri: &str, local: &str) -> XElem {
+            let mut elem = new_elem(uri, local);
+            write_attr(&mut elem, "", "id", &self.id);
+            write_attr_opt(&mut elem, "", "lang", &self.lang);
+            write_child(&mut elem, "", "title", &self.title);
+            write_children(&mut elem, ATOM, "link", &self.links);
+            elem
+        }
+    }
+
+    impl FromXml for Link {
+        fn from_xml(elem: &XElem, pos: &TagPos) -> Result<Link, XmlError> {
+            Ok(Link {
+                href: try!(read_attr(elem, pos, "", "href")),
+                text: try!(read_text(elem, pos))
+            })
+        }
+    }
+
+    impl ToXml for Link {
+        fn to_xml(&self, uri: &str, local: &str) -> XElem {
+            let mut elem = new_elem(uri, local);
+            write_attr(&mut elem, "", "href", &self.href);
+            write_text(&mut elem, &self.text);
+            elem
+        }
+    }
+
+    fn entry() -> Entry {
+        Entry {
+            id: 7,
+            lang: None,
+            title: "T".to_string(),
+            links: vec![Link { href: "/a".to_string(), text: "A".to_string() }]
+        }
+    }
+
+    fn error(input: &str) -> XmlError {
+        from_str::<Entry>(input).err().expect("expected error")
+    }
+
+    #[test]
+    fn test_from_str() {
+        let input = "<entry id='7' xmlns:a='http://www.w3.org/2005/Atom'><title>T</title>\
+                     <link href='/b'/><a:link href='/a'>A</a:link></entry>";
+        assert_eq!(Ok(entry()), from_str(input));
+    }
+
+    #[test]
+    fn test_to_string() {
+        let xml = to_string(&entry(), "entry");
+        assert_eq!("<entry id=\"7\"><title>T</title>\
+                    <link href=\"/a\" xmlns=\"http://www.w3.org/2005/Atom\">A</link></entry>",
+                   xml.as_slice());
+        assert_eq!(Ok(entry()), from_str(xml.as_slice()));
+    }
+
+    #[test]
+    fn test_field_errors() {
+        // Missing fields are reported at tag of element lacking them
+        let err = error("<entry id='7'>\n  <title>T</title>\n  \
+                         <a:link xmlns:a='http://www.w3.org/2005/Atom'/>\n</entry>");
+        assert_eq!(MissingField, err.kind);
+        assert_eq!("missing attribute 'href'", err.msg.as_slice());
+        assert_eq!((3, 3, 36), (err.line, err.col, err.offset));
+
+        let err = error("<entry id='7'/>");
+        assert_eq!(MissingField, err.kind);
+        assert_eq!((1, 1, 0), (err.line, err.col, err.offset));
+
+        // Malformed values are reported at tag holding them
+        let err = error("<entry>\n<title>T</title></entry>\n");
+        assert_eq!(MissingField, err.kind);
+        let err = error("<entry id='x'>\n<title>T</title></entry>\n");
+        assert_eq!(InvalidValue, err.kind);
+        assert_eq!("invalid value 'x' of attribute 'id'", err.msg.as_slice());
+        assert_eq!((1, 1, 0), (err.line, err.col, err.offset));
+
+        let err = from_str::<uint>("<n>\n<m/>x</n>").err().expect("expected error");
+        assert_eq!(InvalidValue, err.kind);
+        assert_eq!((1, 1, 0), (err.line, err.col, err.offset));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(UnknownEntity, error("<entry id='7'>&x;</entry>").kind);
+        assert_eq!(MissingField, error("<!-- no root -->").kind);
+        let input = "<!DOCTYPE entry [<!ATTLIST entry id CDATA '1'>]><entry id='7'/>";
+        assert_eq!("missing child element 'title'", error(input).msg.as_slice());
+        // Built elements have no known positions
+        let elem = new_elem("", "entry");
+        let entry: Result<Entry, XmlError> = FromXml::from_xml(&elem, &TagPos::unknown());
+        assert_eq!(0, entry.err().expect("expected error").line);
+    }
+}
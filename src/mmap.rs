@@ -0,0 +1,161 @@
+//! Input from memory-mapped files.
+//!
+//! Parsing a mapped file reads it straight from page cache, without
+//! copying it through a `BufferedReader`. Text of events is borrowed
+//! from the mapping and offsets in errors and `ParserState` are
+//! file offsets. Only available on Unix.
+
+use libc;
+use std::io::{IoResult, IoError, OtherIoError};
+use std::io::fs;
+use std::mem;
+use std::os::{MemoryMap, MapReadable, MapFd};
+use std::raw;
+
+use parallel::SharedInput;
+use source::SliceSource;
+
+/// A file mapped into memory for reading
+///
+/// E.g.
+///
+///     let file = try!(MappedFile::open(&Path::new("export.xml")));
+///     let mut source = file.source();
+///     let mut parser = Parser::from_reader(&mut source);
+pub struct MappedFile {
+    /// Mapping of the file, `None` if file is empty
+    map: Option<MemoryMap>
+}
+
+impl MappedFile {
+    pub fn open(path: &Path) -> IoResult<MappedFile> {
+        let len = try!(fs::stat(path)).size as uint;
+        // Empty files can't be mapped
+        if len == 0 {
+            return Ok(MappedFile { map: None })
+        }
+
+        let fd = path.with_c_str(|path| unsafe {
+            libc::open(path, libc::O_RDONLY, 0)
+        });
+        if fd < 0 {
+            return Err(IoError::last_error())
+        }
+        let map = MemoryMap::new(len, &[MapReadable, MapFd(fd)]);
+        // Mapping stays valid once file is closed
+        unsafe { libc::close(fd); }
+
+        match map {
+            Ok(map) => Ok(MappedFile { map: Some(map) }),
+            Err(err) => Err(IoError {
+                kind: OtherIoError,
+                desc: "couldn't map file",
+                detail: Some(err.to_string())
+            })
+        }
+    }
+
+    /// Returns contents of the file.
+    ///
+    /// The slice reads the mapping, not a copy. If the file is truncated
+    /// while mapped, reading past its new end raises `SIGBUS`, and other
+    /// changes to the file show up in the slice. Map only files nothing
+    /// else writes to while they're parsed.
+    pub fn as_slice<'a>(&'a self) -> &'a [u8] {
+        match self.map {
+            Some(ref map) => unsafe {
+                mem::transmute(raw::Slice {
+                    data: map.data() as *const u8,
+                    len: map.len()
+                })
+            },
+            None => &[]
+        }
+    }
+
+    /// Returns a source over the file, which lets parser borrow text
+    /// of events from it.
+    pub fn source<'a>(&'a self) -> SliceSource<'a> {
+        SliceSource::new(self.as_slice())
+    }
+}
+
+/// Lets tasks of `parallel::parse_parallel` share the mapping
+impl SharedInput for MappedFile {
+    fn bytes<'a>(&'a self) -> &'a [u8] {
+        self.as_slice()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::MappedFile;
+    use parser::{Parser, XmlEvent, TextEvent, EmptyElem, ErrEvent};
+    use parallel::parse_parallel;
+    use common::UnknownEntity;
+    use std::io::{File, TempDir};
+    use std::str::Slice;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_parse_mapped() {
+        let dir = TempDir::new("xml_air").unwrap();
+        let path = dir.path().join("doc.xml");
+        File::create(&path).write(b"\xEF\xBB\xBFtext<a/>&x;").unwrap();
+
+        let file = MappedFile::open(&path).unwrap();
+        let mut source = file.source();
+        let mut parser = Parser::from_reader(&mut source);
+        match parser.pull() {
+            Some(TextEvent(Slice(text))) => {
+                assert_eq!("text", text);
+                assert_eq!(file.as_slice().slice_from(3).as_ptr(), text.as_ptr());
+            },
+            ev => assert!(false, "expected borrowed text, got {}", ev)
+        }
+        match parser.pull() {
+            Some(EmptyElem(elem)) => assert_eq!("a", elem.name.as_slice()),
+            ev => assert!(false, "expected empty tag, got {}", ev)
+        }
+        parser.pull();
+        match parser.pull() {
+            Some(ErrEvent(err)) => {
+                assert_eq!(UnknownEntity, err.kind);
+                assert_eq!(11, err.offset);
+            },
+            ev => assert!(false, "expected error, got {}", ev)
+        }
+    }
+
+    #[test]
+    fn test_parse_mapped_parallel() {
+        let dir = TempDir::new("xml_air").unwrap();
+        let path = dir.path().join("records.xml");
+        File::create(&path).write(b"<r><a>x</a><b/>\n<c>y</c><d/></r>").unwrap();
+
+        let file = Arc::new(MappedFile::open(&path).unwrap());
+        let mut source = file.source();
+        let mut parser = Parser::from_reader(&mut source);
+        let mut expected = Vec::new();
+        loop {
+            match parser.pull() {
+                Some(ev)    => expected.push(ev.into_owned()),
+                None        => break
+            }
+        }
+        let events: Vec<XmlEvent> = parse_parallel(file.clone(), 2).collect();
+        assert_eq!(expected, events);
+    }
+
+    #[test]
+    fn test_empty_file() {
+        let dir = TempDir::new("xml_air").unwrap();
+        let path = dir.path().join("empty.xml");
+        File::create(&path).unwrap();
+
+        let file = MappedFile::open(&path).unwrap();
+        assert_eq!(0, file.as_slice().len());
+        let mut source = file.source();
+        assert_eq!(None, Parser::from_reader(&mut source).pull());
+    }
+}
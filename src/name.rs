@@ -0,0 +1,215 @@
+//! Interned names of elements, attributes and namespaces.
+//!
+//! Record oriented documents repeat the same few names over and over.
+//! `Parser` keeps them in an `Interner`, so each name is allocated once
+//! and shared by all events and trees built from them.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Writer};
+use std::mem;
+use std::ptr;
+use std::raw;
+use std::rt::heap::{allocate, deallocate};
+use std::str;
+use std::sync::atomic::{AtomicUint, Relaxed, Release, Acquire, fence};
+use serialize::{Encodable, Decodable, Encoder, Decoder};
+
+/// Start of the allocation of a name, followed by its bytes
+struct Header {
+    refs: AtomicUint,
+    len: uint
+}
+
+/// A name, which is cheap to clone and compare
+///
+/// Reference count and bytes of a name share one allocation, like
+/// an `Arc<str>` would.
+pub struct Name {
+    ptr: *mut Header
+}
+
+/// Size of allocation of a name of `len` bytes
+fn alloc_size(len: uint) -> uint {
+    mem::size_of::<Header>() + len
+}
+
+impl Name {
+    /// Creates a name that isn't interned. Use `Interner::intern`
+    /// to share names.
+    pub fn new(name: &str) -> Name {
+        unsafe {
+            let ptr = allocate(alloc_size(name.len()), mem::min_align_of::<Header>());
+            let header = ptr as *mut Header;
+            ptr::write(header, Header {
+                refs: AtomicUint::new(1),
+                len: name.len()
+            });
+            ptr::copy_nonoverlapping_memory(header.offset(1) as *mut u8, name.as_ptr(),
+                                            name.len());
+            Name {
+                ptr: header
+            }
+        }
+    }
+
+    /// Checks if both names share the same allocation
+    pub fn ptr_eq(&self, other: &Name) -> bool {
+        self.ptr == other.ptr
+    }
+}
+
+impl Clone for Name {
+    fn clone(&self) -> Name {
+        unsafe {
+            (*self.ptr).refs.fetch_add(1, Relaxed);
+        }
+        Name {
+            ptr: self.ptr
+        }
+    }
+}
+
+impl Drop for Name {
+    fn drop(&mut self) {
+        unsafe {
+            if (*self.ptr).refs.fetch_sub(1, Release) != 1 {
+                return
+            }
+            // Other names' uses of the bytes happen before they're freed
+            fence(Acquire);
+            let size = alloc_size((*self.ptr).len);
+            deallocate(self.ptr as *mut u8, size, mem::min_align_of::<Header>());
+        }
+    }
+}
+
+impl Str for Name {
+    fn as_slice<'a>(&'a self) -> &'a str {
+        unsafe {
+            let bytes: &'a [u8] = mem::transmute(raw::Slice {
+                data: self.ptr.offset(1) as *const u8,
+                len: (*self.ptr).len
+            });
+            str::raw::from_utf8(bytes)
+        }
+    }
+}
+
+impl Deref<str> for Name {
+    fn deref<'a>(&'a self) -> &'a str {
+        self.as_slice()
+    }
+}
+
+impl PartialEq for Name {
+    fn eq(&self, other: &Name) -> bool {
+        self.ptr_eq(other) || self.as_slice() == other.as_slice()
+    }
+}
+
+impl Eq for Name {}
+
+impl PartialOrd for Name {
+    fn partial_cmp(&self, other: &Name) -> Option<Ordering> {
+        self.as_slice().partial_cmp(&other.as_slice())
+    }
+}
+
+impl Ord for Name {
+    fn cmp(&self, other: &Name) -> Ordering {
+        self.as_slice().cmp(&other.as_slice())
+    }
+}
+
+impl<S: Writer> Hash<S> for Name {
+    fn hash(&self, state: &mut S) {
+        self.as_slice().hash(state)
+    }
+}
+
+impl fmt::Show for Name {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.as_slice().fmt(f)
+    }
+}
+
+impl<S: Encoder<E>, E> Encodable<S, E> for Name {
+    fn encode(&self, s: &mut S) -> Result<(), E> {
+        s.emit_str(self.as_slice())
+    }
+}
+
+impl<D: Decoder<E>, E> Decodable<D, E> for Name {
+    fn decode(d: &mut D) -> Result<Name, E> {
+        let name = try!(d.read_str());
+        Ok(Name::new(name.as_slice()))
+    }
+}
+
+/// Set of names, that returns the same `Name` for equal strings
+#[deriving(Clone)]
+pub struct Interner {
+    /// Each name keyed by itself, so they share one allocation
+    names: HashMap<Name, Name>
+}
+
+impl Interner {
+    pub fn new() -> Interner {
+        Interner {
+            names: HashMap::new()
+        }
+    }
+
+    /// Returns `Name` equal to `name`, adding it if it's new
+    pub fn intern(&mut self, name: &str) -> Name {
+        match self.names.find_equiv(&name) {
+            Some(name)  => return name.clone(),
+            None        => {}
+        }
+        let interned = Name::new(name);
+        self.names.insert(interned.clone(), interned.clone());
+        interned
+    }
+
+    /// Returns `Name` equal to `name`, if it was interned
+    pub fn lookup(&self, name: &str) -> Option<Name> {
+        self.names.find_equiv(&name).map(|name| name.clone())
+    }
+
+    /// Number of interned names
+    pub fn len(&self) -> uint {
+        self.names.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Name, Interner};
+
+    #[test]
+    fn test_intern() {
+        let mut interner = Interner::new();
+        let a = interner.intern("a");
+        let b = interner.intern("b");
+        assert!(a.ptr_eq(&interner.intern("a")));
+        assert!(!a.ptr_eq(&b));
+        assert_eq!(2,               interner.len());
+        assert_eq!(Some(b.clone()), interner.lookup("b"));
+        assert_eq!(None,            interner.lookup("c"));
+    }
+
+    #[test]
+    fn test_name() {
+        let name = Name::new("rec");
+        assert_eq!(name,            Interner::new().intern("rec"));
+        assert_eq!("rec",           name.as_slice());
+        assert_eq!(3,               name.len());
+        assert_eq!("rec".to_string(), format!("{}", name));
+
+        let copy = name.clone();
+        drop(name);
+        assert_eq!("rec",           copy.as_slice());
+        assert_eq!("",              Name::new("").as_slice());
+    }
+}
@@ -0,0 +1,400 @@
+//! Parallel parsing of large in-memory documents made of many
+//! sibling records.
+//!
+//! Input is split between children of the root element, found by a
+//! quick scan that only tells markup from text. Each chunk is parsed in
+//! its own task, by a `Parser` resumed from the state after the root
+//! start tag, so it inherits namespaces declared on the root. Events are
+//! returned in document order.
+//!
+//! A chunk that doesn't end between two children, as can happen in
+//! malformed input, is parsed again sequentially along with the rest
+//! of the input.
+//!
+//! Input is shared by tasks rather than copied, so it may be a file
+//! mapped by `mmap::MappedFile`:
+//!
+//!     let file = Arc::new(try!(MappedFile::open(&Path::new("export.xml"))));
+//!     for event in parse_parallel(file, 8) {
+//!         ...
+//!     }
+
+use std::collections::{RingBuf, Deque};
+use std::io::{Seek, SeekSet};
+use std::sync::{Arc, Future};
+
+use common::Version10;
+use encoding::Utf8;
+use parser::{Parser, ParserState, XmlEvent, ElemStart};
+use source::SliceSource;
+
+/// Bytes in memory that tasks can share
+pub trait SharedInput: Send + Sync {
+    fn bytes<'a>(&'a self) -> &'a [u8];
+}
+
+impl SharedInput for Vec<u8> {
+    fn bytes<'a>(&'a self) -> &'a [u8] {
+        self.as_slice()
+    }
+}
+
+/// Events of a chunk, along with the state it was started from
+struct Chunk {
+    events: Vec<XmlEvent<'static>>,
+    start: ParserState,
+    /// Whether chunk ended between two children of the root
+    complete: bool
+}
+
+/// Iterator over events of a document parsed in parallel,
+/// see `parse_parallel`
+pub struct ParallelEvents<B> {
+    input: Arc<B>,
+    /// Events of parsed chunks, not returned yet
+    events: RingBuf<XmlEvent<'static>>,
+    /// Chunks being parsed, in document order
+    chunks: RingBuf<Future<Chunk>>
+}
+
+/// Parses `input` using up to `tasks` tasks, and returns its events.
+///
+/// Only UTF-8 documents in XML 1.0 are split, others are
+/// parsed by a single task.
+pub fn parse_parallel<B: SharedInput>(input: Arc<B>, tasks: uint) -> ParallelEvents<B> {
+    let mut events = RingBuf::new();
+    let mut chunks = RingBuf::new();
+
+    // Prolog and root start tag are parsed right away
+    let root_state = {
+        let mut source = SliceSource::new(input.bytes());
+        let mut parser = Parser::from_reader(&mut source);
+        let mut found_root = false;
+        loop {
+            match parser.pull() {
+                Some(ev) => {
+                    match ev {
+                        ElemStart(_) if parser.depth == 1 => found_root = true,
+                        _ => {}
+                    }
+                    events.push_back(ev.into_owned());
+                },
+                None => break
+            }
+            if found_root && parser.save_state().is_some() {
+                break
+            }
+        }
+        if found_root { parser.save_state() } else { None }
+    };
+
+    match root_state {
+        Some(state) => {
+            let splittable = state.encoding() == Utf8 && state.version() == Version10;
+            let bounds = if splittable && tasks > 1 {
+                find_boundaries(input.bytes(), state.offset() as uint, tasks)
+            } else {
+                Vec::new()
+            };
+            let mut start = state.clone();
+            for &(offset, line, col) in bounds.iter() {
+                chunks.push_back(spawn_chunk(input.clone(), start, Some(offset)));
+                start = state.with_position(offset as u64, line, col);
+            }
+            chunks.push_back(spawn_chunk(input.clone(), start, None));
+        },
+        None => {}
+    }
+
+    ParallelEvents {
+        input: input,
+        events: events,
+        chunks: chunks
+    }
+}
+
+/// Parses chunk of `input` from `start` to byte offset `end`,
+/// or to the end of input, in a new task
+fn spawn_chunk<B: SharedInput>(input: Arc<B>, start: ParserState, end: Option<uint>)
+                               -> Future<Chunk> {
+    Future::spawn(proc() {
+        let buf = match end {
+            Some(end)   => input.bytes().slice_to(end),
+            None        => input.bytes()
+        };
+        let mut events = Vec::new();
+        let mut complete = end.is_none();
+        {
+            let mut source = SliceSource::new(buf);
+            source.seek(start.input_offset() as i64, SeekSet).unwrap();
+            let mut parser = Parser::from_state(&mut source, start.clone());
+            loop {
+                match parser.pull() {
+                    Some(ev)    => events.push(ev.into_owned()),
+                    None        => break
+                }
+                // Stop before end of chunk is taken for end of input
+                match (end, parser.save_state()) {
+                    (Some(end), Some(state)) if state.offset() == end as u64 => {
+                        complete = state.depth() == start.depth();
+                        break
+                    },
+                    _ => {}
+                }
+            }
+        }
+        Chunk {
+            events: events,
+            start: start,
+            complete: complete
+        }
+    })
+}
+
+impl<B: SharedInput> Iterator<XmlEvent<'static>> for ParallelEvents<B> {
+    fn next(&mut self) -> Option<XmlEvent<'static>> {
+        loop {
+            match self.events.pop_front() {
+                Some(ev) => return Some(ev),
+                None => {}
+            }
+            let chunk = match self.chunks.pop_front() {
+                Some(future)    => future.unwrap(),
+                None            => return None
+            };
+            if chunk.complete {
+                self.events.extend(chunk.events.into_iter());
+            } else {
+                // Split was wrong, so parse rest of input in one go
+                self.chunks = RingBuf::new();
+                self.chunks.push_back(spawn_chunk(self.input.clone(), chunk.start, None));
+            }
+        }
+    }
+}
+
+/// Scans `buf` from `start`, right after root start tag, for offsets
+/// between children of the root that split it into about `parts`
+/// equal parts. Returns offsets with their line and column.
+fn find_boundaries(buf: &[u8], start: uint, parts: uint) -> Vec<(uint, u64, u64)> {
+    let mut scanner = Scanner {
+        buf: buf,
+        pos: 0,
+        line: 1,
+        line_start: 0
+    };
+    scanner.advance(start);
+
+    let step = (buf.len() - start) / parts;
+    let mut next = start + step;
+    let mut bounds = Vec::new();
+    let mut depth = 1u;
+
+    while bounds.len() + 1 < parts {
+        let lt = match scanner.find(b"<") {
+            Some(lt)    => lt,
+            None        => break
+        };
+        scanner.advance(lt);
+        let rest = buf.slice_from(lt);
+        let is_child_end = if rest.starts_with(b"<!--") {
+            scanner.skip_past(b"-->");
+            false
+        } else if rest.starts_with(b"<![CDATA[") {
+            scanner.skip_past(b"]]>");
+            false
+        } else if rest.starts_with(b"<?") {
+            scanner.skip_past(b"?>");
+            false
+        } else if rest.starts_with(b"<!") {
+            scanner.skip_past(b">");
+            false
+        } else if rest.starts_with(b"</") {
+            scanner.skip_past(b">");
+            depth -= 1;
+            if depth == 0 {
+                break
+            }
+            depth == 1
+        } else if rest.len() > 1 && is_name_start_byte(rest[1]) {
+            if scanner.skip_tag() {
+                depth == 1
+            } else {
+                depth += 1;
+                false
+            }
+        } else {
+            scanner.advance(lt + 1);
+            false
+        };
+
+        if is_child_end && scanner.pos >= next {
+            bounds.push((scanner.pos, scanner.line, scanner.col()));
+            next = scanner.pos + step;
+        }
+    }
+    bounds
+}
+
+/// Checks if `b` can start a name, assuming any non-ASCII char can
+fn is_name_start_byte(b: u8) -> bool {
+    match b {
+        b'A'...b'Z' | b'a'...b'z' | b'_' | b':' => true,
+        _ => b >= 0x80
+    }
+}
+
+/// Position in UTF-8 input, which keeps track of lines
+struct Scanner<'a> {
+    buf: &'a [u8],
+    pos: uint,
+    line: u64,
+    /// Offset where current line starts
+    line_start: uint
+}
+
+impl<'a> Scanner<'a> {
+    /// Moves to offset `to`, counting new lines on the way
+    fn advance(&mut self, to: uint) {
+        for i in range(self.pos, to) {
+            let is_newline = match self.buf[i] {
+                b'\n' => true,
+                // Lone `\r`, `\r\n` is counted at `\n`
+                b'\r' => i + 1 >= self.buf.len() || self.buf[i + 1] != b'\n',
+                _     => false
+            };
+            if is_newline {
+                self.line += 1;
+                self.line_start = i + 1;
+            }
+        }
+        self.pos = to;
+    }
+
+    /// Number of chars between start of line and current position
+    fn col(&self) -> u64 {
+        self.buf.slice(self.line_start, self.pos).iter()
+            .filter(|&&b| b & 0xC0 != 0x80)
+            .count() as u64
+    }
+
+    /// Finds offset of `pat` after current position
+    fn find(&self, pat: &[u8]) -> Option<uint> {
+        let rest = self.buf.slice_from(self.pos);
+        if rest.len() < pat.len() {
+            return None
+        }
+        range(0, rest.len() - pat.len() + 1)
+            .find(|&i| rest.slice_from(i).starts_with(pat))
+            .map(|i| self.pos + i)
+    }
+
+    /// Moves past next `pat`, or to end of input if there is none
+    fn skip_past(&mut self, pat: &[u8]) {
+        let to = match self.find(pat) {
+            Some(i) => i + pat.len(),
+            None    => self.buf.len()
+        };
+        self.advance(to);
+    }
+
+    /// Moves past start tag at current position, skipping quoted
+    /// attribute values. Returns whether the tag is empty.
+    fn skip_tag(&mut self) -> bool {
+        let mut i = self.pos + 1;
+        let mut last = 0u8;
+        while i < self.buf.len() {
+            match self.buf[i] {
+                b'>' => {
+                    self.advance(i + 1);
+                    return last == b'/'
+                },
+                quote @ b'"' | quote @ b'\'' => {
+                    self.advance(i + 1);
+                    self.skip_past(&[quote]);
+                    i = self.pos;
+                    last = quote;
+                    continue
+                },
+                b => last = b
+            }
+            i += 1;
+        }
+        self.advance(i);
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_parallel, find_boundaries};
+    use parser::{Parser, XmlEvent};
+    use source::SliceSource;
+    use std::sync::Arc;
+
+    fn parse_sequential(input: &[u8]) -> Vec<XmlEvent<'static>> {
+        let mut source = SliceSource::new(input);
+        let mut parser = Parser::from_reader(&mut source);
+        let mut events = Vec::new();
+        loop {
+            match parser.pull() {
+                Some(ev)    => events.push(ev.into_owned()),
+                None        => return events
+            }
+        }
+    }
+
+    fn make_records(count: uint) -> Vec<u8> {
+        let mut doc = String::from_str("<?xml version='1.0'?>\r\n<!-- export -->\n");
+        doc.push_str("<root xmlns='urn:r' xmlns:p='urn:p'>\n");
+        for i in range(0, count) {
+            doc.push_str(format!("  <p:rec id='{}' note=\"a>b\">\n", i).as_slice());
+            doc.push_str("    <name>\u0107&amp;</name><![CDATA[</p:rec>]]>");
+            doc.push_str("<!-- </p:rec> --><?pi </p:rec>?>");
+            if i % 7 == 0 {
+                doc.push_str("&bogus;");
+            }
+            doc.push_str("\r\n  </p:rec><empty/>\n");
+        }
+        doc.push_str("</root>\n<!-- end -->");
+        doc.into_bytes()
+    }
+
+    #[test]
+    fn test_parse_parallel() {
+        let input = make_records(100);
+        let expected = parse_sequential(input.as_slice());
+        for &tasks in [1u, 2, 4, 13].iter() {
+            let events: Vec<XmlEvent> = parse_parallel(Arc::new(input.clone()), tasks).collect();
+            assert_eq!(expected, events);
+        }
+    }
+
+    #[test]
+    fn test_find_boundaries() {
+        let input = b"<r><a>x</a><b/><!-- <c> --><d t='/>'/>\n<e>y</e></r>";
+        let bounds = find_boundaries(input, 3, 4);
+        assert_eq!(vec![(15, 1, 15), (38, 1, 38)], bounds);
+        let bounds = find_boundaries(input, 3, 2);
+        assert_eq!(vec![(38, 1, 38)], bounds);
+    }
+
+    #[test]
+    fn test_parse_parallel_malformed() {
+        // Quote in attribute name is taken for start of value by scan,
+        // so it finds the end of an empty tag inside text of `a`
+        let input = b"<r><a x\"y=\"z\">\"/><b/><c/><d/></r>".to_vec();
+        assert_eq!(vec![(17, 1, 17), (29, 1, 29)], find_boundaries(input.as_slice(), 3, 3));
+        let expected = parse_sequential(input.as_slice());
+        let events: Vec<XmlEvent> = parse_parallel(Arc::new(input), 3).collect();
+        assert_eq!(expected, events);
+    }
+
+    #[test]
+    fn test_parse_parallel_doctype() {
+        let input = b"<!DOCTYPE r [<!ENTITY e '<r>'>]><r><a/><b/><c>&e;</c><d/></r>".to_vec();
+        let expected = parse_sequential(input.as_slice());
+        let events: Vec<XmlEvent> = parse_parallel(Arc::new(input), 3).collect();
+        assert_eq!(expected, events);
+    }
+}
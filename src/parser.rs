@@ -1,10 +1,32 @@
-use std::io::{Buffer, IoError, EndOfFile};
+use std::io::{Reader, Buffer, IoError, IoResult, EndOfFile};
 use std::num::{from_str_radix};
+use std::cmp::min;
+use std::slice::bytes::copy_memory;
 use std::char::{from_u32};
+use std::collections::{RingBuf, Deque};
+use std::mem;
+use std::str::{MaybeOwned, Slice, Owned, from_utf8};
+use std::io::standard_error;
 use super::{is_digit, is_hex_digit};
+use util::{is_xml10_char, is_xml11_char, is_restricted_char};
+use util::{is_whitespace, is_name_start_char, is_name_char, split_name};
+use common::{XmlError, ErrKind, UnreadableChar, IllegalChar, RestrictedCharError};
+use common::{UnknownToken, NonDigitError, CharParsingError, NumParsingError};
+use common::{PrematureEOF, UnknownEntity, UnboundPrefix, MissingAttrValue};
+use common::{MinMinInComment, UnquotedAttrValue, DuplicateAttr, EmptyEndTag, MismatchedEndTag};
+use common::{UnsupportedDoctype, UnsupportedEncoding};
+use common::{XElem, XmlAttr, XmlNS, XPi, XDecl, XML_NS_URI, XMLNS_NS_URI};
+use common::{ElemNode, TextNode, CDataNode, CommentNode, PINode};
+use common::{ErrBehavior, Ignore, Warn, Fail};
+use common::{XmlVersion, Version10, Version11};
+use encoding::{Encoding, Decoder, DecodedChar, Malformed, DecodeErr};
+use encoding::{sniff, declared_version, decl_pseudo_attr, decl_incomplete, Utf8, Latin1};
+use source::XmlSource;
+use name::{Name, Interner};
+use scan::{find_special, is_special_char};
 
 /// A struct representing states of an XML ER parser
-#[deriving(PartialEq, Eq)]
+#[deriving(PartialEq, Eq, Clone, Encodable, Decodable)]
 enum StateEr {
     Data,
     Tag,
@@ -15,62 +37,14 @@ enum StateEr {
     PiTarget,
     PiTargetAfter,
     PiContent,
-    PiAfter,
     MarkupDecl,
     Comment,
-    CommentDash,
-    CommentEnd,
     Cdata,
-    CdataBracket,
-    CdataEnd,
     Doctype,
-    DoctypeRootNameBefore,
-    DoctypeRootName,
-    DoctypeRootNameAfter,
-    DoctypeIdentDoubleQ,
-    DoctypeIdentSingleQ,
-    DoctypeIntSubset,
-    DoctypeIntSubsetAfter,
-    DoctypeTag,
-    DoctypeMarkupDecl,
-    DoctypeComment,
-    DoctypeCommentDash,
-    DoctypeCommentEnd,
-    DoctypeEnt,
-    DoctypeEntTypeBefore,
-    DoctypeEntParamBefore,
-    DoctypeEntParam,
-    DoctypeEntName,
-    DoctypeEntNameAfter,
-    DoctypeEntValDoubleQ,
-    DoctypeEntValSingleQ,
-    DoctypeEntValAfter,
-    DoctypeEntIdent,
-    DoctypeEntIdentDoubleQ,
-    DoctypeEntIdentSingleQ,
-    DoctypeAttlist,
-    DoctypeAttlistNameBefore,
-    DoctypeAttlistName,
-    DoctypeAttlistNameAfter,
-    DoctypeAttlistAttrname,
-    DoctypeAttlistAttrnameAfter,
-    DoctypeAttlistAttrtype,
-    DoctypeAttlistAttrtypeAfter,
-    DoctypeAttlistAttrdeclBefore,
-    DoctypeAttlistAttrdecl,
-    DoctypeAttlistAttrdeclAfter,
-    DoctypeAttlistAttrvalDoubleQuoted,
-    DoctypeAttlistAttrvalSingleQuoted,
-    DoctypeNotation,
-    DoctypeNotationIdent,
-    DoctypeNotationIdentDoubleQ,
-    DoctypeNotationIdentSingleQ,
-    DoctypePi,
-    DoctypeAfterPi,
-    DoctypeBogusComment,
     TagName,
     EmptyTag,
     TagAttrNameBefore,
+    TagAttrName,
     TagAttrNameAfter,
     TagAttrValueBefore,
     TagAttrValueDoubleQuoted,
@@ -82,17 +56,41 @@ enum StateEr {
 /// Struct that represents what XML events
 /// may be encountered during pull parsing
 /// of documents
+///
+/// Text, CDATA sections and comments are borrowed from input when the
+/// source allows it, see `XmlSource`. Attribute values and PI data are
+/// kept in `XElem` and `XPi`, which trees are built of, so they're
+/// always copied.
 #[deriving(Clone, PartialEq, Eq, Show)]
-pub enum XmlEvent {
-    DeclEvent,
-    ElemStart,
-    ElemEnd,
-    EmptyElem,
-    PIEvent,
-    TextEvent,
-    CDataEvent,
-    ErrEvent,
-    FixMeEvent
+pub enum XmlEvent<'a> {
+    DeclEvent(XDecl),
+    ElemStart(XElem),
+    /// End of element, with its name as written
+    ElemEnd(Name),
+    EmptyElem(XElem),
+    PIEvent(XPi),
+    TextEvent(MaybeOwned<'a>),
+    CDataEvent(MaybeOwned<'a>),
+    CommentEvent(MaybeOwned<'a>),
+    ErrEvent(XmlError)
+}
+
+impl<'a> XmlEvent<'a> {
+    /// Copies any text borrowed from input, so event
+    /// can outlive the parser
+    pub fn into_owned(self) -> XmlEvent<'static> {
+        match self {
+            DeclEvent(decl)     => DeclEvent(decl),
+            ElemStart(elem)     => ElemStart(elem),
+            ElemEnd(name)       => ElemEnd(name),
+            EmptyElem(elem)     => EmptyElem(elem),
+            PIEvent(pi)         => PIEvent(pi),
+            TextEvent(text)     => TextEvent(Owned(text.into_string())),
+            CDataEvent(text)    => CDataEvent(Owned(text.into_string())),
+            CommentEvent(text)  => CommentEvent(Owned(text.into_string())),
+            ErrEvent(err)       => ErrEvent(err)
+        }
+    }
 }
 
 pub struct XmlReader<'r,R :'r> {
@@ -103,28 +101,160 @@ pub struct XmlReader<'r,R :'r> {
     pub col: u64,
     /// `eof` field notifies parser it has reached end of file.
     pub eof: bool,
-    peek_buf: Option<char>,
+    /// Byte offset in input of the current position
+    pub offset: u64,
+    /// Version of XML used to check chars and normalize new lines.
+    /// Detected from XML declaration unless set before reading.
+    pub version: XmlVersion,
+    /// What to do with errors found while reading
+    pub err_behavior: ErrBehavior,
+    /// Set once an error stopped reading under `Fail` behavior
+    failed: bool,
+    /// Chars that were peeked at, but not read yet
+    ahead: RingBuf<Ahead>,
+    /// Raw char read while looking for `\n` after `\r`
+    raw_pending: Option<RawChar>,
+    /// Width of `\r` not read ahead yet, while char after it is decoded
+    cr_width: Option<uint>,
+    /// Number of bytes decoded from source, including lookahead
+    raw_offset: u64,
+    /// Whether last read char is the same in input
+    last_verbatim: bool,
+    /// Recoverable errors found while reading, waiting to be reported
+    errors: Vec<XmlError>,
+    decoder: Decoder,
+    /// Whether encoding still needs to be detected from start of input
+    sniff: bool,
+    /// Start of input taken from source to find end of XML declaration,
+    /// which is decoded before rest of the source
+    prefix: Vec<u8>,
+    /// Number of bytes of `prefix` that were decoded
+    prefix_pos: uint,
+    /// Whether encoding was chosen by user, instead of being detected
+    forced: bool,
+    /// Whether version was chosen by user, instead of being detected
+    forced_version: bool,
     source: &'r mut R
 }
-#[deriving(PartialEq, Eq, Show)]
+#[deriving(PartialEq, Eq, Clone, Show)]
 pub enum ReadChar {
     CharErr(IoError),
     CharEOF,
     Char(char)
 }
 
+/// A decoded char, number of bytes it took in input and whether
+/// it is written in input the same way, i.e. as UTF-8
+type RawChar = (char, uint, bool);
+
+/// Number of chars `XmlReader` can look ahead
+pub static MAX_LOOKAHEAD: uint = 16;
+
+/// A normalized char read ahead of current position
+#[deriving(Clone)]
+struct Ahead {
+    chr: ReadChar,
+    /// Number of bytes in input the char was read from
+    width: uint,
+    /// Whether char is the same in input
+    verbatim: bool
+}
+
+/// Longest start of input kept while looking for end of XML declaration
+static MAX_DECL_LEN: uint = 1024;
+
+/// Buffer over bytes taken from source while detecting encoding,
+/// followed by rest of the source
+struct Prefixed<'a, B: 'a> {
+    prefix: &'a [u8],
+    pos: &'a mut uint,
+    source: &'a mut B
+}
+
+impl<'a, B: Buffer> Reader for Prefixed<'a, B> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        let len = {
+            let avail = try!(self.fill_buf());
+            let len = min(avail.len(), buf.len());
+            copy_memory(buf, avail.slice_to(len));
+            len
+        };
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+impl<'a, B: Buffer> Buffer for Prefixed<'a, B> {
+    fn fill_buf<'b>(&'b mut self) -> IoResult<&'b [u8]> {
+        if *self.pos < self.prefix.len() {
+            Ok(self.prefix.slice_from(*self.pos))
+        } else {
+            self.source.fill_buf()
+        }
+    }
+
+    fn consume(&mut self, amt: uint) {
+        if *self.pos < self.prefix.len() {
+            *self.pos = min(*self.pos + amt, self.prefix.len());
+        } else {
+            self.source.consume(amt);
+        }
+    }
+}
+
+/// Condition on chars, used to tell `XmlReader` where to stop reading
 pub trait Filter {
-    fn is_match(&self, char) -> bool;
+    fn is_match(&mut self, char) -> bool;
+
+    /// Returns true if filter can only match chars `scan::find_special`
+    /// stops on. Reading until such filter matches can skip plain
+    /// text in bulk.
+    fn matches_special_only(&self) -> bool {
+        false
+    }
 }
 
 impl Filter for char {
-    fn is_match(&self, c: char) -> bool {
+    fn is_match(&mut self, c: char) -> bool {
         return *self == c;
     }
+
+    fn matches_special_only(&self) -> bool {
+        is_special_char(*self)
+    }
+}
+
+/// Matches any char in the string, e.g. `"<&"`
+impl<'a> Filter for &'a str {
+    fn is_match(&mut self, c: char) -> bool {
+        self.contains_char(c)
+    }
+
+    fn matches_special_only(&self) -> bool {
+        self.chars().all(is_special_char)
+    }
+}
+
+/// Matches any char in the slice
+impl<'a> Filter for &'a [char] {
+    fn is_match(&mut self, c: char) -> bool {
+        self.contains(&c)
+    }
+
+    fn matches_special_only(&self) -> bool {
+        self.iter().all(|&c| is_special_char(c))
+    }
 }
 
 impl Filter for fn(char) -> bool {
-    fn is_match(&self, c: char) -> bool {
+    fn is_match(&mut self, c: char) -> bool {
+        (*self)(c)
+    }
+}
+
+/// Matches chars closure returns true for
+impl<'a> Filter for |char|: 'a -> bool {
+    fn is_match(&mut self, c: char) -> bool {
         (*self)(c)
     }
 }
@@ -134,17 +264,222 @@ impl<'r, R: Buffer> XmlReader<'r,R> {
     /// Function used for constructing XmlReader from field `data`
     /// that is both a reader and a buffer. One such element is
     /// `BufferedReader`
+    ///
+    /// Encoding of input is detected from its byte order mark or
+    /// `encoding` in its XML declaration, defaulting to UTF-8.
     pub fn from_reader(data: &'r mut R)
                         -> XmlReader<'r,R> {
         XmlReader {
             line: 1,
             col: 0,
             eof: false,
-            peek_buf: None,
+            offset: 0,
+            version: Version10,
+            err_behavior: Warn,
+            failed: false,
+            ahead: RingBuf::new(),
+            raw_pending: None,
+            cr_width: None,
+            raw_offset: 0,
+            last_verbatim: true,
+            errors: Vec::new(),
+            decoder: Decoder::new(Utf8),
+            sniff: true,
+            prefix: Vec::new(),
+            prefix_pos: 0,
+            forced: false,
+            forced_version: false,
             source: data
         }
     }
 
+    /// Constructs XmlReader that decodes `data` using `encoding`,
+    /// regardless of what the document declares. A byte order
+    /// mark matching `encoding` is still skipped.
+    pub fn from_reader_with_encoding(data: &'r mut R, encoding: Encoding)
+                                    -> XmlReader<'r,R> {
+        let mut reader = XmlReader::from_reader(data);
+        reader.decoder = Decoder::new(encoding);
+        reader.forced = true;
+        reader
+    }
+
+    /// Returns encoding used to decode the input. Until first char
+    /// is read this may not be the detected one.
+    pub fn encoding(&self) -> Encoding {
+        self.decoder.encoding.clone()
+    }
+
+    /// Returns input left to decode, starting with `prefix`
+    fn input<'a>(&'a mut self) -> Prefixed<'a, R> {
+        Prefixed {
+            prefix: self.prefix.as_slice(),
+            pos: &mut self.prefix_pos,
+            source: &mut *self.source
+        }
+    }
+
+    /// Detects encoding and XML version from the start of input
+    fn sniff_encoding(&mut self) {
+        self.sniff = false;
+        // XML declaration may not all be in the first buffer of source,
+        // so its bytes are moved to `prefix` until it ends
+        loop {
+            let len = match self.source.fill_buf() {
+                Ok(buf) => {
+                    if self.prefix.is_empty() && !decl_incomplete(buf) {
+                        break
+                    }
+                    let len = min(buf.len(), MAX_DECL_LEN - self.prefix.len());
+                    self.prefix.push_all(buf.slice_to(len));
+                    len
+                },
+                Err(_)  => break
+            };
+            self.source.consume(len);
+            if self.prefix.len() == MAX_DECL_LEN || !decl_incomplete(self.prefix.as_slice()) {
+                break
+            }
+        }
+
+        let (detected, bom_len) = match self.input().fill_buf() {
+            Ok(buf) => sniff(buf),
+            Err(_)  => return
+        };
+        if !self.forced {
+            self.decoder = Decoder::new(detected.clone());
+        }
+        if detected == self.decoder.encoding {
+            self.input().consume(bom_len);
+            self.offset += bom_len as u64;
+            self.raw_offset += bom_len as u64;
+        }
+
+        let encoding = self.decoder.encoding.clone();
+        let version = match self.input().fill_buf() {
+            Ok(buf) => declared_version(buf, &encoding),
+            Err(_)  => None
+        };
+        match version.and_then(|v| XmlVersion::from_str(v.as_slice())) {
+            Some(version) if !self.forced_version => self.version = version,
+            _                                     => {}
+        }
+    }
+
+    /// Reads a single char from source without any normalization.
+    /// Malformed byte sequences are replaced with '\uFFFD' and reported
+    /// as `UnreadableChar` errors.
+    fn read_raw(&mut self) -> IoResult<RawChar> {
+        if self.failed {
+            return Err(standard_error(EndOfFile))
+        }
+        if self.sniff {
+            self.sniff_encoding();
+        }
+        let decoded = {
+            let mut input = Prefixed {
+                prefix: self.prefix.as_slice(),
+                pos: &mut self.prefix_pos,
+                source: &mut *self.source
+            };
+            self.decoder.decode(&mut input)
+        };
+        if !self.prefix.is_empty() && self.prefix_pos == self.prefix.len() {
+            self.prefix = Vec::new();
+            self.prefix_pos = 0;
+        }
+        let retval = match decoded {
+            DecodedChar(c, width) => {
+                self.check_char(c);
+                (c, width, self.decoder.encoding == Utf8)
+            },
+            Malformed(width) => {
+                let msg = format!("malformed {} byte sequence", self.decoder.encoding);
+                self.report_decoded(UnreadableChar, msg);
+                ('\uFFFD', width, false)
+            },
+            DecodeErr(err) => return Err(err)
+        };
+        let (_, width, _) = retval;
+        self.raw_offset += width as u64;
+        Ok(retval)
+    }
+
+    /// Reports chars not matching `Char` production of
+    /// document's XML version
+    fn check_char(&mut self, c: char) {
+        let (legal, restricted) = match self.version {
+            Version10 => (is_xml10_char(c), false),
+            Version11 => (is_xml11_char(c), is_restricted_char(c))
+        };
+        if !legal {
+            let msg = format!("char {} is not allowed in XML {}",
+                              c.escape_unicode(), self.version);
+            self.report_decoded(IllegalChar, msg);
+        } else if restricted {
+            let msg = format!("restricted char {} must be written as reference",
+                              c.escape_unicode());
+            self.report_decoded(RestrictedCharError, msg);
+        }
+    }
+
+    /// Records a recoverable error at the current position, i.e. at
+    /// the char about to be read, as allowed by `err_behavior`
+    fn report(&mut self, kind: ErrKind, msg: String) {
+        let position = (self.line, self.col, self.offset);
+        self.report_at(kind, msg, position);
+    }
+
+    /// Records a recoverable error found in the char being decoded,
+    /// at its own position, which may be after chars read ahead
+    fn report_decoded(&mut self, kind: ErrKind, msg: String) {
+        let (mut line, mut col, mut offset) = (self.line, self.col, self.offset);
+        for ahead in self.ahead.iter() {
+            offset += ahead.width as u64;
+            match ahead.chr {
+                Char('\n') => {
+                    line += 1;
+                    col = 0;
+                },
+                _ => col += 1
+            }
+        }
+        match self.cr_width {
+            Some(width) => {
+                offset += width as u64;
+                line += 1;
+                col = 0;
+            },
+            None => {}
+        }
+        self.report_at(kind, msg, (line, col, offset));
+    }
+
+    /// Records an error before the char at `position`, given as
+    /// line, column and byte offset
+    fn report_at(&mut self, kind: ErrKind, msg: String, position: (u64, u64, u64)) {
+        match self.err_behavior {
+            Ignore  => return,
+            Warn    => {},
+            Fail    => self.failed = true
+        }
+        let (line, col, offset) = position;
+        let err = XmlError {
+            line: line as uint,
+            col: col as uint + 1,
+            offset: offset,
+            msg: msg,
+            kind: kind,
+            mark: None
+        };
+        self.errors.push(err);
+    }
+
+    /// Returns errors found since last call, in order they were found
+    pub fn take_errors(&mut self) -> Vec<XmlError> {
+        mem::replace(&mut self.errors, Vec::new())
+    }
+
     /// A function that returns current line and column in
     /// given `XmlReader`
     pub fn position(&self) -> (u64, u64) {
@@ -156,86 +491,338 @@ impl<'r, R: Buffer> XmlReader<'r,R> {
     /// normalized into '\uFFFD'.
     ///
     /// According to XML-ER implementation supported line endings are:
-    /// `\n`, `\r`, `\r \n`.
+    /// `\n`, `\r`, `\r \n`. XML 1.1 documents also use `\x85`,
+    /// `\r \x85` and `\u2028`.
     pub fn read_nchar(&mut self) -> ReadChar {
-        let chr;
+        let ahead = match self.ahead.pop_front() {
+            Some(ahead) => ahead,
+            None        => self.read_ahead()
+        };
 
-        if self.peek_buf.is_none() {
-            chr = self.source.read_char();
-        } else {
-            chr = Ok(self.peek_buf.unwrap());
-            self.peek_buf = None;
+        self.offset += ahead.width as u64;
+        self.last_verbatim = ahead.verbatim;
+        match ahead.chr {
+            Char('\n') => {
+                self.line += 1;
+                self.col = 0;
+            },
+            Char(_)     => self.col += 1,
+            CharEOF     => self.eof = true,
+            CharErr(_)  => {}
         }
+        ahead.chr
+    }
+
+    /// Reads and normalizes next char, without changing position
+    fn read_ahead(&mut self) -> Ahead {
+        let chr = match self.raw_pending.take() {
+            Some(raw)   => Ok(raw),
+            None        => self.read_raw()
+        };
 
-        let retval = match chr {
+        match chr {
             Err(IoError{kind: EndOfFile, ..}) => {
-                self.eof = true;
-                CharEOF
+                Ahead { chr: CharEOF, width: 0, verbatim: true }
             },
-            Err(err)=> CharErr(err),
-            Ok(chr) if "\r\n".contains_char(chr) => {
-                self.line += 1;
-                self.col = 0;
-
+            Err(err) => {
+                Ahead { chr: CharErr(err), width: 0, verbatim: true }
+            },
+            Ok((chr, width, verbatim)) if self.is_newline(chr) => {
+                let mut width = width;
                 if chr == '\r' {
-                    match self.source.read_char() {
-                        Ok(a) if a != '\n' => {
-                            self.peek_buf = Some(a);
+                    self.cr_width = Some(width);
+                    let next = self.read_raw();
+                    self.cr_width = None;
+                    match next {
+                        Ok((a, a_width, _)) if self.is_newline_after_cr(a) => {
+                            width += a_width;
                         },
+                        Ok(raw) => self.raw_pending = Some(raw),
                         _ => {}
                     }
                 }
-                Char('\n')
-            },
-            Ok(a)   => {
-                self.col += 1;
-                if a == '\x00' {
-                    return Char('\uFFFD')
-                } else{
-                    return Char(a)
+                Ahead {
+                    chr: Char('\n'),
+                    width: width,
+                    verbatim: verbatim && chr == '\n'
                 }
-                Char(a)
+            },
+            Ok(('\x00', width, _)) => {
+                Ahead { chr: Char('\uFFFD'), width: width, verbatim: false }
+            },
+            Ok((chr, width, verbatim)) => {
+                Ahead { chr: Char(chr), width: width, verbatim: verbatim }
             }
-        };
-        retval
+        }
+    }
+
+    fn is_newline(&self, c: char) -> bool {
+        match c {
+            '\r' | '\n'        => true,
+            '\x85' | '\u2028'  => self.version == Version11,
+            _                  => false
+        }
+    }
+
+    /// Checks if `c` forms a single new line together with preceding `\r`
+    fn is_newline_after_cr(&self, c: char) -> bool {
+        c == '\n' || (c == '\x85' && self.version == Version11)
     }
 
     fn peek(&mut self) -> Option<char> {
-        if self.peek_buf.is_none() {
-            let (line,col) = self.position();
-            let old_flag = self.eof;
+        self.peek_n(0)
+    }
 
-            match self.read_nchar() {
-                Char(a) => self.peek_buf = Some(a),
-                _       => self.peek_buf = None,
-            }
-            // If we peeked and saw flag by accident
-            // this resets it back
-            self.eof = old_flag;
-            self.line = line;
-            self.col = col;
+    /// Returns `n`-th char after current position, counting from zero,
+    /// without reading it. Returns `None` if input ends before it.
+    ///
+    /// At most `MAX_LOOKAHEAD` chars can be looked at, `None` is
+    /// returned for chars further ahead.
+    pub fn peek_n(&mut self, n: uint) -> Option<char> {
+        if n >= MAX_LOOKAHEAD {
+            return None
+        }
+        while self.ahead.len() <= n {
+            // Don't read past end of input or an error
+            match self.ahead.back() {
+                Some(&Ahead{chr: Char(_), ..}) | None => {},
+                _ => return None
+            }
+            let ahead = self.read_ahead();
+            self.ahead.push_back(ahead);
+        }
+        match self.ahead.get(n).chr {
+            Char(c) => Some(c),
+            _       => None
+        }
+    }
+
+    /// Checks if input at current position starts with `text`,
+    /// without reading it. New lines in `text` must be normalized.
+    pub fn starts_with(&mut self, text: &str) -> bool {
+        text.chars().enumerate().all(|(i, c)| self.peek_n(i) == Some(c))
+    }
+
+    /// Reads `text` if input at current position starts with it.
+    /// Returns whether it was read.
+    pub fn consume_if(&mut self, text: &str) -> bool {
+        if !self.starts_with(text) {
+            return false
+        }
+        for _ in text.chars() {
+            self.read_nchar();
+        }
+        true
+    }
+
+    /// Reads a run of plain ASCII text straight from the source buffer,
+    /// appending it to `out`. Returns number of chars read.
+    ///
+    /// Only safe to use when reading until a filter that
+    /// `matches_special_only`.
+    fn read_plain(&mut self, out: Option<&mut String>) -> uint {
+        if !self.ahead.is_empty() || self.raw_pending.is_some()
+            || self.sniff || !self.prefix.is_empty() || self.failed {
+            return 0
+        }
+        match self.decoder.encoding {
+            Utf8 | Latin1   => {},
+            _               => return 0
+        }
+
+        let len = match self.source.fill_buf() {
+            Ok(buf) => {
+                let len = find_special(buf);
+                match out {
+                    Some(text)  => text.push_str(from_utf8(buf.slice_to(len)).unwrap()),
+                    None        => {}
+                }
+                len
+            },
+            // Errors are left to be found by `read_nchar`
+            Err(_)  => 0
         };
-        self.peek_buf
+        self.source.consume(len);
+        self.col += len as u64;
+        self.offset += len as u64;
+        self.raw_offset += len as u64;
+        len
     }
 
-    pub fn read_until<Cond: Filter>(&mut self,  cond: Cond, opp: bool)
+    pub fn read_until<Cond: Filter>(&mut self, cond: Cond, opp: bool)
                                     -> String {
         let mut retval = String::new();
+        self.read_until_into(cond, opp, &mut retval);
+        retval
+    }
+
+    /// Reads same text as `read_until`, appending it to `out`,
+    /// so its buffer can be reused.
+    pub fn read_until_into<Cond: Filter>(&mut self, mut cond: Cond, opp: bool,
+                                         out: &mut String) {
+        let fast = opp && cond.matches_special_only();
+
+        loop {
+            if fast {
+                self.read_plain(Some(&mut *out));
+            }
+            match self.peek() {
+                Some(c) if cond.is_match(c) != opp => {
+                    self.read_nchar();
+                    out.push(c);
+                },
+                _ => break
+            }
+        }
+    }
+
+    /// Skips text `read_until` would read, without copying it
+    pub fn skip_until<Cond: Filter>(&mut self, mut cond: Cond, opp: bool) {
+        let fast = opp && cond.matches_special_only();
+
+        loop {
+            if fast {
+                self.read_plain(None);
+            }
+            match self.peek() {
+                Some(c) if cond.is_match(c) != opp => {
+                    self.read_nchar();
+                },
+                _ => break
+            }
+        }
+    }
+
+    /// Reads text until `delim` is found and consumes it. Returns text
+    /// before `delim` and whether it was found before end of input.
+    /// An empty `delim` is found right away.
+    pub fn read_until_str(&mut self, delim: &str) -> (String, bool) {
+        if delim.is_empty() {
+            return (String::new(), true)
+        }
+        let first = delim.char_at(0);
+        let mut retval = String::new();
 
         loop {
+            // Unless text read so far may end with a part of `delim`,
+            // skip to its first char in bulk
+            if !ends_with_prefix(retval.as_slice(), delim) {
+                let text = self.read_until(first, true);
+                retval.push_str(text.as_slice());
+            }
             match self.read_nchar() {
-                Char(c) => {
-                    if cond.is_match(c) == opp {
-                        self.peek_buf = Some(c);
-                        break
-                    } else {
-                        retval.push(c)
+                Char(c) => retval.push(c),
+                _       => return (retval, false)
+            }
+            if retval.as_slice().ends_with(delim) {
+                let len = retval.len() - delim.len();
+                retval.truncate(len);
+                return (retval, true)
+            }
+        }
+    }
+}
+
+/// Checks if `text` ends with a proper, non-empty prefix of `delim`
+fn ends_with_prefix(text: &str, delim: &str) -> bool {
+    delim.char_indices().skip(1).any(|(i, _)| text.ends_with(delim.slice_to(i)))
+}
+
+impl<'r, R: XmlSource<'r>> XmlReader<'r,R> {
+    /// Reads text same as `read_until`. Text is borrowed from input if
+    /// source allows it and no char in it needed normalization.
+    pub fn read_text_until<Cond: Filter>(&mut self, mut cond: Cond, opp: bool)
+                                         -> MaybeOwned<'r> {
+        // Peeking detects encoding, before we check it
+        self.peek();
+        let start = self.offset;
+        let mut owned = if self.can_borrow() {
+            None
+        } else {
+            Some(String::new())
+        };
+        let fast = opp && cond.matches_special_only();
+
+        loop {
+            if fast {
+                self.read_plain(owned.as_mut());
+            }
+            match self.peek() {
+                Some(c) if cond.is_match(c) != opp => {
+                    let before = self.offset;
+                    self.read_nchar();
+                    match owned {
+                        Some(ref mut text) => {
+                            text.push(c);
+                            continue
+                        },
+                        None if self.last_verbatim => continue,
+                        None => {}
                     }
+                    // Text changed, so copy what was borrowed so far
+                    let mut text = self.borrow_str(start, before).unwrap().to_string();
+                    text.push(c);
+                    owned = Some(text);
                 },
                 _ => break
             }
         }
-        retval
+
+        match owned {
+            Some(text)  => Owned(text),
+            None        => Slice(self.borrow_str(start, self.offset).unwrap())
+        }
+    }
+
+    /// Reads text same as `read_until_str`. Text is borrowed from input
+    /// if source allows it and no char in it needed normalization.
+    pub fn read_text_until_str(&mut self, delim: &str) -> (MaybeOwned<'r>, bool) {
+        if delim.is_empty() {
+            return (Slice(""), true)
+        }
+        // Peeking detects encoding, before we check it
+        self.peek();
+        if !self.can_borrow() {
+            let (text, found) = self.read_until_str(delim);
+            return (Owned(text), found)
+        }
+        let start = self.offset;
+        let first = delim.char_at(0);
+        let rest = delim.slice_from(first.len_utf8());
+        let fast = is_special_char(first);
+
+        loop {
+            if fast {
+                self.read_plain(None);
+            }
+            let before = self.offset;
+            let c = match self.read_nchar() {
+                Char(c) => c,
+                _       => return (Slice(self.borrow_str(start, before).unwrap()), false)
+            };
+            if !self.last_verbatim {
+                // Text changed, so copy what was borrowed so far
+                // and read the rest as a copy
+                let mut text = self.borrow_str(start, before).unwrap().to_string();
+                text.push(c);
+                let (more, found) = self.read_until_str(delim);
+                text.push_str(more.as_slice());
+                return (Owned(text), found)
+            }
+            if c == first && self.consume_if(rest) {
+                return (Slice(self.borrow_str(start, before).unwrap()), true)
+            }
+        }
+    }
+
+    fn can_borrow(&self) -> bool {
+        self.decoder.encoding == Utf8
+            && self.source.borrow_slice(self.offset, self.offset).is_some()
+    }
+
+    /// Borrows input between byte offsets `start` and `end`
+    fn borrow_str(&self, start: u64, end: u64) -> Option<&'r str> {
+        self.source.borrow_slice(start, end).and_then(|bytes| from_utf8(bytes))
     }
 }
 
@@ -243,131 +830,1212 @@ impl<'r, R: Buffer> XmlReader<'r,R> {
 pub struct Parser<'r, R:'r> {
     pub depth: uint,
     reader: XmlReader<'r,R>,
+    /// Name of tag or target of processing instruction being read
+    buf: String,
+    /// Text being read, kept borrowed from input while possible
+    text: Option<MaybeOwned<'r>>,
+    /// Attributes of tag being read, as written
+    attrs: Vec<(Name, String)>,
+    /// Name of attribute being read
+    attr_name: String,
+    /// Value of attribute being read
+    attr_value: String,
+    /// Name or digits of reference being read
+    ref_buf: String,
+    /// Qualified names of open elements
+    stack: Vec<Name>,
+    /// Namespaces declared by each open element
+    scopes: Vec<Vec<XmlNS>>,
+    state: StateEr,
+    /// Whether any event was emitted yet
+    started: bool,
+    /// Names used by events
+    names: Interner,
+    /// Events ready to be returned by `pull`
+    pending: RingBuf<XmlEvent<'r>>,
+    /// Whether tokens are read just to be skipped, see `skip_subtree`
+    skipping: bool,
+    /// Depth of element being skipped
+    skip_depth: uint,
+    /// Qualified names of open elements inside the skipped one, each
+    /// followed by a space, so they aren't interned
+    skipped: String,
+    /// Line, column and offset of `<` of last tag read
+    tag_start: (u64, u64, u64)
+}
+
+/// State of a `Parser` after it returned all events of a token,
+/// from which parsing can be resumed with `Parser::from_state`.
+///
+/// Besides the state of XML ER tokenizer and open elements, it holds
+/// chars the parser looked ahead at, so reading resumes at
+/// `input_offset` rather than after last token.
+///
+/// States can be encoded, e.g. as JSON, to checkpoint long running
+/// jobs:
+///
+///     let state = parser.save_state().unwrap();
+///     let saved = json::encode(&state);
+///     ...
+///     let state: ParserState = json::decode(saved.as_slice()).unwrap();
+///     try!(file.seek(state.input_offset() as i64, SeekSet));
+///     let mut reader = BufferedReader::new(file);
+///     let mut parser = Parser::from_state(&mut reader, state);
+#[deriving(Clone, Encodable, Decodable)]
+pub struct ParserState {
+    depth: uint,
     buf: String,
+    text: Option<String>,
+    attrs: Vec<(Name, String)>,
+    attr_name: String,
+    attr_value: String,
+    stack: Vec<Name>,
+    scopes: Vec<Vec<XmlNS>>,
     state: StateEr,
-    event: Option<XmlEvent>,
+    started: bool,
+    line: u64,
+    col: u64,
+    offset: u64,
+    raw_offset: u64,
+    version: XmlVersion,
+    err_behavior: ErrBehavior,
+    failed: bool,
+    /// Chars read ahead, with their widths and whether they're verbatim
+    ahead: Vec<RawChar>,
+    raw_pending: Option<RawChar>,
+    errors: Vec<XmlError>,
+    decoder: Decoder,
+    sniff: bool,
+    forced: bool,
+    forced_version: bool
+}
+
+impl ParserState {
+    /// Byte offset in input after the last token
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Byte offset in input of the first byte parser hasn't read yet.
+    /// Source of resumed parser must start there.
+    pub fn input_offset(&self) -> u64 {
+        self.raw_offset
+    }
+
+    /// Number of open elements
+    pub fn depth(&self) -> uint {
+        self.depth
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.decoder.encoding.clone()
+    }
+
+    pub fn version(&self) -> XmlVersion {
+        self.version.clone()
+    }
+
+    /// Whether state is inside markup, e.g. right after `<` of a tag
+    /// that ended text before it
+    pub fn in_markup(&self) -> bool {
+        self.state != Data
+    }
+
+    /// Returns same state at a different position in input, with
+    /// nothing read ahead. Position must be between two tokens,
+    /// in the same context as this state.
+    pub fn with_position(&self, offset: u64, line: u64, col: u64) -> ParserState {
+        let mut state = self.clone();
+        state.offset = offset;
+        state.raw_offset = offset;
+        state.line = line;
+        state.col = col;
+        state.ahead = Vec::new();
+        state.raw_pending = None;
+        state.errors = Vec::new();
+        state
+    }
 }
 
-impl<'r, R: Buffer> Parser<'r, R> {
+impl<'r, R: XmlSource<'r>> Parser<'r, R> {
     /// Constructs a new Parser from Reader `data`
     /// The Parser will use the given reader as the source for parsing.
     pub fn from_reader(data: &'r mut R)
                      -> Parser<'r, R> {
+        Parser::from_xml_reader(XmlReader::from_reader(data))
+    }
+
+    /// Constructs a new Parser from Reader `data`, decoding it as
+    /// `encoding` instead of detecting its encoding.
+    pub fn from_reader_with_encoding(data: &'r mut R, encoding: Encoding)
+                                    -> Parser<'r, R> {
+        Parser::from_xml_reader(XmlReader::from_reader_with_encoding(data, encoding))
+    }
+
+    fn from_xml_reader(reader: XmlReader<'r, R>) -> Parser<'r, R> {
         Parser {
             depth: 0,
-            reader: XmlReader::from_reader(data),
+            reader: reader,
             buf: String::new(),
+            text: None,
+            attrs: Vec::new(),
+            attr_name: String::new(),
+            attr_value: String::new(),
+            ref_buf: String::new(),
+            stack: Vec::new(),
+            scopes: Vec::new(),
             state: Data,
-            event: None
+            started: false,
+            names: Interner::new(),
+            pending: RingBuf::new(),
+            skipping: false,
+            skip_depth: 0,
+            skipped: String::new(),
+            tag_start: (1, 0, 0)
         }
     }
 
-    /// Consumes elements from reader until it is ready to emit a token.
-    /// Upon consuming token the values of parsers can be looked for values
-    pub fn pull(&mut self) -> Option<XmlEvent> {
-        self.event = None;
-        while self.event.is_none() {
-            // If end of file is encountered escape loop
-            // and return None
-            if self.reader.eof { break; }
-            // If we correctly processed a token we'll change
-            // self.event to a value that isn't None.
-            match self.state {
-                Data    => self.data_state(),
-                _       => self.event = Some(FixMeEvent),
-            };
+    /// Constructs a Parser which continues parsing from `state`.
+    /// Reading starts at current position of `data`, which has to be
+    /// at `state.input_offset()` of the original input. Sources that
+    /// lend text are expected to count offsets from start of input too,
+    /// see `SliceSource::seek`.
+    ///
+    /// Names interned before aren't part of the state. Pass them with
+    /// `set_interner` to share them with the resumed parser.
+    pub fn from_state(data: &'r mut R, state: ParserState)
+                     -> Parser<'r, R> {
+        let mut parser = Parser::from_reader(data);
+        parser.depth = state.depth;
+        parser.buf = state.buf;
+        parser.text = state.text.map(|text| Owned(text));
+        parser.attrs = state.attrs;
+        parser.attr_name = state.attr_name;
+        parser.attr_value = state.attr_value;
+        parser.stack = state.stack;
+        parser.scopes = state.scopes;
+        parser.state = state.state;
+        parser.started = state.started;
+
+        {
+            let reader = &mut parser.reader;
+            reader.line = state.line;
+            reader.col = state.col;
+            reader.offset = state.offset;
+            reader.raw_offset = state.raw_offset;
+            reader.version = state.version;
+            reader.err_behavior = state.err_behavior;
+            reader.failed = state.failed;
+            for &(c, width, verbatim) in state.ahead.iter() {
+                reader.ahead.push_back(Ahead {
+                    chr: Char(c),
+                    width: width,
+                    verbatim: verbatim
+                });
+            }
+            reader.raw_pending = state.raw_pending;
+            reader.errors = state.errors;
+            reader.decoder = state.decoder;
+            reader.sniff = state.sniff;
+            reader.forced = state.forced;
+            reader.forced_version = state.forced_version;
         }
-        self.event
+        parser
     }
 
-    fn data_state(&mut self) {
-        let chr = self.reader.read_nchar();
-        match chr {
-            Char('&')   => self.data_state(),
-            Char('<')   => self.state = Tag,
-            Char(a)     => {/*TODO */},
-            _           => self.event = None,
-        };
+    /// Returns state parsing can be resumed from, or `None` if
+    /// some events of the last token weren't pulled yet.
+    pub fn save_state(&self) -> Option<ParserState> {
+        if !self.pending.is_empty() {
+            return None
+        }
+        let reader = &self.reader;
+        Some(ParserState {
+            depth: self.depth,
+            buf: self.buf.clone(),
+            text: self.text.as_ref().map(|text| text.as_slice().to_string()),
+            attrs: self.attrs.clone(),
+            attr_name: self.attr_name.clone(),
+            attr_value: self.attr_value.clone(),
+            stack: self.stack.clone(),
+            scopes: self.scopes.clone(),
+            state: self.state.clone(),
+            started: self.started,
+            line: reader.line,
+            col: reader.col,
+            offset: reader.offset,
+            raw_offset: reader.raw_offset,
+            version: reader.version.clone(),
+            err_behavior: reader.err_behavior.clone(),
+            failed: reader.failed,
+            // End of input or an error is found again when resumed
+            ahead: reader.ahead.iter().filter_map(|ahead| match ahead.chr {
+                Char(c) => Some((c, ahead.width, ahead.verbatim)),
+                _       => None
+            }).collect(),
+            raw_pending: reader.raw_pending,
+            errors: reader.errors.clone(),
+            decoder: reader.decoder.clone(),
+            sniff: reader.sniff,
+            forced: reader.forced,
+            forced_version: reader.forced_version
+        })
     }
 
-    fn consume_entity(&mut self) {
-        let chr = self.reader.read_nchar();
-        match chr {
-            Char('#') => {
-                self.buf.push_str("&#");
-                match self.reader.read_nchar() {
-                    Char('x') => {
-                        match self.reader.peek(){
-                            Some(a) if is_hex_digit(a) => {
-                                self.consume_num(true)
-                            }
-                            _ =>  self.buf.push('x')
-                        }
-                    },
-                    Char(a) if is_digit(a) => {
-                        self.buf = String::new();
-                        self.buf.push(a);
-                    },
-                    Char(_) => {
-                        //TODO
-                    }
-                    CharErr(_)
-                    | CharEOF => {
-                        self.event = None;
-                    }
-                }
-                let text = self.buf.clone();
-                //TODO
-            },
-            Char(_) => {
-                //TODO
-            }
-            CharErr(_)
-            | CharEOF => {
-                //TODO
-            },
+    /// Returns error of `kind` at current position of parser, e.g. for
+    /// a document that ends without something its reader expected
+    pub fn position_error(&self, kind: ErrKind, msg: String) -> XmlError {
+        XmlError {
+            line: self.reader.line as uint,
+            col: self.reader.col as uint + 1,
+            offset: self.reader.offset,
+            msg: msg,
+            kind: kind,
+            mark: None
         }
     }
 
-    fn consume_num(&mut self, is_hex: bool) {
-        let radix;
-        let filter = if is_hex {
-            radix = 16;
-            is_hex_digit
-        } else {
-            radix = 10;
-            is_digit
-        };
-        let digits = self.reader.read_until(filter, false);
-        self.buf.push_str(digits.as_slice());
-        let chr = match from_str_radix::<u32>(self.buf.as_slice(), radix){
-            Some(x) => {
-                let conv_chr = from_u32(x);
-                match conv_chr {
-                    Some(c) => c,
-                    // For now just make a dummy value
-                    None => '\uFFFD',
-                }
-            },
-            // For now just make a dummy value
-            None => '\uFFFD'
-        };
-        self.buf = String::from_char(1u,chr);
+    /// Returns line, column and byte offset of `<` of last tag read,
+    /// e.g. of element whose `ElemStart` was just pulled, counted
+    /// like those of an `XmlError`.
+    pub fn tag_position(&self) -> (uint, uint, u64) {
+        let (line, col, offset) = self.tag_start;
+        (line as uint, col as uint + 1, offset)
     }
-}
 
+    /// Returns interner holding names of elements, attributes and
+    /// namespaces parser has seen. Names can be looked up in it,
+    /// or added to share them with other trees.
+    pub fn interner<'a>(&'a mut self) -> &'a mut Interner {
+        &mut self.names
+    }
 
-#[cfg(test)]
-mod test {
-    use super::{XmlReader, Char};
+    /// Makes parser use names from `names`, e.g. interner
+    /// of another parser
+    pub fn set_interner(&mut self, names: Interner) {
+        self.names = names;
+    }
 
-    use std::io::BufReader;
-    #[test]
-    fn test_eof() {
-        let mut read = BufReader::new(b"ab\r\n");
-        let mut xml_read = XmlReader::from_reader(&mut read);
-        xml_read.read_nchar();
-        assert!(!xml_read.eof);
+    /// Sets XML version used to check chars, overriding the
+    /// version found in document's XML declaration
+    pub fn set_version(&mut self, version: XmlVersion) {
+        self.reader.version = version;
+        self.reader.forced_version = true;
+    }
+
+    /// Sets what parser does with recoverable errors it finds.
+    /// Default behavior is `Warn`.
+    pub fn set_err_behavior(&mut self, behavior: ErrBehavior) {
+        self.reader.err_behavior = behavior;
+    }
+
+    /// Consumes elements from reader until it is ready to emit a token.
+    /// Upon consuming token the values of parsers can be looked for values
+    ///
+    /// Errors found while reading a token are returned as `ErrEvent`s
+    /// right after the token's event.
+    ///
+    /// Malformed markup is recovered from as in XML-ER, so every
+    /// `ElemStart` is followed by its `ElemEnd`. End tags close elements
+    /// left open inside theirs, elements open at end of input are closed
+    /// there, and end tags of no open element are dropped.
+    pub fn pull(&mut self) -> Option<XmlEvent<'r>> {
+        while self.pending.is_empty() {
+            // If end of file is encountered escape loop
+            // and return None
+            if self.reader.eof {
+                self.close_unclosed();
+                self.flush_errors();
+                break;
+            }
+            // If we correctly processed a token, it will
+            // be queued in self.pending
+            self.read_token();
+            // Errors are reported after the token they were found in
+            if !self.pending.is_empty() {
+                self.flush_errors();
+            }
+        }
+        self.pending.pop_front()
+    }
+
+    /// Reads text up to the end of input source has so far, and returns
+    /// its event followed by its errors, or nothing unless parser is
+    /// between tokens in content. Source must not end inside a char,
+    /// reference or `\r\n`.
+    ///
+    /// Used by `PushParser`, so long text is returned as it arrives.
+    pub fn pull_text(&mut self) -> Vec<XmlEvent<'r>> {
+        let mut events = Vec::new();
+        if self.state != Data || !self.pending.is_empty() {
+            return events
+        }
+        loop {
+            let text = self.reader.read_text_until("<&", true);
+            self.push_text(text);
+            if self.reader.peek() != Some('&') {
+                break
+            }
+            self.reader.read_nchar();
+            let text = self.read_reference();
+            self.push_text(text);
+        }
+        self.flush_text();
+        self.flush_errors();
+        loop {
+            match self.pending.pop_front() {
+                Some(event) => events.push(event),
+                None        => return events
+            }
+        }
+    }
+
+    /// Reads element whose `ElemStart` was just pulled, up to and
+    /// including its end, and returns it with its children. Errors
+    /// found meanwhile are returned by the following pulls.
+    ///
+    /// E.g.
+    ///
+    ///     match parser.pull() {
+    ///         Some(ElemStart(elem)) if elem.is_named("record") => {
+    ///             let record = parser.read_subtree(elem);
+    ///             ...
+    ///         },
+    ///         ...
+    ///     }
+    pub fn read_subtree(&mut self, elem: XElem) -> XElem {
+        // Element and its open descendants
+        let mut open = vec![elem];
+        let mut errors = Vec::new();
+        loop {
+            let event = match self.pull() {
+                Some(event) => event,
+                None        => break
+            };
+            let node = match event {
+                ElemStart(elem)     => {
+                    open.push(elem);
+                    None
+                },
+                EmptyElem(elem)     => Some(ElemNode(elem)),
+                TextEvent(text)     => Some(TextNode(text.into_string())),
+                CDataEvent(text)    => Some(CDataNode(text.into_string())),
+                CommentEvent(text)  => Some(CommentNode(text.into_string())),
+                PIEvent(pi)         => Some(PINode(pi)),
+                ErrEvent(err)       => {
+                    errors.push(err);
+                    None
+                },
+                // Parser matches every start with an end
+                ElemEnd(_) if open.len() == 1   => break,
+                ElemEnd(_)          => Some(ElemNode(open.pop().unwrap())),
+                DeclEvent(_)        => None
+            };
+            match node {
+                Some(node)  => open.last_mut().unwrap().children.push(node),
+                None        => {}
+            }
+        }
+
+        while open.len() > 1 {
+            let elem = open.pop().unwrap();
+            open.last_mut().unwrap().children.push(ElemNode(elem));
+        }
+        // Errors of end tag's token are still pending, and follow these
+        for err in errors.into_iter().rev() {
+            self.pending.push_front(ErrEvent(err));
+        }
+        open.pop().unwrap()
+    }
+
+    /// Skips rest of the innermost open element, e.g. one whose
+    /// `ElemStart` was just pulled, up to and including its end.
+    /// Text and tags in it are read without being copied, their names
+    /// aren't interned and references aren't expanded, and no events
+    /// are built for them. Errors found meanwhile are returned by the
+    /// following pulls.
+    pub fn skip_subtree(&mut self) {
+        let depth = self.depth;
+        let pending = self.pending.len();
+        self.skipping = true;
+        self.skip_depth = depth;
+        while self.depth >= depth && self.pending.len() == pending && !self.reader.eof {
+            self.read_token();
+        }
+        if self.reader.eof {
+            self.close_unclosed();
+        }
+        self.skipping = false;
+        self.flush_errors();
+    }
+
+    /// Runs parser in its current state, which may queue events
+    fn read_token(&mut self) {
+        match self.state {
+            Data                        => self.data_state(),
+            Tag                         => self.tag_state(),
+            TagName                     => self.tag_name_state(),
+            TagAttrNameBefore           => self.attr_name_before_state(),
+            TagAttrName                 => self.attr_name_state(),
+            TagAttrNameAfter            => self.attr_name_after_state(),
+            TagAttrValueBefore          => self.attr_value_before_state(),
+            TagAttrValueDoubleQuoted    => self.attr_value_quoted_state('"'),
+            TagAttrValueSingleQuoted    => self.attr_value_quoted_state('\''),
+            TagAttrValueUnquoted        => self.attr_value_unquoted_state(),
+            EmptyTag                    => self.empty_tag_state(),
+            EndTag                      => self.end_tag_state(),
+            EndTagName                  => self.end_tag_name_state(),
+            EndTagNameAfter             => self.end_tag_name_after_state(),
+            Pi                          => self.pi_state(),
+            PiTarget                    => self.pi_target_state(),
+            PiTargetAfter               => self.pi_target_after_state(),
+            PiContent                   => self.pi_content_state(),
+            MarkupDecl                  => self.markup_decl_state(),
+            Comment                     => self.comment_state(),
+            Cdata                       => self.cdata_state(),
+            Doctype                     => self.doctype_state(),
+            BogusComment                => self.bogus_comment_state()
+        }
+    }
+
+    fn emit(&mut self, event: XmlEvent<'r>) {
+        self.started = true;
+        // Only ends of elements a mismatched end tag closed along with
+        // skipped one are kept
+        if self.skipping {
+            match event {
+                ElemEnd(_) if self.depth + 1 < self.skip_depth  => {},
+                _                                               => return
+            }
+        }
+        self.pending.push_back(event);
+    }
+
+    fn flush_errors(&mut self) {
+        // Errors found while skipping are kept until it ends
+        if self.skipping {
+            return
+        }
+        for err in self.reader.take_errors().into_iter() {
+            self.emit(ErrEvent(err));
+        }
+    }
+
+    fn error(&mut self, kind: ErrKind, msg: &str) {
+        self.reader.report(kind, msg.to_string());
+    }
+
+    /// Appends `piece` to text being read. Text stays borrowed
+    /// from input until a second piece is appended to it.
+    fn push_text(&mut self, piece: MaybeOwned<'r>) {
+        if self.skipping || piece.as_slice().is_empty() {
+            return
+        }
+        self.text = match self.text.take() {
+            None            => Some(piece),
+            Some(text)      => {
+                let mut text = text.into_string();
+                text.push_str(piece.as_slice());
+                Some(Owned(text))
+            }
+        };
+    }
+
+    /// Emits text read so far, if there is any
+    fn flush_text(&mut self) {
+        match self.text.take() {
+            Some(text)  => self.emit(TextEvent(text)),
+            None        => {}
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        self.reader.read_until(is_whitespace, false);
+    }
+
+    fn data_state(&mut self) {
+        if self.skipping {
+            self.reader.skip_until("<&", true);
+        } else {
+            let text = self.reader.read_text_until("<&", true);
+            self.push_text(text);
+        }
+
+        match self.reader.read_nchar() {
+            // Text is emitted once we know `<` starts markup
+            Char('<')       => {
+                self.tag_start = (self.reader.line, self.reader.col - 1, self.reader.offset - 1);
+                self.state = Tag;
+                return
+            },
+            Char('&')       => {
+                let text = self.read_reference();
+                self.push_text(text);
+                return
+            },
+            Char(_)
+            | CharEOF       => {},
+            CharErr(err)    => {
+                // Source is broken, there is nothing more to read
+                self.reader.report(UnreadableChar, err.desc.to_string());
+                self.reader.eof = true;
+            }
+        };
+        self.flush_text();
+    }
+
+    /// Reads a reference after `&`, and returns text it stands for.
+    /// Unknown or malformed references are reported, and kept
+    /// as they were written. While skipping, references are only
+    /// checked, and empty text is returned.
+    fn read_reference(&mut self) -> MaybeOwned<'static> {
+        // Errors are reported at the `&`
+        let start = (self.reader.line, self.reader.col - 1, self.reader.offset - 1);
+        if self.reader.peek() == Some('#') {
+            self.reader.read_nchar();
+            return self.read_char_reference(start)
+        }
+
+        self.ref_buf.clear();
+        self.reader.read_until_into(is_name_char, false, &mut self.ref_buf);
+        let name = self.ref_buf.as_slice();
+        if name.is_empty() {
+            let msg = "'&' must start a reference".to_string();
+            self.reader.report_at(UnknownEntity, msg, start);
+            return Slice("&")
+        }
+        let closed = self.reader.peek() == Some(';');
+        let value = match name {
+            "lt"    => Some("<"),
+            "gt"    => Some(">"),
+            "amp"   => Some("&"),
+            "apos"  => Some("'"),
+            "quot"  => Some("\""),
+            _       => None
+        };
+        match value {
+            Some(value) if closed => {
+                self.reader.read_nchar();
+                if self.skipping { Slice("") } else { Slice(value) }
+            },
+            _ => {
+                let msg = format!("unknown or unclosed entity &{}", name);
+                self.reader.report_at(UnknownEntity, msg, start);
+                if self.skipping { Slice("") } else { Owned(format!("&{}", name)) }
+            }
+        }
+    }
+
+    /// Reads a character reference after `&#`, whose `&` is at `start`
+    fn read_char_reference(&mut self, start: (u64, u64, u64)) -> MaybeOwned<'static> {
+        let (radix, prefix) = if self.reader.peek() == Some('x') {
+            self.reader.read_nchar();
+            (16, "&#x")
+        } else {
+            (10, "&#")
+        };
+        let filter = if radix == 16 { is_hex_digit } else { is_digit };
+        self.ref_buf.clear();
+        self.reader.read_until_into(filter, false, &mut self.ref_buf);
+        let digits = self.ref_buf.as_slice();
+
+        let text = if digits.is_empty() {
+            let msg = "character reference has no digits".to_string();
+            self.reader.report_at(NonDigitError, msg, start);
+            Owned(prefix.to_string())
+        } else if self.reader.peek() != Some(';') {
+            let msg = "character reference must end with ';'".to_string();
+            self.reader.report_at(NumParsingError, msg, start);
+            Owned(format!("{}{}", prefix, digits))
+        } else {
+            self.reader.read_nchar();
+            let chr = from_str_radix::<u32>(digits, radix).and_then(from_u32);
+            let legal = match (chr, self.reader.version.clone()) {
+                (Some(c), Version10) => is_xml10_char(c),
+                (Some(c), Version11) => is_xml11_char(c),
+                (None, _)            => false
+            };
+            if legal {
+                if self.skipping {
+                    return Slice("")
+                }
+                Owned(String::from_char(1, chr.unwrap()))
+            } else {
+                let msg = format!("{}{}; doesn't refer to a legal char", prefix, digits);
+                self.reader.report_at(CharParsingError, msg, start);
+                Slice("\uFFFD")
+            }
+        };
+        if self.skipping { Slice("") } else { text }
+    }
+
+    fn tag_state(&mut self) {
+        match self.reader.peek() {
+            Some('/') => {
+                self.reader.read_nchar();
+                self.state = EndTag;
+            },
+            Some('?') => {
+                self.reader.read_nchar();
+                self.state = Pi;
+            },
+            Some('!') => {
+                self.reader.read_nchar();
+                self.state = MarkupDecl;
+            },
+            Some(c) if is_name_start_char(c) => {
+                self.state = TagName;
+            },
+            _ => {
+                // Not markup after all, `<` is just text
+                self.error(UnknownToken, "'<' must start a tag");
+                self.push_text(Slice("<"));
+                self.state = Data;
+                return
+            }
+        }
+        self.flush_text();
+    }
+
+    fn tag_name_state(&mut self) {
+        self.buf.clear();
+        self.reader.read_until_into(" \t\n/>", true, &mut self.buf);
+        self.attrs.clear();
+        self.state = TagAttrNameBefore;
+    }
+
+    fn attr_name_before_state(&mut self) {
+        self.skip_whitespace();
+        match self.reader.peek() {
+            Some('/') => {
+                self.reader.read_nchar();
+                self.state = EmptyTag;
+            },
+            Some('>') => {
+                self.reader.read_nchar();
+                self.emit_start_tag(false);
+            },
+            Some(_) => {
+                self.state = TagAttrName;
+            },
+            None => {
+                self.error(PrematureEOF, "end of input inside tag");
+                self.emit_start_tag(false);
+            }
+        }
+    }
+
+    fn attr_name_state(&mut self) {
+        self.attr_name.clear();
+        self.reader.read_until_into(" \t\n/>=", true, &mut self.attr_name);
+        self.attr_value.clear();
+        self.state = TagAttrNameAfter;
+    }
+
+    fn attr_name_after_state(&mut self) {
+        self.skip_whitespace();
+        if self.reader.peek() == Some('=') {
+            self.reader.read_nchar();
+            self.state = TagAttrValueBefore;
+        } else {
+            let msg = format!("attribute {} has no value", self.attr_name);
+            self.reader.report(MissingAttrValue, msg);
+            self.push_attr();
+            self.state = TagAttrNameBefore;
+        }
+    }
+
+    fn attr_value_before_state(&mut self) {
+        self.skip_whitespace();
+        match self.reader.peek() {
+            Some('"') => {
+                self.reader.read_nchar();
+                self.state = TagAttrValueDoubleQuoted;
+            },
+            Some('\'') => {
+                self.reader.read_nchar();
+                self.state = TagAttrValueSingleQuoted;
+            },
+            Some('>') | None => {
+                let msg = format!("attribute {} has no value", self.attr_name);
+                self.reader.report(MissingAttrValue, msg);
+                self.push_attr();
+                self.state = TagAttrNameBefore;
+            },
+            Some(_) => {
+                self.state = TagAttrValueUnquoted;
+            }
+        }
+    }
+
+    fn attr_value_quoted_state(&mut self, quote: char) {
+        let delims = if quote == '"' { "\"&" } else { "'&" };
+        if self.skipping {
+            self.reader.skip_until(delims, true);
+        } else {
+            let text = self.reader.read_text_until(delims, true);
+            // Literal white space in values is normalized to spaces
+            for c in text.as_slice().chars() {
+                self.attr_value.push(if is_whitespace(c) { ' ' } else { c });
+            }
+        }
+
+        match self.reader.read_nchar() {
+            Char('&') => {
+                let text = self.read_reference();
+                self.attr_value.push_str(text.as_slice());
+            },
+            Char(_) => {
+                self.push_attr();
+                self.state = TagAttrNameBefore;
+            },
+            _ => {
+                self.error(PrematureEOF, "end of input inside attribute value");
+                self.push_attr();
+                self.state = TagAttrNameBefore;
+            }
+        }
+    }
+
+    /// Reads value that isn't quoted, up to white space or `>`
+    fn attr_value_unquoted_state(&mut self) {
+        self.reader.read_until_into(" \t\n&>", true, &mut self.attr_value);
+        if self.reader.peek() == Some('&') {
+            self.reader.read_nchar();
+            let text = self.read_reference();
+            self.attr_value.push_str(text.as_slice());
+            return
+        }
+
+        let msg = format!("value of attribute {} must be quoted", self.attr_name);
+        self.reader.report(UnquotedAttrValue, msg);
+        // `/` right before `>` ends an empty tag rather than the value
+        let is_empty = self.reader.peek() == Some('>')
+                       && self.attr_value.as_slice().ends_with("/");
+        if is_empty {
+            self.attr_value.pop();
+        }
+        self.push_attr();
+        self.state = if is_empty { EmptyTag } else { TagAttrNameBefore };
+    }
+
+    /// Adds attribute that was read to the tag. Repeated attributes
+    /// are dropped, so the first value wins.
+    fn push_attr(&mut self) {
+        if self.skipping {
+            return
+        }
+        let name = self.names.intern(self.attr_name.as_slice());
+        if self.attrs.iter().any(|&(ref attr, _)| *attr == name) {
+            let msg = format!("attribute {} is repeated", name);
+            self.reader.report(DuplicateAttr, msg);
+            self.attr_value.clear();
+            return
+        }
+        let value = mem::replace(&mut self.attr_value, String::new());
+        self.attrs.push((name, value));
+    }
+
+    fn empty_tag_state(&mut self) {
+        if self.reader.peek() == Some('>') {
+            self.reader.read_nchar();
+            self.emit_start_tag(true);
+        } else {
+            self.error(UnknownToken, "'/' in tag must be followed by '>'");
+            self.state = TagAttrNameBefore;
+        }
+    }
+
+    /// Finds namespace bound to `prefix` in scope of the current element
+    fn lookup_ns(&mut self, prefix: &str) -> Option<Name> {
+        if prefix == "xml" {
+            return Some(self.names.intern(XML_NS_URI))
+        } else if prefix == "xmlns" {
+            return Some(self.names.intern(XMLNS_NS_URI))
+        }
+        for scope in self.scopes.iter().rev() {
+            for ns in scope.iter() {
+                if ns.name.as_slice() == prefix {
+                    return Some(ns.uri.clone())
+                }
+            }
+        }
+        None
+    }
+
+    /// Resolves namespace of a name with `prefix`. Unprefixed attributes
+    /// are in no namespace, unlike unprefixed elements.
+    fn resolve_ns(&mut self, prefix: &str, is_attr: bool) -> XmlNS {
+        let uri = if prefix.is_empty() && is_attr {
+            None
+        } else {
+            self.lookup_ns(prefix)
+        };
+        let uri = match uri {
+            Some(uri)   => uri,
+            None        => {
+                if !prefix.is_empty() {
+                    let msg = format!("prefix {} is not bound to a namespace", prefix);
+                    self.reader.report(UnboundPrefix, msg);
+                }
+                self.names.intern("")
+            }
+        };
+        XmlNS {
+            name: self.names.intern(prefix),
+            uri: uri
+        }
+    }
+
+    /// Builds element out of tag with qualified name `qname`, resolving
+    /// names of element and its attributes to namespaces
+    fn build_elem(&mut self, qname: &Name) -> XElem {
+        let mut scope = Vec::new();
+        for &(ref name, ref value) in self.attrs.iter() {
+            let (prefix, local) = split_name(name.as_slice());
+            if prefix == "xmlns" || (prefix.is_empty() && local == "xmlns") {
+                scope.push(XmlNS {
+                    name: self.names.intern(if prefix.is_empty() { "" } else { local }),
+                    uri: self.names.intern(value.as_slice())
+                });
+            }
+        }
+        self.scopes.push(scope);
+
+        let attrs = mem::replace(&mut self.attrs, Vec::new());
+        let mut attributes = Vec::with_capacity(attrs.len());
+        for (name, value) in attrs.into_iter() {
+            let (prefix, local) = split_name(name.as_slice());
+            let namespace = if prefix.is_empty() && local == "xmlns" {
+                XmlNS {
+                    name: self.names.intern(""),
+                    uri: self.names.intern(XMLNS_NS_URI)
+                }
+            } else {
+                self.resolve_ns(prefix, true)
+            };
+            attributes.push(XmlAttr {
+                name: if prefix.is_empty() { name.clone() } else { self.names.intern(local) },
+                value: value,
+                namespace: namespace
+            });
+        }
+
+        let (prefix, local) = split_name(qname.as_slice());
+        XElem {
+            name: if prefix.is_empty() { qname.clone() } else { self.names.intern(local) },
+            namespace: self.resolve_ns(prefix, false),
+            attributes: attributes,
+            children: Vec::new()
+        }
+    }
+
+    fn emit_start_tag(&mut self, is_empty: bool) {
+        // Skipped elements only need to be matched with end tags
+        if self.skipping {
+            if !is_empty {
+                self.skipped.push_str(self.buf.as_slice());
+                self.skipped.push(' ');
+                self.depth += 1;
+            }
+            self.state = Data;
+            return
+        }
+        let qname = self.names.intern(self.buf.as_slice());
+        let elem = self.build_elem(&qname);
+        if is_empty {
+            self.scopes.pop();
+            self.emit(EmptyElem(elem));
+        } else {
+            self.stack.push(qname);
+            self.depth += 1;
+            self.emit(ElemStart(elem));
+        }
+        self.state = Data;
+    }
+
+    fn end_tag_state(&mut self) {
+        match self.reader.peek() {
+            Some(c) if is_name_start_char(c) => self.state = EndTagName,
+            Some('>') => {
+                // Stray `</>` is dropped
+                self.reader.read_nchar();
+                self.error(EmptyEndTag, "end tag must have a name");
+                self.flush_errors();
+                self.state = Data;
+            },
+            _ => {
+                self.error(UnknownToken, "'</' must be followed by a name");
+                self.state = BogusComment;
+            }
+        }
+    }
+
+    fn end_tag_name_state(&mut self) {
+        self.buf.clear();
+        self.reader.read_until_into(" \t\n>", true, &mut self.buf);
+        self.state = EndTagNameAfter;
+    }
+
+    fn end_tag_name_after_state(&mut self) {
+        self.skip_whitespace();
+        match self.reader.read_nchar() {
+            Char('>') => {},
+            Char(_) => {
+                self.error(UnknownToken, "end tag must only contain a name");
+                self.reader.read_until('>', true);
+                self.reader.read_nchar();
+            },
+            _ => self.error(PrematureEOF, "end of input inside end tag")
+        }
+
+        // End tag closes the innermost element with its name, along with
+        // elements open inside it. End tags of no open element are dropped.
+        if self.close_skipped() {
+            self.state = Data;
+            return
+        }
+        let open = self.stack.iter().rposition(|open| open.as_slice() == self.buf.as_slice());
+        match open {
+            Some(i) => {
+                if i + 1 < self.stack.len() || !self.skipped.is_empty() {
+                    let msg = format!("end tag {} closes elements inside it", self.buf);
+                    self.reader.report(MismatchedEndTag, msg);
+                }
+                self.drop_skipped();
+                while self.stack.len() > i {
+                    self.close_elem();
+                }
+            },
+            None => {
+                let msg = format!("end tag {} has no open element", self.buf);
+                self.reader.report(MismatchedEndTag, msg);
+                self.flush_errors();
+            }
+        }
+        self.state = Data;
+    }
+
+    /// Closes the innermost skipped element named as the end tag in
+    /// `buf`, along with elements open inside it. Returns false if no
+    /// skipped element has that name.
+    fn close_skipped(&mut self) -> bool {
+        let mut found = None;
+        let mut pos = 0;
+        for open in self.skipped.as_slice().split(' ') {
+            if open == self.buf.as_slice() && pos < self.skipped.len() {
+                found = Some(pos);
+            }
+            pos += open.len() + 1;
+        }
+        let start = match found {
+            Some(start) => start,
+            None        => return false
+        };
+        let closed = self.skipped.as_slice().slice_from(start).split(' ').count() - 1;
+        if closed > 1 {
+            let msg = format!("end tag {} closes elements inside it", self.buf);
+            self.reader.report(MismatchedEndTag, msg);
+        }
+        self.skipped.truncate(start);
+        self.depth -= closed;
+        true
+    }
+
+    /// Closes all open skipped elements, which have no events
+    fn drop_skipped(&mut self) {
+        let open = self.skipped.as_slice().split(' ').count() - 1;
+        self.depth -= open;
+        self.skipped.clear();
+    }
+
+    /// Ends the innermost open element
+    fn close_elem(&mut self) {
+        let name = self.stack.pop().unwrap();
+        self.scopes.pop();
+        self.depth -= 1;
+        self.emit(ElemEnd(name));
+    }
+
+    /// Ends elements left open at end of input, unless parsing
+    /// stopped on an error
+    fn close_unclosed(&mut self) {
+        self.drop_skipped();
+        if self.stack.is_empty() || self.reader.failed {
+            return
+        }
+        let msg = format!("end of input inside element {}", self.stack.last().unwrap());
+        self.reader.report(PrematureEOF, msg);
+        while !self.stack.is_empty() {
+            self.close_elem();
+        }
+    }
+
+    fn markup_decl_state(&mut self) {
+        if self.reader.consume_if("--") {
+            self.state = Comment;
+        } else if self.reader.consume_if("[CDATA[") {
+            self.state = Cdata;
+        } else if self.reader.consume_if("DOCTYPE") {
+            self.state = Doctype;
+        } else {
+            self.error(UnknownToken, "'<!' must start a comment, CDATA or DOCTYPE");
+            self.state = BogusComment;
+        }
+    }
+
+    fn comment_state(&mut self) {
+        let (text, closed) = self.reader.read_text_until_str("-->");
+        if !closed {
+            self.error(PrematureEOF, "end of input inside comment");
+        } else if text.as_slice().contains("--") || text.as_slice().ends_with("-") {
+            self.error(MinMinInComment, "comment must not contain '--'");
+        }
+        self.emit(CommentEvent(text));
+        self.state = Data;
+    }
+
+    fn cdata_state(&mut self) {
+        let (text, closed) = self.reader.read_text_until_str("]]>");
+        if !closed {
+            self.error(PrematureEOF, "end of input inside CDATA section");
+        }
+        self.emit(CDataEvent(text));
+        self.state = Data;
+    }
+
+    /// Skips document type declaration, along with its internal subset.
+    /// DTDs aren't processed, so the declaration is only reported.
+    fn doctype_state(&mut self) {
+        self.error(UnsupportedDoctype, "document type declaration is ignored");
+        let mut subset = false;
+        loop {
+            match self.reader.read_nchar() {
+                Char('>') if !subset    => break,
+                Char('[')               => subset = true,
+                Char(']')               => subset = false,
+                Char(quote @ '"')
+                | Char(quote @ '\'')    => {
+                    self.reader.read_until(quote, true);
+                    self.reader.read_nchar();
+                },
+                Char('<')               => {
+                    // Markup in internal subset may contain quotes and `>`
+                    if self.reader.consume_if("!--") {
+                        self.reader.read_until_str("-->");
+                    } else if self.reader.consume_if("?") {
+                        self.reader.read_until_str("?>");
+                    }
+                },
+                Char(_)                 => {},
+                CharEOF | CharErr(_)    => {
+                    self.error(PrematureEOF, "end of input inside DOCTYPE");
+                    break
+                }
+            }
+        }
+        // Declaration has no event to report errors after
+        self.flush_errors();
+        self.state = Data;
+    }
+
+    fn pi_state(&mut self) {
+        match self.reader.peek() {
+            Some(c) if is_name_start_char(c) => self.state = PiTarget,
+            _ => {
+                self.error(UnknownToken, "'<?' must be followed by a target");
+                self.state = BogusComment;
+            }
+        }
+    }
+
+    fn pi_target_state(&mut self) {
+        self.buf = self.reader.read_until(is_name_char, false);
+        self.state = PiTargetAfter;
+    }
+
+    fn pi_target_after_state(&mut self) {
+        self.skip_whitespace();
+        self.state = PiContent;
+    }
+
+    fn pi_content_state(&mut self) {
+        let (value, closed) = self.reader.read_until_str("?>");
+        if !closed {
+            self.error(PrematureEOF, "end of input inside processing instruction");
+        }
+
+        let target = mem::replace(&mut self.buf, String::new());
+        // Declaration can only be the first thing in document
+        if target.as_slice() == "xml" && !self.started {
+            let decl = XDecl {
+                version: decl_pseudo_attr(value.as_slice(), "version")
+                            .unwrap_or("1.0".to_string()),
+                encoding: decl_pseudo_attr(value.as_slice(), "encoding"),
+                standalone: decl_pseudo_attr(value.as_slice(), "standalone")
+                            .map(|s| s.as_slice() == "yes")
+            };
+            self.check_encoding(&decl);
+            self.emit(DeclEvent(decl));
+        } else {
+            self.emit(PIEvent(XPi {
+                target: target,
+                value: value
+            }));
+        }
+        self.state = Data;
+    }
+
+    /// Reports encoding declared in `decl` if it can't be decoded,
+    /// unless encoding was chosen by user
+    fn check_encoding(&mut self, decl: &XDecl) {
+        let label = match decl.encoding {
+            Some(ref label) => label,
+            None            => return
+        };
+        if self.reader.forced || Encoding::from_label(label.as_slice()).is_some() {
+            return
+        }
+        let msg = format!("encoding {} is not supported, input is read as {}",
+                          label, self.reader.encoding());
+        let start = self.tag_start;
+        self.reader.report_at(UnsupportedEncoding, msg, start);
+    }
+
+    fn bogus_comment_state(&mut self) {
+        self.reader.read_until('>', true);
+        self.reader.read_nchar();
+        self.state = Data;
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::{XmlReader, Parser, Char, CharEOF, TextEvent, ErrEvent};
+    use super::{ElemStart, ElemEnd, EmptyElem, PIEvent, DeclEvent};
+    use super::{CommentEvent, CDataEvent, ParserState, MAX_LOOKAHEAD};
+    use serialize::json;
+    use std::io::{Seek, SeekSet};
+    use source::SliceSource;
+    use std::str::{Slice, Owned};
+    use encoding::{Utf8, Utf16Le, Utf16Be, Latin1};
+    use common::{UnreadableChar, IllegalChar, RestrictedCharError};
+    use common::{UnknownEntity, UnboundPrefix, MissingAttrValue};
+    use common::{MinMinInComment, UnknownToken, PrematureEOF, UnsupportedDoctype};
+    use common::UnsupportedEncoding;
+    use common::{UnquotedAttrValue, DuplicateAttr, EmptyEndTag, MismatchedEndTag};
+    use common::{Fail, Ignore, Version11, XmlNS, XPi, XML_NS_URI};
+    use name::Name;
+    use writer::elem_to_string;
+
+    use std::io::{BufReader, BufferedReader};
+    #[test]
+    fn test_eof() {
+        let mut read = BufReader::new(b"ab\r\n");
+        let mut xml_read = XmlReader::from_reader(&mut read);
+        xml_read.read_nchar();
+        assert!(!xml_read.eof);
 
         xml_read.read_nchar();
         assert!(!xml_read.eof);
@@ -435,4 +2103,587 @@ mod test {
         xml_read = XmlReader::from_reader(&mut read4);
         assert_eq!("".to_string(),   xml_read.read_until('b', false));
     }
+
+    #[test]
+    fn test_read_utf16() {
+        let mut read = BufReader::new(b"\xFE\xFF\x00a\x00\r\x00\n\x00b");
+        let mut xml_read = XmlReader::from_reader(&mut read);
+        assert_eq!(Char('a'),       xml_read.read_nchar());
+        assert_eq!(Utf16Be,         xml_read.encoding());
+        assert_eq!(Char('\n'),      xml_read.read_nchar());
+        assert_eq!(Char('b'),       xml_read.read_nchar());
+        assert_eq!((2u64,1u64),     xml_read.position());
+        assert_eq!(CharEOF,         xml_read.read_nchar());
+
+        let mut read2 = BufReader::new(b"<\x00?\x00");
+        xml_read = XmlReader::from_reader(&mut read2);
+        assert_eq!(Char('<'),       xml_read.read_nchar());
+        assert_eq!(Utf16Le,         xml_read.encoding());
+    }
+
+    #[test]
+    fn test_read_declared_encoding() {
+        let mut read = BufReader::new(b"<?xml version='1.0' encoding='latin1'?>\xE9");
+        let mut xml_read = XmlReader::from_reader(&mut read);
+        let decl = xml_read.read_until('>', true);
+        assert_eq!("<?xml version='1.0' encoding='latin1'?".to_string(), decl);
+        assert_eq!(Latin1,          xml_read.encoding());
+        xml_read.read_nchar();
+        assert_eq!(Char('é'),       xml_read.read_nchar());
+    }
+
+    #[test]
+    fn test_read_forced_encoding() {
+        let mut read = BufReader::new(b"\xEF\xBB\xBF\xC3\xA9");
+        let mut xml_read = XmlReader::from_reader_with_encoding(&mut read, Latin1);
+        assert_eq!(Char('\xEF'),    xml_read.read_nchar());
+
+        let mut read2 = BufReader::new(b"\xEF\xBB\xBF\xC3\xA9");
+        xml_read = XmlReader::from_reader_with_encoding(&mut read2, Utf8);
+        assert_eq!(Char('é'),       xml_read.read_nchar());
+    }
+
+    #[test]
+    fn test_read_malformed() {
+        let mut read = BufReader::new(b"a\xC3(\xFFb");
+        let mut xml_read = XmlReader::from_reader(&mut read);
+        assert_eq!(Char('a'),       xml_read.read_nchar());
+        assert_eq!(Char('\uFFFD'),  xml_read.read_nchar());
+        assert_eq!(Char('('),       xml_read.read_nchar());
+        assert_eq!(Char('\uFFFD'),  xml_read.read_nchar());
+        assert_eq!(Char('b'),       xml_read.read_nchar());
+        assert_eq!(5u64,            xml_read.offset);
+
+        let errors = xml_read.take_errors();
+        assert_eq!(2,               errors.len());
+        assert_eq!(UnreadableChar,  errors[0].kind);
+        assert_eq!((1, 2, 1),       (errors[0].line, errors[0].col, errors[0].offset));
+        assert_eq!((1, 4, 3),       (errors[1].line, errors[1].col, errors[1].offset));
+        assert!(xml_read.take_errors().is_empty());
+    }
+
+    #[test]
+    fn test_pull_malformed() {
+        let mut read = BufReader::new(b"a\xFFb");
+        let mut parser = Parser::from_reader(&mut read);
+        assert_eq!(Some(TextEvent(Owned("a\uFFFDb".to_string()))), parser.pull());
+        match parser.pull() {
+            Some(ErrEvent(err)) => assert_eq!(UnreadableChar, err.kind),
+            ev                  => assert!(false, "expected error, got {}", ev)
+        }
+        assert_eq!(None, parser.pull());
+    }
+
+    #[test]
+    fn test_read_illegal_char() {
+        let mut read = BufReader::new(b"a\x01\xEF\xBF\xBFb");
+        let mut xml_read = XmlReader::from_reader(&mut read);
+        assert_eq!("a\x01\uFFFFb".to_string(), xml_read.read_until('<', true));
+
+        let errors = xml_read.take_errors();
+        assert_eq!(vec![IllegalChar, IllegalChar],
+                   errors.iter().map(|e| e.kind.clone()).collect::<Vec<_>>());
+        assert_eq!((3, 2),          (errors[1].col, errors[1].offset));
+    }
+
+    #[test]
+    fn test_sniff_split_decl() {
+        // Source returns declaration over several reads
+        let input = b"<?xml version='1.1' encoding='ISO-8859-1'?><a>\xE9</a>";
+        let mut read = BufferedReader::with_capacity(8, BufReader::new(input));
+        let mut xml_read = XmlReader::from_reader(&mut read);
+        assert_eq!(Char('<'),       xml_read.read_nchar());
+        assert_eq!(Latin1,          xml_read.encoding());
+        assert_eq!(Version11,       xml_read.version);
+        xml_read.read_until('a', true);
+        assert_eq!("a>\u00E9".to_string(), xml_read.read_until('<', true));
+        assert_eq!(47u64,           xml_read.offset);
+    }
+
+    #[test]
+    fn test_read_xml11() {
+        let mut read = BufReader::new(b"<?xml version='1.1'?>a\xC2\x85b\r\xC2\x85\xE2\x80\xA8\x01");
+        let mut xml_read = XmlReader::from_reader(&mut read);
+        xml_read.read_until('a', true);
+        assert_eq!(Version11,       xml_read.version);
+        assert_eq!("a\nb\n\n\x01".to_string(), xml_read.read_until('<', true));
+        assert_eq!((4u64, 1u64),    xml_read.position());
+
+        let errors = xml_read.take_errors();
+        assert_eq!(1,                   errors.len());
+        assert_eq!(RestrictedCharError, errors[0].kind);
+    }
+
+    #[test]
+    fn test_err_behavior() {
+        let mut read = BufReader::new(b"a\x00b\x00c");
+        let mut parser = Parser::from_reader(&mut read);
+        parser.set_err_behavior(Ignore);
+        assert_eq!(Some(TextEvent(Owned("a\uFFFDb\uFFFDc".to_string()))), parser.pull());
+        assert_eq!(None, parser.pull());
+
+        let mut read2 = BufReader::new(b"a\x00b\x00c");
+        parser = Parser::from_reader(&mut read2);
+        parser.set_err_behavior(Fail);
+        assert_eq!(Some(TextEvent(Owned("a\uFFFD".to_string()))), parser.pull());
+        match parser.pull() {
+            Some(ErrEvent(err)) => assert_eq!(IllegalChar, err.kind),
+            ev                  => assert!(false, "expected error, got {}", ev)
+        }
+        assert_eq!(None, parser.pull());
+    }
+
+    #[test]
+    fn test_peek_after_cr() {
+        let mut read = BufReader::new(b"a\rb");
+        let mut xml_read = XmlReader::from_reader(&mut read);
+        xml_read.read_nchar();
+        assert_eq!(Some('\n'),      xml_read.peek());
+        assert_eq!(Char('\n'),      xml_read.read_nchar());
+        assert_eq!(Some('b'),       xml_read.peek());
+        assert_eq!((2u64,0u64),     xml_read.position());
+        assert_eq!(2u64,            xml_read.offset);
+    }
+
+    #[test]
+    fn test_text_borrowed() {
+        let input = "\uFEFFab\u0107<a/>";
+        let mut source = SliceSource::from_str(input);
+        let mut parser = Parser::from_reader(&mut source);
+        match parser.pull() {
+            Some(TextEvent(Slice(text))) => {
+                assert_eq!("ab\u0107", text);
+                assert_eq!(input.slice_from(3).as_ptr(), text.as_ptr());
+            },
+            ev => assert!(false, "expected borrowed text, got {}", ev)
+        }
+    }
+
+    #[test]
+    fn test_comment_and_cdata_borrowed() {
+        let input = "<!-- a-b --><![CDATA[b]]\u0107]]><!--\r\n-->";
+        let mut source = SliceSource::from_str(input);
+        let mut parser = Parser::from_reader(&mut source);
+        match parser.pull() {
+            Some(CommentEvent(Slice(text))) => {
+                assert_eq!(" a-b ", text);
+                assert_eq!(input.slice_from(4).as_ptr(), text.as_ptr());
+            },
+            ev => assert!(false, "expected borrowed comment, got {}", ev)
+        }
+        match parser.pull() {
+            Some(CDataEvent(Slice(text))) => assert_eq!("b]]\u0107", text),
+            ev => assert!(false, "expected borrowed CDATA, got {}", ev)
+        }
+        // New lines are normalized, so comment is copied
+        match parser.pull() {
+            Some(CommentEvent(Owned(text))) => assert_eq!("\n".to_string(), text),
+            ev => assert!(false, "expected owned comment, got {}", ev)
+        }
+    }
+
+    #[test]
+    fn test_text_normalized() {
+        let mut source = SliceSource::from_str("a\r\nb\x00");
+        let mut parser = Parser::from_reader(&mut source);
+        assert_eq!(Some(TextEvent(Owned("a\nb\uFFFD".to_string()))), parser.pull());
+
+        let mut read = BufReader::new(b"ab");
+        let mut parser = Parser::from_reader(&mut read);
+        match parser.pull() {
+            Some(TextEvent(Owned(text))) => assert_eq!("ab".to_string(), text),
+            ev => assert!(false, "expected owned text, got {}", ev)
+        }
+    }
+
+    #[test]
+    fn test_read_until_bulk() {
+        let mut text = String::new();
+        for _ in range(0u, 100) {
+            text.push_str("plain text, ");
+        }
+        text.push_str("\u0107\r\nmore<");
+        let mut read = BufReader::new(text.as_bytes());
+        let mut xml_read = XmlReader::from_reader(&mut read);
+        let expected = text.as_slice().slice_to(text.len() - 7).to_string() + "\nmore";
+        assert_eq!(expected,        xml_read.read_until('<', true));
+        assert_eq!((2u64, 4u64),    xml_read.position());
+        assert_eq!((text.len() - 1) as u64, xml_read.offset);
+        assert_eq!(Char('<'),       xml_read.read_nchar());
+    }
+
+    #[test]
+    fn test_read_until_set() {
+        let mut read = BufReader::new(b"ab&c<d");
+        let mut xml_read = XmlReader::from_reader(&mut read);
+        assert_eq!("ab".to_string(),    xml_read.read_until("<&", true));
+        assert_eq!(Char('&'),           xml_read.read_nchar());
+        let set = ['<', 'x'];
+        assert_eq!("c".to_string(),     xml_read.read_until(set.as_slice(), true));
+        assert_eq!("<d".to_string(),    xml_read.read_until(|c: char| c != 'e', false));
+    }
+
+    #[test]
+    fn test_read_until_str() {
+        let mut read = BufReader::new(b"a-b--c->x]]]>y?");
+        let mut xml_read = XmlReader::from_reader(&mut read);
+        assert_eq!(("a-b--c".to_string(), true),    xml_read.read_until_str("->"));
+        assert_eq!(("x]".to_string(), true),        xml_read.read_until_str("]]>"));
+        assert_eq!((1u64, 13u64),                   xml_read.position());
+        assert_eq!((String::new(), true),           xml_read.read_until_str(""));
+        assert_eq!((1u64, 13u64),                   xml_read.position());
+        assert_eq!(("y?".to_string(), false),       xml_read.read_until_str("?>"));
+    }
+
+    #[test]
+    fn test_pull_tags() {
+        let mut source = SliceSource::from_str("<a b='1'\n c=\"x\ty\"><b/></a >");
+        let mut parser = Parser::from_reader(&mut source);
+        match parser.pull() {
+            Some(ElemStart(elem)) => {
+                assert_eq!("a",             elem.name.as_slice());
+                assert_eq!(2,               elem.attributes.len());
+                assert_eq!("1".to_string(), elem.attributes[0].value);
+                assert_eq!("c",             elem.attributes[1].name.as_slice());
+                assert_eq!("x y".to_string(), elem.attributes[1].value);
+            },
+            ev => assert!(false, "expected start tag, got {}", ev)
+        }
+        assert_eq!(1, parser.depth);
+        match parser.pull() {
+            Some(EmptyElem(elem)) => assert_eq!("b", elem.name.as_slice()),
+            ev => assert!(false, "expected empty tag, got {}", ev)
+        }
+        assert_eq!(Some(ElemEnd(Name::new("a"))), parser.pull());
+        assert_eq!(0, parser.depth);
+        assert_eq!(None, parser.pull());
+    }
+
+    #[test]
+    fn test_pull_namespaces() {
+        let mut source = SliceSource::from_str(
+            "<a xmlns='urn:a' xmlns:p='urn:p'><p:b p:c='' d='' xml:e=''/></a><q:f/>");
+        let mut parser = Parser::from_reader(&mut source);
+        match parser.pull() {
+            Some(ElemStart(elem)) => assert_eq!("urn:a", elem.namespace.uri.as_slice()),
+            ev => assert!(false, "expected start tag, got {}", ev)
+        }
+        match parser.pull() {
+            Some(EmptyElem(elem)) => {
+                let p = XmlNS { name: Name::new("p"), uri: Name::new("urn:p") };
+                assert_eq!(p,                   elem.namespace);
+                assert_eq!(p,                   elem.attributes[0].namespace);
+                assert_eq!("",                  elem.attributes[1].namespace.uri.as_slice());
+                assert_eq!(XML_NS_URI,          elem.attributes[2].namespace.uri.as_slice());
+            },
+            ev => assert!(false, "expected empty tag, got {}", ev)
+        }
+        assert_eq!(Some(ElemEnd(Name::new("a"))), parser.pull());
+        match parser.pull() {
+            Some(EmptyElem(elem)) => assert_eq!("", elem.namespace.uri.as_slice()),
+            ev => assert!(false, "expected empty tag, got {}", ev)
+        }
+        match parser.pull() {
+            Some(ErrEvent(err)) => assert_eq!(UnboundPrefix, err.kind),
+            ev => assert!(false, "expected error, got {}", ev)
+        }
+    }
+
+    #[test]
+    fn test_pull_references() {
+        let mut source = SliceSource::from_str("a&lt;&#x107;&#98;<b c='&amp;'/>&foo;");
+        let mut parser = Parser::from_reader(&mut source);
+        assert_eq!(Some(TextEvent(Owned("a<\u0107b".to_string()))), parser.pull());
+        match parser.pull() {
+            Some(EmptyElem(elem)) => assert_eq!("&".to_string(), elem.attributes[0].value),
+            ev => assert!(false, "expected empty tag, got {}", ev)
+        }
+        assert_eq!(Some(TextEvent(Owned("&foo;".to_string()))), parser.pull());
+        match parser.pull() {
+            Some(ErrEvent(err)) => assert_eq!(UnknownEntity, err.kind),
+            ev => assert!(false, "expected error, got {}", ev)
+        }
+    }
+
+    #[test]
+    fn test_pull_missing_attr_value() {
+        let mut source = SliceSource::from_str("<a b c='1'>");
+        let mut parser = Parser::from_reader(&mut source);
+        match parser.pull() {
+            Some(ElemStart(elem)) => {
+                assert_eq!("b",             elem.attributes[0].name.as_slice());
+                assert_eq!(String::new(),   elem.attributes[0].value);
+                assert_eq!("1".to_string(), elem.attributes[1].value);
+            },
+            ev => assert!(false, "expected start tag, got {}", ev)
+        }
+        match parser.pull() {
+            Some(ErrEvent(err)) => assert_eq!(MissingAttrValue, err.kind),
+            ev => assert!(false, "expected error, got {}", ev)
+        }
+    }
+
+    #[test]
+    fn test_pull_recovered() {
+        let input = "<a b=x&amp;y c='1' c='2' d=z/><e><f></g></e>t</>\
+                     <h><i></h>";
+        let mut source = SliceSource::from_str(input);
+        let mut parser = Parser::from_reader(&mut source);
+        let mut events = Vec::new();
+        loop {
+            match parser.pull() {
+                Some(EmptyElem(elem)) => {
+                    let attrs: Vec<String> = elem.attributes.iter().map(|attr| {
+                        format!("{}={}", attr.name, attr.value)
+                    }).collect();
+                    events.push(format!("empty {} {}", elem.name, attrs.as_slice().connect(" ")));
+                },
+                Some(ElemStart(elem))   => events.push(format!("start {}", elem.name)),
+                Some(ElemEnd(name))     => events.push(format!("end {}", name)),
+                Some(TextEvent(text))   => events.push(format!("text {}", text)),
+                Some(ErrEvent(err))     => events.push(format!("error {}", err.kind)),
+                Some(ev)                => events.push(format!("{}", ev)),
+                None                    => break
+            }
+        }
+        let expected = ["empty a b=x&y c=1 d=z",
+                        "error UnquotedAttrValue", "error DuplicateAttr",
+                        "error UnquotedAttrValue",
+                        "start e", "start f", "error MismatchedEndTag",
+                        "end f", "end e", "error MismatchedEndTag",
+                        "text t", "error EmptyEndTag",
+                        "start h", "start i", "end i", "end h", "error MismatchedEndTag"];
+        assert_eq!(expected.iter().map(|s| s.to_string()).collect::<Vec<String>>(), events);
+        assert_eq!(0, parser.depth);
+    }
+
+    #[test]
+    fn test_pull_pi() {
+        let mut source = SliceSource::from_str(
+            "<?xml version='1.1' standalone='yes'?><?php echo ?> ?><?xml?>");
+        let mut parser = Parser::from_reader(&mut source);
+        match parser.pull() {
+            Some(DeclEvent(decl)) => {
+                assert_eq!("1.1".to_string(), decl.version);
+                assert_eq!(None,              decl.encoding);
+                assert_eq!(Some(true),        decl.standalone);
+            },
+            ev => assert!(false, "expected declaration, got {}", ev)
+        }
+        let pi = XPi { target: "php".to_string(), value: "echo ".to_string() };
+        assert_eq!(Some(PIEvent(pi)), parser.pull());
+        assert_eq!(Some(TextEvent(Slice(" ?>"))), parser.pull());
+        let pi = XPi { target: "xml".to_string(), value: String::new() };
+        assert_eq!(Some(PIEvent(pi)), parser.pull());
+    }
+
+    #[test]
+    fn test_pull_unsupported_encoding() {
+        let mut read = BufReader::new(b"<?xml version='1.0' encoding='windows-1252'?><a/>");
+        let mut parser = Parser::from_reader(&mut read);
+        match parser.pull() {
+            Some(DeclEvent(decl)) => assert_eq!(Some("windows-1252".to_string()), decl.encoding),
+            ev => assert!(false, "expected declaration, got {}", ev)
+        }
+        match parser.pull() {
+            Some(ErrEvent(err)) => {
+                assert_eq!(UnsupportedEncoding, err.kind);
+                assert_eq!((1, 1, 0),       (err.line, err.col, err.offset));
+            },
+            ev => assert!(false, "expected error, got {}", ev)
+        }
+        assert_eq!(Utf8,                parser.reader.encoding());
+
+        // Encoding chosen by user is used whatever is declared
+        let mut read = BufReader::new(b"<?xml version='1.0' encoding='Shift_JIS'?><a/>");
+        let mut parser = Parser::from_reader_with_encoding(&mut read, Latin1);
+        match parser.pull() {
+            Some(DeclEvent(_)) => {},
+            ev => assert!(false, "expected declaration, got {}", ev)
+        }
+        match parser.pull() {
+            Some(EmptyElem(_)) => {},
+            ev => assert!(false, "expected empty tag, got {}", ev)
+        }
+    }
+
+    #[test]
+    fn test_peek_n() {
+        let mut read = BufReader::new(b"<!\r\n-x");
+        let mut xml_read = XmlReader::from_reader(&mut read);
+        assert_eq!(Some('-'),       xml_read.peek_n(3));
+        assert_eq!(None,            xml_read.peek_n(5));
+        assert_eq!(None,            xml_read.peek_n(MAX_LOOKAHEAD));
+        assert_eq!(Some('<'),       xml_read.peek());
+        assert!(xml_read.starts_with("<!\n-"));
+        assert!(!xml_read.starts_with("<!\n-xy"));
+        assert!(!xml_read.consume_if("<?"));
+        assert!(xml_read.consume_if("<!\n"));
+        assert_eq!((2u64, 0u64),    xml_read.position());
+        assert_eq!(4u64,            xml_read.offset);
+        assert_eq!("-x".to_string(), xml_read.read_until('<', true));
+        assert_eq!(6u64,            xml_read.offset);
+    }
+
+    #[test]
+    fn test_peek_malformed() {
+        let mut read = BufReader::new(b"ab\xFF\r\xFF");
+        let mut xml_read = XmlReader::from_reader(&mut read);
+        assert_eq!(Some('\n'),      xml_read.peek_n(3));
+        let errors = xml_read.take_errors();
+        assert_eq!(2,               errors.len());
+        assert_eq!((1, 3, 2),       (errors[0].line, errors[0].col, errors[0].offset));
+        assert_eq!((2, 1, 4),       (errors[1].line, errors[1].col, errors[1].offset));
+    }
+
+    #[test]
+    fn test_pull_markup_decl() {
+        let mut source = SliceSource::from_str("<!-- a -- b --><![CDATA[<x>]]]><!foo>");
+        let mut parser = Parser::from_reader(&mut source);
+        assert_eq!(Some(CommentEvent(Owned(" a -- b ".to_string()))), parser.pull());
+        match parser.pull() {
+            Some(ErrEvent(err)) => assert_eq!(MinMinInComment, err.kind),
+            ev => assert!(false, "expected error, got {}", ev)
+        }
+        assert_eq!(Some(CDataEvent(Owned("<x>]".to_string()))), parser.pull());
+        match parser.pull() {
+            Some(ErrEvent(err)) => assert_eq!(UnknownToken, err.kind),
+            ev => assert!(false, "expected error, got {}", ev)
+        }
+        assert_eq!(None, parser.pull());
+    }
+
+    #[test]
+    fn test_pull_doctype() {
+        let input = "<!DOCTYPE r [<!ENTITY e \"a>]\"><!-- ]> -->]><r/>";
+        let mut source = SliceSource::from_str(input);
+        let mut parser = Parser::from_reader(&mut source);
+        match parser.pull() {
+            Some(ErrEvent(err)) => assert_eq!(UnsupportedDoctype, err.kind),
+            ev => assert!(false, "expected error, got {}", ev)
+        }
+        match parser.pull() {
+            Some(EmptyElem(elem)) => assert_eq!("r", elem.name.as_slice()),
+            ev => assert!(false, "expected empty tag, got {}", ev)
+        }
+        assert_eq!(None, parser.pull());
+    }
+
+    #[test]
+    fn test_resume_from_state() {
+        let input = "<a xmlns='urn:a'>\r\n<b>t&amp;x</b></a>";
+        let mut source = SliceSource::from_str(input);
+        let mut parser = Parser::from_reader(&mut source);
+        let mut expected = Vec::new();
+        loop {
+            match parser.pull() {
+                Some(ev) => expected.push(ev),
+                None => break
+            }
+        }
+
+        let mut source = SliceSource::from_str(input);
+        let saved = {
+            let mut parser = Parser::from_reader(&mut source);
+            assert_eq!(Some(expected[0].clone()), parser.pull());
+            assert_eq!(Some(expected[1].clone()), parser.pull());
+            json::encode(&parser.save_state().unwrap())
+        };
+        let state: ParserState = json::decode(saved.as_slice()).unwrap();
+        assert_eq!(20,              state.offset());
+
+        let mut source = SliceSource::from_str(input);
+        source.seek(state.input_offset() as i64, SeekSet).unwrap();
+        let mut parser = Parser::from_state(&mut source, state);
+        assert_eq!(1,               parser.depth);
+        for ev in expected.slice_from(2).iter() {
+            assert_eq!(Some(ev.clone()), parser.pull());
+        }
+        assert_eq!(None,            parser.pull());
+        assert_eq!((2u64, 18u64),   parser.reader.position());
+    }
+
+    #[test]
+    fn test_read_subtree() {
+        let input = "<r><a k='1'>x<b>y<c/></b><!--z--></a><d/></r>";
+        let mut source = SliceSource::from_str(input);
+        let mut parser = Parser::from_reader(&mut source);
+        parser.pull();
+        let a = match parser.pull() {
+            Some(ElemStart(elem)) => parser.read_subtree(elem),
+            ev => return assert!(false, "expected start tag, got {}", ev)
+        };
+        assert_eq!("<a k=\"1\">x<b>y<c/></b><!--z--></a>".to_string(), elem_to_string(&a));
+        assert_eq!(1, parser.depth);
+        match parser.pull() {
+            Some(EmptyElem(elem)) => assert_eq!("d", elem.name.as_slice()),
+            ev => assert!(false, "expected empty tag, got {}", ev)
+        }
+
+        // Errors inside come after the subtree, elements left open are
+        // closed at end of input
+        let mut source = SliceSource::from_str("<a><b>&x;<c>");
+        let mut parser = Parser::from_reader(&mut source);
+        parser.pull();
+        let b = match parser.pull() {
+            Some(ElemStart(elem)) => parser.read_subtree(elem),
+            ev => return assert!(false, "expected start tag, got {}", ev)
+        };
+        assert_eq!("<b>&amp;x;<c/></b>".to_string(), elem_to_string(&b));
+        match parser.pull() {
+            Some(ErrEvent(err)) => assert_eq!(UnknownEntity, err.kind),
+            ev => assert!(false, "expected error, got {}", ev)
+        }
+        assert_eq!(Some(ElemEnd(Name::new("a"))), parser.pull());
+        match parser.pull() {
+            Some(ErrEvent(err)) => assert_eq!(PrematureEOF, err.kind),
+            ev => assert!(false, "expected error, got {}", ev)
+        }
+        assert_eq!(None, parser.pull());
+    }
+
+    #[test]
+    fn test_tag_position() {
+        let mut source = SliceSource::from_str("<r>\n  x<a k='1'/></r>");
+        let mut parser = Parser::from_reader(&mut source);
+        parser.pull();
+        assert_eq!((1, 1, 0), parser.tag_position());
+        loop {
+            match parser.pull() {
+                Some(EmptyElem(_))  => break,
+                Some(_)             => {},
+                None                => return assert!(false, "expected empty tag")
+            }
+        }
+        assert_eq!((2, 4, 7), parser.tag_position());
+    }
+
+    #[test]
+    fn test_skip_subtree() {
+        let input = "<r><a>x&y;<b k='1 &amp; 2'>y<c/></b><!--z--></a><d/></r>";
+        let mut source = SliceSource::from_str(input);
+        let mut parser = Parser::from_reader(&mut source);
+        parser.pull();
+        match parser.pull() {
+            Some(ElemStart(elem)) => assert_eq!("a", elem.name.as_slice()),
+            ev => assert!(false, "expected start tag, got {}", ev)
+        }
+        parser.skip_subtree();
+        assert_eq!(1, parser.depth);
+        // Names of skipped tags aren't interned
+        assert_eq!(None, parser.names.lookup("b"));
+        assert_eq!(None, parser.names.lookup("k"));
+        match parser.pull() {
+            Some(ErrEvent(err)) => assert_eq!(UnknownEntity, err.kind),
+            ev => assert!(false, "expected error, got {}", ev)
+        }
+        match parser.pull() {
+            Some(EmptyElem(elem)) => assert_eq!("d", elem.name.as_slice()),
+            ev => assert!(false, "expected empty tag, got {}", ev)
+        }
+        // Rest of the current element is skipped
+        parser.skip_subtree();
+        assert_eq!(0, parser.depth);
+        assert_eq!(None, parser.pull());
+    }
 }
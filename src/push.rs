@@ -0,0 +1,530 @@
+//! Push mode parsing, for input that arrives in chunks, e.g. from
+//! a socket.
+//!
+//! `PushParser` keeps bytes of the token it's in the middle of, together
+//! with the `ParserState` after the last complete token. Once the bytes
+//! ending the token arrive, it's parsed again from that state, so a token,
+//! UTF-8 sequence or `\r\n` split between chunks is read as if it was not.
+//! Until then, only received bytes are searched for its end, so a long
+//! comment arriving in small chunks isn't parsed again on each of them.
+//!
+//! Text is the exception, as it can be split. Text received so far is
+//! returned right away, so long text isn't parsed again on each feed,
+//! and arrives as several `TextEvent`s.
+
+use std::cell::Cell;
+use std::cmp::min;
+use std::mem::replace;
+use std::io::{Reader, Buffer, IoResult, EndOfFile, ResourceUnavailable};
+use std::io::standard_error;
+use std::slice::bytes::copy_memory;
+
+use common::{ErrBehavior, Warn, XmlVersion};
+use encoding::{Encoding, Utf8, Latin1};
+use name::Interner;
+use parser::{Parser, ParserState, XmlEvent};
+use source::XmlSource;
+
+/// Number of bytes needed to detect encoding of input without
+/// an XML declaration, see `PushParser::feed`
+static SNIFF_LEN: uint = 512;
+
+/// Bytes ending the markup token being received, and where in input
+/// they're searched for
+struct TokenEnd {
+    delim: &'static [u8],
+    /// Byte offset in input of the token's contents, after the
+    /// bytes its kind was found by
+    from: u64
+}
+
+/// Source over bytes received so far. Reading past them marks
+/// source as `starved`, unless all input was received.
+struct ChunkSource<'a> {
+    buf: &'a [u8],
+    pos: uint,
+    closed: bool,
+    starved: &'a Cell<bool>
+}
+
+impl<'a> ChunkSource<'a> {
+    fn end_of_data<T>(&self) -> IoResult<T> {
+        if self.closed {
+            Err(standard_error(EndOfFile))
+        } else {
+            self.starved.set(true);
+            Err(standard_error(ResourceUnavailable))
+        }
+    }
+}
+
+impl<'a> Reader for ChunkSource<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        if self.pos >= self.buf.len() {
+            return self.end_of_data()
+        }
+        let len = min(buf.len(), self.buf.len() - self.pos);
+        copy_memory(buf, self.buf.slice(self.pos, self.pos + len));
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+impl<'a> Buffer for ChunkSource<'a> {
+    fn fill_buf<'b>(&'b mut self) -> IoResult<&'b [u8]> {
+        if self.pos >= self.buf.len() {
+            self.end_of_data()
+        } else {
+            Ok(self.buf.slice_from(self.pos))
+        }
+    }
+
+    fn consume(&mut self, amt: uint) {
+        self.pos = min(self.pos + amt, self.buf.len());
+    }
+}
+
+impl<'a, 'b> XmlSource<'a> for ChunkSource<'b> {}
+
+/// Parser that is given input as it arrives, instead of pulling it
+/// from a blocking reader.
+///
+/// E.g.
+///
+///     let mut parser = PushParser::new();
+///     for chunk in chunks {
+///         for event in parser.feed(chunk).into_iter() {
+///             ...
+///         }
+///     }
+///     let rest = parser.finish();
+pub struct PushParser {
+    /// Received bytes, from the start of the token being parsed
+    input: Vec<u8>,
+    /// Number of bytes at the start of `input` that were parsed, which
+    /// are dropped once they're most of it
+    consumed: uint,
+    /// Byte offset of `input` in the whole input
+    base: u64,
+    /// State after last complete token, `None` before first one
+    state: Option<ParserState>,
+    /// End of the markup token input stopped in, if it's known
+    token_end: Option<TokenEnd>,
+    /// Whether all input was received
+    closed: bool,
+    /// Names interned so far, lent to parser of each feed
+    names: Interner,
+    encoding: Option<Encoding>,
+    version: Option<XmlVersion>,
+    err_behavior: ErrBehavior
+}
+
+impl PushParser {
+    pub fn new() -> PushParser {
+        PushParser {
+            input: Vec::new(),
+            consumed: 0,
+            base: 0,
+            state: None,
+            token_end: None,
+            closed: false,
+            names: Interner::new(),
+            encoding: None,
+            version: None,
+            err_behavior: Warn
+        }
+    }
+
+    /// Constructs a PushParser decoding input as `encoding`,
+    /// instead of detecting its encoding.
+    pub fn with_encoding(encoding: Encoding) -> PushParser {
+        let mut parser = PushParser::new();
+        parser.encoding = Some(encoding);
+        parser
+    }
+
+    /// Sets XML version used to check chars, overriding the
+    /// version found in document's XML declaration.
+    /// Has no effect once parsing started.
+    pub fn set_version(&mut self, version: XmlVersion) {
+        self.version = Some(version);
+    }
+
+    /// Sets what parser does with recoverable errors it finds.
+    /// Has no effect once parsing started.
+    pub fn set_err_behavior(&mut self, behavior: ErrBehavior) {
+        self.err_behavior = behavior;
+    }
+
+    /// Parses `data` following input received so far, and returns
+    /// events of all tokens completed by it.
+    ///
+    /// Parsing doesn't start until first `SNIFF_LEN` bytes or a `>`
+    /// arrive, so the XML declaration can be used to detect encoding.
+    pub fn feed(&mut self, data: &[u8]) -> Vec<XmlEvent<'static>> {
+        let received = self.input.len();
+        self.input.push_all(data);
+        if self.state.is_none() && !self.closed && self.input.len() < SNIFF_LEN
+            && !self.input.contains(&b'>') {
+            return Vec::new()
+        }
+        if !self.token_ended(received) {
+            return Vec::new()
+        }
+        self.parse()
+    }
+
+    /// Checks if bytes received after the first `received` ones may end
+    /// the markup token being received. Bytes before them were searched
+    /// by earlier feeds.
+    fn token_ended(&self, received: uint) -> bool {
+        let end = match self.token_end {
+            Some(ref end)   => end,
+            None            => return true
+        };
+        let from = (end.from - self.base) as uint;
+        // Delimiter may start in bytes received before
+        let start = if received > from + end.delim.len() {
+            received + 1 - end.delim.len()
+        } else {
+            from
+        };
+        self.input.slice_from(start).windows(end.delim.len()).any(|bytes| bytes == end.delim)
+    }
+
+    /// Marks end of input, and returns events of remaining tokens
+    pub fn finish(&mut self) -> Vec<XmlEvent<'static>> {
+        self.closed = true;
+        self.parse()
+    }
+
+    /// Returns byte offset in input after the last complete token
+    pub fn offset(&self) -> u64 {
+        match self.state {
+            Some(ref state) => state.offset(),
+            None            => 0
+        }
+    }
+
+    fn parse(&mut self) -> Vec<XmlEvent<'static>> {
+        let starved = Cell::new(false);
+        let mut events = Vec::new();
+        let state = {
+            let mut source = ChunkSource {
+                buf: self.input.slice_from(self.consumed),
+                pos: 0,
+                closed: self.closed,
+                starved: &starved
+            };
+            let mut parser = match self.state.take() {
+                Some(state) => Parser::from_state(&mut source, state),
+                None        => self.start(&mut source)
+            };
+            parser.set_interner(replace(&mut self.names, Interner::new()));
+            let mut state = parser.save_state();
+            let mut token = Vec::new();
+
+            loop {
+                let event = parser.pull();
+                // Token may continue in bytes that didn't arrive yet,
+                // so it's parsed again from last state next time
+                if starved.get() {
+                    break
+                }
+                let done = event.is_none();
+                match event {
+                    Some(event) => token.push(event.into_owned()),
+                    None        => {}
+                }
+                match parser.save_state() {
+                    Some(saved) => {
+                        events.extend(token.into_iter());
+                        token = Vec::new();
+                        state = Some(saved);
+                    },
+                    None => continue
+                }
+                if done {
+                    break
+                }
+            }
+            self.names = replace(parser.interner(), Interner::new());
+            state.unwrap()
+        };
+        let state = if self.closed {
+            state
+        } else {
+            self.parse_text(state, &mut events)
+        };
+
+        // Parsed bytes are dropped once copying the rest costs less. Bytes
+        // read ahead are kept, as the unfinished token may start in them.
+        let parsed = (state.offset() - self.base) as uint;
+        if parsed * 2 >= self.input.len() {
+            self.input = self.input.slice_from(parsed).to_vec();
+            self.base = state.offset();
+        }
+        self.consumed = (state.input_offset() - self.base) as uint;
+        self.token_end = if self.closed { None } else { self.find_token_end(&state) };
+        self.state = Some(state);
+        events
+    }
+
+    /// Returns end of the markup token following `state`, if its start
+    /// was received and input is in an encoding compatible with ASCII
+    fn find_token_end(&self, state: &ParserState) -> Option<TokenEnd> {
+        match state.encoding() {
+            Utf8 | Latin1   => {},
+            _               => return None
+        }
+        let start = (state.offset() - self.base) as uint;
+        let token = self.input.slice_from(start);
+        let (token, start) = if token.starts_with(b"<") {
+            (token.slice_from(1), start + 1)
+        } else if state.in_markup() {
+            (token, start)
+        } else {
+            return None
+        };
+        let (delim, len) = if token.starts_with(b"!--") {
+            (b"-->", 3)
+        } else if token.starts_with(b"--") {
+            (b"-->", 2)
+        } else if token.starts_with(b"![CDATA[") {
+            (b"]]>", 8)
+        } else if token.starts_with(b"[CDATA[") {
+            (b"]]>", 7)
+        } else if token.is_empty() || b"![CDATA[".starts_with(token) || b"!--".starts_with(token)
+                  || b"[CDATA[".starts_with(token) || b"--".starts_with(token) {
+            // Kind of token isn't known yet
+            return None
+        } else {
+            // Other markup, including PIs and bogus comments, ends at `>`
+            (b">", 0)
+        };
+        Some(TokenEnd {
+            delim: delim,
+            from: self.base + (start + len) as u64
+        })
+    }
+
+    /// Parses text following `state` up to where received input ends,
+    /// adding its events to `events`, and returns state after it
+    fn parse_text(&self, state: ParserState, events: &mut Vec<XmlEvent<'static>>)
+                  -> ParserState {
+        // Chars read ahead may start a reference
+        if state.offset() != state.input_offset() {
+            return state
+        }
+        let start = (state.input_offset() - self.base) as uint;
+        let len = text_len(self.input.slice_from(start), &state.encoding());
+        if len == 0 {
+            return state
+        }
+        let starved = Cell::new(false);
+        let mut source = ChunkSource {
+            buf: self.input.slice(start, start + len),
+            pos: 0,
+            closed: false,
+            starved: &starved
+        };
+        let mut parser = Parser::from_state(&mut source, state);
+        for event in parser.pull_text().into_iter() {
+            events.push(event.into_owned());
+        }
+        parser.save_state().unwrap()
+    }
+
+    /// Constructs parser for the start of input
+    fn start<'a, 'b: 'a>(&self, source: &'a mut ChunkSource<'b>)
+                         -> Parser<'a, ChunkSource<'b>> {
+        let mut parser = match self.encoding {
+            Some(ref encoding) => Parser::from_reader_with_encoding(source, encoding.clone()),
+            None               => Parser::from_reader(source)
+        };
+        match self.version {
+            Some(ref version) => parser.set_version(version.clone()),
+            None              => {}
+        }
+        parser.set_err_behavior(self.err_behavior.clone());
+        parser
+    }
+}
+
+/// Returns length of text at the start of `buf` that can be parsed
+/// before more input arrives. It ends before markup, and not inside
+/// a reference, a char or `\r\n`.
+fn text_len(buf: &[u8], encoding: &Encoding) -> uint {
+    let mut len = match *encoding {
+        Utf8 | Latin1   => buf.iter().position(|&b| b == b'<').unwrap_or(buf.len()),
+        _               => return 0
+    };
+    match buf.slice_to(len).iter().rposition(|&b| b == b'&') {
+        Some(amp) if !buf.slice(amp, len).contains(&b';') => len = amp,
+        _                                                => {}
+    }
+    if *encoding == Utf8 {
+        len = utf8_len(buf.slice_to(len));
+    }
+    if len > 0 && buf[len - 1] == b'\r' {
+        len -= 1;
+    }
+    len
+}
+
+/// Returns length of `buf` without a UTF-8 sequence cut at its end
+fn utf8_len(buf: &[u8]) -> uint {
+    let mut start = buf.len();
+    while start > 0 && buf.len() - start < 4 {
+        start -= 1;
+        let b = buf[start];
+        if b & 0xC0 != 0x80 {
+            let width = if b < 0x80 { 1 } else if b < 0xE0 { 2 } else if b < 0xF0 { 3 } else { 4 };
+            return if start + width > buf.len() { start } else { buf.len() }
+        }
+    }
+    buf.len()
+}
+
+#[cfg(test)]
+mod test {
+    use super::PushParser;
+    use parser::{Parser, XmlEvent, TextEvent, CommentEvent, ElemEnd, EmptyElem, ErrEvent};
+    use common::{UnreadableChar, UnsupportedDoctype};
+    use name::Name;
+    use std::io::BufReader;
+    use std::str::Owned;
+
+    fn text(text: &str) -> XmlEvent<'static> {
+        TextEvent(Owned(text.to_string()))
+    }
+
+    /// Joins adjacent text events, as text may be split between feeds
+    fn join_text(events: Vec<XmlEvent<'static>>) -> Vec<XmlEvent<'static>> {
+        let mut joined = Vec::new();
+        for event in events.into_iter() {
+            match (joined.pop(), event) {
+                (Some(TextEvent(a)), TextEvent(b)) => {
+                    let mut text = a.into_string();
+                    text.push_str(b.as_slice());
+                    joined.push(TextEvent(Owned(text)));
+                },
+                (last, event) => {
+                    joined.extend(last.into_iter());
+                    joined.push(event);
+                }
+            }
+        }
+        joined
+    }
+
+    fn pull_all(input: &[u8]) -> Vec<XmlEvent<'static>> {
+        let mut read = BufReader::new(input);
+        let mut parser = Parser::from_reader(&mut read);
+        let mut events = Vec::new();
+        loop {
+            match parser.pull() {
+                Some(event) => events.push(event.into_owned()),
+                None        => return events
+            }
+        }
+    }
+
+    #[test]
+    fn test_feed_bytes() {
+        let input = "<?xml version='1.0'?><a b='x\u0107'>t\r\nx&amp;\u4E00</a><!-- c -->"
+                    .as_bytes();
+        let mut parser = PushParser::new();
+        let mut events = Vec::new();
+        for b in input.iter() {
+            events.extend(parser.feed(&[*b]).into_iter());
+        }
+        events.extend(parser.finish().into_iter());
+        assert_eq!(pull_all(input), join_text(events));
+        assert_eq!(input.len() as u64, parser.offset());
+    }
+
+    #[test]
+    fn test_feed_long_comment() {
+        // Parsing the comment again on each byte would take quadratic time
+        let body = String::from_char(1 << 20, 'c');
+        let input = format!("<a><!--{}--></a>", body);
+        let mut parser = PushParser::new();
+        let mut events = Vec::new();
+        for b in input.as_bytes().iter() {
+            events.extend(parser.feed(&[*b]).into_iter());
+        }
+        events.extend(parser.finish().into_iter());
+        assert_eq!(3, events.len());
+        assert_eq!(CommentEvent(Owned(body)), events[1]);
+        assert_eq!(ElemEnd(Name::new("a")), events[2]);
+    }
+
+    #[test]
+    fn test_feed_chunks() {
+        let mut parser = PushParser::new();
+        assert_eq!(1, parser.feed(b"<a>").len());
+        assert_eq!(vec![text("te")], parser.feed(b"te"));
+        assert_eq!(vec![text("xt")], parser.feed(b"xt<"));
+        assert_eq!(Vec::new(), parser.feed(b"/a"));
+        assert_eq!(vec![ElemEnd(Name::new("a"))], parser.feed(b">"));
+        assert_eq!(Vec::new(), parser.finish());
+    }
+
+    #[test]
+    fn test_feed_shares_names() {
+        let mut parser = PushParser::new();
+        let first = parser.feed(b"<r><a/>");
+        let second = parser.feed(b"<a/>");
+        match (&first[1], &second[0]) {
+            (&EmptyElem(ref a), &EmptyElem(ref b)) => assert!(a.name.ptr_eq(&b.name)),
+            ev => assert!(false, "expected empty tags, got {}", ev)
+        }
+    }
+
+    #[test]
+    fn test_feed_split_text() {
+        let mut parser = PushParser::new();
+        assert_eq!(1, parser.feed(b"<a>").len());
+        // Text ends before a cut char, `\r` or reference
+        assert_eq!(vec![text("ab")], parser.feed(b"ab\xC4"));
+        assert_eq!(vec![text("\u0107c&d")], parser.feed(b"\x87c&amp;d&l"));
+        assert_eq!(vec![text("<")], parser.feed(b"t;\r"));
+        assert_eq!(vec![text("\n")], parser.feed(b"\n</a"));
+        assert_eq!(vec![ElemEnd(Name::new("a"))], parser.feed(b">"));
+        assert_eq!(Vec::new(), parser.finish());
+    }
+
+    #[test]
+    fn test_finish_malformed() {
+        let mut parser = PushParser::new();
+        assert_eq!(2, parser.feed(b"<a/>a\xC4").len());
+        let events = parser.finish();
+        assert_eq!(TextEvent(Owned("\uFFFD".to_string())), events[0]);
+        match events[1] {
+            ErrEvent(ref err) => assert_eq!(UnreadableChar, err.kind),
+            ref ev => assert!(false, "expected error, got {}", ev)
+        }
+    }
+
+    #[test]
+    fn test_feed_doctype() {
+        let input = b"<!DOCTYPE a [<!ENTITY e 'x'>]>\n<a/>";
+        let mut parser = PushParser::new();
+        let mut events = Vec::new();
+        for chunk in input.chunks(7) {
+            events.extend(parser.feed(chunk).into_iter());
+        }
+        events.extend(parser.finish().into_iter());
+        assert_eq!(3, events.len());
+        match events[0] {
+            ErrEvent(ref err) => assert_eq!(UnsupportedDoctype, err.kind),
+            ref ev => assert!(false, "expected error, got {}", ev)
+        }
+        assert_eq!(TextEvent(Owned("\n".to_string())), events[1]);
+        match events[2] {
+            EmptyElem(ref elem) => assert_eq!("a", elem.name.as_slice()),
+            ref ev => assert!(false, "expected empty tag, got {}", ev)
+        }
+    }
+}
@@ -0,0 +1,176 @@
+//! Fast scanning of byte buffers for bytes the parser has to look at.
+//!
+//! Plain ASCII text, that is printable chars other than `<`, `&`, quotes
+//! and `]`, needs no decoding, checking or normalization, so runs of it
+//! can be copied in bulk. On x86-64 buffers are tested 16 bytes at a time
+//! in SSE2 registers. Elsewhere they're tested a machine word at a time
+//! (SIMD within a register). Both fall back to single bytes at the ends.
+
+use std::mem::size_of;
+
+static ONES: u64  = 0x0101010101010101;
+static HIGHS: u64 = 0x8080808080808080;
+
+/// Size of a word in bytes
+static WORD: uint = 8;
+
+/// Checks if byte `b` has to be looked at by the parser, either
+/// because it's one of delimiters `<&"']`, a control char, including
+/// `\r` and `\n`, or it's not ASCII.
+#[inline]
+pub fn is_special(b: u8) -> bool {
+    match b {
+        b'<' | b'&' | b'"' | b'\'' | b']' => true,
+        0x20...0x7E                     => false,
+        _                               => true
+    }
+}
+
+/// Same as `is_special`, for chars
+#[inline]
+pub fn is_special_char(c: char) -> bool {
+    (c as u32) > 0x7F || is_special(c as u8)
+}
+
+/// Sets high bit of each byte in `x` that is zero. Bytes after
+/// the first zero byte may be marked wrongly, which is fine since
+/// we only care if there is one.
+#[inline]
+fn zero_bytes(x: u64) -> u64 {
+    (x - ONES) & !x & HIGHS
+}
+
+/// Checks if any byte in `word` is special
+#[inline]
+fn has_special(word: u64) -> bool {
+    // Bytes less than 0x20, and bytes with high bit set
+    let control = (word - ONES * 0x20) & !word & HIGHS;
+    let high = word & HIGHS;
+    let delims = zero_bytes(word ^ (ONES * b'<' as u64))
+               | zero_bytes(word ^ (ONES * b'&' as u64))
+               | zero_bytes(word ^ (ONES * b'"' as u64))
+               | zero_bytes(word ^ (ONES * b'\'' as u64))
+               | zero_bytes(word ^ (ONES * b']' as u64))
+               | zero_bytes(word ^ (ONES * 0x7F));
+    (control | high | delims) != 0
+}
+
+/// Blocks of 16 bytes, which LLVM keeps in SSE2 registers
+#[cfg(target_arch = "x86_64")]
+mod block {
+    use std::mem::transmute;
+    use std::simd::u8x16;
+
+    /// Size of a block in bytes
+    pub static LEN: uint = 16;
+
+    #[inline]
+    fn splat(b: u8) -> u8x16 {
+        u8x16(b, b, b, b, b, b, b, b, b, b, b, b, b, b, b, b)
+    }
+
+    /// Sets high bit of each byte in `x` that is zero, and clears
+    /// the others. Bytes are subtracted separately, so unlike
+    /// `super::zero_bytes` no byte is marked wrongly.
+    #[inline]
+    fn zero_bytes(x: u8x16) -> u8x16 {
+        (x - splat(1)) & (x ^ splat(0xFF)) & splat(0x80)
+    }
+
+    /// Checks if any byte in aligned block at `ptr` is special
+    #[inline]
+    pub unsafe fn has_special(ptr: *const u8) -> bool {
+        let block = *(ptr as *const u8x16);
+        let control = (block - splat(0x20)) & (block ^ splat(0xFF)) & splat(0x80);
+        let high = block & splat(0x80);
+        let delims = zero_bytes(block ^ splat(b'<'))
+                   | zero_bytes(block ^ splat(b'&'))
+                   | zero_bytes(block ^ splat(b'"'))
+                   | zero_bytes(block ^ splat(b'\''))
+                   | zero_bytes(block ^ splat(b']'))
+                   | zero_bytes(block ^ splat(0x7F));
+        let found: [u64, ..2] = transmute(control | high | delims);
+        (found[0] | found[1]) != 0
+    }
+}
+
+/// Portable fallback, where a block is a word
+#[cfg(not(target_arch = "x86_64"))]
+mod block {
+    pub static LEN: uint = super::WORD;
+
+    #[inline]
+    pub unsafe fn has_special(ptr: *const u8) -> bool {
+        super::has_special(*(ptr as *const u64))
+    }
+}
+
+/// Returns index of first special byte in `buf`, or
+/// its length if there is none.
+pub fn find_special(buf: &[u8]) -> uint {
+    debug_assert!(size_of::<u64>() == WORD);
+    let len = buf.len();
+    let ptr = buf.as_ptr();
+    let mut i = 0u;
+
+    // Go byte by byte until blocks are aligned
+    while i < len && (ptr as uint + i) % block::LEN != 0 {
+        if is_special(buf[i]) {
+            return i
+        }
+        i += 1;
+    }
+
+    while i + block::LEN <= len {
+        if unsafe { block::has_special(ptr.offset(i as int)) } {
+            break
+        }
+        i += block::LEN;
+    }
+
+    // Rest is narrowed down a word, then a byte at a time
+    while i + WORD <= len {
+        let word = unsafe { *(ptr.offset(i as int) as *const u64) };
+        if has_special(word) {
+            break
+        }
+        i += WORD;
+    }
+
+    while i < len {
+        if is_special(buf[i]) {
+            return i
+        }
+        i += 1;
+    }
+    len
+}
+
+#[cfg(test)]
+mod test {
+    use super::{find_special, is_special_char};
+
+    #[test]
+    fn test_find_special() {
+        assert_eq!(0,   find_special(b"<abc"));
+        assert_eq!(3,   find_special(b"abc"));
+        assert_eq!(0,   find_special(b""));
+        for &delim in b"<&\"']\r\n\t\x00\x7F\x80\xFF".iter() {
+            let mut buf = Vec::from_elem(80, b'x');
+            for pos in range(1u, 80) {
+                buf.as_mut_slice()[pos] = delim;
+                assert_eq!(pos, find_special(buf.as_slice()));
+                assert_eq!(pos - 1, find_special(buf.slice_from(1)));
+                buf.as_mut_slice()[pos] = b'x';
+            }
+        }
+    }
+
+    #[test]
+    fn test_special_char() {
+        assert!(is_special_char('<'));
+        assert!(is_special_char('\r'));
+        assert!(is_special_char('\u0100'));
+        assert!(!is_special_char('a'));
+    }
+}
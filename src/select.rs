@@ -0,0 +1,720 @@
+//! CSS selectors over element trees.
+//!
+//! Supports type, universal, class, id and attribute selectors, the
+//! descendant, child and sibling combinators, `:not`, `:nth-child`,
+//! `:nth-last-child`, `:first-child` and `:last-child`. Type selectors
+//! and attribute names match qualified names as `XElem::is_named` and
+//! `XElem::attr` do, a colon in them has to be escaped, e.g. `svg\:rect`.
+//!
+//! E.g.
+//!
+//!     let links = try!(Selector::parse("div.nav > a[href^='http']"));
+//!     for link in root.select(&links).iter() {
+//!         ...
+//!     }
+
+use std::fmt;
+
+use common::{XElem, XNode, ElemNode};
+use util::is_whitespace;
+
+/// Error in parsing a selector
+#[deriving(PartialEq, Eq, Clone)]
+pub struct SelectError {
+    pub msg: String,
+    /// Char offset in selector
+    pub pos: uint
+}
+
+impl fmt::Show for SelectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {}", self.msg, self.pos)
+    }
+}
+
+/// A parsed group of selectors
+#[deriving(Clone, Show)]
+pub struct Selector {
+    /// Comma separated selectors, any of which has to match
+    complex: Vec<Complex>
+}
+
+/// Compound selectors joined by combinators. Rightmost compound,
+/// that the matched element is tested against, comes first.
+#[deriving(Clone, Show)]
+struct Complex {
+    parts: Vec<(Compound, Combinator)>
+}
+
+#[deriving(PartialEq, Eq, Clone, Show)]
+enum Combinator {
+    /// Whitespace
+    Descendant,
+    /// `>`
+    Child,
+    /// `+`
+    NextSibling,
+    /// `~`
+    SubsequentSibling,
+    /// Leftmost compound has nothing before it
+    NoCombinator
+}
+
+/// Simple selectors that all have to match the same element
+#[deriving(Clone, Show)]
+struct Compound {
+    /// Type selector, `None` for universal one
+    name: Option<String>,
+    simple: Vec<Simple>
+}
+
+#[deriving(Clone, Show)]
+enum Simple {
+    IdSel(String),
+    ClassSel(String),
+    AttrSel(String, AttrOp),
+    /// `an+b` counted from first or last child
+    NthSel(int, int, bool),
+    NotSel(Box<Compound>)
+}
+
+#[deriving(Clone, Show)]
+enum AttrOp {
+    /// `[attr]`
+    Exists,
+    /// `[attr=v]`
+    Equals(String),
+    /// `[attr~=v]`, whitespace separated word
+    Includes(String),
+    /// `[attr|=v]`, `v` or `v-` prefix
+    DashMatch(String),
+    /// `[attr^=v]`
+    Prefix(String),
+    /// `[attr$=v]`
+    Suffix(String),
+    /// `[attr*=v]`
+    Substring(String)
+}
+
+impl Selector {
+    pub fn parse(selector: &str) -> Result<Selector, SelectError> {
+        let mut parser = SelectorParser {
+            chars: selector.chars().collect(),
+            pos: 0
+        };
+        let mut complex = vec![try!(parser.parse_complex())];
+        while parser.consume(',') {
+            complex.push(try!(parser.parse_complex()));
+        }
+        match parser.peek() {
+            Some(c) => Err(parser.error(format!("unexpected {}", c))),
+            None    => Ok(Selector { complex: complex })
+        }
+    }
+}
+
+impl XElem {
+    /// Returns descendants matching `selector` in document order.
+    /// Element itself is taken for the root, so it can match parts of
+    /// the selector but isn't returned.
+    pub fn select<'a>(&'a self, selector: &Selector) -> Vec<&'a XElem> {
+        let mut found = Vec::new();
+        let mut path = vec![Entry { elem: self, index: 0 }];
+        walk(selector, &mut path, &mut found, false);
+        found
+    }
+
+    /// Returns first descendant matching `selector`
+    pub fn select_first<'a>(&'a self, selector: &Selector) -> Option<&'a XElem> {
+        let mut found = Vec::new();
+        let mut path = vec![Entry { elem: self, index: 0 }];
+        walk(selector, &mut path, &mut found, true);
+        found.into_iter().next()
+    }
+}
+
+/// Element on path from the root, along with its index in children
+/// of its parent
+struct Entry<'a> {
+    elem: &'a XElem,
+    index: uint
+}
+
+/// Element being matched. It's a sibling of the element at `depth`
+/// on the path, or that element itself, so its ancestors are the ones
+/// before `depth`.
+struct Cursor<'a> {
+    elem: &'a XElem,
+    depth: uint,
+    index: uint
+}
+
+/// Adds descendants of last element of `path` that match `selector`
+/// to `found`. Returns whether search is over.
+fn walk<'a>(selector: &Selector, path: &mut Vec<Entry<'a>>, found: &mut Vec<&'a XElem>,
+            first_only: bool) -> bool {
+    let parent = path.last().unwrap().elem;
+    for (index, child) in parent.children.iter().enumerate() {
+        let elem = match *child {
+            ElemNode(ref elem)  => elem,
+            _                   => continue
+        };
+        path.push(Entry { elem: elem, index: index });
+        let cursor = Cursor {
+            elem: elem,
+            depth: path.len() - 1,
+            index: index
+        };
+        if selector.complex.iter().any(|complex| match_parts(complex.parts.as_slice(),
+                                                             path.as_slice(), &cursor)) {
+            found.push(elem);
+            if first_only {
+                return true
+            }
+        }
+        if walk(selector, path, found, first_only) {
+            return true
+        }
+        path.pop();
+    }
+    false
+}
+
+/// Checks if `cursor` matches first of `parts` and elements around
+/// it match the rest
+fn match_parts(parts: &[(Compound, Combinator)], path: &[Entry], cursor: &Cursor) -> bool {
+    let (ref compound, ref combinator) = parts[0];
+    if !match_compound(compound, path, cursor) {
+        return false
+    }
+    let rest = parts.slice_from(1);
+    match *combinator {
+        NoCombinator        => true,
+        Descendant          => range(0, cursor.depth).rev().any(|depth| {
+            match_parts(rest, path, &at_depth(path, depth))
+        }),
+        Child               => cursor.depth > 0 && {
+            match_parts(rest, path, &at_depth(path, cursor.depth - 1))
+        },
+        NextSibling         => match prev_siblings(path, cursor).into_iter().next() {
+            Some(sibling)   => match_parts(rest, path, &sibling),
+            None            => false
+        },
+        SubsequentSibling   => prev_siblings(path, cursor).iter().any(|sibling| {
+            match_parts(rest, path, sibling)
+        })
+    }
+}
+
+fn at_depth<'a>(path: &[Entry<'a>], depth: uint) -> Cursor<'a> {
+    Cursor {
+        elem: path[depth].elem,
+        depth: depth,
+        index: path[depth].index
+    }
+}
+
+/// Returns siblings of the element that precede it, closest first
+fn prev_siblings<'a>(path: &[Entry<'a>], cursor: &Cursor) -> Vec<Cursor<'a>> {
+    if cursor.depth == 0 {
+        return Vec::new()
+    }
+    let parent = path[cursor.depth - 1].elem;
+    let mut siblings = Vec::new();
+    for index in range(0, cursor.index).rev() {
+        match parent.children[index] {
+            ElemNode(ref elem)  => siblings.push(Cursor {
+                elem: elem,
+                depth: cursor.depth,
+                index: index
+            }),
+            _                   => {}
+        }
+    }
+    siblings
+}
+
+fn match_compound(compound: &Compound, path: &[Entry], cursor: &Cursor) -> bool {
+    let elem = cursor.elem;
+    match compound.name {
+        Some(ref name) if !elem.is_named(name.as_slice()) => return false,
+        _ => {}
+    }
+    compound.simple.iter().all(|simple| match *simple {
+        IdSel(ref id)               => elem.attr("id") == Some(id.as_slice()),
+        ClassSel(ref class)         => elem.attr("class").map_or(false, |classes| {
+            has_word(classes, class.as_slice())
+        }),
+        AttrSel(ref name, ref op)   => match elem.attr(name.as_slice()) {
+            Some(value) => match_attr(op, value),
+            None        => false
+        },
+        NthSel(a, b, from_end)      => {
+            let (position, count) = child_position(path, cursor);
+            let position = if from_end { count + 1 - position } else { position };
+            match_nth(a, b, position as int)
+        },
+        NotSel(ref compound)        => !match_compound(&**compound, path, cursor)
+    })
+}
+
+fn match_attr(op: &AttrOp, value: &str) -> bool {
+    match *op {
+        Exists                  => true,
+        Equals(ref v)           => value == v.as_slice(),
+        Includes(ref v)         => has_word(value, v.as_slice()),
+        DashMatch(ref v)        => {
+            value == v.as_slice()
+            || (value.starts_with(v.as_slice()) && value.slice_from(v.len()).starts_with("-"))
+        },
+        // Empty value matches nothing
+        Prefix(ref v)           => !v.is_empty() && value.starts_with(v.as_slice()),
+        Suffix(ref v)           => !v.is_empty() && value.ends_with(v.as_slice()),
+        Substring(ref v)        => !v.is_empty() && value.contains(v.as_slice())
+    }
+}
+
+/// Checks if whitespace separated `list` contains `word`
+fn has_word(list: &str, word: &str) -> bool {
+    list.split(|c: char| is_whitespace(c) || c == '\r').any(|w| !w.is_empty() && w == word)
+}
+
+/// Checks if `position` is `a*n + b` for some `n >= 0`
+fn match_nth(a: int, b: int, position: int) -> bool {
+    if a == 0 {
+        position == b
+    } else {
+        let n = position - b;
+        n % a == 0 && n / a >= 0
+    }
+}
+
+/// Returns position of element among element children of its parent,
+/// and their count. Root is the only child.
+fn child_position(path: &[Entry], cursor: &Cursor) -> (uint, uint) {
+    if cursor.depth == 0 {
+        return (1, 1)
+    }
+    let children = path[cursor.depth - 1].elem.children.as_slice();
+    fn is_elem(node: &&XNode) -> bool {
+        match **node {
+            ElemNode(_) => true,
+            _           => false
+        }
+    }
+    let position = children.slice_to(cursor.index).iter().filter(|node| is_elem(node)).count();
+    let count = children.iter().filter(|node| is_elem(node)).count();
+    (position + 1, count)
+}
+
+struct SelectorParser {
+    chars: Vec<char>,
+    pos: uint
+}
+
+impl SelectorParser {
+    fn peek(&self) -> Option<char> {
+        self.chars.as_slice().get(self.pos).map(|&c| c)
+    }
+
+    fn consume(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), SelectError> {
+        if self.consume(c) {
+            Ok(())
+        } else {
+            Err(self.error(format!("expected {}", c)))
+        }
+    }
+
+    fn error(&self, msg: String) -> SelectError {
+        SelectError {
+            msg: msg,
+            pos: self.pos
+        }
+    }
+
+    /// Skips whitespace, returning whether there was any
+    fn skip_whitespace(&mut self) -> bool {
+        let start = self.pos;
+        while self.peek().map_or(false, |c| is_whitespace(c) || c == '\r') {
+            self.pos += 1;
+        }
+        self.pos > start
+    }
+
+    fn parse_complex(&mut self) -> Result<Complex, SelectError> {
+        self.skip_whitespace();
+        // Compounds with combinators that follow them, left to right
+        let mut parts = Vec::new();
+        loop {
+            let compound = try!(self.parse_compound());
+            let had_whitespace = self.skip_whitespace();
+            let combinator = match self.peek() {
+                Some('>')   => Child,
+                Some('+')   => NextSibling,
+                Some('~')   => SubsequentSibling,
+                Some(',') | None => {
+                    parts.push((compound, NoCombinator));
+                    break
+                },
+                Some(_) if had_whitespace => Descendant,
+                Some(c)     => return Err(self.error(format!("unexpected {}", c)))
+            };
+            if combinator != Descendant {
+                self.pos += 1;
+                self.skip_whitespace();
+            }
+            parts.push((compound, combinator));
+        }
+
+        // Each compound is linked to the one on its left
+        let mut combinators: Vec<Combinator> = parts.iter().map(|&(_, ref c)| c.clone()).collect();
+        combinators.pop();
+        combinators.insert(0, NoCombinator);
+        let parts = parts.into_iter().map(|(compound, _)| compound)
+                         .zip(combinators.into_iter())
+                         .collect::<Vec<(Compound, Combinator)>>()
+                         .into_iter().rev().collect();
+        Ok(Complex { parts: parts })
+    }
+
+    fn parse_compound(&mut self) -> Result<Compound, SelectError> {
+        let start = self.pos;
+        let name = if self.consume('*') {
+            None
+        } else if self.is_ident_start() {
+            Some(try!(self.parse_ident()))
+        } else {
+            None
+        };
+
+        let mut simple = Vec::new();
+        loop {
+            let sel = match self.peek() {
+                Some('#')   => {
+                    self.pos += 1;
+                    IdSel(try!(self.parse_ident()))
+                },
+                Some('.')   => {
+                    self.pos += 1;
+                    ClassSel(try!(self.parse_ident()))
+                },
+                Some('[')   => {
+                    self.pos += 1;
+                    try!(self.parse_attr())
+                },
+                Some(':')   => {
+                    self.pos += 1;
+                    try!(self.parse_pseudo())
+                },
+                _           => break
+            };
+            simple.push(sel);
+        }
+        if self.pos == start {
+            return Err(self.error("expected selector".to_string()))
+        }
+        Ok(Compound {
+            name: name,
+            simple: simple
+        })
+    }
+
+    fn parse_attr(&mut self) -> Result<Simple, SelectError> {
+        self.skip_whitespace();
+        let name = try!(self.parse_ident());
+        self.skip_whitespace();
+        if self.consume(']') {
+            return Ok(AttrSel(name, Exists))
+        }
+        let op = match self.peek() {
+            Some('=')   => "=",
+            Some('~')   => "~=",
+            Some('|')   => "|=",
+            Some('^')   => "^=",
+            Some('$')   => "$=",
+            Some('*')   => "*=",
+            _           => return Err(self.error("expected attribute operator".to_string()))
+        };
+        self.pos += 1;
+        if op.len() == 2 {
+            try!(self.expect('='));
+        }
+        self.skip_whitespace();
+        let value = match self.peek() {
+            Some(quote @ '"') | Some(quote @ '\'') => {
+                self.pos += 1;
+                let start = self.pos;
+                while self.peek().map_or(false, |c| c != quote) {
+                    self.pos += 1;
+                }
+                let value = String::from_chars(self.chars.slice(start, self.pos));
+                try!(self.expect(quote));
+                value
+            },
+            _ => try!(self.parse_ident())
+        };
+        self.skip_whitespace();
+        try!(self.expect(']'));
+        let op = match op {
+            "="     => Equals(value),
+            "~="    => Includes(value),
+            "|="    => DashMatch(value),
+            "^="    => Prefix(value),
+            "$="    => Suffix(value),
+            _       => Substring(value)
+        };
+        Ok(AttrSel(name, op))
+    }
+
+    fn parse_pseudo(&mut self) -> Result<Simple, SelectError> {
+        let start = self.pos;
+        let name = try!(self.parse_ident());
+        let sel = match name.as_slice() {
+            "first-child"   => NthSel(0, 1, false),
+            "last-child"    => NthSel(0, 1, true),
+            "nth-child" | "nth-last-child" => {
+                try!(self.expect('('));
+                let arg_start = self.pos;
+                while self.peek().map_or(false, |c| c != ')') {
+                    self.pos += 1;
+                }
+                let arg = String::from_chars(self.chars.slice(arg_start, self.pos));
+                let (a, b) = match parse_nth(arg.as_slice()) {
+                    Some(nth)   => nth,
+                    None        => {
+                        self.pos = arg_start;
+                        return Err(self.error(format!("invalid argument {}", arg)))
+                    }
+                };
+                try!(self.expect(')'));
+                NthSel(a, b, name.as_slice() == "nth-last-child")
+            },
+            "not"           => {
+                try!(self.expect('('));
+                self.skip_whitespace();
+                let compound = try!(self.parse_compound());
+                self.skip_whitespace();
+                try!(self.expect(')'));
+                NotSel(box compound)
+            },
+            _ => {
+                self.pos = start;
+                return Err(self.error(format!("unsupported pseudo-class {}", name)))
+            }
+        };
+        Ok(sel)
+    }
+
+    fn is_ident_start(&self) -> bool {
+        match self.peek() {
+            Some(c) => is_ident_char(c) && !(c >= '0' && c <= '9') || c == '\\',
+            None    => false
+        }
+    }
+
+    /// Parses identifier, where any char can be escaped by a backslash
+    fn parse_ident(&mut self) -> Result<String, SelectError> {
+        if !self.is_ident_start() {
+            return Err(self.error("expected identifier".to_string()))
+        }
+        let mut ident = String::new();
+        loop {
+            match self.peek() {
+                Some('\\') => {
+                    self.pos += 1;
+                    match self.peek() {
+                        Some(c) => ident.push(c),
+                        None    => return Err(self.error("expected escaped char".to_string()))
+                    }
+                },
+                Some(c) if is_ident_char(c) => ident.push(c),
+                _ => return Ok(ident)
+            }
+            self.pos += 1;
+        }
+    }
+}
+
+fn is_ident_char(c: char) -> bool {
+    match c {
+        'a'...'z' | 'A'...'Z' | '0'...'9' | '-' | '_' => true,
+        _ => c >= '\x80'
+    }
+}
+
+/// Parses `an+b` argument of `:nth-child`, along with `odd` and `even`
+fn parse_nth(arg: &str) -> Option<(int, int)> {
+    let arg: String = arg.chars().filter(|&c| !is_whitespace(c) && c != '\r').collect();
+    match arg.as_slice() {
+        "odd"   => return Some((2, 1)),
+        "even"  => return Some((2, 0)),
+        _       => {}
+    }
+    let parse_int = |s: &str| {
+        let digits = if s.starts_with("+") { s.slice_from(1) } else { s };
+        from_str::<int>(digits)
+    };
+    match arg.as_slice().find('n') {
+        Some(i) => {
+            let a = match arg.as_slice().slice_to(i) {
+                "" | "+"    => Some(1),
+                "-"         => Some(-1),
+                a           => parse_int(a)
+            };
+            let b = match arg.as_slice().slice_from(i + 1) {
+                ""          => Some(0),
+                b if b.starts_with("+") || b.starts_with("-") => parse_int(b),
+                _           => None
+            };
+            match (a, b) {
+                (Some(a), Some(b))  => Some((a, b)),
+                _                   => None
+            }
+        },
+        None => parse_int(arg.as_slice()).map(|b| (0, b))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Selector, match_nth, parse_nth};
+    use common::{XElem, XmlNS, XmlAttr, XNode, ElemNode, TextNode, CommentNode};
+    use name::Name;
+
+    fn elem(name: &str, attrs: &[(&str, &str)], children: Vec<XNode>) -> XNode {
+        let no_ns = XmlNS { name: Name::new(""), uri: Name::new("") };
+        ElemNode(XElem {
+            name: Name::new(name),
+            namespace: no_ns.clone(),
+            attributes: attrs.iter().map(|&(name, value)| XmlAttr {
+                name: Name::new(name),
+                value: value.to_string(),
+                namespace: no_ns.clone()
+            }).collect(),
+            children: children
+        })
+    }
+
+    /// `<html><body><div id="nav" class="nav top"><a href="http://x">1</a>
+    /// <!-- c --><a href="/y" lang="en-US">2</a><span/><a>3</a></div>
+    /// <p class="note">4</p><p>5</p></body></html>`
+    fn make_tree() -> XElem {
+        let tree = elem("html", [], vec![elem("body", [], vec![
+            elem("div", [("id", "nav"), ("class", "nav top")], vec![
+                elem("a", [("href", "http://x")], vec![TextNode("1".to_string())]),
+                CommentNode(" c ".to_string()),
+                elem("a", [("href", "/y"), ("lang", "en-US")], vec![TextNode("2".to_string())]),
+                elem("span", [], Vec::new()),
+                elem("a", [], vec![TextNode("3".to_string())])
+            ]),
+            elem("p", [("class", "note")], vec![TextNode("4".to_string())]),
+            elem("p", [], vec![TextNode("5".to_string())])
+        ])]);
+        match tree {
+            ElemNode(elem)  => elem,
+            _               => unreachable!()
+        }
+    }
+
+    /// Returns text of selected elements, or name of empty ones
+    fn select(root: &XElem, selector: &str) -> String {
+        let selector = Selector::parse(selector).unwrap();
+        let found: Vec<String> = root.select(&selector).iter().map(|elem| {
+            if elem.children.is_empty() { elem.name.as_slice().to_string() } else { elem.text() }
+        }).collect();
+        found.connect(" ")
+    }
+
+    #[test]
+    fn test_simple() {
+        let root = make_tree();
+        assert_eq!("123",                  select(&root, "div").as_slice());
+        assert_eq!("1 2 3",                select(&root, "a").as_slice());
+        assert_eq!("123",                  select(&root, "#nav.top").as_slice());
+        assert_eq!("4",                    select(&root, "p.note").as_slice());
+        assert_eq!("",                     select(&root, ".no").as_slice());
+        assert_eq!(8,                      select(&root, "*").as_slice().split(' ').count());
+        assert_eq!("1 2",                  select(&root, "a[href]").as_slice());
+        assert_eq!("1",                    select(&root, "[href^='http']").as_slice());
+        assert_eq!("2",                    select(&root, "a[href$=y]").as_slice());
+        assert_eq!("1",                    select(&root, "a[href*=\"//\"]").as_slice());
+        assert_eq!("2",                    select(&root, "a[lang|=en]").as_slice());
+        assert_eq!("123",                  select(&root, "[class~=nav]").as_slice());
+        assert_eq!("5",                    select(&root, "p[class=''], p:not([class])").as_slice());
+        assert_eq!("1 2 3 4 5",            select(&root, "a, p").as_slice());
+    }
+
+    #[test]
+    fn test_combinators() {
+        let root = make_tree();
+        assert_eq!("1 2 3",                select(&root, "body a").as_slice());
+        assert_eq!("1 2 3",                select(&root, "html  div>a").as_slice());
+        assert_eq!("",                     select(&root, "body > a").as_slice());
+        assert_eq!("2",                    select(&root, "a + a").as_slice());
+        assert_eq!("3",                    select(&root, "span+a").as_slice());
+        assert_eq!("span 3",               select(&root, "a[lang] ~ *").as_slice());
+        assert_eq!("5",                    select(&root, "div ~ p ~ p").as_slice());
+        assert_eq!("4 5",                  select(&root, "html div ~ p").as_slice());
+    }
+
+    #[test]
+    fn test_pseudo() {
+        let root = make_tree();
+        assert_eq!("12345 123 1",          select(&root, ":first-child").as_slice());
+        assert_eq!("12345 3 5",            select(&root, ":last-child").as_slice());
+        assert_eq!("1 span",               select(&root, "div > :nth-child(odd)").as_slice());
+        assert_eq!("2 3",                  select(&root, "div > :nth-child(2n)").as_slice());
+        assert_eq!("2",                    select(&root, "a:nth-child(2)").as_slice());
+        assert_eq!("1 2",                  select(&root, "div :nth-child(-n + 2)").as_slice());
+        assert_eq!("span 3",               select(&root, "div :nth-last-child(-n+2)").as_slice());
+        assert_eq!("span",                 select(&root, "div > :not(a)").as_slice());
+        assert_eq!("2 3",                  select(&root, "a:not([href^=http])").as_slice());
+    }
+
+    #[test]
+    fn test_select_first() {
+        let root = make_tree();
+        let selector = Selector::parse("p").unwrap();
+        assert_eq!("4".to_string(), root.select_first(&selector).unwrap().text());
+        let selector = Selector::parse("html").unwrap();
+        assert!(root.select_first(&selector).is_none());
+        // Root matches parts of selector
+        let selector = Selector::parse("html > body").unwrap();
+        assert!(root.select_first(&selector).is_some());
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(0, Selector::parse("").unwrap_err().pos);
+        assert_eq!(3, Selector::parse("a >").unwrap_err().pos);
+        assert_eq!(2, Selector::parse("a,,b").unwrap_err().pos);
+        assert_eq!(6, Selector::parse("a[href").unwrap_err().pos);
+        assert_eq!(2, Selector::parse("a:hover").unwrap_err().pos);
+        assert_eq!(12, Selector::parse("a:nth-child(x)").unwrap_err().pos);
+        assert_eq!(1, Selector::parse("a!").unwrap_err().pos);
+    }
+
+    #[test]
+    fn test_nth() {
+        assert_eq!(Some((2, 1)),    parse_nth("odd"));
+        assert_eq!(Some((0, 3)),    parse_nth(" 3 "));
+        assert_eq!(Some((-1, 3)),   parse_nth("-n + 3"));
+        assert_eq!(Some((1, 0)),    parse_nth("n"));
+        assert_eq!(Some((3, -2)),   parse_nth("3n-2"));
+        assert_eq!(None,            parse_nth("n3"));
+        assert!(match_nth(2, 1, 5));
+        assert!(!match_nth(2, 1, 4));
+        assert!(match_nth(-1, 3, 3));
+        assert!(!match_nth(-1, 3, 4));
+        assert!(match_nth(0, 2, 2));
+    }
+}
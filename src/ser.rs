@@ -0,0 +1,600 @@
+//! Encoding of types implementing `Encodable` as XML.
+//!
+//! Values are encoded into an element tree, mapped the same way `de`
+//! reads them, so encoded values can be decoded back:
+//!
+//!     let mut encoder = XmlEncoder::new("feed");
+//!     encoder.bind_namespace("xlink", "http://www.w3.org/1999/xlink");
+//!     try!(feed.encode(&mut encoder));
+//!     let elem = try!(encoder.into_elem());
+//!
+//! Structs are mapped to elements as follows:
+//!
+//! * Fields of the encoded value are written in the root element, which
+//!   is named as given to `XmlEncoder::new`, or after the type of the
+//!   value if that's empty.
+//! * Field `name` holding text, a number or a unit variant is written
+//!   as attribute `name`, or as child element `name` when attribute
+//!   fields are turned off. Other fields are written as child elements.
+//!   Trailing `_` is dropped from field names.
+//! * Field `p__name` is written in namespace bound to `p` by
+//!   `XmlEncoder::bind_namespace`. Bound namespaces are declared in the
+//!   root element.
+//! * Field `_text` is written as text of the element, or as a CDATA
+//!   section when CDATA text is turned on.
+//! * `None` fields are left out. Items of sequences are written as child
+//!   elements named as the field, or `item` in the root.
+//! * Variants with fields are written as a child element named as the
+//!   variant, with the variant fields in it.
+//! * Maps are written as elements with an attribute or child element for
+//!   each entry, named by its key.
+//!
+//! Text is checked to be allowed in XML 1.0 and field names to be valid
+//! XML names, so encoded values are always well-formed.
+//!
+//! This is a libserialize `Encoder`, not a serde `Serializer`, as serde
+//! isn't a dependency of this crate.
+//!
+//! Only built with `serde` feature.
+
+use std::collections::HashMap;
+use serialize;
+use serialize::Encodable;
+
+use common::{XElem, XmlNS, XmlAttr, ElemNode, TextNode, CDataNode};
+use name::Name;
+use util::{is_name_start_char, is_name_char, is_xml10_char, split_name};
+use writer::elem_to_string;
+
+/// Error in encoding a value
+#[deriving(Clone, PartialEq, Show)]
+pub enum EncoderError {
+    /// Prefix of a name that isn't bound to a namespace
+    UnboundPrefixError(String),
+    /// Root name, field name or map key that isn't a valid XML name
+    InvalidNameError(String),
+    /// Char of text that isn't allowed in XML 1.0
+    InvalidCharError(char),
+    ApplicationError(String)
+}
+
+pub type EncodeResult = Result<(), EncoderError>;
+
+/// Name a value is written under
+#[deriving(Clone)]
+struct Target {
+    prefix: String,
+    local: String
+}
+
+impl Target {
+    /// Target of field `name`, which must map to a valid XML name
+    fn field(name: &str) -> Result<Target, EncoderError> {
+        let (prefix, local) = match name.find_str("__") {
+            Some(i) => (name.slice_to(i), name.slice_from(i + 2)),
+            None    => ("", name)
+        };
+        let target = Target {
+            prefix: prefix.to_string(),
+            local: local.trim_right_chars('_').to_string()
+        };
+        let valid = (prefix.is_empty() || is_name(prefix)) && is_name(target.local.as_slice());
+        if valid || target.is_text() {
+            Ok(target)
+        } else {
+            Err(InvalidNameError(name.to_string()))
+        }
+    }
+
+    fn is_text(&self) -> bool {
+        self.prefix.is_empty() && self.local.as_slice() == "_text"
+    }
+}
+
+/// Encoder of values into an element
+pub struct XmlEncoder {
+    /// Elements being written, starting with the root
+    stack: Vec<XElem>,
+    /// Name of next value, or `None` if it's written as content of
+    /// element on top of stack
+    target: Option<Target>,
+    /// Whether next value can be written as an attribute
+    as_attr: bool,
+    /// Names of items of sequences being written
+    items: Vec<Target>,
+    /// Key of map entry, while it's being written
+    key: Option<String>,
+    in_key: bool,
+    root: String,
+    namespaces: HashMap<String, String>,
+    attr_fields: bool,
+    cdata_text: bool
+}
+
+impl XmlEncoder {
+    /// Constructs encoder of a value into element named `root`, which
+    /// can have a prefix bound by `bind_namespace`
+    pub fn new(root: &str) -> XmlEncoder {
+        let no_ns = XmlNS {
+            name: Name::new(""),
+            uri: Name::new("")
+        };
+        XmlEncoder {
+            stack: vec![XElem::new(no_ns, "")],
+            target: None,
+            as_attr: false,
+            items: Vec::new(),
+            key: None,
+            in_key: false,
+            root: root.to_string(),
+            namespaces: HashMap::new(),
+            attr_fields: true,
+            cdata_text: false
+        }
+    }
+
+    /// Binds `prefix` of field names to namespace `uri`. Empty prefix
+    /// binds the default namespace of elements.
+    pub fn bind_namespace(&mut self, prefix: &str, uri: &str) {
+        self.namespaces.insert(prefix.to_string(), uri.to_string());
+    }
+
+    /// Sets whether fields with text values are written as attributes,
+    /// which is the default, or as child elements
+    pub fn set_attr_fields(&mut self, attr_fields: bool) {
+        self.attr_fields = attr_fields;
+    }
+
+    /// Sets whether text is written as CDATA sections rather than
+    /// escaped, which is the default
+    pub fn set_cdata_text(&mut self, cdata_text: bool) {
+        self.cdata_text = cdata_text;
+    }
+
+    /// Returns root element with the encoded value
+    pub fn into_elem(mut self) -> Result<XElem, EncoderError> {
+        let mut elem = self.stack.pop().unwrap();
+        let (prefix, local) = split_name(self.root.as_slice());
+        if !is_name(local) {
+            return Err(InvalidNameError(self.root.clone()))
+        }
+        elem.name = Name::new(local);
+        elem.namespace = try!(self.namespace(prefix, false));
+
+        let mut bound: Vec<(&String, &String)> = self.namespaces.iter().collect();
+        bound.sort();
+        for (i, &(prefix, uri)) in bound.iter().enumerate() {
+            let ns = XmlNS {
+                name: Name::new(prefix.as_slice()),
+                uri: Name::new(uri.as_slice())
+            };
+            elem.attributes.insert(i, XmlAttr::declaration(&ns));
+        }
+        elem.declare_namespaces();
+        Ok(elem)
+    }
+
+    /// Returns namespace bound to `prefix`. Unprefixed attributes are
+    /// in no namespace.
+    fn namespace(&self, prefix: &str, is_attr: bool) -> Result<XmlNS, EncoderError> {
+        let uri = match self.namespaces.find_equiv(&prefix) {
+            Some(uri) if !is_attr || !prefix.is_empty() => uri.as_slice(),
+            _ if prefix.is_empty()  => "",
+            _                       => return Err(UnboundPrefixError(prefix.to_string()))
+        };
+        Ok(XmlNS {
+            name: Name::new(prefix),
+            uri: Name::new(uri)
+        })
+    }
+
+    fn top(&mut self) -> &mut XElem {
+        self.stack.last_mut().unwrap()
+    }
+
+    fn new_elem(&self, target: &Target) -> Result<XElem, EncoderError> {
+        let ns = try!(self.namespace(target.prefix.as_slice(), false));
+        Ok(XElem::new(ns, target.local.as_slice()))
+    }
+
+    fn append_text(&mut self, text: String) {
+        if text.is_empty() {
+            return
+        }
+        let node = if self.cdata_text { CDataNode(text) } else { TextNode(text) };
+        self.top().children.push(node);
+    }
+
+    /// Writes text value under the current target
+    fn write_text(&mut self, text: String) -> EncodeResult {
+        match text.as_slice().chars().find(|&c| !is_xml10_char(c)) {
+            Some(c) => return Err(InvalidCharError(c)),
+            None    => {}
+        }
+        if self.in_key {
+            self.key = Some(text);
+            return Ok(())
+        }
+        match self.target.take() {
+            Some(ref target) if !target.is_text() => {
+                if self.as_attr {
+                    let ns = try!(self.namespace(target.prefix.as_slice(), true));
+                    let elem = self.top();
+                    if ns.uri.as_slice().is_empty() {
+                        elem.set_attr(target.local.as_slice(), text.as_slice());
+                    } else {
+                        // Declared in root, rather than in each element
+                        elem.attributes.push(XmlAttr {
+                            name: Name::new(target.local.as_slice()),
+                            value: text,
+                            namespace: ns
+                        });
+                    }
+                } else {
+                    let elem = try!(self.new_elem(target));
+                    self.stack.push(elem);
+                    self.append_text(text);
+                    self.close(true);
+                }
+            },
+            _ => self.append_text(text)
+        }
+        Ok(())
+    }
+
+    /// Starts element of a struct, map or variant written under the
+    /// current target. Without target, its content is written in element
+    /// on top of stack. Returns whether an element was started.
+    fn open(&mut self) -> Result<bool, EncoderError> {
+        if self.in_key {
+            return Err(ApplicationError("map keys must be text".to_string()))
+        }
+        match self.target.take() {
+            Some(target)    => {
+                let elem = try!(self.new_elem(&target));
+                self.stack.push(elem);
+                Ok(true)
+            },
+            None            => Ok(false)
+        }
+    }
+
+    fn close(&mut self, opened: bool) {
+        if opened {
+            let elem = self.stack.pop().unwrap();
+            self.top().children.push(ElemNode(elem));
+        }
+    }
+
+    /// Names root after type `name` of the encoded value, if it has
+    /// no name given
+    fn name_root(&mut self, name: &str) {
+        if self.root.is_empty() && self.stack.len() == 1 && self.target.is_none() {
+            self.root = name.to_string();
+        }
+    }
+}
+
+fn is_name(name: &str) -> bool {
+    match name.chars().next() {
+        Some(c) if c != ':' && is_name_start_char(c)    => {},
+        _                                               => return false
+    }
+    name.chars().all(|c| c != ':' && is_name_char(c))
+}
+
+/// Encodes `value` as XML, in root element named `root`
+pub fn encode<T: Encodable<XmlEncoder, EncoderError>>(value: &T, root: &str)
+                                                     -> Result<String, EncoderError> {
+    let mut encoder = XmlEncoder::new(root);
+    try!(value.encode(&mut encoder));
+    Ok(elem_to_string(&try!(encoder.into_elem())))
+}
+
+impl serialize::Encoder<EncoderError> for XmlEncoder {
+    fn emit_nil(&mut self) -> EncodeResult {
+        self.target = None;
+        Ok(())
+    }
+
+    fn emit_uint(&mut self, v: uint) -> EncodeResult { self.write_text(v.to_string()) }
+    fn emit_u64(&mut self, v: u64) -> EncodeResult { self.write_text(v.to_string()) }
+    fn emit_u32(&mut self, v: u32) -> EncodeResult { self.write_text(v.to_string()) }
+    fn emit_u16(&mut self, v: u16) -> EncodeResult { self.write_text(v.to_string()) }
+    fn emit_u8(&mut self, v: u8) -> EncodeResult { self.write_text(v.to_string()) }
+    fn emit_int(&mut self, v: int) -> EncodeResult { self.write_text(v.to_string()) }
+    fn emit_i64(&mut self, v: i64) -> EncodeResult { self.write_text(v.to_string()) }
+    fn emit_i32(&mut self, v: i32) -> EncodeResult { self.write_text(v.to_string()) }
+    fn emit_i16(&mut self, v: i16) -> EncodeResult { self.write_text(v.to_string()) }
+    fn emit_i8(&mut self, v: i8) -> EncodeResult { self.write_text(v.to_string()) }
+    fn emit_bool(&mut self, v: bool) -> EncodeResult { self.write_text(v.to_string()) }
+    fn emit_f64(&mut self, v: f64) -> EncodeResult { self.write_text(v.to_string()) }
+    fn emit_f32(&mut self, v: f32) -> EncodeResult { self.write_text(v.to_string()) }
+    fn emit_char(&mut self, v: char) -> EncodeResult { self.write_text(v.to_string()) }
+    fn emit_str(&mut self, v: &str) -> EncodeResult { self.write_text(v.to_string()) }
+
+    fn emit_enum(&mut self, name: &str, f: |&mut XmlEncoder| -> EncodeResult) -> EncodeResult {
+        self.name_root(name);
+        f(self)
+    }
+
+    fn emit_enum_variant(&mut self, v_name: &str, _v_id: uint, len: uint,
+                         f: |&mut XmlEncoder| -> EncodeResult) -> EncodeResult {
+        if len == 0 {
+            return self.write_text(v_name.to_string())
+        }
+        let opened = try!(self.open());
+        let ns = try!(self.namespace("", false));
+        self.stack.push(XElem::new(ns, v_name));
+        try!(f(self));
+        self.close(true);
+        self.close(opened);
+        Ok(())
+    }
+
+    fn emit_enum_variant_arg(&mut self, a_idx: uint, f: |&mut XmlEncoder| -> EncodeResult)
+                            -> EncodeResult {
+        if a_idx > 0 {
+            return Err(ApplicationError("variants with several fields are not supported"
+                                        .to_string()))
+        }
+        self.target = None;
+        f(self)
+    }
+
+    fn emit_enum_struct_variant(&mut self, v_name: &str, v_id: uint, len: uint,
+                                f: |&mut XmlEncoder| -> EncodeResult) -> EncodeResult {
+        self.emit_enum_variant(v_name, v_id, len, f)
+    }
+
+    fn emit_enum_struct_variant_field(&mut self, f_name: &str, f_idx: uint,
+                                      f: |&mut XmlEncoder| -> EncodeResult) -> EncodeResult {
+        self.emit_struct_field(f_name, f_idx, f)
+    }
+
+    fn emit_struct(&mut self, name: &str, _len: uint,
+                   f: |&mut XmlEncoder| -> EncodeResult) -> EncodeResult {
+        self.name_root(name);
+        let opened = try!(self.open());
+        try!(f(self));
+        self.close(opened);
+        Ok(())
+    }
+
+    fn emit_struct_field(&mut self, f_name: &str, _f_idx: uint,
+                         f: |&mut XmlEncoder| -> EncodeResult) -> EncodeResult {
+        self.target = Some(try!(Target::field(f_name)));
+        self.as_attr = self.attr_fields;
+        f(self)
+    }
+
+    fn emit_tuple(&mut self, len: uint, f: |&mut XmlEncoder| -> EncodeResult) -> EncodeResult {
+        self.emit_seq(len, f)
+    }
+
+    fn emit_tuple_arg(&mut self, idx: uint, f: |&mut XmlEncoder| -> EncodeResult)
+                     -> EncodeResult {
+        self.emit_seq_elt(idx, f)
+    }
+
+    fn emit_tuple_struct(&mut self, _name: &str, len: uint,
+                         f: |&mut XmlEncoder| -> EncodeResult) -> EncodeResult {
+        self.emit_seq(len, f)
+    }
+
+    fn emit_tuple_struct_arg(&mut self, f_idx: uint, f: |&mut XmlEncoder| -> EncodeResult)
+                            -> EncodeResult {
+        self.emit_seq_elt(f_idx, f)
+    }
+
+    fn emit_option(&mut self, f: |&mut XmlEncoder| -> EncodeResult) -> EncodeResult {
+        f(self)
+    }
+
+    fn emit_option_none(&mut self) -> EncodeResult {
+        self.target = None;
+        Ok(())
+    }
+
+    fn emit_option_some(&mut self, f: |&mut XmlEncoder| -> EncodeResult) -> EncodeResult {
+        f(self)
+    }
+
+    fn emit_seq(&mut self, _len: uint, f: |&mut XmlEncoder| -> EncodeResult) -> EncodeResult {
+        if self.in_key {
+            return Err(ApplicationError("map keys must be text".to_string()))
+        }
+        let item = match self.target.take() {
+            Some(target)    => target,
+            None            => try!(Target::field("item"))
+        };
+        self.items.push(item);
+        let res = f(self);
+        self.items.pop();
+        res
+    }
+
+    fn emit_seq_elt(&mut self, _idx: uint, f: |&mut XmlEncoder| -> EncodeResult)
+                   -> EncodeResult {
+        self.target = self.items.last().map(|item| item.clone());
+        self.as_attr = false;
+        f(self)
+    }
+
+    fn emit_map(&mut self, _len: uint, f: |&mut XmlEncoder| -> EncodeResult) -> EncodeResult {
+        let opened = try!(self.open());
+        try!(f(self));
+        self.close(opened);
+        Ok(())
+    }
+
+    fn emit_map_elt_key(&mut self, _idx: uint, f: |&mut XmlEncoder| -> EncodeResult)
+                       -> EncodeResult {
+        self.in_key = true;
+        let res = f(self);
+        self.in_key = false;
+        try!(res);
+        match self.key.take() {
+            Some(ref key) if is_name(key.as_slice())    => {
+                self.target = Some(Target {
+                    prefix: String::new(),
+                    local: key.clone()
+                });
+                Ok(())
+            },
+            Some(key)   => Err(InvalidNameError(key)),
+            None        => Err(ApplicationError("map keys must be text".to_string()))
+        }
+    }
+
+    fn emit_map_elt_val(&mut self, _idx: uint, f: |&mut XmlEncoder| -> EncodeResult)
+                       -> EncodeResult {
+        self.as_attr = self.attr_fields;
+        f(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{XmlEncoder, encode, UnboundPrefixError, InvalidNameError, InvalidCharError};
+    use std::collections::HashMap;
+    use serialize::Encodable;
+    use de::decode;
+    use writer::elem_to_string;
+
+    #[deriving(Encodable, Decodable, PartialEq, Show)]
+    enum Kind {
+        Book,
+        Article
+    }
+
+    #[deriving(Encodable, Decodable, PartialEq, Show)]
+    struct Link {
+        rel: String
+    }
+
+    #[deriving(Encodable, Decodable, PartialEq, Show)]
+    struct Entry {
+        id: uint,
+        type_: Kind,
+        tags: Vec<String>,
+        link: Link,
+        _text: String,
+        xlink__href: Option<String>
+    }
+
+    #[deriving(Encodable, Decodable, PartialEq, Show)]
+    struct Feed {
+        title: String,
+        entry: Vec<Entry>,
+        updated: Option<String>
+    }
+
+    fn feed() -> Feed {
+        Feed {
+            title: "News & more".to_string(),
+            entry: vec![
+                Entry {
+                    id: 1,
+                    type_: Book,
+                    tags: vec!["a".to_string(), "b".to_string()],
+                    link: Link { rel: "self".to_string() },
+                    _text: "one".to_string(),
+                    xlink__href: Some("#1".to_string())
+                },
+                Entry {
+                    id: 2,
+                    type_: Article,
+                    tags: Vec::new(),
+                    link: Link { rel: "alt".to_string() },
+                    _text: "a < b".to_string(),
+                    xlink__href: None
+                }
+            ],
+            updated: None
+        }
+    }
+
+    #[test]
+    fn test_encode_struct() {
+        let mut encoder = XmlEncoder::new("feed");
+        encoder.bind_namespace("xlink", "http://www.w3.org/1999/xlink");
+        feed().encode(&mut encoder).unwrap();
+        let output = elem_to_string(&encoder.into_elem().unwrap());
+        assert_eq!("<feed xmlns:xlink=\"http://www.w3.org/1999/xlink\" title=\"News &amp; more\">\
+                    <entry id=\"1\" type=\"Book\" xlink:href=\"#1\"><tags>a</tags><tags>b</tags>\
+                    <link rel=\"self\"/>one</entry>\
+                    <entry id=\"2\" type=\"Article\"><link rel=\"alt\"/>a &lt; b</entry>\
+                    </feed>", output.as_slice());
+        assert_eq!(feed(), decode(output.as_slice()).unwrap());
+    }
+
+    #[test]
+    fn test_encode_options() {
+        let link = Link { rel: "a]]>b".to_string() };
+        let mut encoder = XmlEncoder::new("");
+        encoder.bind_namespace("", "urn:d");
+        encoder.set_attr_fields(false);
+        encoder.set_cdata_text(true);
+        link.encode(&mut encoder).unwrap();
+        assert_eq!("<Link xmlns=\"urn:d\"><rel><![CDATA[a]]]]><![CDATA[>b]]></rel></Link>",
+                   elem_to_string(&encoder.into_elem().unwrap()).as_slice());
+
+        assert_eq!("<item>1</item>", encode(&1u, "item").unwrap().as_slice());
+        assert_eq!("<list><item>1</item><item>2</item></list>",
+                   encode(&vec![1u, 2], "list").unwrap().as_slice());
+    }
+
+    #[deriving(Encodable, Decodable, PartialEq, Show)]
+    struct Circle {
+        r: uint
+    }
+
+    #[deriving(Encodable, Decodable, PartialEq, Show)]
+    enum Shape {
+        CircleShape(Circle),
+        Square { side: uint },
+        Dot
+    }
+
+    #[deriving(Encodable, Decodable, PartialEq, Show)]
+    struct Drawing {
+        shape: Vec<Shape>,
+        attrs: HashMap<String, String>
+    }
+
+    #[test]
+    fn test_encode_enums() {
+        let mut attrs = HashMap::new();
+        attrs.insert("a".to_string(), "x".to_string());
+        let drawing = Drawing {
+            shape: vec![CircleShape(Circle { r: 1 }), Square { side: 2 }, Dot],
+            attrs: attrs
+        };
+        let output = encode(&drawing, "drawing").unwrap();
+        assert_eq!("<drawing><shape><CircleShape r=\"1\"/></shape>\
+                    <shape><Square side=\"2\"/></shape><shape>Dot</shape>\
+                    <attrs a=\"x\"/></drawing>", output.as_slice());
+        assert_eq!(drawing, decode(output.as_slice()).unwrap());
+    }
+
+    #[deriving(Encodable)]
+    struct Unnamed {
+        p__: uint
+    }
+
+    #[test]
+    fn test_encode_errors() {
+        let entry = feed().entry.into_iter().next().unwrap();
+        assert_eq!(UnboundPrefixError("xlink".to_string()), encode(&entry, "entry").unwrap_err());
+        assert_eq!(InvalidNameError("1a".to_string()), encode(&1u, "1a").unwrap_err());
+
+        let mut map = HashMap::new();
+        map.insert("a b".to_string(), 1u);
+        assert_eq!(InvalidNameError("a b".to_string()), encode(&map, "map").unwrap_err());
+
+        assert_eq!(InvalidCharError('\x01'), encode(&"a\x01".to_string(), "s").unwrap_err());
+        let unnamed = Unnamed { p__: 1 };
+        assert_eq!(InvalidNameError("p__".to_string()), encode(&unnamed, "u").unwrap_err());
+    }
+}
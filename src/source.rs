@@ -0,0 +1,166 @@
+use std::io::{Reader, Buffer, Stream, Seek, IoResult, EndOfFile, InvalidInput};
+use std::io::{SeekStyle, SeekSet, SeekCur, SeekEnd};
+use std::io::{BufferedReader, BufferedStream, BufReader, MemReader};
+use std::io::standard_error;
+use std::slice::bytes::copy_memory;
+use std::cmp::min;
+
+/// Trait for sources `Parser` can read from. Sources that hold whole
+/// input in memory can lend parts of it for lifetime `'a`, so text in
+/// events doesn't need to be copied.
+pub trait XmlSource<'a>: Buffer {
+    /// Returns input between byte offsets `start` and `end`, counted
+    /// from the start of source, if it can be borrowed.
+    fn borrow_slice(&self, start: u64, end: u64) -> Option<&'a [u8]> {
+        None
+    }
+}
+
+impl<'a, R: Reader> XmlSource<'a> for BufferedReader<R> {}
+
+impl<'a, S: Stream> XmlSource<'a> for BufferedStream<S> {}
+
+impl<'a, 'b> XmlSource<'a> for BufReader<'b> {}
+
+impl<'a> XmlSource<'a> for MemReader {}
+
+/// Adapts any `Buffer` without its own `XmlSource` impl into a source,
+/// which never lends text.
+///
+/// E.g.
+///
+///     let mut source = BufferSource::new(stdin_buffer);
+///     let mut parser = Parser::from_reader(&mut source);
+pub struct BufferSource<B> {
+    inner: B
+}
+
+impl<B: Buffer> BufferSource<B> {
+    pub fn new(inner: B) -> BufferSource<B> {
+        BufferSource {
+            inner: inner
+        }
+    }
+
+    /// Returns the wrapped buffer
+    pub fn unwrap(self) -> B {
+        self.inner
+    }
+}
+
+impl<B: Buffer> Reader for BufferSource<B> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        self.inner.read(buf)
+    }
+}
+
+impl<B: Buffer> Buffer for BufferSource<B> {
+    fn fill_buf<'b>(&'b mut self) -> IoResult<&'b [u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: uint) {
+        self.inner.consume(amt)
+    }
+}
+
+impl<'a, B: Buffer> XmlSource<'a> for BufferSource<B> {}
+
+/// A source over a string or bytes in memory, which lets parser
+/// borrow text of events from it.
+///
+/// E.g.
+///
+///     let mut source = SliceSource::from_str("<a>text</a>");
+///     let mut parser = Parser::from_reader(&mut source);
+pub struct SliceSource<'a> {
+    buf: &'a [u8],
+    pos: uint
+}
+
+impl<'a> SliceSource<'a> {
+    pub fn new(buf: &'a [u8]) -> SliceSource<'a> {
+        SliceSource {
+            buf: buf,
+            pos: 0
+        }
+    }
+
+    pub fn from_str(text: &'a str) -> SliceSource<'a> {
+        SliceSource::new(text.as_bytes())
+    }
+}
+
+impl<'a> Reader for SliceSource<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        if self.pos >= self.buf.len() {
+            return Err(standard_error(EndOfFile))
+        }
+        let len = min(buf.len(), self.buf.len() - self.pos);
+        copy_memory(buf, self.buf.slice(self.pos, self.pos + len));
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+impl<'a> Buffer for SliceSource<'a> {
+    fn fill_buf<'b>(&'b mut self) -> IoResult<&'b [u8]> {
+        if self.pos >= self.buf.len() {
+            Err(standard_error(EndOfFile))
+        } else {
+            Ok(self.buf.slice_from(self.pos))
+        }
+    }
+
+    fn consume(&mut self, amt: uint) {
+        self.pos = min(self.pos + amt, self.buf.len());
+    }
+}
+
+/// Seeking lets a parser resumed from `ParserState` borrow text,
+/// as offsets stay counted from start of input.
+impl<'a> Seek for SliceSource<'a> {
+    fn tell(&self) -> IoResult<u64> {
+        Ok(self.pos as u64)
+    }
+
+    fn seek(&mut self, pos: i64, style: SeekStyle) -> IoResult<()> {
+        let base = match style {
+            SeekSet => 0,
+            SeekCur => self.pos as i64,
+            SeekEnd => self.buf.len() as i64
+        };
+        if base + pos < 0 {
+            return Err(standard_error(InvalidInput))
+        }
+        self.pos = (base + pos) as uint;
+        Ok(())
+    }
+}
+
+impl<'a: 'b, 'b> XmlSource<'b> for SliceSource<'a> {
+    fn borrow_slice(&self, start: u64, end: u64) -> Option<&'b [u8]> {
+        let (start, end) = (start as uint, end as uint);
+        if start <= end && end <= self.buf.len() {
+            Some(self.buf.slice(start, end))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::BufferSource;
+    use parser::{Parser, TextEvent};
+    use std::io::BufReader;
+    use std::str::Owned;
+
+    #[test]
+    fn test_buffer_source() {
+        let mut source = BufferSource::new(BufReader::new(b"text"));
+        let mut parser = Parser::from_reader(&mut source);
+        assert_eq!(Some(TextEvent(Owned("text".to_string()))), parser.pull());
+        assert_eq!(None, parser.pull());
+    }
+}
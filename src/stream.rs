@@ -0,0 +1,634 @@
+//! Matching of XPath paths while pull parsing.
+//!
+//! `StreamMatcher` runs paths against events of a `Parser` and returns
+//! just the matches, so values can be taken out of documents too large
+//! for a tree. Only forward paths are supported: child and descendant
+//! steps with name tests and attribute predicates, optionally ending in
+//! `@attr` or `text()`, e.g. `//item[@type='book']/title/text()`.
+//! Matched elements are built with their subtrees, nothing else of the
+//! document is kept.
+//!
+//! E.g.
+//!
+//!     let paths = vec![try!(StreamPath::compile("/feed/entry/@id", &ctx))];
+//!     for (_, id) in StreamMatcher::new(&mut parser, paths) {
+//!         ...
+//!     }
+
+use common::{XElem, XNode, XmlError, ElemNode, TextNode, CDataNode, CommentNode, PINode};
+use parser::{Parser, DeclEvent, ElemStart, ElemEnd, EmptyElem, PIEvent};
+use parser::{TextEvent, CDataEvent, CommentEvent, ErrEvent};
+use source::XmlSource;
+use util::{is_whitespace, is_name_start_char, is_name_char};
+use xpath::{Context, XPathError, SyntaxError, UnboundPrefix};
+
+/// A compiled path, see module documentation for supported syntax
+#[deriving(Clone, Show)]
+pub struct StreamPath {
+    steps: Vec<Step>,
+    target: Target
+}
+
+#[deriving(Clone, Show)]
+struct Step {
+    /// Whether step is preceded by `//`
+    descendant: bool,
+    test: ElemTest,
+    preds: Vec<AttrPred>
+}
+
+#[deriving(Clone, Show)]
+enum ElemTest {
+    /// `*`
+    AnyElem,
+    /// `p:*`, with namespace of prefix
+    AnyInNs(String),
+    /// Namespace and local name
+    NamedElem(String, String)
+}
+
+/// `[@attr]`, `[@attr='v']` or `[@attr!='v']`
+#[deriving(Clone, Show)]
+struct AttrPred {
+    uri: String,
+    local: String,
+    /// Value compared to, and whether it has to be equal
+    value: Option<(String, bool)>
+}
+
+/// What is returned for matched elements
+#[deriving(Clone, Show)]
+enum Target {
+    ElemTarget,
+    /// Value of attribute with namespace and local name
+    AttrTarget(String, String),
+    /// Text children
+    TextTarget
+}
+
+/// A match of a path
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum Matched {
+    /// Element along with its subtree
+    ElemMatch(XElem),
+    /// Value of attribute, or text between other children
+    ValueMatch(String)
+}
+
+impl StreamPath {
+    /// Compiles `expr`, resolving its prefixes by namespaces of `ctx`
+    pub fn compile(expr: &str, ctx: &Context) -> Result<StreamPath, XPathError> {
+        let mut parser = PathParser {
+            chars: expr.chars().collect(),
+            pos: 0,
+            ctx: ctx
+        };
+        parser.parse_path()
+    }
+}
+
+impl Step {
+    fn is_match(&self, elem: &XElem) -> bool {
+        let name_matches = match self.test {
+            AnyElem                         => true,
+            AnyInNs(ref uri)                => elem.namespace.uri.as_slice() == uri.as_slice(),
+            NamedElem(ref uri, ref local)   => elem.is_named_ns(uri.as_slice(), local.as_slice())
+        };
+        name_matches && self.preds.iter().all(|pred| {
+            match elem.attr_ns(pred.uri.as_slice(), pred.local.as_slice()) {
+                Some(value) => match pred.value {
+                    Some((ref expected, equal)) => (value == expected.as_slice()) == equal,
+                    None                        => true
+                },
+                None        => false
+            }
+        })
+    }
+}
+
+/// Open element along with what its children are matched against
+struct Open {
+    /// Paths, with index of their step, that children can match
+    states: Vec<(uint, uint)>,
+    /// Whether element is being built, as part of a matched subtree
+    built: bool,
+    /// Slots for matches of element by `ElemTarget` paths, along
+    /// with the paths
+    slots: Vec<(uint, uint)>,
+    /// Paths with `TextTarget` that matched element
+    text_paths: Vec<uint>,
+    /// Text of element read since its last child that isn't text
+    text: Option<String>
+}
+
+/// Iterator over matches of paths in events of a parser, along with
+/// index of path that matched. Matches are returned in document order,
+/// a matched element once it ends.
+pub struct StreamMatcher<'p, 'r: 'p, R: 'r> {
+    parser: &'p mut Parser<'r, R>,
+    paths: Vec<StreamPath>,
+    /// Open elements, synchronized with `parser.depth`. First one is
+    /// for the document.
+    open: Vec<Open>,
+    /// Elements of matched subtrees being built
+    builder: Vec<XElem>,
+    /// Matches in document order, `None` for elements that didn't end
+    matches: Vec<Option<(uint, Matched)>>,
+    /// Index of first match not returned yet
+    next: uint,
+    /// Errors reported by parser
+    pub errors: Vec<XmlError>,
+    done: bool
+}
+
+impl<'p, 'r: 'p, R: XmlSource<'r>> StreamMatcher<'p, 'r, R> {
+    pub fn new(parser: &'p mut Parser<'r, R>, paths: Vec<StreamPath>)
+              -> StreamMatcher<'p, 'r, R> {
+        let states = range(0, paths.len()).map(|path| (path, 0)).collect();
+        StreamMatcher {
+            parser: parser,
+            paths: paths,
+            open: vec![Open {
+                states: states,
+                built: false,
+                slots: Vec::new(),
+                text_paths: Vec::new(),
+                text: None
+            }],
+            builder: Vec::new(),
+            matches: Vec::new(),
+            next: 0,
+            errors: Vec::new(),
+            done: false
+        }
+    }
+
+    /// Handles next event, returning false at end of input
+    fn step(&mut self) -> bool {
+        let event = match self.parser.pull() {
+            Some(event) => event,
+            None        => return false
+        };
+        match event {
+            ElemStart(elem)     => self.start(elem),
+            EmptyElem(elem)     => {
+                self.start(elem);
+                self.end();
+            },
+            // Parser decides which elements end tags close
+            ElemEnd(_)          => {},
+            TextEvent(text)     => self.text(text.as_slice(), false),
+            CDataEvent(text)    => self.text(text.as_slice(), true),
+            CommentEvent(text)  => self.append_node(CommentNode(text.into_string())),
+            PIEvent(pi)         => self.append_node(PINode(pi)),
+            ErrEvent(err)       => self.errors.push(err),
+            DeclEvent(_)        => {}
+        }
+        while self.open.len() > self.parser.depth + 1 {
+            self.end();
+        }
+        true
+    }
+
+    fn start(&mut self, elem: XElem) {
+        self.flush_text();
+        let parent_states = self.open.last().unwrap().states.clone();
+        let mut open = Open {
+            states: Vec::new(),
+            built: !self.builder.is_empty(),
+            slots: Vec::new(),
+            text_paths: Vec::new(),
+            text: None
+        };
+        for &(path, index) in parent_states.iter() {
+            let steps = self.paths[path].steps.as_slice();
+            if steps[index].descendant && !open.states.contains(&(path, index)) {
+                open.states.push((path, index));
+            }
+            if !steps[index].is_match(&elem) {
+                continue
+            }
+            if index + 1 < steps.len() {
+                if !open.states.contains(&(path, index + 1)) {
+                    open.states.push((path, index + 1));
+                }
+                continue
+            }
+            match self.paths[path].target {
+                ElemTarget                      => {
+                    open.built = true;
+                    open.slots.push((self.matches.len(), path));
+                    self.matches.push(None);
+                },
+                AttrTarget(ref uri, ref local)  => {
+                    match elem.attr_ns(uri.as_slice(), local.as_slice()) {
+                        Some(value) => {
+                            self.matches.push(Some((path, ValueMatch(value.to_string()))));
+                        },
+                        None        => {}
+                    }
+                },
+                TextTarget                      => open.text_paths.push(path)
+            }
+        }
+        if open.built {
+            self.builder.push(elem);
+        }
+        self.open.push(open);
+    }
+
+    fn end(&mut self) {
+        self.flush_text();
+        let mut open = self.open.pop().unwrap();
+        if !open.built {
+            return
+        }
+        let elem = self.builder.pop().unwrap();
+        let last = if self.builder.is_empty() { open.slots.pop() } else { None };
+        for &(slot, path) in open.slots.iter() {
+            self.matches.as_mut_slice()[slot] = Some((path, ElemMatch(elem.clone())));
+        }
+        // Outermost element is moved to its match, rest to their parents
+        match last {
+            Some((slot, path))  => {
+                self.matches.as_mut_slice()[slot] = Some((path, ElemMatch(elem)));
+            },
+            None                => self.builder.last_mut().unwrap().children.push(ElemNode(elem))
+        }
+    }
+
+    fn text(&mut self, text: &str, is_cdata: bool) {
+        match self.builder.last_mut() {
+            Some(elem)  => elem.children.push(if is_cdata {
+                CDataNode(text.to_string())
+            } else {
+                TextNode(text.to_string())
+            }),
+            None        => {}
+        }
+        let open = self.open.last_mut().unwrap();
+        if !open.text_paths.is_empty() {
+            let mut s = open.text.take().unwrap_or(String::new());
+            s.push_str(text);
+            open.text = Some(s);
+        }
+    }
+
+    fn append_node(&mut self, node: XNode) {
+        self.flush_text();
+        match self.builder.last_mut() {
+            Some(elem)  => elem.children.push(node),
+            None        => {}
+        }
+    }
+
+    /// Adds text read since last child as matches of `text()` paths
+    fn flush_text(&mut self) {
+        let open = self.open.last_mut().unwrap();
+        match open.text.take() {
+            Some(text)  => for &path in open.text_paths.iter() {
+                self.matches.push(Some((path, ValueMatch(text.clone()))));
+            },
+            None        => {}
+        }
+    }
+}
+
+impl<'p, 'r: 'p, R: XmlSource<'r>> Iterator<(uint, Matched)> for StreamMatcher<'p, 'r, R> {
+    fn next(&mut self) -> Option<(uint, Matched)> {
+        loop {
+            if self.next < self.matches.len() {
+                match self.matches.as_mut_slice()[self.next].take() {
+                    Some(matched)   => {
+                        self.next += 1;
+                        if self.next == self.matches.len() {
+                            self.matches.clear();
+                            self.next = 0;
+                        }
+                        return Some(matched)
+                    },
+                    None            => {}
+                }
+            } else if self.done {
+                return None
+            }
+            if !self.done && !self.step() {
+                // Elements left open end with input
+                while self.open.len() > 1 {
+                    self.end();
+                }
+                self.done = true;
+            }
+        }
+    }
+}
+
+struct PathParser<'a> {
+    chars: Vec<char>,
+    pos: uint,
+    ctx: &'a Context
+}
+
+impl<'a> PathParser<'a> {
+    fn peek(&self) -> Option<char> {
+        self.chars.as_slice().get(self.pos).map(|&c| c)
+    }
+
+    fn consume(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), XPathError> {
+        if self.consume(c) {
+            Ok(())
+        } else {
+            Err(self.error(format!("expected {}", c)))
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().map_or(false, |c| is_whitespace(c) || c == '\r') {
+            self.pos += 1;
+        }
+    }
+
+    fn error(&self, msg: String) -> XPathError {
+        XPathError {
+            kind: SyntaxError,
+            msg: msg,
+            pos: Some(self.pos)
+        }
+    }
+
+    fn parse_path(&mut self) -> Result<StreamPath, XPathError> {
+        let mut steps = Vec::new();
+        loop {
+            if !self.consume('/') {
+                let msg = if steps.is_empty() { "path must start with /" } else { "expected /" };
+                return Err(self.error(msg.to_string()))
+            }
+            let descendant = self.consume('/');
+            // Attributes and text of `//` are those of any element
+            let any_elem = Step {
+                descendant: true,
+                test: AnyElem,
+                preds: Vec::new()
+            };
+
+            if self.consume('@') {
+                let (uri, local) = try!(self.parse_name(true));
+                if descendant {
+                    steps.push(any_elem);
+                } else if steps.is_empty() {
+                    return Err(self.error("document has no attributes".to_string()))
+                }
+                return self.finish(steps, AttrTarget(uri, local.unwrap()))
+            }
+
+            self.skip_whitespace();
+            let start = self.pos;
+            let (uri, local) = try!(self.parse_name(false));
+            if local.as_ref().map_or(false, |local| local.as_slice() == "text")
+               && uri.is_empty() && self.consume('(') {
+                try!(self.expect(')'));
+                if descendant {
+                    steps.push(any_elem);
+                } else if steps.is_empty() {
+                    self.pos = start;
+                    return Err(self.error("document has no text".to_string()))
+                }
+                return self.finish(steps, TextTarget)
+            }
+            if self.consume('(') || self.consume(':') {
+                self.pos = start;
+                return Err(self.error("only child and descendant steps are supported".to_string()))
+            }
+
+            let test = match (uri, local) {
+                (uri, Some(local))  => NamedElem(uri, local),
+                (uri, None)         => if uri.is_empty() { AnyElem } else { AnyInNs(uri) }
+            };
+            let mut preds = Vec::new();
+            while self.consume('[') {
+                preds.push(try!(self.parse_pred()));
+            }
+            steps.push(Step {
+                descendant: descendant,
+                test: test,
+                preds: preds
+            });
+
+            self.skip_whitespace();
+            if self.peek().is_none() {
+                return Ok(StreamPath {
+                    steps: steps,
+                    target: ElemTarget
+                })
+            }
+        }
+    }
+
+    /// Checks that `target` ends path
+    fn finish(&mut self, steps: Vec<Step>, target: Target) -> Result<StreamPath, XPathError> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(c) => Err(self.error(format!("unexpected {}", c))),
+            None    => Ok(StreamPath {
+                steps: steps,
+                target: target
+            })
+        }
+    }
+
+    fn parse_pred(&mut self) -> Result<AttrPred, XPathError> {
+        if !self.consume('@') {
+            return Err(self.error("only attribute predicates are supported".to_string()))
+        }
+        let (uri, local) = try!(self.parse_name(true));
+        let equal = if self.consume('=') {
+            Some(true)
+        } else if self.consume('!') {
+            try!(self.expect('='));
+            Some(false)
+        } else {
+            None
+        };
+        let value = match equal {
+            Some(equal) => Some((try!(self.parse_literal()), equal)),
+            None        => None
+        };
+        try!(self.expect(']'));
+        Ok(AttrPred {
+            uri: uri,
+            local: local.unwrap(),
+            value: value
+        })
+    }
+
+    fn parse_literal(&mut self) -> Result<String, XPathError> {
+        self.skip_whitespace();
+        let quote = match self.peek() {
+            Some(c) if c == '"' || c == '\'' => c,
+            _ => return Err(self.error("expected string literal".to_string()))
+        };
+        self.pos += 1;
+        let start = self.pos;
+        while self.peek().map_or(false, |c| c != quote) {
+            self.pos += 1;
+        }
+        if self.peek().is_none() {
+            return Err(self.error("unterminated string literal".to_string()))
+        }
+        self.pos += 1;
+        Ok(String::from_chars(self.chars.slice(start, self.pos - 1)))
+    }
+
+    /// Parses a name test, returning its namespace and local name,
+    /// which is `None` for `*`. Unprefixed names have no namespace.
+    fn parse_name(&mut self, is_attr: bool) -> Result<(String, Option<String>), XPathError> {
+        self.skip_whitespace();
+        let start = self.pos;
+        if self.consume('*') {
+            if is_attr {
+                self.pos = start;
+                return Err(self.error("expected attribute name".to_string()))
+            }
+            return Ok((String::new(), None))
+        }
+        let first = try!(self.parse_ncname());
+        // No whitespace is allowed around colon of a name
+        if self.peek() != Some(':') || !self.chars.as_slice().get(self.pos + 1)
+                                           .map_or(false, |&c| c == '*' || is_name_start_char(c)) {
+            return Ok((String::new(), Some(first)))
+        }
+        self.pos += 1;
+        let uri = match self.ctx.namespace_uri(first.as_slice()) {
+            Some(uri)   => uri,
+            None        => return Err(XPathError {
+                kind: UnboundPrefix,
+                msg: format!("prefix {} is not bound to a namespace", first),
+                pos: Some(start)
+            })
+        };
+        if self.peek() == Some('*') && !is_attr {
+            self.pos += 1;
+            return Ok((uri, None))
+        }
+        let local = try!(self.parse_ncname());
+        Ok((uri, Some(local)))
+    }
+
+    fn parse_ncname(&mut self) -> Result<String, XPathError> {
+        match self.peek() {
+            Some(c) if c != ':' && is_name_start_char(c) => {},
+            _ => return Err(self.error("expected name".to_string()))
+        }
+        let start = self.pos;
+        while self.peek().map_or(false, |c| c != ':' && is_name_char(c)) {
+            self.pos += 1;
+        }
+        Ok(String::from_chars(self.chars.slice(start, self.pos)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{StreamPath, StreamMatcher, Matched, ElemMatch, ValueMatch};
+    use parser::Parser;
+    use source::SliceSource;
+    use writer::elem_to_string;
+    use xpath::{Context, SyntaxError, UnboundPrefix};
+
+    /// Returns matches of `paths` in `input`, elements written as XML
+    fn matches(input: &str, paths: &[&str]) -> Vec<(uint, String)> {
+        let mut ctx = Context::new();
+        ctx.bind_namespace("n", "urn:n");
+        let paths = paths.iter().map(|path| StreamPath::compile(*path, &ctx).unwrap()).collect();
+        let mut source = SliceSource::from_str(input);
+        let mut parser = Parser::from_reader(&mut source);
+        StreamMatcher::new(&mut parser, paths).map(|(path, matched): (uint, Matched)| {
+            (path, match matched {
+                ElemMatch(elem)     => elem_to_string(&elem),
+                ValueMatch(value)   => value
+            })
+        }).collect()
+    }
+
+    fn values(input: &str, path: &str) -> Vec<String> {
+        matches(input, [path]).into_iter().map(|(_, value)| value).collect()
+    }
+
+    static FEED: &'static str = "<feed><entry id='1' type='a'><t>x</t></entry>\
+                                 <group><entry id='2'><t>y<!--c-->z</t></entry></group>\
+                                 <entry id='3' type='b'><t><![CDATA[w]]></t></entry></feed>";
+
+    #[test]
+    fn test_paths() {
+        assert_eq!(vec!["1".to_string(), "3".to_string()],
+                   values(FEED, "/feed/entry/@id"));
+        assert_eq!(vec!["1".to_string(), "2".to_string(), "3".to_string()],
+                   values(FEED, "//entry/@id"));
+        assert_eq!(vec!["<t>x</t>".to_string()],
+                   values(FEED, "/feed/entry[@type='a']/t"));
+        assert_eq!(vec!["3".to_string()],
+                   values(FEED, "//*[@type != \"a\"]/@id"));
+        assert_eq!(vec!["<entry id=\"2\"><t>y<!--c-->z</t></entry>".to_string()],
+                   values(FEED, "/*/*/entry"));
+        assert_eq!(vec!["x".to_string(), "y".to_string(), "z".to_string(), "w".to_string()],
+                   values(FEED, "//t/text()"));
+        assert_eq!(vec!["1".to_string(), "2".to_string(), "3".to_string()],
+                   values(FEED, "//@id"));
+        assert_eq!(Vec::<String>::new(), values(FEED, "/entry"));
+    }
+
+    #[test]
+    fn test_nested_matches() {
+        // Matches come in document order, whichever ends first
+        let input = "<a><b><b>x</b></b><b>y</b></a>";
+        assert_eq!(vec!["<b><b>x</b></b>".to_string(), "<b>x</b>".to_string(),
+                        "<b>y</b>".to_string()],
+                   values(input, "//b"));
+        assert_eq!(vec![(1, "<b><b>x</b></b>".to_string()), (0, "x".to_string()),
+                        (1, "<b>y</b>".to_string())],
+                   matches(input, ["//b/b/text()", "/a/b"]));
+    }
+
+    #[test]
+    fn test_namespaces() {
+        let input = "<r xmlns:m='urn:n'><m:a m:k='1' k='2'/><a k='3'/></r>";
+        assert_eq!(vec!["1".to_string()], values(input, "/r/n:a/@n:k"));
+        assert_eq!(vec!["2".to_string()], values(input, "/r/n:*/@k"));
+        assert_eq!(vec!["3".to_string()], values(input, "/r/a[@k]/@k"));
+    }
+
+    #[test]
+    fn test_unclosed() {
+        // Elements open at end of input are closed there
+        assert_eq!(vec!["<b>x<c/></b>".to_string()], values("<a><b>x<c/>", "//b"));
+    }
+
+    #[test]
+    fn test_errors() {
+        let ctx = Context::new();
+        let err = StreamPath::compile("a/b", &ctx).unwrap_err();
+        assert_eq!((SyntaxError, Some(0)), (err.kind, err.pos));
+        let err = StreamPath::compile("/a/../b", &ctx).unwrap_err();
+        assert_eq!((SyntaxError, Some(3)), (err.kind, err.pos));
+        let err = StreamPath::compile("/a[1]", &ctx).unwrap_err();
+        assert_eq!((SyntaxError, Some(3)), (err.kind, err.pos));
+        let err = StreamPath::compile("/a/@b/c", &ctx).unwrap_err();
+        assert_eq!((SyntaxError, Some(5)), (err.kind, err.pos));
+        let err = StreamPath::compile("/child::a", &ctx).unwrap_err();
+        assert_eq!((SyntaxError, Some(1)), (err.kind, err.pos));
+        let err = StreamPath::compile("/p:a", &ctx).unwrap_err();
+        assert_eq!((UnboundPrefix, Some(1)), (err.kind, err.pos));
+        let err = StreamPath::compile("/text()", &ctx).unwrap_err();
+        assert_eq!((SyntaxError, Some(1)), (err.kind, err.pos));
+    }
+}
@@ -15,3 +15,76 @@ pub fn is_digit(c: char) -> bool {
         _ => return false,
     }
 }
+
+/// Checks if `c` matches the `Char` production of XML 1.0
+pub fn is_xml10_char(c: char) -> bool {
+    match c {
+        '\x09' | '\x0A' | '\x0D'
+        | '\x20'...'\uD7FF'
+        | '\uE000'...'\uFFFD'
+        | '\U00010000'...'\U0010FFFF' => true,
+        _ => false,
+    }
+}
+
+/// Checks if `c` matches the `Char` production of XML 1.1
+pub fn is_xml11_char(c: char) -> bool {
+    match c {
+        '\x01'...'\uD7FF'
+        | '\uE000'...'\uFFFD'
+        | '\U00010000'...'\U0010FFFF' => true,
+        _ => false,
+    }
+}
+
+/// Checks if `c` matches the `RestrictedChar` production of XML 1.1.
+/// Such chars may appear in a document only as character references.
+pub fn is_restricted_char(c: char) -> bool {
+    match c {
+        '\x01'...'\x08'
+        | '\x0B'...'\x0C'
+        | '\x0E'...'\x1F'
+        | '\x7F'...'\x84'
+        | '\x86'...'\x9F' => true,
+        _ => false,
+    }
+}
+
+/// Checks if `c` is XML whitespace, after new lines were normalized
+pub fn is_whitespace(c: char) -> bool {
+    match c {
+        ' ' | '\t' | '\n' => true,
+        _ => false,
+    }
+}
+
+/// Checks if `c` matches the `NameStartChar` production
+pub fn is_name_start_char(c: char) -> bool {
+    match c {
+        ':' | 'A'...'Z' | '_' | 'a'...'z'
+        | '\xC0'...'\xD6' | '\xD8'...'\xF6' | '\xF8'...'\u02FF'
+        | '\u0370'...'\u037D' | '\u037F'...'\u1FFF'
+        | '\u200C'...'\u200D' | '\u2070'...'\u218F'
+        | '\u2C00'...'\u2FEF' | '\u3001'...'\uD7FF'
+        | '\uF900'...'\uFDCF' | '\uFDF0'...'\uFFFD'
+        | '\U00010000'...'\U000EFFFF' => true,
+        _ => false,
+    }
+}
+
+/// Checks if `c` matches the `NameChar` production
+pub fn is_name_char(c: char) -> bool {
+    match c {
+        '-' | '.' | '0'...'9' | '\xB7'
+        | '\u0300'...'\u036F' | '\u203F'...'\u2040' => true,
+        _ => is_name_start_char(c),
+    }
+}
+
+/// Splits qualified name into its prefix and local part
+pub fn split_name<'a>(qname: &'a str) -> (&'a str, &'a str) {
+    match qname.find(':') {
+        Some(i) => (qname.slice_to(i), qname.slice_from(i + 1)),
+        None    => ("", qname)
+    }
+}
@@ -0,0 +1,180 @@
+//! Writing of document trees as XML.
+//!
+//! Names are written with the prefixes in their `XmlNS`, and namespace
+//! declarations are written as the attributes they are stored as.
+//! Documents edited by methods of `XElem` and `XDoc` have all the
+//! declarations they need, a lone element gets them from
+//! `XElem::declare_namespaces`.
+
+use std::io::{Writer, IoResult, MemWriter};
+
+use common::{XDoc, XElem, XmlAttr, XNode, XPi};
+use common::{ElemNode, TextNode, CDataNode, CommentNode, PINode};
+
+/// Writes processing instructions and root element of `doc`
+pub fn write_doc<W: Writer>(w: &mut W, doc: &XDoc) -> IoResult<()> {
+    for pi in doc.pis().iter() {
+        try!(write_pi(w, pi));
+    }
+    write_elem(w, doc.root())
+}
+
+/// Writes `elem` along with its descendants
+pub fn write_elem<W: Writer>(w: &mut W, elem: &XElem) -> IoResult<()> {
+    try!(w.write_char('<'));
+    try!(write_name(w, elem.namespace.name.as_slice(), elem.name.as_slice()));
+    for attr in elem.attributes.iter() {
+        try!(write_attr(w, attr));
+    }
+    if elem.children.is_empty() {
+        return w.write_str("/>")
+    }
+    try!(w.write_char('>'));
+    for child in elem.children.iter() {
+        try!(write_node(w, child));
+    }
+    try!(w.write_str("</"));
+    try!(write_name(w, elem.namespace.name.as_slice(), elem.name.as_slice()));
+    w.write_char('>')
+}
+
+/// Writes `node`, escaping its text as needed
+pub fn write_node<W: Writer>(w: &mut W, node: &XNode) -> IoResult<()> {
+    match *node {
+        ElemNode(ref elem)      => write_elem(w, elem),
+        TextNode(ref text)      => write_escaped(w, text.as_slice(), false),
+        CDataNode(ref text)     => {
+            try!(w.write_str("<![CDATA["));
+            // `]]>` can't be inside a section, so it's split between two
+            try!(w.write_str(text.as_slice().replace("]]>", "]]]]><![CDATA[>").as_slice()));
+            w.write_str("]]>")
+        },
+        CommentNode(ref text)   => {
+            try!(w.write_str("<!--"));
+            try!(w.write_str(comment_text(text.as_slice()).as_slice()));
+            w.write_str("-->")
+        },
+        PINode(ref pi)          => write_pi(w, pi)
+    }
+}
+
+/// Returns `elem` written as XML
+pub fn elem_to_string(elem: &XElem) -> String {
+    let mut w = MemWriter::new();
+    write_elem(&mut w, elem).unwrap();
+    String::from_utf8(w.unwrap()).unwrap()
+}
+
+/// Returns `doc` written as XML
+pub fn doc_to_string(doc: &XDoc) -> String {
+    let mut w = MemWriter::new();
+    write_doc(&mut w, doc).unwrap();
+    String::from_utf8(w.unwrap()).unwrap()
+}
+
+fn write_name<W: Writer>(w: &mut W, prefix: &str, local: &str) -> IoResult<()> {
+    if !prefix.is_empty() {
+        try!(w.write_str(prefix));
+        try!(w.write_char(':'));
+    }
+    w.write_str(local)
+}
+
+fn write_attr<W: Writer>(w: &mut W, attr: &XmlAttr) -> IoResult<()> {
+    try!(w.write_char(' '));
+    try!(write_name(w, attr.namespace.name.as_slice(), attr.name.as_slice()));
+    try!(w.write_str("=\""));
+    try!(write_escaped(w, attr.value.as_slice(), true));
+    w.write_char('"')
+}
+
+/// Returns `text` with a space put between dashes, and after a final
+/// one, as comments can't contain `--` or end with `-`
+fn comment_text(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c == '-' && out.as_slice().ends_with("-") {
+            out.push(' ');
+        }
+        out.push(c);
+    }
+    if out.as_slice().ends_with("-") {
+        out.push(' ');
+    }
+    out
+}
+
+fn write_pi<W: Writer>(w: &mut W, pi: &XPi) -> IoResult<()> {
+    try!(w.write_str("<?"));
+    try!(w.write_str(pi.target.as_slice()));
+    if !pi.value.is_empty() {
+        try!(w.write_char(' '));
+        // `?>` would end the instruction, so it's split by a space
+        try!(w.write_str(pi.value.as_slice().replace("?>", "? >").as_slice()));
+    }
+    w.write_str("?>")
+}
+
+/// Writes `text` with markup characters replaced by references.
+/// In attribute values whitespace other than space is replaced too,
+/// so it isn't normalized when read back.
+fn write_escaped<W: Writer>(w: &mut W, text: &str, in_attr: bool) -> IoResult<()> {
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        let escaped = match c {
+            '&'                 => "&amp;",
+            '<'                 => "&lt;",
+            '>' if !in_attr     => "&gt;",
+            '"' if in_attr      => "&quot;",
+            '\t' if in_attr     => "&#9;",
+            '\n' if in_attr     => "&#10;",
+            '\r'                => "&#13;",
+            _                   => continue
+        };
+        try!(w.write_str(text.slice(start, i)));
+        try!(w.write_str(escaped));
+        start = i + 1;
+    }
+    w.write_str(text.slice_from(start))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{elem_to_string, doc_to_string};
+    use common::{XDoc, XElem, XmlNS, XPi};
+    use common::{TextNode, CDataNode, CommentNode, PINode};
+    use name::Name;
+
+    fn ns(prefix: &str, uri: &str) -> XmlNS {
+        XmlNS {
+            name: Name::new(prefix),
+            uri: Name::new(uri)
+        }
+    }
+
+    #[test]
+    fn test_write_escaped() {
+        let mut elem = XElem::new(ns("", ""), "a");
+        elem.set_attr("v", "<\"&'\n>");
+        elem.append_child(TextNode("a<b>&\"\r".to_string()));
+        elem.append_child(CDataNode("x]]>y".to_string()));
+        elem.append_child(CommentNode(" c ".to_string()));
+        elem.append_child(PINode(XPi { target: "t".to_string(), value: String::new() }));
+        elem.append_child(CommentNode("a--b---c-".to_string()));
+        elem.append_child(PINode(XPi { target: "t".to_string(), value: "a?>b".to_string() }));
+        assert_eq!("<a v=\"&lt;&quot;&amp;'&#10;>\">a&lt;b&gt;&amp;\"&#13;\
+                    <![CDATA[x]]]]><![CDATA[>y]]><!-- c --><?t?>\
+                    <!--a- -b- - -c- --><?t a? >b?></a>".to_string(),
+                   elem_to_string(&elem));
+    }
+
+    #[test]
+    fn test_write_doc() {
+        let mut root = XElem::new(ns("p", "urn:p"), "r");
+        root.append_child(XElem::new(ns("", ""), "e").wrap(XElem::new(ns("p", "urn:p"), "w")));
+        let mut doc = XDoc::new(root);
+        doc.push_pi(XPi { target: "s".to_string(), value: "href='x'".to_string() });
+        assert_eq!("<?s href='x'?><p:r xmlns:p=\"urn:p\"><p:w><e/></p:w></p:r>".to_string(),
+                   doc_to_string(&doc));
+    }
+}
@@ -0,0 +1,1772 @@
+//! XPath 1.0 expressions over documents.
+//!
+//! Expressions are compiled once by `XPath::compile` and evaluated
+//! against a `dom::Document`, which has the parent links reverse axes
+//! need. Trees of `XDoc` are evaluated through `Document::from_xdoc`,
+//! and nodes found are mapped back to them by `Document::xdoc_pos`.
+//! Prefixes in expressions are resolved by namespaces bound in `Context`.
+//!
+//! E.g.
+//!
+//!     let mut ctx = Context::new();
+//!     ctx.bind_namespace("c", "urn:config");
+//!     let path = try!(XPath::compile("string(/c:config/c:db/@host)"));
+//!     let host = try!(path.evaluate(&ctx, &doc, doc.root()));
+
+use std::ascii::StrAsciiExt;
+use std::collections::HashMap;
+use std::f64;
+use std::num::Float;
+use std::uint;
+
+use common::{XmlNS, XML_NS_URI};
+use dom::{Document, NodeId, RootKind, ElementKind, TextKind, CDataKind, CommentKind, PIKind};
+use name::Name;
+use util::{is_digit, is_whitespace, is_name_start_char, is_name_char};
+
+/// A node, as seen by XPath. Attributes and namespaces aren't nodes of
+/// `Document`, so they are identified by their element.
+#[deriving(Clone, PartialEq, Eq, Hash, Show)]
+pub enum XPathNode {
+    /// Node of document
+    DocNode(NodeId),
+    /// Attribute at index in attributes of element
+    AttrNode(NodeId, uint),
+    /// Namespace at index in namespaces in scope of element,
+    /// sorted by prefix
+    NsNode(NodeId, uint)
+}
+
+/// Result of an expression
+#[deriving(Clone, PartialEq, Show)]
+pub enum Value {
+    /// Nodes in document order
+    NodeSetValue(Vec<XPathNode>),
+    StringValue(String),
+    NumberValue(f64),
+    BooleanValue(bool)
+}
+
+impl Value {
+    /// Converts value to a string, like the `string` function
+    pub fn string(&self, doc: &Document) -> String {
+        match *self {
+            NodeSetValue(ref nodes) => match nodes.as_slice().head() {
+                Some(node)  => string_value(doc, node),
+                None        => String::new()
+            },
+            StringValue(ref s)      => s.clone(),
+            NumberValue(n)          => number_to_string(n),
+            BooleanValue(b)         => b.to_string()
+        }
+    }
+
+    /// Converts value to a number, like the `number` function
+    pub fn number(&self, doc: &Document) -> f64 {
+        match *self {
+            NumberValue(n)          => n,
+            BooleanValue(b)         => if b { 1.0 } else { 0.0 },
+            _                       => string_to_number(self.string(doc).as_slice())
+        }
+    }
+
+    /// Converts value to a boolean, like the `boolean` function
+    pub fn boolean(&self) -> bool {
+        match *self {
+            NodeSetValue(ref nodes) => !nodes.is_empty(),
+            StringValue(ref s)      => !s.is_empty(),
+            NumberValue(n)          => n != 0.0 && !n.is_nan(),
+            BooleanValue(b)         => b
+        }
+    }
+}
+
+#[deriving(PartialEq, Eq, Clone, Show)]
+pub enum XPathErrKind {
+    /// Expression can't be parsed
+    SyntaxError,
+    UnknownFunction,
+    UnknownVariable,
+    /// Prefix isn't bound in `Context`
+    UnboundPrefix,
+    /// Function was called with wrong number of arguments
+    ArgumentError,
+    /// Value that isn't a node-set was used as one
+    NodeSetExpected
+}
+
+/// Error in compiling or evaluating an expression
+#[deriving(PartialEq, Eq, Clone, Show)]
+pub struct XPathError {
+    pub kind: XPathErrKind,
+    pub msg: String,
+    /// Char offset in expression, for syntax errors
+    pub pos: Option<uint>
+}
+
+fn error(kind: XPathErrKind, msg: String) -> XPathError {
+    XPathError {
+        kind: kind,
+        msg: msg,
+        pos: None
+    }
+}
+
+fn syntax_error(msg: String, pos: uint) -> XPathError {
+    XPathError {
+        kind: SyntaxError,
+        msg: msg,
+        pos: Some(pos)
+    }
+}
+
+/// Namespaces and variables expressions are evaluated with
+pub struct Context {
+    namespaces: HashMap<String, String>,
+    variables: HashMap<String, Value>
+}
+
+impl Context {
+    pub fn new() -> Context {
+        Context {
+            namespaces: HashMap::new(),
+            variables: HashMap::new()
+        }
+    }
+
+    /// Binds `prefix` to namespace `uri` in expressions
+    pub fn bind_namespace(&mut self, prefix: &str, uri: &str) {
+        self.namespaces.insert(prefix.to_string(), uri.to_string());
+    }
+
+    /// Returns namespace `prefix` is bound to, `xml` being always bound
+    pub fn namespace_uri(&self, prefix: &str) -> Option<String> {
+        if prefix == "xml" {
+            return Some(XML_NS_URI.to_string())
+        }
+        self.namespaces.find_equiv(&prefix).map(|uri| uri.clone())
+    }
+
+    /// Sets value of variable `$name`
+    pub fn set_variable(&mut self, name: &str, value: Value) {
+        self.variables.insert(name.to_string(), value);
+    }
+}
+
+/// A compiled expression
+#[deriving(Clone, Show)]
+pub struct XPath {
+    expr: Expr
+}
+
+impl XPath {
+    pub fn compile(expr: &str) -> Result<XPath, XPathError> {
+        let tokens = try!(tokenize(expr));
+        let mut parser = ExprParser {
+            tokens: tokens,
+            pos: 0,
+            end: expr.char_len()
+        };
+        let expr = try!(parser.parse_expr());
+        match parser.peek() {
+            Some(token) => return Err(parser.error(format!("unexpected {}", token))),
+            None        => {}
+        }
+        Ok(XPath {
+            expr: expr
+        })
+    }
+
+    /// Evaluates expression with `node` as context node.
+    ///
+    /// Only `Document`s can be evaluated against. A tree of `XDoc` has
+    /// to be converted by `Document::from_xdoc` first, which copies all
+    /// of its nodes, so a converted document should be kept and reused
+    /// for all expressions evaluated against that tree. Resulting nodes
+    /// are in the copy; `Document::xdoc_pos` finds them in the tree.
+    pub fn evaluate(&self, ctx: &Context, doc: &Document, node: NodeId)
+                   -> Result<Value, XPathError> {
+        let eval = Evaluator::new(ctx, doc);
+        let focus = Focus {
+            node: DocNode(node),
+            position: 1,
+            size: 1
+        };
+        eval.eval(&self.expr, &focus)
+    }
+}
+
+/// Compiles `expr` and evaluates it with root of `doc` as context node
+pub fn evaluate(expr: &str, ctx: &Context, doc: &Document) -> Result<Value, XPathError> {
+    let path = try!(XPath::compile(expr));
+    path.evaluate(ctx, doc, doc.root())
+}
+
+#[deriving(PartialEq, Clone, Show)]
+enum Token {
+    TSlash,
+    TDoubleSlash,
+    TPipe,
+    TPlus,
+    TMinus,
+    TEqual,
+    TNotEqual,
+    TLess,
+    TLessEqual,
+    TGreater,
+    TGreaterEqual,
+    TAnd,
+    TOr,
+    TMod,
+    TDiv,
+    TMultiply,
+    TLParen,
+    TRParen,
+    TLBracket,
+    TRBracket,
+    TDot,
+    TDotDot,
+    TAt,
+    TComma,
+    TColonColon,
+    /// Prefix and local name, `None` standing for `*`
+    TNameTest(Option<String>, Option<String>),
+    TNodeType(String),
+    TFunctionName(String),
+    TAxisName(String),
+    TLiteral(String),
+    TNumber(f64),
+    TVariable(String)
+}
+
+/// Checks if an operand can't end with `token`, so what follows
+/// it isn't an operator
+fn is_operand_start(token: &Token) -> bool {
+    match *token {
+        TAt | TColonColon | TLParen | TLBracket | TComma
+        | TAnd | TOr | TMod | TDiv | TMultiply | TSlash | TDoubleSlash
+        | TPipe | TPlus | TMinus | TEqual | TNotEqual
+        | TLess | TLessEqual | TGreater | TGreaterEqual => true,
+        _ => false
+    }
+}
+
+fn is_ncname_start_char(c: char) -> bool {
+    c != ':' && is_name_start_char(c)
+}
+
+fn is_ncname_char(c: char) -> bool {
+    c != ':' && is_name_char(c)
+}
+
+/// Splits expression into tokens, along with their char offsets
+fn tokenize(expr: &str) -> Result<Vec<(Token, uint)>, XPathError> {
+    let chars: Vec<char> = expr.chars().collect();
+    let len = chars.len();
+    let at = |i: uint| if i < len { Some(chars[i]) } else { None };
+    let read_ncname = |start: uint| {
+        let mut end = start;
+        while end < len && is_ncname_char(chars[end]) {
+            end += 1;
+        }
+        (String::from_chars(chars.slice(start, end)), end)
+    };
+
+    let mut tokens: Vec<(Token, uint)> = Vec::new();
+    let mut i = 0;
+    while i < len {
+        let c = chars[i];
+        if is_whitespace(c) || c == '\r' {
+            i += 1;
+            continue
+        }
+        let start = i;
+        // Disambiguates `*` and names like `div`, see 3.7 of the spec
+        let operator_expected = match tokens.last() {
+            Some(&(ref token, _))   => !is_operand_start(token),
+            None                    => false
+        };
+        let next = at(i + 1);
+        let token = match c {
+            '/' if next == Some('/') => { i += 2; TDoubleSlash },
+            '/'                     => { i += 1; TSlash },
+            '|'                     => { i += 1; TPipe },
+            '+'                     => { i += 1; TPlus },
+            '-'                     => { i += 1; TMinus },
+            '='                     => { i += 1; TEqual },
+            '!' if next == Some('=') => { i += 2; TNotEqual },
+            '<' if next == Some('=') => { i += 2; TLessEqual },
+            '<'                     => { i += 1; TLess },
+            '>' if next == Some('=') => { i += 2; TGreaterEqual },
+            '>'                     => { i += 1; TGreater },
+            '('                     => { i += 1; TLParen },
+            ')'                     => { i += 1; TRParen },
+            '['                     => { i += 1; TLBracket },
+            ']'                     => { i += 1; TRBracket },
+            '@'                     => { i += 1; TAt },
+            ','                     => { i += 1; TComma },
+            ':' if next == Some(':') => { i += 2; TColonColon },
+            '.' if next == Some('.') => { i += 2; TDotDot },
+            '.' if !next.map_or(false, is_digit) => { i += 1; TDot },
+            '*' if operator_expected => { i += 1; TMultiply },
+            '*'                     => { i += 1; TNameTest(None, None) },
+            '"' | '\''              => {
+                let end = match range(i + 1, len).find(|&j| chars[j] == c) {
+                    Some(end)   => end,
+                    None        => return Err(syntax_error("unclosed literal".to_string(), i))
+                };
+                let literal = String::from_chars(chars.slice(i + 1, end));
+                i = end + 1;
+                TLiteral(literal)
+            },
+            '.' | '0'...'9'         => {
+                let mut end = i;
+                while end < len && (is_digit(chars[end]) || chars[end] == '.') {
+                    end += 1;
+                }
+                let number = String::from_chars(chars.slice(i, end));
+                if number.as_slice().chars().filter(|&c| c == '.').count() > 1 {
+                    return Err(syntax_error(format!("malformed number {}", number), i))
+                }
+                i = end;
+                TNumber(string_to_number(number.as_slice()))
+            },
+            '$'                     => {
+                if !at(i + 1).map_or(false, is_ncname_start_char) {
+                    return Err(syntax_error("expected variable name".to_string(), i))
+                }
+                let (mut name, end) = read_ncname(i + 1);
+                i = end;
+                if at(i) == Some(':') && at(i + 1).map_or(false, is_ncname_start_char) {
+                    let (local, end) = read_ncname(i + 1);
+                    name = format!("{}:{}", name, local);
+                    i = end;
+                }
+                TVariable(name)
+            },
+            c if is_ncname_start_char(c) => {
+                let (name, end) = read_ncname(i);
+                i = end;
+                if operator_expected {
+                    match name.as_slice() {
+                        "and"   => TAnd,
+                        "or"    => TOr,
+                        "mod"   => TMod,
+                        "div"   => TDiv,
+                        _       => {
+                            let msg = format!("expected operator, found {}", name);
+                            return Err(syntax_error(msg, start))
+                        }
+                    }
+                } else {
+                    let mut prefix = None;
+                    let mut local = Some(name.clone());
+                    if at(i) == Some(':') && at(i + 1) == Some('*') {
+                        prefix = Some(name.clone());
+                        local = None;
+                        i += 2;
+                    } else if at(i) == Some(':') && at(i + 1).map_or(false, is_ncname_start_char) {
+                        let (name, end) = read_ncname(i + 1);
+                        prefix = local;
+                        local = Some(name);
+                        i = end;
+                    }
+                    let mut j = i;
+                    while at(j).map_or(false, |c| is_whitespace(c) || c == '\r') {
+                        j += 1;
+                    }
+                    let is_node_type = match name.as_slice() {
+                        "comment" | "text" | "processing-instruction" | "node" => true,
+                        _ => false
+                    };
+                    if at(j) == Some('(') && prefix.is_none() && is_node_type {
+                        TNodeType(name)
+                    } else if at(j) == Some('(') && local.is_some() {
+                        let qname = match prefix {
+                            Some(prefix)    => format!("{}:{}", prefix, local.unwrap()),
+                            None            => name
+                        };
+                        TFunctionName(qname)
+                    } else if at(j) == Some(':') && at(j + 1) == Some(':') && prefix.is_none() {
+                        TAxisName(name)
+                    } else {
+                        TNameTest(prefix, local)
+                    }
+                }
+            },
+            _ => return Err(syntax_error(format!("unexpected character {}", c), i))
+        };
+        tokens.push((token, start));
+    }
+    Ok(tokens)
+}
+
+#[deriving(PartialEq, Eq, Clone, Show)]
+enum CompareOp {
+    EqOp,
+    NotEqOp,
+    LtOp,
+    LtEqOp,
+    GtOp,
+    GtEqOp
+}
+
+impl CompareOp {
+    /// Returns operator that gives same result with operands swapped
+    fn swap(self) -> CompareOp {
+        match self {
+            LtOp    => GtOp,
+            LtEqOp  => GtEqOp,
+            GtOp    => LtOp,
+            GtEqOp  => LtEqOp,
+            op      => op
+        }
+    }
+}
+
+#[deriving(PartialEq, Eq, Clone, Show)]
+enum ArithOp {
+    AddOp,
+    SubOp,
+    MulOp,
+    DivOp,
+    ModOp
+}
+
+#[deriving(PartialEq, Eq, Clone, Show)]
+enum Axis {
+    AncestorAxis,
+    AncestorOrSelfAxis,
+    AttributeAxis,
+    ChildAxis,
+    DescendantAxis,
+    DescendantOrSelfAxis,
+    FollowingAxis,
+    FollowingSiblingAxis,
+    NamespaceAxis,
+    ParentAxis,
+    PrecedingAxis,
+    PrecedingSiblingAxis,
+    SelfAxis
+}
+
+impl Axis {
+    fn from_name(name: &str) -> Option<Axis> {
+        let axis = match name {
+            "ancestor"              => AncestorAxis,
+            "ancestor-or-self"      => AncestorOrSelfAxis,
+            "attribute"             => AttributeAxis,
+            "child"                 => ChildAxis,
+            "descendant"            => DescendantAxis,
+            "descendant-or-self"    => DescendantOrSelfAxis,
+            "following"             => FollowingAxis,
+            "following-sibling"     => FollowingSiblingAxis,
+            "namespace"             => NamespaceAxis,
+            "parent"                => ParentAxis,
+            "preceding"             => PrecedingAxis,
+            "preceding-sibling"     => PrecedingSiblingAxis,
+            "self"                  => SelfAxis,
+            _                       => return None
+        };
+        Some(axis)
+    }
+}
+
+#[deriving(PartialEq, Eq, Clone, Show)]
+enum NodeTest {
+    /// `*`
+    AnyNameTest,
+    /// `prefix:*`
+    PrefixTest(String),
+    /// Optional prefix and local name
+    QNameTest(Option<String>, String),
+    /// `text()`
+    TextTest,
+    /// `comment()`
+    CommentTest,
+    /// `processing-instruction()`, with optional target
+    PITest(Option<String>),
+    /// `node()`
+    AnyNodeTest
+}
+
+#[deriving(Clone, Show)]
+struct Step {
+    axis: Axis,
+    test: NodeTest,
+    predicates: Vec<Expr>
+}
+
+#[deriving(Clone, Show)]
+enum PathStart {
+    /// Path starts at root node
+    RootStart,
+    /// Path starts at context node
+    ContextStart,
+    /// Path starts at nodes of a filter expression
+    ExprStart(Box<Expr>)
+}
+
+#[deriving(Clone, Show)]
+enum Expr {
+    OrExpr(Box<Expr>, Box<Expr>),
+    AndExpr(Box<Expr>, Box<Expr>),
+    CompareExpr(CompareOp, Box<Expr>, Box<Expr>),
+    ArithExpr(ArithOp, Box<Expr>, Box<Expr>),
+    NegExpr(Box<Expr>),
+    UnionExpr(Box<Expr>, Box<Expr>),
+    LiteralExpr(String),
+    NumberExpr(f64),
+    VariableExpr(String),
+    FunctionExpr(String, Vec<Expr>),
+    FilterExpr(Box<Expr>, Vec<Expr>),
+    PathExpr(PathStart, Vec<Step>)
+}
+
+/// Recursive descent parser, following grammar of the spec
+struct ExprParser {
+    tokens: Vec<(Token, uint)>,
+    pos: uint,
+    /// Length of expression, which is offset of errors at its end
+    end: uint
+}
+
+impl ExprParser {
+    fn peek<'a>(&'a self) -> Option<&'a Token> {
+        match self.tokens.as_slice().get(self.pos) {
+            Some(&(ref token, _))   => Some(token),
+            None                    => None
+        }
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.peek().map(|token| token.clone());
+        if token.is_some() {
+            self.pos += 1;
+        }
+        token
+    }
+
+    /// Consumes next token if it's `token`
+    fn consume(&mut self, token: &Token) -> bool {
+        if self.peek() == Some(token) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, token: Token) -> Result<(), XPathError> {
+        if self.consume(&token) {
+            Ok(())
+        } else {
+            Err(self.error(format!("expected {}", token)))
+        }
+    }
+
+    /// Returns syntax error at current token
+    fn error(&self, msg: String) -> XPathError {
+        let pos = match self.tokens.as_slice().get(self.pos) {
+            Some(&(_, pos)) => pos,
+            None            => self.end
+        };
+        syntax_error(msg, pos)
+    }
+
+    fn parse_expr(&mut self) -> Result<Expr, XPathError> {
+        let mut expr = try!(self.parse_and());
+        while self.consume(&TOr) {
+            let right = try!(self.parse_and());
+            expr = OrExpr(box expr, box right);
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, XPathError> {
+        let mut expr = try!(self.parse_equality());
+        while self.consume(&TAnd) {
+            let right = try!(self.parse_equality());
+            expr = AndExpr(box expr, box right);
+        }
+        Ok(expr)
+    }
+
+    fn parse_equality(&mut self) -> Result<Expr, XPathError> {
+        let mut expr = try!(self.parse_relational());
+        loop {
+            let op = match self.peek() {
+                Some(&TEqual)       => EqOp,
+                Some(&TNotEqual)    => NotEqOp,
+                _                   => return Ok(expr)
+            };
+            self.pos += 1;
+            let right = try!(self.parse_relational());
+            expr = CompareExpr(op, box expr, box right);
+        }
+    }
+
+    fn parse_relational(&mut self) -> Result<Expr, XPathError> {
+        let mut expr = try!(self.parse_additive());
+        loop {
+            let op = match self.peek() {
+                Some(&TLess)            => LtOp,
+                Some(&TLessEqual)       => LtEqOp,
+                Some(&TGreater)         => GtOp,
+                Some(&TGreaterEqual)    => GtEqOp,
+                _                       => return Ok(expr)
+            };
+            self.pos += 1;
+            let right = try!(self.parse_additive());
+            expr = CompareExpr(op, box expr, box right);
+        }
+    }
+
+    fn parse_additive(&mut self) -> Result<Expr, XPathError> {
+        let mut expr = try!(self.parse_multiplicative());
+        loop {
+            let op = match self.peek() {
+                Some(&TPlus)    => AddOp,
+                Some(&TMinus)   => SubOp,
+                _               => return Ok(expr)
+            };
+            self.pos += 1;
+            let right = try!(self.parse_multiplicative());
+            expr = ArithExpr(op, box expr, box right);
+        }
+    }
+
+    fn parse_multiplicative(&mut self) -> Result<Expr, XPathError> {
+        let mut expr = try!(self.parse_unary());
+        loop {
+            let op = match self.peek() {
+                Some(&TMultiply)    => MulOp,
+                Some(&TDiv)         => DivOp,
+                Some(&TMod)         => ModOp,
+                _                   => return Ok(expr)
+            };
+            self.pos += 1;
+            let right = try!(self.parse_unary());
+            expr = ArithExpr(op, box expr, box right);
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, XPathError> {
+        if self.consume(&TMinus) {
+            let expr = try!(self.parse_unary());
+            Ok(NegExpr(box expr))
+        } else {
+            self.parse_union()
+        }
+    }
+
+    fn parse_union(&mut self) -> Result<Expr, XPathError> {
+        let mut expr = try!(self.parse_path());
+        while self.consume(&TPipe) {
+            let right = try!(self.parse_path());
+            expr = UnionExpr(box expr, box right);
+        }
+        Ok(expr)
+    }
+
+    fn parse_path(&mut self) -> Result<Expr, XPathError> {
+        let is_primary = match self.peek() {
+            Some(&TVariable(_)) | Some(&TLParen) | Some(&TLiteral(_))
+            | Some(&TNumber(_)) | Some(&TFunctionName(_)) => true,
+            _ => false
+        };
+        if !is_primary {
+            return self.parse_location_path()
+        }
+
+        let primary = try!(self.parse_primary());
+        let predicates = try!(self.parse_predicates());
+        let filter = if predicates.is_empty() {
+            primary
+        } else {
+            FilterExpr(box primary, predicates)
+        };
+        let mut steps = Vec::new();
+        if self.consume(&TSlash) {
+            try!(self.parse_relative_path(&mut steps));
+        } else if self.consume(&TDoubleSlash) {
+            steps.push(descendant_or_self());
+            try!(self.parse_relative_path(&mut steps));
+        } else {
+            return Ok(filter)
+        }
+        Ok(PathExpr(ExprStart(box filter), steps))
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, XPathError> {
+        match self.next() {
+            Some(TVariable(name))   => Ok(VariableExpr(name)),
+            Some(TLiteral(s))       => Ok(LiteralExpr(s)),
+            Some(TNumber(n))        => Ok(NumberExpr(n)),
+            Some(TLParen)           => {
+                let expr = try!(self.parse_expr());
+                try!(self.expect(TRParen));
+                Ok(expr)
+            },
+            Some(TFunctionName(name)) => {
+                try!(self.expect(TLParen));
+                let mut args = Vec::new();
+                if !self.consume(&TRParen) {
+                    loop {
+                        args.push(try!(self.parse_expr()));
+                        if self.consume(&TRParen) {
+                            break
+                        }
+                        try!(self.expect(TComma));
+                    }
+                }
+                Ok(FunctionExpr(name, args))
+            },
+            _ => unreachable!()
+        }
+    }
+
+    fn parse_predicates(&mut self) -> Result<Vec<Expr>, XPathError> {
+        let mut predicates = Vec::new();
+        while self.consume(&TLBracket) {
+            predicates.push(try!(self.parse_expr()));
+            try!(self.expect(TRBracket));
+        }
+        Ok(predicates)
+    }
+
+    fn parse_location_path(&mut self) -> Result<Expr, XPathError> {
+        let mut steps = Vec::new();
+        if self.consume(&TSlash) {
+            // Lone `/` selects the root
+            if self.is_step_start() {
+                try!(self.parse_relative_path(&mut steps));
+            }
+            Ok(PathExpr(RootStart, steps))
+        } else if self.consume(&TDoubleSlash) {
+            steps.push(descendant_or_self());
+            try!(self.parse_relative_path(&mut steps));
+            Ok(PathExpr(RootStart, steps))
+        } else {
+            try!(self.parse_relative_path(&mut steps));
+            Ok(PathExpr(ContextStart, steps))
+        }
+    }
+
+    fn is_step_start(&self) -> bool {
+        match self.peek() {
+            Some(&TDot) | Some(&TDotDot) | Some(&TAt) | Some(&TAxisName(_))
+            | Some(&TNameTest(..)) | Some(&TNodeType(_)) => true,
+            _ => false
+        }
+    }
+
+    fn parse_relative_path(&mut self, steps: &mut Vec<Step>) -> Result<(), XPathError> {
+        steps.push(try!(self.parse_step()));
+        loop {
+            if self.consume(&TDoubleSlash) {
+                steps.push(descendant_or_self());
+            } else if !self.consume(&TSlash) {
+                return Ok(())
+            }
+            steps.push(try!(self.parse_step()));
+        }
+    }
+
+    fn parse_step(&mut self) -> Result<Step, XPathError> {
+        if self.consume(&TDot) {
+            return Ok(Step { axis: SelfAxis, test: AnyNodeTest, predicates: Vec::new() })
+        } else if self.consume(&TDotDot) {
+            return Ok(Step { axis: ParentAxis, test: AnyNodeTest, predicates: Vec::new() })
+        }
+
+        let axis = if self.consume(&TAt) {
+            AttributeAxis
+        } else {
+            match self.peek() {
+                Some(&TAxisName(ref name)) => match Axis::from_name(name.as_slice()) {
+                    Some(axis)  => axis,
+                    None        => return Err(self.error(format!("unknown axis {}", name)))
+                },
+                _ => ChildAxis
+            }
+        };
+        if self.peek().map_or(false, |token| match *token { TAxisName(_) => true, _ => false }) {
+            self.pos += 1;
+            try!(self.expect(TColonColon));
+        }
+
+        let test = match self.next() {
+            Some(TNameTest(None, None))         => AnyNameTest,
+            Some(TNameTest(Some(prefix), None)) => PrefixTest(prefix),
+            Some(TNameTest(prefix, Some(local))) => QNameTest(prefix, local),
+            Some(TNodeType(name))               => {
+                try!(self.expect(TLParen));
+                let test = match name.as_slice() {
+                    "comment"   => CommentTest,
+                    "text"      => TextTest,
+                    "node"      => AnyNodeTest,
+                    _           => match self.peek() {
+                        Some(&TLiteral(ref target)) => PITest(Some(target.clone())),
+                        _                           => PITest(None)
+                    }
+                };
+                match test {
+                    PITest(Some(_)) => self.pos += 1,
+                    _               => {}
+                }
+                try!(self.expect(TRParen));
+                test
+            },
+            token => {
+                // Error is reported at the unexpected token
+                if token.is_some() {
+                    self.pos -= 1;
+                }
+                return Err(self.error("expected node test".to_string()))
+            }
+        };
+        let predicates = try!(self.parse_predicates());
+        Ok(Step {
+            axis: axis,
+            test: test,
+            predicates: predicates
+        })
+    }
+}
+
+/// Step that `//` stands for
+fn descendant_or_self() -> Step {
+    Step {
+        axis: DescendantOrSelfAxis,
+        test: AnyNodeTest,
+        predicates: Vec::new()
+    }
+}
+
+/// Context of evaluating an expression
+struct Focus {
+    node: XPathNode,
+    position: uint,
+    size: uint
+}
+
+struct Evaluator<'a> {
+    ctx: &'a Context,
+    doc: &'a Document,
+    /// Position of each node of document in document order
+    order: Vec<uint>
+}
+
+impl<'a> Evaluator<'a> {
+    fn new(ctx: &'a Context, doc: &'a Document) -> Evaluator<'a> {
+        let root = doc.root();
+        let mut order = Vec::from_elem(doc.len(), 0u);
+        for (i, id) in doc.descendants(root).enumerate() {
+            order.as_mut_slice()[id.index()] = i + 1;
+        }
+        Evaluator {
+            ctx: ctx,
+            doc: doc,
+            order: order
+        }
+    }
+
+    fn eval(&self, expr: &Expr, focus: &Focus) -> Result<Value, XPathError> {
+        let value = match *expr {
+            OrExpr(ref left, ref right) => {
+                let value = try!(self.eval(&**left, focus)).boolean()
+                            || try!(self.eval(&**right, focus)).boolean();
+                BooleanValue(value)
+            },
+            AndExpr(ref left, ref right) => {
+                let value = try!(self.eval(&**left, focus)).boolean()
+                            && try!(self.eval(&**right, focus)).boolean();
+                BooleanValue(value)
+            },
+            CompareExpr(ref op, ref left, ref right) => {
+                let left = try!(self.eval(&**left, focus));
+                let right = try!(self.eval(&**right, focus));
+                BooleanValue(self.compare(op.clone(), &left, &right))
+            },
+            ArithExpr(ref op, ref left, ref right) => {
+                let left = try!(self.eval(&**left, focus)).number(self.doc);
+                let right = try!(self.eval(&**right, focus)).number(self.doc);
+                NumberValue(match *op {
+                    AddOp   => left + right,
+                    SubOp   => left - right,
+                    MulOp   => left * right,
+                    DivOp   => left / right,
+                    ModOp   => left % right
+                })
+            },
+            NegExpr(ref expr)       => {
+                NumberValue(-try!(self.eval(&**expr, focus)).number(self.doc))
+            },
+            UnionExpr(ref left, ref right) => {
+                let mut nodes = try!(self.eval_nodes(&**left, focus));
+                nodes.extend(try!(self.eval_nodes(&**right, focus)).into_iter());
+                self.sort_nodes(&mut nodes);
+                NodeSetValue(nodes)
+            },
+            LiteralExpr(ref s)      => StringValue(s.clone()),
+            NumberExpr(n)           => NumberValue(n),
+            VariableExpr(ref name)  => match self.ctx.variables.find(name) {
+                Some(value) => value.clone(),
+                None        => return Err(error(UnknownVariable,
+                                                format!("variable ${} isn't set", name)))
+            },
+            FunctionExpr(ref name, ref args) => {
+                try!(self.call(name.as_slice(), args.as_slice(), focus))
+            },
+            FilterExpr(ref expr, ref predicates) => {
+                let nodes = try!(self.eval_nodes(&**expr, focus));
+                NodeSetValue(try!(self.filter(nodes, predicates.as_slice())))
+            },
+            PathExpr(ref start, ref steps) => {
+                let mut nodes = match *start {
+                    RootStart               => vec![DocNode(self.doc.root())],
+                    ContextStart            => vec![focus.node.clone()],
+                    ExprStart(ref expr)     => try!(self.eval_nodes(&**expr, focus))
+                };
+                for step in steps.iter() {
+                    nodes = try!(self.eval_step(step, nodes.as_slice()));
+                }
+                NodeSetValue(nodes)
+            }
+        };
+        Ok(value)
+    }
+
+    /// Evaluates expression that must return a node-set
+    fn eval_nodes(&self, expr: &Expr, focus: &Focus) -> Result<Vec<XPathNode>, XPathError> {
+        match try!(self.eval(expr, focus)) {
+            NodeSetValue(nodes) => Ok(nodes),
+            value               => Err(error(NodeSetExpected,
+                                             format!("expected node-set, got {}", value)))
+        }
+    }
+
+    /// Selects nodes `step` leads to from each of `nodes`
+    fn eval_step(&self, step: &Step, nodes: &[XPathNode]) -> Result<Vec<XPathNode>, XPathError> {
+        let uri = match step.test {
+            QNameTest(Some(ref prefix), _) | PrefixTest(ref prefix) => {
+                Some(try!(self.resolve_prefix(prefix.as_slice())))
+            },
+            QNameTest(None, _) => Some(String::new()),
+            _ => None
+        };
+        let mut selected = Vec::new();
+        for node in nodes.iter() {
+            let matched: Vec<XPathNode> = self.axis_nodes(&step.axis, node).into_iter()
+                              .filter(|node| self.is_match(step, &uri, node))
+                              .collect();
+            // Predicates count positions along the axis
+            selected.extend(try!(self.filter(matched, step.predicates.as_slice())).into_iter());
+        }
+        self.sort_nodes(&mut selected);
+        Ok(selected)
+    }
+
+    /// Keeps `nodes` that all of `predicates` are true for
+    fn filter(&self, nodes: Vec<XPathNode>, predicates: &[Expr])
+              -> Result<Vec<XPathNode>, XPathError> {
+        let mut nodes = nodes;
+        for predicate in predicates.iter() {
+            let size = nodes.len();
+            let mut kept = Vec::new();
+            for (i, node) in nodes.into_iter().enumerate() {
+                let focus = Focus {
+                    node: node.clone(),
+                    position: i + 1,
+                    size: size
+                };
+                let is_match = match try!(self.eval(predicate, &focus)) {
+                    NumberValue(n)  => n == (i + 1) as f64,
+                    value           => value.boolean()
+                };
+                if is_match {
+                    kept.push(node);
+                }
+            }
+            nodes = kept;
+        }
+        Ok(nodes)
+    }
+
+    fn resolve_prefix(&self, prefix: &str) -> Result<String, XPathError> {
+        match self.ctx.namespace_uri(prefix) {
+            Some(uri)   => Ok(uri),
+            None        => Err(error(UnboundPrefix,
+                                     format!("prefix {} is not bound to a namespace", prefix)))
+        }
+    }
+
+    /// Checks if `node` passes node test of `step`. Name tests have
+    /// their namespace resolved to `uri`.
+    fn is_match(&self, step: &Step, uri: &Option<String>, node: &XPathNode) -> bool {
+        let is_principal = match (&step.axis, node) {
+            (&AttributeAxis, &AttrNode(..))     => true,
+            (&NamespaceAxis, &NsNode(..))       => true,
+            (&AttributeAxis, _) | (&NamespaceAxis, _) => false,
+            (_, &DocNode(id))                   => self.doc.element(id).is_some(),
+            _                                   => false
+        };
+        match step.test {
+            AnyNameTest             => is_principal,
+            PrefixTest(_)           => is_principal && node_name(self.doc, node).map_or(false,
+                                           |(_, _, ns)| Some(ns) == *uri),
+            QNameTest(_, ref local) => is_principal && node_name(self.doc, node).map_or(false,
+                                           |(_, name, ns)| name == *local && Some(ns) == *uri),
+            AnyNodeTest             => true,
+            _ => match *node {
+                DocNode(id) => match (&step.test, self.doc.kind(id)) {
+                    (&TextTest, &TextKind(_))       => true,
+                    (&TextTest, &CDataKind(_))      => true,
+                    (&CommentTest, &CommentKind(_)) => true,
+                    (&PITest(ref target), &PIKind(ref pi)) => {
+                        target.as_ref().map_or(true, |target| *target == pi.target)
+                    },
+                    _                               => false
+                },
+                _ => false
+            }
+        }
+    }
+
+    /// Returns nodes along `axis` from `node`, in order of the axis
+    fn axis_nodes(&self, axis: &Axis, node: &XPathNode) -> Vec<XPathNode> {
+        let doc = self.doc;
+        // Element that attributes and namespaces belong to
+        let (id, is_doc_node) = match *node {
+            DocNode(id)                     => (id, true),
+            AttrNode(id, _) | NsNode(id, _) => (id, false)
+        };
+        let mut nodes = Vec::new();
+        match *axis {
+            SelfAxis                => nodes.push(node.clone()),
+            ChildAxis if is_doc_node => {
+                nodes.extend(doc.children(id).map(|id| DocNode(id)));
+            },
+            DescendantAxis if is_doc_node => {
+                nodes.extend(doc.descendants(id).map(|id| DocNode(id)));
+            },
+            DescendantOrSelfAxis    => {
+                nodes.push(node.clone());
+                if is_doc_node {
+                    nodes.extend(doc.descendants(id).map(|id| DocNode(id)));
+                }
+            },
+            ParentAxis              => {
+                if !is_doc_node {
+                    nodes.push(DocNode(id));
+                } else {
+                    match doc.parent(id) {
+                        Some(parent)    => nodes.push(DocNode(parent)),
+                        None            => {}
+                    }
+                }
+            },
+            AncestorAxis | AncestorOrSelfAxis => {
+                if *axis == AncestorOrSelfAxis {
+                    nodes.push(node.clone());
+                }
+                if !is_doc_node {
+                    nodes.push(DocNode(id));
+                }
+                nodes.extend(doc.ancestors(id).map(|id| DocNode(id)));
+            },
+            FollowingSiblingAxis if is_doc_node => {
+                let mut sibling = doc.next_sibling(id);
+                while sibling.is_some() {
+                    let next = sibling.unwrap();
+                    nodes.push(DocNode(next));
+                    sibling = doc.next_sibling(next);
+                }
+            },
+            PrecedingSiblingAxis if is_doc_node => {
+                let mut sibling = doc.prev_sibling(id);
+                while sibling.is_some() {
+                    let prev = sibling.unwrap();
+                    nodes.push(DocNode(prev));
+                    sibling = doc.prev_sibling(prev);
+                }
+            },
+            FollowingAxis           => {
+                // Content of element follows its attributes
+                if !is_doc_node {
+                    nodes.extend(doc.descendants(id).map(|id| DocNode(id)));
+                }
+                let mut current = Some(id);
+                while current.is_some() {
+                    let node = current.unwrap();
+                    let mut sibling = doc.next_sibling(node);
+                    while sibling.is_some() {
+                        let next = sibling.unwrap();
+                        nodes.push(DocNode(next));
+                        nodes.extend(doc.descendants(next).map(|id| DocNode(id)));
+                        sibling = doc.next_sibling(next);
+                    }
+                    current = doc.parent(node);
+                }
+            },
+            PrecedingAxis           => {
+                let mut current = Some(id);
+                while current.is_some() {
+                    let node = current.unwrap();
+                    let mut sibling = doc.prev_sibling(node);
+                    while sibling.is_some() {
+                        let prev = sibling.unwrap();
+                        let mut subtree = vec![DocNode(prev)];
+                        subtree.extend(doc.descendants(prev).map(|id| DocNode(id)));
+                        nodes.extend(subtree.into_iter().rev());
+                        sibling = doc.prev_sibling(prev);
+                    }
+                    current = doc.parent(node);
+                }
+            },
+            AttributeAxis if is_doc_node => match doc.element(id) {
+                Some(elem) => {
+                    for (i, attr) in elem.attributes.iter().enumerate() {
+                        if !attr.is_declaration() {
+                            nodes.push(AttrNode(id, i));
+                        }
+                    }
+                },
+                None => {}
+            },
+            NamespaceAxis if is_doc_node && doc.element(id).is_some() => {
+                let count = namespaces(doc, id).len();
+                nodes.extend(range(0, count).map(|i| NsNode(id, i)));
+            },
+            _ => {}
+        }
+        nodes
+    }
+
+    /// Sorts `nodes` in document order and removes duplicates
+    fn sort_nodes(&self, nodes: &mut Vec<XPathNode>) {
+        nodes.sort_by(|a, b| self.order_key(a).cmp(&self.order_key(b)));
+        nodes.dedup();
+    }
+
+    /// Returns key of `node`, that sorts nodes in document order.
+    /// Namespaces of element come before its attributes.
+    fn order_key(&self, node: &XPathNode) -> (uint, uint, uint) {
+        match *node {
+            DocNode(id)     => (self.order[id.index()], 0, 0),
+            NsNode(id, i)   => (self.order[id.index()], 1, i),
+            AttrNode(id, i) => (self.order[id.index()], 2, i)
+        }
+    }
+
+    /// Compares `left` and `right` as described in 3.4 of the spec
+    fn compare(&self, op: CompareOp, left: &Value, right: &Value) -> bool {
+        let doc = self.doc;
+        match (left, right) {
+            (&NodeSetValue(ref left), &NodeSetValue(ref right)) => {
+                let right: Vec<Value> = right.iter()
+                                             .map(|node| StringValue(string_value(doc, node)))
+                                             .collect();
+                left.iter().any(|node| {
+                    let left = StringValue(string_value(doc, node));
+                    right.iter().any(|right| self.compare_atoms(op.clone(), &left, right))
+                })
+            },
+            (&NodeSetValue(ref nodes), other) => {
+                nodes.iter().any(|node| {
+                    let value = match *other {
+                        BooleanValue(_) => BooleanValue(true),
+                        NumberValue(_)  => NumberValue(string_to_number(
+                                               string_value(doc, node).as_slice())),
+                        _               => StringValue(string_value(doc, node))
+                    };
+                    self.compare_atoms(op.clone(), &value, other)
+                }) || match *other {
+                    // Empty node-set is false
+                    BooleanValue(_) if nodes.is_empty() => {
+                        self.compare_atoms(op.clone(), &BooleanValue(false), other)
+                    },
+                    _ => false
+                }
+            },
+            (_, &NodeSetValue(_)) => self.compare(op.swap(), right, left),
+            _ => self.compare_atoms(op, left, right)
+        }
+    }
+
+    /// Compares values that aren't node-sets
+    fn compare_atoms(&self, op: CompareOp, left: &Value, right: &Value) -> bool {
+        let doc = self.doc;
+        match op {
+            EqOp | NotEqOp => {
+                let is_equal = match (left, right) {
+                    (&BooleanValue(_), _) | (_, &BooleanValue(_)) => {
+                        left.boolean() == right.boolean()
+                    },
+                    (&NumberValue(_), _) | (_, &NumberValue(_)) => {
+                        left.number(doc) == right.number(doc)
+                    },
+                    _ => left.string(doc) == right.string(doc)
+                };
+                is_equal == (op == EqOp)
+            },
+            _ => {
+                let (left, right) = (left.number(doc), right.number(doc));
+                match op {
+                    LtOp    => left < right,
+                    LtEqOp  => left <= right,
+                    GtOp    => left > right,
+                    _       => left >= right
+                }
+            }
+        }
+    }
+
+    /// Calls function of the core library
+    fn call(&self, name: &str, args: &[Expr], focus: &Focus) -> Result<Value, XPathError> {
+        let doc = self.doc;
+        let (min, max) = match name {
+            "last" | "position" | "true" | "false"                  => (0, 0),
+            "count" | "id" | "boolean" | "not" | "lang" | "sum"
+            | "floor" | "ceiling" | "round"                         => (1, 1),
+            "local-name" | "namespace-uri" | "name" | "string"
+            | "string-length" | "normalize-space" | "number"        => (0, 1),
+            "starts-with" | "contains" | "substring-before"
+            | "substring-after"                                     => (2, 2),
+            "substring"                                             => (2, 3),
+            "translate"                                             => (3, 3),
+            "concat"                                                => (2, uint::MAX),
+            _ => return Err(error(UnknownFunction, format!("unknown function {}", name)))
+        };
+        if args.len() < min || args.len() > max {
+            return Err(error(ArgumentError,
+                             format!("wrong number of arguments to {}: {}", name, args.len())))
+        }
+
+        let mut values = Vec::with_capacity(args.len());
+        for arg in args.iter() {
+            values.push(try!(self.eval(arg, focus)));
+        }
+        // Argument as a string, defaulting to the context node
+        let string_arg = |i: uint| match values.as_slice().get(i) {
+            Some(value) => value.string(doc),
+            None        => string_value(doc, &focus.node)
+        };
+        // First node of argument, defaulting to the context node
+        let node_arg = |i: uint| -> Result<Option<XPathNode>, XPathError> {
+            match values.as_slice().get(i) {
+                Some(&NodeSetValue(ref nodes)) => Ok(nodes.as_slice().head().map(|n| n.clone())),
+                Some(value) => Err(error(NodeSetExpected,
+                                         format!("expected node-set, got {}", value))),
+                None        => Ok(Some(focus.node.clone()))
+            }
+        };
+
+        let value = match name {
+            "last"          => NumberValue(focus.size as f64),
+            "position"      => NumberValue(focus.position as f64),
+            "count"         => match values[0] {
+                NodeSetValue(ref nodes) => NumberValue(nodes.len() as f64),
+                ref value => return Err(error(NodeSetExpected,
+                                              format!("expected node-set, got {}", value)))
+            },
+            "id"            => {
+                let ids = match values[0] {
+                    NodeSetValue(ref nodes) => {
+                        let strings: Vec<String> = nodes.iter()
+                                                        .map(|node| string_value(doc, node))
+                                                        .collect();
+                        strings.as_slice().connect(" ")
+                    },
+                    ref value => value.string(doc)
+                };
+                NodeSetValue(self.find_ids(ids.as_slice()))
+            },
+            "local-name" | "namespace-uri" | "name" => {
+                let name_parts = try!(node_arg(0)).and_then(|node| node_name(doc, &node));
+                StringValue(match name_parts {
+                    Some((prefix, local, uri)) => match name {
+                        "local-name"    => local,
+                        "namespace-uri" => uri,
+                        _ if prefix.is_empty() => local,
+                        _               => format!("{}:{}", prefix, local)
+                    },
+                    None => String::new()
+                })
+            },
+            "string"        => StringValue(string_arg(0)),
+            "concat"        => {
+                let mut s = String::new();
+                for value in values.iter() {
+                    s.push_str(value.string(doc).as_slice());
+                }
+                StringValue(s)
+            },
+            "starts-with"   => BooleanValue(string_arg(0).as_slice()
+                                                .starts_with(string_arg(1).as_slice())),
+            "contains"      => BooleanValue(string_arg(0).as_slice()
+                                                .contains(string_arg(1).as_slice())),
+            "substring-before" | "substring-after" => {
+                let (s, pat) = (string_arg(0), string_arg(1));
+                StringValue(match s.as_slice().find_str(pat.as_slice()) {
+                    Some(i) if name == "substring-before"   => s.as_slice().slice_to(i).to_string(),
+                    Some(i)     => s.as_slice().slice_from(i + pat.len()).to_string(),
+                    None        => String::new()
+                })
+            },
+            "substring"     => {
+                let start = round(values[1].number(doc));
+                let end = match values.as_slice().get(2) {
+                    Some(len)   => start + round(len.number(doc)),
+                    None        => f64::INFINITY
+                };
+                let s = string_arg(0);
+                let chars: Vec<char> = s.as_slice().chars().enumerate()
+                    .filter(|&(i, _)| {
+                        let pos = (i + 1) as f64;
+                        pos >= start && pos < end
+                    })
+                    .map(|(_, c)| c)
+                    .collect();
+                StringValue(String::from_chars(chars.as_slice()))
+            },
+            "string-length" => NumberValue(string_arg(0).as_slice().char_len() as f64),
+            "normalize-space" => {
+                let s = string_arg(0);
+                let words: Vec<&str> = s.as_slice()
+                                        .split(|c: char| is_whitespace(c) || c == '\r')
+                                        .filter(|word| !word.is_empty())
+                                        .collect();
+                StringValue(words.as_slice().connect(" "))
+            },
+            "translate"     => {
+                let (from, to) = (string_arg(1), string_arg(2));
+                let from: Vec<char> = from.as_slice().chars().collect();
+                let to: Vec<char> = to.as_slice().chars().collect();
+                let mut s = String::new();
+                for c in string_arg(0).as_slice().chars() {
+                    match from.iter().position(|&f| f == c) {
+                        Some(i) if i < to.len() => s.push(to[i]),
+                        Some(_)                 => {},
+                        None                    => s.push(c)
+                    }
+                }
+                StringValue(s)
+            },
+            "boolean"       => BooleanValue(values[0].boolean()),
+            "not"           => BooleanValue(!values[0].boolean()),
+            "true"          => BooleanValue(true),
+            "false"         => BooleanValue(false),
+            "lang"          => BooleanValue(self.is_lang(&focus.node, string_arg(0).as_slice())),
+            "number"        => NumberValue(match values.as_slice().get(0) {
+                Some(value) => value.number(doc),
+                None        => string_to_number(string_value(doc, &focus.node).as_slice())
+            }),
+            "sum"           => match values[0] {
+                NodeSetValue(ref nodes) => NumberValue(nodes.iter().fold(0.0, |sum, node| {
+                    sum + string_to_number(string_value(doc, node).as_slice())
+                })),
+                ref value => return Err(error(NodeSetExpected,
+                                              format!("expected node-set, got {}", value)))
+            },
+            "floor"         => NumberValue(values[0].number(doc).floor()),
+            "ceiling"       => NumberValue(values[0].number(doc).ceil()),
+            _               => NumberValue(round(values[0].number(doc)))
+        };
+        Ok(value)
+    }
+
+    /// Finds elements with `xml:id` among whitespace separated `ids`
+    fn find_ids(&self, ids: &str) -> Vec<XPathNode> {
+        let ids: Vec<&str> = ids.split(|c: char| is_whitespace(c) || c == '\r')
+                                .filter(|id| !id.is_empty())
+                                .collect();
+        let root = self.doc.root();
+        self.doc.descendants(root).filter(|&id| {
+            self.doc.element(id).map_or(false, |elem| elem.attributes.iter().any(|attr| {
+                attr.name.as_slice() == "id" && attr.namespace.uri.as_slice() == XML_NS_URI
+                    && ids.contains(&attr.value.as_slice())
+            }))
+        }).map(|id| DocNode(id)).collect()
+    }
+
+    /// Checks if language in `xml:lang` of `node` or its ancestors is
+    /// `lang` or its sublanguage
+    fn is_lang(&self, node: &XPathNode, lang: &str) -> bool {
+        let id = match *node {
+            DocNode(id) | AttrNode(id, _) | NsNode(id, _) => id
+        };
+        let mut current = Some(id);
+        while current.is_some() {
+            let node = current.unwrap();
+            let found = self.doc.element(node).and_then(|elem| {
+                elem.attributes.iter().find(|attr| {
+                    attr.name.as_slice() == "lang" && attr.namespace.uri.as_slice() == XML_NS_URI
+                })
+            });
+            match found {
+                Some(attr) => {
+                    let value = attr.value.as_slice().to_ascii_lower();
+                    let lang = lang.to_ascii_lower();
+                    return value == lang
+                           || value.as_slice().starts_with(format!("{}-", lang).as_slice())
+                },
+                None => current = self.doc.parent(node)
+            }
+        }
+        false
+    }
+}
+
+/// Returns namespaces in scope of element `id`, sorted by prefix
+fn namespaces(doc: &Document, id: NodeId) -> Vec<XmlNS> {
+    let mut seen = vec!["xml".to_string()];
+    let mut found = vec![XmlNS {
+        name: Name::new("xml"),
+        uri: Name::new(XML_NS_URI)
+    }];
+    let mut current = Some(id);
+    while current.is_some() {
+        let node = current.unwrap();
+        match doc.element(node) {
+            Some(elem) => {
+                for ns in elem.attributes.iter().filter_map(|attr| attr.declared_ns()) {
+                    if seen.iter().any(|prefix| prefix.as_slice() == ns.name.as_slice()) {
+                        continue
+                    }
+                    seen.push(ns.name.as_slice().to_string());
+                    // Empty uri undeclares default namespace
+                    if !ns.uri.as_slice().is_empty() {
+                        found.push(ns);
+                    }
+                }
+            },
+            None => {}
+        }
+        current = doc.parent(node);
+    }
+    found.sort_by(|a, b| a.name.cmp(&b.name));
+    found
+}
+
+/// Returns prefix, local name and namespace uri of `node`,
+/// if it has a name
+fn node_name(doc: &Document, node: &XPathNode) -> Option<(String, String, String)> {
+    match *node {
+        DocNode(id) => match *doc.kind(id) {
+            ElementKind(ref elem) => Some((elem.namespace.name.as_slice().to_string(),
+                                           elem.name.as_slice().to_string(),
+                                           elem.namespace.uri.as_slice().to_string())),
+            PIKind(ref pi) => Some((String::new(), pi.target.clone(), String::new())),
+            _ => None
+        },
+        AttrNode(id, i) => doc.element(id).map(|elem| {
+            let attr = &elem.attributes[i];
+            (attr.namespace.name.as_slice().to_string(),
+             attr.name.as_slice().to_string(),
+             attr.namespace.uri.as_slice().to_string())
+        }),
+        NsNode(id, i) => {
+            let ns = namespaces(doc, id).swap_remove(i).unwrap();
+            Some((String::new(), ns.name.as_slice().to_string(), String::new()))
+        }
+    }
+}
+
+/// Returns string-value of `node`
+fn string_value(doc: &Document, node: &XPathNode) -> String {
+    match *node {
+        DocNode(id) => match *doc.kind(id) {
+            RootKind | ElementKind(_)   => doc.text(id),
+            TextKind(ref s) | CDataKind(ref s) | CommentKind(ref s) => s.clone(),
+            PIKind(ref pi)              => pi.value.clone()
+        },
+        AttrNode(id, i) => doc.element(id).map_or(String::new(), |elem| {
+            elem.attributes[i].value.clone()
+        }),
+        NsNode(id, i) => {
+            namespaces(doc, id).swap_remove(i).unwrap().uri.as_slice().to_string()
+        }
+    }
+}
+
+/// Rounds `n` to closest integer, halves towards positive infinity
+fn round(n: f64) -> f64 {
+    if n.is_nan() || n.is_infinite() {
+        n
+    } else {
+        (n + 0.5).floor()
+    }
+}
+
+/// Converts `n` to a string, without exponent
+fn number_to_string(n: f64) -> String {
+    if n.is_nan() {
+        "NaN".to_string()
+    } else if n.is_infinite() {
+        if n > 0.0 { "Infinity".to_string() } else { "-Infinity".to_string() }
+    } else if n == 0.0 {
+        // Negative zero too
+        "0".to_string()
+    } else if n == n.trunc() {
+        format!("{:.0}", n)
+    } else {
+        format!("{}", n)
+    }
+}
+
+/// Converts string to number, which is NaN unless `s` is an optional
+/// minus followed by digits with optional decimal point
+fn string_to_number(s: &str) -> f64 {
+    let s = s.trim_chars(|c: char| is_whitespace(c) || c == '\r');
+    let (sign, digits) = if s.starts_with("-") {
+        ("-", s.slice_from(1))
+    } else {
+        ("", s)
+    };
+    let (int, frac) = match digits.find('.') {
+        Some(i) => (digits.slice_to(i), digits.slice_from(i + 1)),
+        None    => (digits, "")
+    };
+    let is_valid = !(int.is_empty() && frac.is_empty())
+                   && int.chars().all(is_digit) && frac.chars().all(is_digit);
+    if !is_valid {
+        return f64::NAN
+    }
+    let normalized = format!("{}{}.{}",
+                             sign,
+                             if int.is_empty() { "0" } else { int },
+                             if frac.is_empty() { "0" } else { frac });
+    from_str::<f64>(normalized.as_slice()).unwrap_or(f64::NAN)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{XPath, Context, Value, XPathNode, evaluate};
+    use super::{NodeSetValue, StringValue, NumberValue, BooleanValue};
+    use super::{DocNode, AttrNode, NsNode};
+    use super::{SyntaxError, UnknownFunction, UnboundPrefix, ArgumentError};
+    use super::{number_to_string, string_to_number};
+    use dom::{Document, NodeId, TreePos};
+    use common::{XDoc, XElem, XmlNS, TextNode, ElemNode};
+    use name::Name;
+    use parser::Parser;
+    use source::SliceSource;
+
+    static DOC: &'static str = "<?pi x?>\
+        <config xmlns='urn:c' xmlns:e='urn:e' version='2'>\
+          <db host='localhost' port='5432'>main</db>\
+          <db host='backup' port='5433' xml:lang='en-GB'><!--spare-->second</db>\
+          <e:cache size='64'><e:item>a</e:item><e:item>b</e:item></e:cache>\
+          <plain xmlns=''>x<![CDATA[y]]></plain>\
+        </config>";
+
+    fn parse(input: &str) -> Document {
+        let mut source = SliceSource::from_str(input);
+        let mut parser = Parser::from_reader(&mut source);
+        Document::from_parser(&mut parser)
+    }
+
+    fn context() -> Context {
+        let mut ctx = Context::new();
+        ctx.bind_namespace("c", "urn:c");
+        ctx.bind_namespace("x", "urn:e");
+        ctx
+    }
+
+    fn eval(doc: &Document, expr: &str) -> Value {
+        evaluate(expr, &context(), doc).unwrap()
+    }
+
+    fn eval_string(doc: &Document, expr: &str) -> String {
+        eval(doc, expr).string(doc)
+    }
+
+    fn eval_nodes(doc: &Document, expr: &str) -> Vec<XPathNode> {
+        match eval(doc, expr) {
+            NodeSetValue(nodes) => nodes,
+            value               => {
+                assert!(false, "expected node-set for {}, got {}", expr, value);
+                Vec::new()
+            }
+        }
+    }
+
+    #[test]
+    fn test_paths() {
+        let doc = parse(DOC);
+        assert_eq!(2, eval_nodes(&doc, "/c:config/c:db").len());
+        assert_eq!("second".to_string(), eval_string(&doc, "/c:config/c:db[2]/text()"));
+        assert_eq!("backup".to_string(), eval_string(&doc, "//c:db[@port > 5432]/@host"));
+        assert_eq!("main".to_string(), eval_string(&doc, "//c:db[@host='localhost']"));
+        assert_eq!("b".to_string(), eval_string(&doc, "//x:item[last()]"));
+        assert_eq!("ab".to_string(), eval_string(&doc, "/*/x:cache"));
+        assert_eq!(2, eval_nodes(&doc, "/c:config/x:*/*").len());
+        // Unprefixed names are in no namespace
+        assert_eq!(0, eval_nodes(&doc, "//db").len());
+        assert_eq!("xy".to_string(), eval_string(&doc, "//plain"));
+        assert_eq!(2, eval_nodes(&doc, "//plain/text()").len());
+        assert_eq!(vec![DocNode(NodeId(0))], eval_nodes(&doc, "/"));
+        assert_eq!("x".to_string(), eval_string(&doc, "/processing-instruction('pi')"));
+        assert_eq!(1, eval_nodes(&doc, "//comment()").len());
+        assert_eq!("2".to_string(), eval_string(&doc, "//c:db/../@version"));
+        assert_eq!(4, eval_nodes(&doc, "//c:db | //x:item | //c:db[1]").len());
+        assert_eq!("b".to_string(), eval_string(&doc, "(//x:item)[2]"));
+        assert_eq!("5433".to_string(), eval_string(&doc, "//c:db[2]/attribute::port"));
+    }
+
+    #[test]
+    fn test_axes() {
+        let doc = parse(DOC);
+        assert_eq!("backup".to_string(),
+                   eval_string(&doc, "//c:db[1]/following-sibling::*[1]/@host"));
+        assert_eq!("main".to_string(),
+                   eval_string(&doc, "//x:cache/preceding-sibling::c:db[last()]"));
+        assert_eq!("second".to_string(),
+                   eval_string(&doc, "//x:item[1]/preceding::c:db[1]"));
+        assert_eq!("b".to_string(), eval_string(&doc, "//x:item[1]/following::*[1]"));
+        assert_eq!("a".to_string(), eval_string(&doc, "//x:cache/@size/following::x:item"));
+        assert_eq!(3, eval_nodes(&doc, "//x:item[2]/ancestor-or-self::*").len());
+        assert_eq!("config".to_string(),
+                   eval_string(&doc, "local-name(//x:item/ancestor::*[last()])"));
+        assert_eq!(4, eval_nodes(&doc, "/c:config/descendant::*[not(self::x:item)]").len());
+        // `e`, default and `xml`
+        let ns = eval_nodes(&doc, "//x:item[1]/namespace::*");
+        assert_eq!(3, ns.len());
+        assert!(ns.iter().all(|node| match *node { NsNode(..) => true, _ => false }));
+        assert_eq!("urn:e".to_string(), eval_string(&doc, "//x:item[1]/namespace::e"));
+        assert_eq!(2, eval_nodes(&doc, "//plain/namespace::*").len());
+        match eval_nodes(&doc, "//c:db[1]/@*").as_slice() {
+            [AttrNode(_, 0), AttrNode(_, 1)] => {},
+            nodes => assert!(false, "expected attributes, got {}", nodes)
+        }
+    }
+
+    #[test]
+    fn test_functions() {
+        let doc = parse(DOC);
+        assert_eq!(NumberValue(2.0), eval(&doc, "count(//c:db)"));
+        assert_eq!(NumberValue(10865.0), eval(&doc, "sum(//c:db/@port)"));
+        assert_eq!("e:item".to_string(), eval_string(&doc, "name(//x:item)"));
+        assert_eq!("urn:e".to_string(), eval_string(&doc, "namespace-uri(//x:item)"));
+        assert_eq!("main:5432".to_string(),
+                   eval_string(&doc, "concat(//c:db, ':', //c:db/@port)"));
+        assert_eq!(BooleanValue(true), eval(&doc, "starts-with(//c:db/@host, 'local')"));
+        assert_eq!(BooleanValue(true), eval(&doc, "contains('localhost', 'alh')"));
+        assert_eq!("1999".to_string(), eval_string(&doc, "substring-before('1999/04/01', '/')"));
+        assert_eq!("04/01".to_string(), eval_string(&doc, "substring-after('1999/04/01', '/')"));
+        assert_eq!("234".to_string(), eval_string(&doc, "substring('12345', 1.5, 2.6)"));
+        assert_eq!("12".to_string(), eval_string(&doc, "substring('12345', 0, 3)"));
+        assert_eq!("".to_string(), eval_string(&doc, "substring('12345', 0 div 0, 3)"));
+        assert_eq!(NumberValue(6.0), eval(&doc, "string-length('ab\u0107def')"));
+        assert_eq!("a b".to_string(), eval_string(&doc, "normalize-space('  a \n\t b ')"));
+        assert_eq!("BAr".to_string(), eval_string(&doc, "translate('bar', 'abc-', 'ABC')"));
+        assert_eq!(BooleanValue(true), eval(&doc, "//c:db[lang('en')]/@port = 5433"));
+        assert_eq!(BooleanValue(false), eval(&doc, "lang('en')"));
+        assert_eq!(NumberValue(-2.0), eval(&doc, "floor(-1.5)"));
+        assert_eq!(NumberValue(2.0), eval(&doc, "ceiling(1.2)"));
+        assert_eq!(NumberValue(-1.0), eval(&doc, "round(-1.5)"));
+        assert_eq!(BooleanValue(true), eval(&doc, "boolean(//c:db) and not(false()) or true()"));
+
+        let doc = parse("<a xml:id='x'><b xml:id='y'/><c xml:id='z'>x y</c></a>");
+        assert_eq!(2, eval_nodes(&doc, "id('y z')").len());
+        assert_eq!(2, eval_nodes(&doc, "id(//c)").len());
+    }
+
+    #[test]
+    fn test_operators() {
+        let doc = parse(DOC);
+        assert_eq!(NumberValue(7.0), eval(&doc, "1 + 2 * 3"));
+        assert_eq!(NumberValue(-1.0), eval(&doc, "5 mod 3 - 3"));
+        assert_eq!(NumberValue(2.5), eval(&doc, "5 div 2"));
+        assert_eq!(NumberValue(-2.0), eval(&doc, "- -(-2)"));
+        assert_eq!("Infinity".to_string(), eval_string(&doc, "1 div 0"));
+        assert_eq!("NaN".to_string(), eval_string(&doc, "number('1e3')"));
+        // `div` and `*` as names
+        assert_eq!(NumberValue(0.0), eval(&doc, "count(div) * count(*/div)"));
+        assert_eq!(BooleanValue(true), eval(&doc, "//c:db/@port = 5433"));
+        assert_eq!(BooleanValue(true), eval(&doc, "//c:db/@port != 5433"));
+        assert_eq!(BooleanValue(true), eval(&doc, "//c:db/@host = //c:db[2]/@host"));
+        assert_eq!(BooleanValue(true), eval(&doc, "5432 < //c:db/@port"));
+        // Empty node-set equals no string, but equals false
+        assert_eq!(BooleanValue(false), eval(&doc, "//missing != 'x'"));
+        assert_eq!(BooleanValue(true), eval(&doc, "//missing = false()"));
+        assert_eq!(BooleanValue(true), eval(&doc, "'2' = 2.0 and true() = 'x'"));
+    }
+
+    #[test]
+    fn test_context() {
+        let doc = parse(DOC);
+        let mut ctx = context();
+        ctx.set_variable("port", NumberValue(5433.0));
+        ctx.set_variable("hosts", eval(&doc, "//c:db/@host"));
+        let path = XPath::compile("//c:db[@port = $port]/@host").unwrap();
+        let host = path.evaluate(&ctx, &doc, doc.root()).unwrap();
+        assert_eq!("backup".to_string(), host.string(&doc));
+        let path = XPath::compile("count($hosts)").unwrap();
+        assert_eq!(NumberValue(2.0), path.evaluate(&ctx, &doc, doc.root()).unwrap());
+
+        // Relative to element
+        let db = match eval_nodes(&doc, "//c:db[2]").as_slice() {
+            [DocNode(id)]   => id,
+            nodes           => {
+                assert!(false, "expected one element, got {}", nodes);
+                doc.root()
+            }
+        };
+        let path = XPath::compile("string(@port) = '5433' and position() = 1").unwrap();
+        assert_eq!(BooleanValue(true), path.evaluate(&ctx, &doc, db).unwrap());
+        let path = XPath::compile("comment()").unwrap();
+        assert_eq!("spare".to_string(), path.evaluate(&ctx, &doc, db).unwrap().string(&doc));
+    }
+
+    #[test]
+    fn test_errors() {
+        let doc = parse(DOC);
+        let err = XPath::compile("//c:db[").unwrap_err();
+        assert_eq!(SyntaxError, err.kind);
+        assert_eq!(Some(7), err.pos);
+        assert_eq!(Some(2), XPath::compile("a b").unwrap_err().pos);
+        assert_eq!(SyntaxError, XPath::compile("bogus::a").unwrap_err().kind);
+        assert_eq!(SyntaxError, XPath::compile("'a").unwrap_err().kind);
+        assert_eq!(SyntaxError, XPath::compile("1..2").unwrap_err().kind);
+
+        let ctx = Context::new();
+        assert_eq!(UnboundPrefix, evaluate("//c:db", &ctx, &doc).unwrap_err().kind);
+        assert_eq!(UnknownFunction, evaluate("f()", &ctx, &doc).unwrap_err().kind);
+        assert_eq!(ArgumentError, evaluate("not()", &ctx, &doc).unwrap_err().kind);
+    }
+
+    #[test]
+    fn test_xdoc() {
+        let ns = XmlNS { name: Name::new(""), uri: Name::new("") };
+        let mut root = XElem::new(ns.clone(), "a");
+        root.append_child(TextNode("x".to_string()));
+        for value in ["1", "2"].iter() {
+            let mut child = XElem::new(ns.clone(), "b");
+            child.set_attr("v", *value);
+            root.append_child(ElemNode(child));
+        }
+        let mut xdoc = XDoc::new(root);
+
+        let doc = Document::from_xdoc(&xdoc);
+        assert_eq!(vec![DocNode(NodeId(4))], eval_nodes(&doc, "/a/b[@v='2']"));
+        assert_eq!(Some(TreePos(vec![2])), doc.xdoc_pos(NodeId(4)));
+        xdoc.root_mut().remove_child(2);
+        assert_eq!(2, xdoc.root().children.len());
+    }
+
+    #[test]
+    fn test_numbers() {
+        assert_eq!("1".to_string(),     number_to_string(1.0));
+        assert_eq!("-0.5".to_string(),  number_to_string(-0.5));
+        assert_eq!("0".to_string(),     number_to_string(-0.0));
+        assert_eq!(12.5,                string_to_number(" 12.5\n"));
+        assert_eq!(-0.5,                string_to_number("-.5"));
+        assert_eq!(3.0,                 string_to_number("3."));
+        assert!(string_to_number("").is_nan());
+        assert!(string_to_number("+1").is_nan());
+        assert!(string_to_number(".").is_nan());
+    }
+}
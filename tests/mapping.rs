@@ -0,0 +1,74 @@
+// Crate linkage metadata
+#![crate_name = "mapping"]
+#![crate_type="bin"]
+
+#![feature(phase)]
+
+#[phase(plugin)]
+extern crate xml_air_macros;
+extern crate xml_air;
+
+use xml_air::mapping::{from_str, to_string};
+use xml_air::common::{MissingField, InvalidValue};
+
+#[derive(FromXml, ToXml)]
+#[deriving(PartialEq, Show)]
+struct Feed {
+    #[xml(attr = "version")]
+    version: uint,
+    #[xml(attr, ns = "http://www.w3.org/1999/xlink")]
+    href: Option<String>,
+    #[xml(child = "title")]
+    title: String,
+    #[xml(child = "entry", ns = "http://www.w3.org/2005/Atom")]
+    entries: Vec<Entry>,
+    updated: Option<String>
+}
+
+#[derive(FromXml, ToXml)]
+#[deriving(PartialEq, Show)]
+struct Entry {
+    #[xml(attr)]
+    id: u32,
+    #[xml(text)]
+    summary: String
+}
+
+fn feed() -> Feed {
+    Feed {
+        version: 2,
+        href: Some("/feed".to_string()),
+        title: "News".to_string(),
+        entries: vec![Entry { id: 1, summary: "First".to_string() }],
+        updated: None
+    }
+}
+
+#[test]
+fn test_derived_from_xml() {
+    let input = "<feed version='2' xmlns:l='http://www.w3.org/1999/xlink' l:href='/feed'>\
+                 <title>News</title>\
+                 <entry xmlns='http://www.w3.org/2005/Atom' id='1'>First</entry></feed>";
+    assert_eq!(Ok(feed()), from_str(input));
+}
+
+#[test]
+fn test_derived_round_trip() {
+    let xml = to_string(&feed(), "feed");
+    assert_eq!(Ok(feed()), from_str(xml.as_slice()));
+}
+
+#[test]
+fn test_derived_errors() {
+    let input = "<feed version='2'>\n<title>News</title>\n\
+                 <a:entry xmlns:a='http://www.w3.org/2005/Atom'>First</a:entry>\n</feed>";
+    let err = from_str::<Feed>(input).err().expect("expected error");
+    assert_eq!(MissingField, err.kind);
+    assert_eq!("missing attribute 'id'", err.msg.as_slice());
+    assert_eq!((3, 1, 39), (err.line, err.col, err.offset));
+
+    let input = "<feed version='two'><title/></feed>";
+    let err = from_str::<Feed>(input).err().expect("expected error");
+    assert_eq!(InvalidValue, err.kind);
+    assert_eq!((1, 1, 0), (err.line, err.col, err.offset));
+}
@@ -0,0 +1,219 @@
+//! Data driven conformance harness.
+//!
+//! Every `*.xml` file in `tests/xml-er` is run through the parser and
+//! the resulting event stream is compared, line by line, with the
+//! expected stream stored next to it in a `*.out` file. Cases the parser
+//! can't handle yet are listed in `tests/xml-er/PENDING`; they are still
+//! run, but only reported.
+//!
+//! Tests of the catalog in `tests/xmlconf`, laid out as the W3C XML
+//! Conformance Test Suite, are checked for well-formedness only. It holds
+//! a pinned subset of documents without a DOCTYPE; catalogs of the full
+//! suite can be added to `xmlconf.xml` as further entities.
+
+use std::io::{BufferedReader, File};
+use std::io::fs::{readdir, PathExtensions};
+
+use xml::common::XElem;
+use xml::parser::{Parser, XmlEvent};
+use xml::parser::{DeclEvent, ElemStart, ElemEnd, EmptyElem, PIEvent};
+use xml::parser::{TextEvent, CDataEvent, CommentEvent, ErrEvent};
+
+static XML_ER_DIR: &'static str = "tests/xml-er";
+static XMLCONF_DIR: &'static str = "tests/xmlconf";
+/// Upper bound on events pulled from a single case, so a state that
+/// never advances can't hang the test run.
+static MAX_EVENTS: uint = 10000;
+
+/// Joins `prefix` and `name` the way they were written
+fn qualified(prefix: &str, name: &str) -> String {
+    if prefix.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}:{}", prefix, name)
+    }
+}
+
+/// Renders name and attributes of an element
+fn describe_elem(elem: &XElem) -> String {
+    let mut out = qualified(elem.namespace.name.as_slice(), elem.name.as_slice());
+    for attr in elem.attributes.iter() {
+        out.push_str(format!(" {}=\"{}\"",
+                             qualified(attr.namespace.name.as_slice(), attr.name.as_slice()),
+                             attr.value.escape_default()).as_slice());
+    }
+    out
+}
+
+/// Renders a single event in the format used by `*.out` files.
+fn describe(event: &XmlEvent) -> String {
+    match *event {
+        DeclEvent(ref decl)     => format!("decl \"{}\"", decl.version.escape_default()),
+        ElemStart(ref elem)     => format!("start {}", describe_elem(elem)),
+        ElemEnd(ref name)       => format!("end {}", name),
+        EmptyElem(ref elem)     => format!("empty {}", describe_elem(elem)),
+        PIEvent(ref pi)         => format!("pi {} \"{}\"", pi.target, pi.value.escape_default()),
+        TextEvent(ref text)     => format!("text \"{}\"", text.as_slice().escape_default()),
+        CDataEvent(ref text)    => format!("cdata \"{}\"", text.as_slice().escape_default()),
+        CommentEvent(ref text)  => format!("comment \"{}\"", text.as_slice().escape_default()),
+        ErrEvent(ref err)       => format!("error {}", err.kind),
+    }
+}
+
+/// Pulls every event out of the file at `path` and renders them.
+fn run_case(path: &Path) -> Vec<String> {
+    let file = File::open(path).unwrap();
+    let mut reader = BufferedReader::new(file);
+    let mut parser = Parser::from_reader(&mut reader);
+    let mut out = Vec::new();
+
+    loop {
+        match parser.pull() {
+            Some(ev) => {
+                out.push(describe(&ev));
+                if out.len() >= MAX_EVENTS {
+                    break
+                }
+            },
+            None => break
+        }
+    }
+    out
+}
+
+fn read_lines(path: &Path) -> Vec<String> {
+    let contents = File::open(path).read_to_string().unwrap();
+    contents.as_slice().lines()
+            .map(|l| l.trim_right().to_string())
+            .filter(|l| !l.is_empty())
+            .collect()
+}
+
+fn pending_cases(dir: &Path) -> Vec<String> {
+    let path = dir.join("PENDING");
+    if path.exists() {
+        read_lines(&path)
+    } else {
+        Vec::new()
+    }
+}
+
+#[test]
+fn test_xml_er_corpus() {
+    let dir = Path::new(XML_ER_DIR);
+    let pending = pending_cases(&dir);
+    let mut cases = readdir(&dir).unwrap();
+    cases.sort();
+
+    let mut failed = Vec::new();
+    let mut fixed = Vec::new();
+
+    for case in cases.iter().filter(|p| p.extension_str() == Some("xml")) {
+        let name = case.filestem_str().unwrap().to_string();
+        let expected = read_lines(&case.with_extension("out"));
+        let actual = run_case(case);
+        let is_pending = pending.contains(&name);
+
+        if actual != expected && !is_pending {
+            failed.push(format!("{}: expected {}, got {}", name, expected, actual));
+        } else if actual == expected && is_pending {
+            fixed.push(name);
+        }
+    }
+
+    assert!(fixed.is_empty(), "passing cases still marked PENDING: {}", fixed);
+    assert!(failed.is_empty(), "failing cases:\n{}", failed.as_slice().connect("\n"));
+}
+
+/// A test from the W3C catalog, reduced to what a
+/// non-validating parser can check.
+struct W3cCase {
+    id: String,
+    uri: Path,
+    not_wf: bool
+}
+
+/// Returns value of the pseudo-attribute `name` in tag contents `tag`.
+fn attr_value<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let pat = format!(" {}=\"", name);
+    tag.find_str(pat.as_slice()).and_then(|start| {
+        let rest = tag.slice_from(start + pat.len());
+        rest.find('"').map(|end| rest.slice_to(end))
+    })
+}
+
+/// Returns system literal of the external ID in entity declaration `decl`
+fn system_literal<'a>(decl: &'a str) -> Option<&'a str> {
+    decl.find_str(" SYSTEM").and_then(|start| {
+        let rest = decl.slice_from(start + " SYSTEM".len()).trim_left();
+        let quote = match rest.chars().next() {
+            Some(c) if c == '"' || c == '\'' => c,
+            _                               => return None
+        };
+        let rest = rest.slice_from(1);
+        rest.find(quote).map(|end| rest.slice_to(end))
+    })
+}
+
+/// Collects `<TEST>` entries of a catalog, descending into sub-catalogs
+/// declared as external entities, as done by `xmlconf.xml`.
+///
+/// The catalogs are scanned textually rather than with our own parser,
+/// so a parser bug can't make tests silently disappear.
+fn collect_w3c(catalog: &Path, cases: &mut Vec<W3cCase>) {
+    let contents = File::open(catalog).read_to_string().unwrap();
+    let text = contents.as_slice();
+    let base = catalog.dir_path();
+
+    for decl in text.split_str("<!ENTITY").skip(1) {
+        let decl = decl.slice_to(decl.find('>').unwrap_or(decl.len()));
+        match system_literal(decl) {
+            Some(sub) if !sub.ends_with(".dtd") => {
+                collect_w3c(&base.join(sub), cases)
+            },
+            _ => {}
+        }
+    }
+
+    for test in text.split_str("<TEST").skip(1) {
+        let tag = test.slice_to(test.find('>').unwrap_or(test.len()));
+        let (id, uri, kind) = match (attr_value(tag, "ID"),
+                                     attr_value(tag, "URI"),
+                                     attr_value(tag, "TYPE")) {
+            (Some(id), Some(uri), Some(kind)) => (id, uri, kind),
+            _ => continue
+        };
+        // Tests for other editions or namespace-unaware
+        // parsers don't apply to us
+        if attr_value(tag, "NAMESPACE") == Some("no") {
+            continue
+        }
+        cases.push(W3cCase {
+            id: id.to_string(),
+            uri: base.join(uri),
+            not_wf: kind == "not-wf"
+        });
+    }
+}
+
+#[test]
+fn test_w3c_conformance() {
+    let catalog = Path::new(XMLCONF_DIR).join("xmlconf.xml");
+    assert!(catalog.exists(), "{} not found", catalog.display());
+
+    let mut cases = Vec::new();
+    collect_w3c(&catalog, &mut cases);
+    assert!(!cases.is_empty(), "no tests in {}", catalog.display());
+
+    let mut failed = Vec::new();
+    for case in cases.iter() {
+        let events = run_case(&case.uri);
+        let has_error = events.iter().any(|e| e.as_slice().starts_with("error"));
+        if has_error != case.not_wf {
+            failed.push(case.id.clone());
+        }
+    }
+
+    assert!(failed.is_empty(), "{} of {} W3C tests failed: {}",
+            failed.len(), cases.len(), failed);
+}
@@ -6,3 +6,5 @@ extern crate "xml_air" as xml;
 
 #[cfg(test)]
 mod test_parser;
+#[cfg(test)]
+mod test_conformance;
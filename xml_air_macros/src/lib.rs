@@ -0,0 +1,254 @@
+// Crate linkage metadata
+#![crate_name = "xml_air_macros"]
+
+
+//Metadata
+#![comment = "Derived mapping of structs to XML elements, for xml_air"]
+#![license = "MIT/LGPL"]
+#![crate_type = "dylib"]
+
+#![feature(plugin_registrar, quote, globs)]
+
+//! Compiler plugin deriving `FromXml` and `ToXml` of `xml_air::mapping`
+//! for structs, with `#[derive(FromXml, ToXml)]`. See `mapping` module
+//! for how fields are mapped by their `#[xml(...)]` attributes.
+//!
+//! Generated impls name items as `::xml_air::...`, so the crate using
+//! the plugin has to link `xml_air` under that name.
+
+extern crate rustc;
+extern crate syntax;
+
+use rustc::plugin::Registry;
+use syntax::ast::{Item, ItemStruct, MetaItem, MetaWord, MetaList, MetaNameValue};
+use syntax::ast::{StructField, NamedField, UnnamedField, Ident, TyPath, LitStr};
+use syntax::attr::{AttrMetaMethods, mark_used};
+use syntax::codemap::Span;
+use syntax::ext::base::{ExtCtxt, ItemDecorator};
+use syntax::ext::build::AstBuilder;
+use syntax::parse::token;
+use syntax::ptr::P;
+
+/// What a field is read from
+#[deriving(PartialEq)]
+enum Kind {
+    Attr,
+    Text,
+    Child
+}
+
+/// How many values a field holds, by its type
+#[deriving(PartialEq)]
+enum Arity {
+    One,
+    /// `Option` field
+    Maybe,
+    /// `Vec` field
+    Many
+}
+
+/// Field of a struct, and where it's mapped to
+struct Field {
+    ident: Ident,
+    kind: Kind,
+    arity: Arity,
+    /// Local name of attribute or child element
+    name: String,
+    /// Namespace of attribute or child element
+    ns: String
+}
+
+#[plugin_registrar]
+pub fn registrar(reg: &mut Registry) {
+    reg.register_syntax_extension(token::intern("derive"), ItemDecorator(box expand_derive));
+}
+
+/// Expands `#[derive(FromXml, ToXml)]` on a struct into impls
+/// of those traits
+fn expand_derive(cx: &mut ExtCtxt, span: Span, meta: &MetaItem, item: &Item,
+                 push: |P<Item>|) {
+    let traits = match meta.node {
+        MetaList(_, ref traits) if !traits.is_empty() => traits,
+        _ => return cx.span_err(span, "expected #[derive(FromXml, ToXml)]")
+    };
+    let fields = match item.node {
+        ItemStruct(ref def, ref generics) if !generics.is_parameterized() => {
+            def.fields.iter().filter_map(|field| read_field(cx, field)).collect::<Vec<Field>>()
+        },
+        _ => {
+            return cx.span_err(item.span, "FromXml and ToXml can only be derived \
+                                           for structs without type parameters")
+        }
+    };
+    for t in traits.iter() {
+        match t.node {
+            MetaWord(ref name) if name.get() == "FromXml" => {
+                push(from_xml_impl(cx, span, item.ident, fields.as_slice()))
+            },
+            MetaWord(ref name) if name.get() == "ToXml" => {
+                push(to_xml_impl(cx, span, item.ident, fields.as_slice()))
+            },
+            _ => cx.span_err(t.span, "only FromXml and ToXml can be derived")
+        }
+    }
+}
+
+/// Reads mapping of `field` from its `#[xml(...)]` attributes. Fields
+/// without them are child elements named as the field.
+fn read_field(cx: &mut ExtCtxt, field: &StructField) -> Option<Field> {
+    let ident = match field.node.kind {
+        NamedField(ident, _)    => ident,
+        UnnamedField(_)         => {
+            cx.span_err(field.span, "fields without names can't be mapped to XML");
+            return None
+        }
+    };
+    let mut kind = None;
+    let mut name = token::get_ident(ident).get().to_string();
+    let mut ns = String::new();
+    for attr in field.node.attrs.iter().filter(|attr| attr.check_name("xml")) {
+        mark_used(attr);
+        let items = match attr.meta_item_list() {
+            Some(items) => items,
+            None        => {
+                cx.span_err(attr.span, "expected #[xml(attr)], #[xml(text)] or #[xml(child)]");
+                continue
+            }
+        };
+        for meta in items.iter() {
+            let (key, value) = match meta.node {
+                MetaWord(ref key)               => (key.get(), None),
+                MetaNameValue(ref key, ref lit) => match lit.node {
+                    LitStr(ref value, _)    => (key.get(), Some(value.get().to_string())),
+                    _                       => {
+                        cx.span_err(lit.span, "expected a string");
+                        continue
+                    }
+                },
+                MetaList(..)                    => {
+                    cx.span_err(meta.span, "expected attr, text, child or ns");
+                    continue
+                }
+            };
+            let field_kind = match key {
+                "attr"  => Attr,
+                "text"  => Text,
+                "child" => Child,
+                "ns"    => match value {
+                    Some(value) => {
+                        ns = value;
+                        continue
+                    },
+                    None        => {
+                        cx.span_err(meta.span, "expected ns = \"uri\"");
+                        continue
+                    }
+                },
+                _       => {
+                    cx.span_err(meta.span, "expected attr, text, child or ns");
+                    continue
+                }
+            };
+            if kind.is_some() && kind != Some(field_kind) {
+                cx.span_err(meta.span, "field can be only one of attr, text or child");
+            }
+            match value {
+                Some(_) if field_kind == Text   => cx.span_err(meta.span, "text has no name"),
+                Some(value)                     => name = value,
+                None                            => {}
+            }
+            kind = Some(field_kind);
+        }
+    }
+    let kind = kind.unwrap_or(Child);
+
+    let arity = match field.node.ty.node {
+        TyPath(ref path, _, _) => match path.segments.last() {
+            Some(segment) => match token::get_ident(segment.identifier).get() {
+                "Option"    => Maybe,
+                "Vec"       => Many,
+                _           => One
+            },
+            None => One
+        },
+        _ => One
+    };
+    if arity == Many && kind != Child {
+        cx.span_err(field.span, "only child elements can be read into Vec fields");
+    }
+
+    Some(Field {
+        ident: ident,
+        kind: kind,
+        arity: arity,
+        name: name,
+        ns: ns
+    })
+}
+
+/// Returns name of function in `xml_air::mapping` reading or writing
+/// `field`, prefixed with `prefix`
+fn mapping_fn(cx: &ExtCtxt, prefix: &str, field: &Field) -> Ident {
+    let kind = match field.kind {
+        Attr    => "attr",
+        Text    => "text",
+        Child   => "child"
+    };
+    let name = match field.arity {
+        One     => format!("{}_{}", prefix, kind),
+        Maybe   => format!("{}_{}_opt", prefix, kind),
+        Many    => format!("{}_children", prefix)
+    };
+    cx.ident_of(name.as_slice())
+}
+
+fn from_xml_impl(cx: &mut ExtCtxt, span: Span, name: Ident, fields: &[Field]) -> P<Item> {
+    let values = fields.iter().map(|field| {
+        let read = mapping_fn(cx, "read", field);
+        let value = match field.kind {
+            Text    => quote_expr!(cx, try!(::xml_air::mapping::$read(elem, pos))),
+            _       => {
+                let ns = field.ns.as_slice();
+                let local = field.name.as_slice();
+                quote_expr!(cx, try!(::xml_air::mapping::$read(elem, pos, $ns, $local)))
+            }
+        };
+        cx.field_imm(span, field.ident, value)
+    }).collect();
+    let value = cx.expr_struct_ident(span, name, values);
+    let body = cx.expr_ok(span, value);
+    quote_item!(cx,
+        impl ::xml_air::mapping::FromXml for $name {
+            fn from_xml(elem: &::xml_air::common::XElem, pos: &::xml_air::mapping::TagPos)
+                        -> Result<$name, ::xml_air::common::XmlError> {
+                $body
+            }
+        }
+    ).unwrap()
+}
+
+fn to_xml_impl(cx: &mut ExtCtxt, span: Span, name: Ident, fields: &[Field]) -> P<Item> {
+    let mut stmts = vec![quote_stmt!(cx, let mut elem = ::xml_air::mapping::new_elem(uri, local))];
+    for field in fields.iter() {
+        let write = mapping_fn(cx, "write", field);
+        let ident = field.ident;
+        stmts.push(match field.kind {
+            Text    => quote_stmt!(cx, ::xml_air::mapping::$write(&mut elem, &self.$ident)),
+            _       => {
+                let ns = field.ns.as_slice();
+                let local = field.name.as_slice();
+                quote_stmt!(cx, ::xml_air::mapping::$write(&mut elem, $ns, $local,
+                                                           &self.$ident))
+            }
+        });
+    }
+    let elem = quote_expr!(cx, elem);
+    let body = cx.expr_block(cx.block(span, stmts, Some(elem)));
+    quote_item!(cx,
+        impl ::xml_air::mapping::ToXml for $name {
+            fn to_xml(&self, uri: &str, local: &str) -> ::xml_air::common::XElem {
+                $body
+            }
+        }
+    ).unwrap()
+}